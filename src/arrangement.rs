@@ -0,0 +1,617 @@
+use std::collections::BTreeMap;
+use std::str::FromStr;
+
+use midly::{MetaMessage, Smf};
+use serde::Deserialize;
+
+use crate::dsl::dsl::Groups;
+use crate::dsl::version::DslVersion;
+use crate::error::PolyError;
+use crate::midi::core::{
+    drums_track_header, end_tracks, time_signature_event, tracks_to_smf, write_events, DrumMap, DrumPart, EventGrid,
+    MidiTempo, Tick,
+};
+use crate::midi::markers::Marker;
+use crate::midi::time::TimeSignature;
+use crate::midi::timeline::{Ducking, Timeline};
+use crate::midi::transform::Crashes;
+
+static DEFAULT_TEMPO: u16 = 120;
+static DEFAULT_TIME_SIGNATURE: &str = "4/4";
+
+/// A part of the song with its own groove, e.g. a verse or a chorus.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Section {
+    pub parts: BTreeMap<DrumPart, Groups>,
+    /// Played over the groove in the last bar of the section, if there's one.
+    pub fill: BTreeMap<DrumPart, Groups>,
+    /// What the groove does under the fill. The parts the fill plays are always left out of it.
+    pub ducking: Ducking,
+    /// Bars of the section played from other sections instead, counting from 1: the bar of the section along with
+    /// the section it's taken from and the bar of that one.
+    pub bars: BTreeMap<u32, (String, u32)>,
+    pub tempo: u16,
+    pub time_signature: TimeSignature,
+}
+
+/// The whole song: named sections and the order they're played in.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Arrangement {
+    pub sections: BTreeMap<String, Section>,
+    /// Section names along with the number of times the section is repeated.
+    pub order: Vec<(String, u32)>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ArrangementFile {
+    version: Option<u32>,
+    order: Vec<OrderEntry>,
+    sections: BTreeMap<String, SectionFile>,
+    timekeeper: Option<TimekeeperFile>,
+    duck: Option<String>,
+}
+
+/// A pattern keeping time for the whole song, moved from one part to the next for every section.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TimekeeperFile {
+    pattern: String,
+    rotate: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum OrderEntry {
+    Once(String),
+    Repeated { section: String, repeat: u32 },
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+struct SectionFile {
+    /// The section everything this one doesn't set is taken from.
+    from: Option<String>,
+    tempo: Option<u16>,
+    time_signature: Option<String>,
+    #[serde(default)]
+    parts: BTreeMap<String, String>,
+    /// `None` if the section doesn't set it, so that `fill = {}` leaves out the fill of the section it's from.
+    fill: Option<BTreeMap<String, String>>,
+    /// The part playing the timekeeper in this section, instead of the next one of the rotation.
+    timekeeper: Option<String>,
+    duck: Option<String>,
+    #[serde(default)]
+    bars: BTreeMap<String, String>,
+}
+
+/// The section with what it doesn't set taken from the section it's `from`, and from the one that one is from. Parts
+/// and bars are added to the ones of that section, replacing the ones it has.
+fn resolve(name: &str, files: &BTreeMap<String, SectionFile>, seen: &mut Vec<String>) -> Result<SectionFile, String> {
+    let file = files.get(name).ok_or_else(|| format!("Unknown section '{}'", name))?;
+    let Some(from) = &file.from else { return Ok(file.clone()) };
+    if seen.iter().any(|s| s == name) {
+        return Err(format!("Section '{}' is copied from itself", name));
+    }
+    seen.push(name.to_string());
+    let base = resolve(from, files, seen)?;
+    let mut parts = base.parts;
+    parts.extend(file.parts.clone());
+    let mut bars = base.bars;
+    bars.extend(file.bars.clone());
+    Ok(SectionFile {
+        from: None,
+        tempo: file.tempo.or(base.tempo),
+        time_signature: file.time_signature.clone().or(base.time_signature),
+        parts,
+        fill: file.fill.clone().or(base.fill),
+        timekeeper: file.timekeeper.clone().or(base.timekeeper),
+        duck: file.duck.clone().or(base.duck),
+        bars,
+    })
+}
+
+/// Reads the bars of a section taken from other sections, e.g. `4 = "fill"` for the first bar of the `fill` section
+/// as the fourth bar, or `4 = "verse:2"` for the second bar of the verse.
+fn parse_bars(section: &str, bars: &BTreeMap<String, String>) -> Result<BTreeMap<u32, (String, u32)>, String> {
+    let bar_number = |s: &str| u32::from_str(s).ok().filter(|bar| *bar > 0);
+    bars.iter()
+        .map(|(bar, reference)| {
+            let bar = bar_number(bar).ok_or_else(|| format!("Section '{}': '{}' isn't a bar number", section, bar))?;
+            let (from, from_bar) = match reference.rsplit_once(':') {
+                Some((from, from_bar)) => (from, bar_number(from_bar)),
+                None => (reference.as_str(), Some(1)),
+            };
+            match from_bar {
+                Some(from_bar) => Ok((bar, (from.to_string(), from_bar))),
+                None => Err(format!("Section '{}': '{}' isn't a bar of a section", section, reference)),
+            }
+        })
+        .collect()
+}
+
+fn parse_parts(
+    version: DslVersion,
+    section: &str,
+    parts: &BTreeMap<String, String>,
+) -> Result<BTreeMap<DrumPart, Groups>, String> {
+    parts
+        .iter()
+        .map(|(part, pattern)| {
+            let part = DrumPart::from_str(part).map_err(|e| format!("Section '{}': {}", section, e))?;
+            match version.parse(pattern) {
+                Ok(groups) => Ok((part, groups)),
+                Err(e) => Err(format!("Section '{}', {}: {}", section, part.name(), e)),
+            }
+        })
+        .collect()
+}
+
+impl FromStr for Arrangement {
+    type Err = String;
+
+    /// Reads an arrangement from TOML:
+    ///
+    /// ```toml
+    /// version = 1
+    /// order = ["verse", { section = "chorus", repeat = 2 }]
+    ///
+    /// [sections.verse]
+    /// tempo = 120
+    /// time-signature = "4/4"
+    /// parts = { kick = "8x-x-", hi-hat = "8x" }
+    /// fill = { snare = "16xxxx", tom3 = "16----xxxx" }
+    ///
+    /// [sections.chorus]
+    /// parts = { kick = "4x", snare = "4-x" }
+    ///
+    /// [timekeeper]
+    /// pattern = "8x"
+    /// rotate = ["hi-hat", "ride"]
+    /// ```
+    ///
+    /// `version` is the version of the DSL the patterns are written in, 1 if omitted. The `timekeeper` pattern is
+    /// added to every section on the next part of `rotate`, in the order the sections are first played, so the verse
+    /// gets it on the hi-hat and the chorus on the ride. A section with `timekeeper = "ride"` gets it on that part
+    /// instead, and a section with a pattern of its own for the part keeps that.
+    ///
+    /// `duck` tells what the groove does under the fills: `mute` stops it, which is the default, `thin` keeps its
+    /// notes on the beats, softer, and `keep` plays it on. It's set for the whole song at the top and can be set for
+    /// a section too.
+    ///
+    /// A section with `from = "verse"` is a copy of the verse, with the parts and the settings it has replacing the
+    /// ones of the verse. `bars = { 4 = "fill" }` plays the first bar of the `fill` section as the fourth bar of the
+    /// section, counting over its repeats, and `4 = "verse:2"` the second bar of the verse. The bars are taken from
+    /// the sections as written, without the bars they take from others, and have to be in the same time signature.
+    /// Every bar swapped has to be played wherever the section is in `order`. `fill = {}` leaves out the fill of the
+    /// verse.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let file: ArrangementFile = toml::from_str(s).map_err(|e| e.to_string())?;
+        let version = match file.version {
+            Some(number) => DslVersion::try_from(number).map_err(|e| e.to_string())?,
+            None => DslVersion::V1,
+        };
+        let ducking = file.duck.as_deref().map_or(Ok(Ducking::default()), Ducking::from_str)?;
+        let files = file
+            .sections
+            .keys()
+            .map(|name| resolve(name, &file.sections, &mut Vec::new()).map(|section| (name.clone(), section)))
+            .collect::<Result<BTreeMap<String, SectionFile>, String>>()?;
+        let mut sections = files
+            .iter()
+            .map(|(name, section)| {
+                let time_signature = section.time_signature.as_deref().unwrap_or(DEFAULT_TIME_SIGNATURE);
+                let section = Section {
+                    parts: parse_parts(version, name, &section.parts)?,
+                    fill: parse_parts(version, name, &section.fill.clone().unwrap_or_default())?,
+                    ducking: match &section.duck {
+                        Some(duck) => Ducking::from_str(duck).map_err(|e| format!("Section '{}': {}", name, e))?,
+                        None => ducking,
+                    },
+                    bars: parse_bars(name, &section.bars)?,
+                    tempo: section.tempo.unwrap_or(DEFAULT_TEMPO),
+                    time_signature: TimeSignature::from_str(time_signature)
+                        .map_err(|e| format!("Section '{}': {}", name, e))?,
+                };
+                if section.tempo == 0 {
+                    return Err(format!("Section '{}': {}", name, PolyError::Tempo(section.tempo)));
+                }
+                Ok((name.clone(), section))
+            })
+            .collect::<Result<BTreeMap<String, Section>, String>>()?;
+        let order = file
+            .order
+            .into_iter()
+            .map(|entry| {
+                let (name, times) = match entry {
+                    OrderEntry::Once(name) => (name, 1),
+                    OrderEntry::Repeated { section, repeat } => (section, repeat),
+                };
+                if sections.contains_key(&name) {
+                    Ok((name, times))
+                } else {
+                    Err(format!("Unknown section '{}'", name))
+                }
+            })
+            .collect::<Result<Vec<(String, u32)>, String>>()?;
+        let mut pinned = BTreeMap::new();
+        for (name, section) in &files {
+            if let Some(part) = &section.timekeeper {
+                let part = DrumPart::from_str(part).map_err(|e| format!("Section '{}': {}", name, e))?;
+                pinned.insert(name.clone(), part);
+            }
+        }
+        match file.timekeeper {
+            Some(timekeeper) => {
+                let pattern = version.parse(&timekeeper.pattern).map_err(|e| format!("Timekeeper: {}", e))?;
+                let rotation = timekeeper
+                    .rotate
+                    .iter()
+                    .map(|part| DrumPart::from_str(part).map_err(|e| format!("Timekeeper: {}", e)))
+                    .collect::<Result<Vec<DrumPart>, String>>()?;
+                if rotation.is_empty() {
+                    return Err("The timekeeper has no parts to rotate".to_string());
+                }
+                let mut keepers = pinned;
+                let mut turn = 0;
+                for (name, _) in &order {
+                    if !keepers.contains_key(name) {
+                        keepers.insert(name.clone(), rotation[turn % rotation.len()]);
+                        turn += 1;
+                    }
+                }
+                for (name, part) in keepers {
+                    if let Some(section) = sections.get_mut(&name) {
+                        section.parts.entry(part).or_insert_with(|| pattern.clone());
+                    }
+                }
+            }
+            None => {
+                if let Some(name) = pinned.keys().next() {
+                    return Err(format!("Section '{}' has a timekeeper, but the arrangement doesn't", name));
+                }
+            }
+        }
+        if let Some((name, _)) = sections.iter().find(|(_, section)| section.parts.is_empty()) {
+            return Err(format!("Section '{}' has no parts", name));
+        }
+        for (name, section) in &sections {
+            for (from, bar) in section.bars.values() {
+                let Some(from_section) = sections.get(from) else {
+                    return Err(format!("Section '{}': Unknown section '{}'", name, from));
+                };
+                if from_section.time_signature != section.time_signature {
+                    return Err(format!("Section '{}': '{}' is in another time signature", name, from));
+                }
+                let bars = from_section.to_timeline(1).map_err(|e| format!("Section '{}': {}", from, e))?.bars();
+                if *bar > bars {
+                    return Err(format!("Section '{}': '{}' has no bar {}", name, from, bar));
+                }
+            }
+        }
+        for (name, times) in &order {
+            let section = &sections[name];
+            let Some(last) = section.bars.keys().next_back() else { continue };
+            let timeline = section.to_timeline(1).map_err(|e| format!("Section '{}': {}", name, e))?;
+            let bars = timeline.bars().saturating_mul(*times);
+            if *last > bars {
+                return Err(format!("Section '{}': bar {} comes after its last bar, {}", name, last, bars));
+            }
+        }
+        Ok(Arrangement { sections, order })
+    }
+}
+
+impl Section {
+    /// The groove repeated `times` times, with the fill over the groove ducked under it in the last bar.
+    pub fn to_timeline(&self, times: u32) -> Result<Timeline, PolyError> {
+        let groove = Timeline::from_groups(&self.parts, self.time_signature)?.repeat(times);
+        if self.fill.is_empty() || groove.bars() == 0 {
+            Ok(groove)
+        } else {
+            let last = groove.bars() - 1;
+            let fill = Timeline::from_groups(&self.fill, self.time_signature)?.slice(0..1);
+            let parts: Vec<DrumPart> = self.fill.keys().copied().collect();
+            let under = groove.slice(last..last + 1).duck(0..1, self.ducking, &parts);
+            Ok(groove.slice(0..last).concat(&under.overlay(&fill)))
+        }
+    }
+}
+
+impl Arrangement {
+    /// Renders the sections one after another into a single drum track, changing the tempo and the time signature
+    /// where the sections do. With `crashes`, the sections are where the crashes go.
+    pub fn to_smf<'a>(
+        &self,
+        text: &'a str,
+        drum_map: &DrumMap,
+        crashes: Option<Crashes>,
+    ) -> Result<Smf<'a>, PolyError> {
+        let mut events = Vec::new();
+        let mut meta_events: Vec<(Tick, MetaMessage)> = Vec::new();
+        let mut starts = Vec::new();
+        let mut time = Tick(0);
+        let mut previous: Option<&Section> = None;
+        for (name, times) in &self.order {
+            let section = &self.sections[name];
+            starts.push(time);
+            if let Some(previous) = previous {
+                if previous.tempo != section.tempo {
+                    meta_events.push((time, MetaMessage::Tempo(MidiTempo::from_tempo(section.tempo).0)));
+                }
+                if previous.time_signature != section.time_signature {
+                    meta_events.push((time, time_signature_event(section.time_signature)));
+                }
+            }
+            let timeline = self.section_timeline(section, *times)?;
+            events.extend(timeline.events().iter().map(|e| {
+                let mut e = *e;
+                e.tick = e.tick + time;
+                e
+            }));
+            time = time + timeline.length();
+            previous = Some(section);
+        }
+        let first = self.order.first().map(|(name, _)| &self.sections[name]);
+        let mut track = drums_track_header(
+            first.map_or(TimeSignature::from_str(DEFAULT_TIME_SIGNATURE).unwrap(), |s| s.time_signature),
+            MidiTempo::from_tempo(first.map_or(DEFAULT_TEMPO, |s| s.tempo)),
+            text,
+            "Drumkit",
+        );
+        events.sort();
+        if let Some(crashes) = crashes {
+            starts.dedup();
+            events = crashes.place(events, &starts);
+        }
+        let last = write_events(EventGrid::new(events, time), &meta_events, drum_map, &mut track);
+        Ok(tracks_to_smf(end_tracks(vec![(track, last)], time)))
+    }
+
+    /// The section played `times` times, with the bars it takes from other sections.
+    fn section_timeline(&self, section: &Section, times: u32) -> Result<Timeline, PolyError> {
+        let mut timeline = section.to_timeline(times)?;
+        for (bar, (from, from_bar)) in &section.bars {
+            if *bar > timeline.bars() {
+                return Err(PolyError::NoBar(*bar));
+            }
+            let taken = self.sections[from].to_timeline(1)?.slice(from_bar - 1..*from_bar);
+            timeline = timeline.slice(0..bar - 1).concat(&taken).concat(&timeline.slice(*bar..timeline.bars()));
+        }
+        Ok(timeline)
+    }
+
+    /// Where the sections start in ticks, along with their names, in the order they're played.
+    pub fn sections(&self) -> Result<Vec<Marker>, PolyError> {
+        let mut time = Tick(0);
+        let mut starts = Vec::new();
+        for (name, times) in &self.order {
+            starts.push((time.0 as u32, name.clone()));
+            time = time + self.section_timeline(&self.sections[name], *times)?.length();
+        }
+        Ok(starts)
+    }
+}
+
+#[cfg(test)]
+use midly::{MidiMessage, TrackEventKind};
+
+#[cfg(test)]
+static SONG: &str = r#"
+order = ["intro", { section = "verse", repeat = 2 }]
+
+[sections.intro]
+parts = { hi-hat = "4x" }
+
+[sections.verse]
+tempo = 90
+time-signature = "3/4"
+parts = { kick = "4x--", snare = "4-x-" }
+fill = { tom3 = "8xxxxxx" }
+"#;
+
+#[test]
+fn test_parse_arrangement() {
+    let song = Arrangement::from_str(SONG).unwrap();
+    assert_eq!(song.order, vec![("intro".to_string(), 1), ("verse".to_string(), 2)]);
+    assert_eq!(song.sections["intro"].tempo, 120);
+    assert_eq!(song.sections["verse"].time_signature, TimeSignature::from_str("3/4").unwrap());
+    assert!(Arrangement::from_str("order = [\"chorus\"]\n[sections.verse]\nparts = { kick = \"4x\" }").is_err());
+    assert!(Arrangement::from_str("order = []\n[sections.verse]\nparts = { cowbell = \"4x\" }").is_err());
+    assert!(Arrangement::from_str("order = []\n[sections.verse]\ntempo = 0\nparts = { kick = \"4x\" }").is_err());
+    assert!(Arrangement::from_str(&format!("version = 1\n{}", SONG)).is_ok());
+    assert!(Arrangement::from_str(&format!("version = 2\n{}", SONG)).is_err());
+}
+
+#[test]
+fn test_render_arrangement() {
+    let song = Arrangement::from_str(SONG).unwrap();
+    let smf = song.to_smf("", &DrumMap::default(), None).unwrap();
+    let mut time = 0;
+    let mut meta = Vec::new();
+    let mut keys = Vec::new();
+    for event in smf.tracks[0].iter() {
+        time += event.delta.as_int();
+        match event.kind {
+            TrackEventKind::Meta(MetaMessage::Tempo(t)) => meta.push((time, t.as_int())),
+            TrackEventKind::Midi { message: MidiMessage::NoteOn { key, .. }, .. } => keys.push((time, key.as_int())),
+            _ => {}
+        }
+    }
+    // A bar of 4/4, then the first bar of 3/4 with the groove and the second one with the fill.
+    assert_eq!(time, 192 + 144 * 2);
+    assert_eq!(meta, vec![(0, 500000), (192, 666666)]);
+    assert_eq!(keys[4..6], [(192, 36), (240, 38)]);
+    assert_eq!(keys[6..], (0..6).map(|i| (336 + i * 24, 43)).collect::<Vec<_>>()[..]);
+}
+
+#[test]
+fn test_render_arrangement_resting_section() {
+    let song = r#"
+order = ["verse", "break"]
+
+[sections.verse]
+parts = { kick = "4x" }
+
+[sections.break]
+tempo = 60
+parts = { kick = "1-" }
+"#;
+    let smf = Arrangement::from_str(song).unwrap().to_smf("", &DrumMap::default(), None).unwrap();
+    let mut time = 0;
+    let mut meta = Vec::new();
+    for event in smf.tracks[0].iter() {
+        time += event.delta.as_int();
+        if let TrackEventKind::Meta(MetaMessage::Tempo(t)) = event.kind {
+            meta.push((time, t.as_int()));
+        }
+    }
+    // The tempo changes for the break even though nothing is played after it.
+    assert_eq!(meta, vec![(0, 500000), (192, 1000000)]);
+    assert_eq!(time, 384);
+}
+
+#[test]
+fn test_arrangement_crashes() {
+    let song = Arrangement::from_str(SONG).unwrap();
+    let smf = song.to_smf("", &DrumMap::default(), Some(Crashes { chance: 1.0, seed: 0 })).unwrap();
+    let mut time = 0;
+    let mut crashes = Vec::new();
+    for event in smf.tracks[0].iter() {
+        time += event.delta.as_int();
+        if let TrackEventKind::Midi { message: MidiMessage::NoteOn { key, .. }, .. } = event.kind {
+            if key == 49 {
+                crashes.push(time);
+            }
+        }
+    }
+    assert_eq!(crashes, vec![0, 192]);
+}
+
+#[test]
+fn test_arrangement_sections() {
+    let song = Arrangement::from_str(SONG).unwrap();
+    assert_eq!(song.sections().unwrap(), vec![(0, "intro".to_string()), (192, "verse".to_string())]);
+}
+
+#[test]
+fn test_arrangement_timekeeper() {
+    let song = Arrangement::from_str(
+        r#"
+order = ["verse", "chorus", "verse", "bridge", "outro"]
+
+[timekeeper]
+pattern = "8x"
+rotate = ["hi-hat", "ride"]
+
+[sections.verse]
+parts = { kick = "4x" }
+
+[sections.chorus]
+parts = { kick = "4x", ride = "4x" }
+
+[sections.bridge]
+timekeeper = "open-hi-hat"
+
+[sections.outro]
+parts = { kick = "4x" }
+"#,
+    )
+    .unwrap();
+    let keeps_time = |section: &str, part: DrumPart| song.sections[section].parts.get(&part).map(|g| g.to_string());
+    assert_eq!(keeps_time("verse", DrumPart::HiHat), Some("8x".to_string()));
+    // The chorus keeps its own ride pattern.
+    assert_eq!(keeps_time("chorus", DrumPart::RideCymbal), Some("4x".to_string()));
+    assert_eq!(keeps_time("bridge", DrumPart::OpenHiHat), Some("8x".to_string()));
+    assert_eq!(song.sections["bridge"].parts.len(), 1);
+    assert_eq!(keeps_time("outro", DrumPart::HiHat), Some("8x".to_string()));
+    assert!(Arrangement::from_str("order = []\n[sections.verse]\ntimekeeper = \"ride\"").is_err());
+    let empty = "order = [\"verse\"]\n[timekeeper]\npattern = \"8x\"\nrotate = []\n[sections.verse]";
+    assert!(Arrangement::from_str(empty).is_err());
+    assert!(Arrangement::from_str("order = []\n[sections.verse]\nparts = {}").is_err());
+}
+
+#[test]
+fn test_arrangement_ducking() {
+    let song = |song: String| Arrangement::from_str(&song).unwrap().to_smf("", &DrumMap::default(), None).unwrap();
+    let fill_bar = |smf: &Smf| {
+        let mut time = 0;
+        let mut keys = Vec::new();
+        for event in smf.tracks[0].iter() {
+            time += event.delta.as_int();
+            if let TrackEventKind::Midi { message: MidiMessage::NoteOn { key, vel }, .. } = event.kind {
+                if time >= 336 {
+                    keys.push((time, key.as_int(), vel.as_int()));
+                }
+            }
+        }
+        keys.into_iter().filter(|(_, key, _)| *key != 43).collect::<Vec<_>>()
+    };
+    assert_eq!(fill_bar(&song(SONG.to_string())), vec![]);
+    assert_eq!(fill_bar(&song(format!("duck = \"thin\"\n{}", SONG))), vec![(336, 36, 60), (384, 38, 60)]);
+    // The section's own ducking goes first.
+    let keep = SONG.replace("[sections.verse]", "[sections.verse]\nduck = \"keep\"");
+    assert_eq!(fill_bar(&song(format!("duck = \"thin\"\n{}", keep))), vec![(336, 36, 100), (384, 38, 100)]);
+    assert!(Arrangement::from_str(&format!("duck = \"loud\"\n{}", SONG)).is_err());
+}
+
+#[test]
+fn test_arrangement_bar_references() {
+    let song = Arrangement::from_str(
+        r#"
+order = [{ section = "verse", repeat = 2 }, { section = "chorus", repeat = 3 }]
+
+[sections.verse]
+parts = { kick = "4x", hi-hat = "8x" }
+
+[sections.chorus]
+from = "verse"
+parts = { snare = "4-x" }
+bars = { 2 = "break", 3 = "break:2" }
+
+[sections.break]
+parts = { tom3 = "1x-", kick = "1x-" }
+"#,
+    )
+    .unwrap();
+    let chorus = &song.sections["chorus"];
+    let parts: Vec<DrumPart> = chorus.parts.keys().copied().collect();
+    assert_eq!(parts, vec![DrumPart::KickDrum, DrumPart::SnareDrum, DrumPart::HiHat]);
+    assert_eq!(chorus.bars[&3], ("break".to_string(), 2));
+    let song = Arrangement { order: vec![("chorus".to_string(), 3)], ..song };
+    let shorter = Arrangement { order: vec![("chorus".to_string(), 2)], ..song.clone() };
+    assert_eq!(shorter.to_smf("", &DrumMap::default(), None), Err(PolyError::NoBar(3)));
+    let smf = song.to_smf("", &DrumMap::default(), None).unwrap();
+    let mut time = 0;
+    let mut keys = Vec::new();
+    for event in smf.tracks[0].iter() {
+        time += event.delta.as_int();
+        if let TrackEventKind::Midi { message: MidiMessage::NoteOn { key, .. }, .. } = event.kind {
+            keys.push((time, key.as_int()));
+        }
+    }
+    // The second bar is the first bar of the break and the third one its second bar, which is empty.
+    assert_eq!(time, 192 * 3);
+    assert_eq!(keys.into_iter().filter(|(time, _)| *time >= 192).collect::<Vec<_>>(), vec![(192, 36), (192, 43)]);
+    let copy = |section: &str| format!("order = []\n[sections.verse]\nparts = {{ kick = \"4x\" }}\n{}", section);
+    assert!(Arrangement::from_str(&copy("[sections.chorus]\nfrom = \"chorus\"")).is_err());
+    assert!(Arrangement::from_str(&copy("[sections.chorus]\nfrom = \"bridge\"")).is_err());
+    assert!(Arrangement::from_str(&copy("[sections.chorus]\nfrom = \"verse\"\nbars = { 1 = \"verse:2\" }")).is_err());
+    assert!(Arrangement::from_str(&copy("[sections.chorus]\nfrom = \"verse\"\nbars = { 0 = \"verse\" }")).is_err());
+    let waltz = "[sections.chorus]\nfrom = \"verse\"\ntime-signature = \"3/4\"\nbars = { 1 = \"verse\" }";
+    assert!(Arrangement::from_str(&copy(waltz)).is_err());
+    assert!(Arrangement::from_str(&copy("[sections.chorus]\nfrom = \"verse\"\nbars = { 1 = \"verse:1\" }")).is_ok());
+    // Every time the chorus is played, it has to get to the bars it swaps.
+    let past_the_end = "[sections.chorus]\nfrom = \"verse\"\nbars = { 2 = \"verse\" }".to_string();
+    let played = |order: &str| Arrangement::from_str(&copy(&past_the_end).replace("order = []", order));
+    assert!(played("order = [{ section = \"chorus\", repeat = 2 }]").is_ok());
+    assert_eq!(
+        played("order = [{ section = \"chorus\", repeat = 2 }, \"chorus\"]"),
+        Err("Section 'chorus': bar 2 comes after its last bar, 1".to_string())
+    );
+    // A copy keeps the fill unless it clears it.
+    let filled = "order = []\n[sections.verse]\nparts = { kick = \"4x\" }\nfill = { snare = \"4x\" }\n\
+                  [sections.chorus]\nfrom = \"verse\"";
+    assert!(!Arrangement::from_str(filled).unwrap().sections["chorus"].fill.is_empty());
+    assert!(Arrangement::from_str(&format!("{}\nfill = {{}}", filled)).unwrap().sections["chorus"].fill.is_empty());
+}
@@ -0,0 +1,191 @@
+use std::path::Path;
+use std::str::FromStr;
+
+/// Audio file formats the rendered drums can be written in. WAV is always available, the others are encoded with
+/// libraries that come with the `flac`, `ogg` and `mp3` features.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AudioFormat {
+    Wav,
+    Flac,
+    /// Ogg Vorbis.
+    Ogg,
+    Mp3,
+}
+
+impl FromStr for AudioFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "wav" => Ok(AudioFormat::Wav),
+            "flac" => Ok(AudioFormat::Flac),
+            "ogg" => Ok(AudioFormat::Ogg),
+            "mp3" => Ok(AudioFormat::Mp3),
+            _ => Err(format!("Unknown audio format '{}', expected 'wav', 'flac', 'ogg' or 'mp3'", s)),
+        }
+    }
+}
+
+impl AudioFormat {
+    /// The format matching the extension of the file, WAV if there's none.
+    pub fn from_path(path: &Path) -> Result<Self, String> {
+        match path.extension() {
+            Some(extension) => AudioFormat::from_str(&extension.to_string_lossy()),
+            None => Ok(AudioFormat::Wav),
+        }
+    }
+
+    /// The cargo feature the format's encoder comes with.
+    pub fn feature(self) -> &'static str {
+        match self {
+            AudioFormat::Wav => "audio",
+            AudioFormat::Flac => "flac",
+            AudioFormat::Ogg => "ogg",
+            AudioFormat::Mp3 => "mp3",
+        }
+    }
+
+    /// Whether this build can encode the format.
+    pub fn is_supported(self) -> bool {
+        match self {
+            AudioFormat::Wav => true,
+            AudioFormat::Flac => cfg!(feature = "flac"),
+            AudioFormat::Ogg => cfg!(feature = "ogg"),
+            AudioFormat::Mp3 => cfg!(feature = "mp3"),
+        }
+    }
+}
+
+/// 16-bit samples, interleaved. Anything louder than full scale is clipped.
+fn to_i16(frames: &[[f32; 2]]) -> Vec<i16> {
+    frames
+        .iter()
+        .flat_map(|frame| frame.map(|value| (value.clamp(-1.0, 1.0) * i16::MAX as f32) as i16))
+        .collect()
+}
+
+/// Encodes stereo frames in the format, fails if the format's feature is off.
+pub fn encode(frames: &[[f32; 2]], sample_rate: u32, format: AudioFormat) -> Result<Vec<u8>, String> {
+    match format {
+        AudioFormat::Wav => wav(frames, sample_rate),
+        #[cfg(feature = "flac")]
+        AudioFormat::Flac => flac(frames, sample_rate),
+        #[cfg(feature = "ogg")]
+        AudioFormat::Ogg => ogg(frames, sample_rate),
+        #[cfg(feature = "mp3")]
+        AudioFormat::Mp3 => mp3(frames, sample_rate),
+        #[allow(unreachable_patterns)]
+        _ => Err(format!("Built without the '{}' feature, which is needed to encode it", format.feature())),
+    }
+}
+
+fn wav(frames: &[[f32; 2]], sample_rate: u32) -> Result<Vec<u8>, String> {
+    let spec = hound::WavSpec {
+        channels: 2,
+        sample_rate,
+        bits_per_sample: 16,
+        sample_format: hound::SampleFormat::Int,
+    };
+    let mut out = std::io::Cursor::new(Vec::new());
+    let mut writer = hound::WavWriter::new(&mut out, spec).map_err(|e| e.to_string())?;
+    for value in to_i16(frames) {
+        writer.write_sample(value).map_err(|e| e.to_string())?;
+    }
+    writer.finalize().map_err(|e| e.to_string())?;
+    Ok(out.into_inner())
+}
+
+#[cfg(feature = "flac")]
+fn flac(frames: &[[f32; 2]], sample_rate: u32) -> Result<Vec<u8>, String> {
+    use flacenc::component::BitRepr;
+    use flacenc::error::Verify;
+
+    let samples: Vec<i32> = to_i16(frames).into_iter().map(i32::from).collect();
+    let config = flacenc::config::Encoder::default().into_verified().map_err(|(_, e)| e.to_string())?;
+    let source = flacenc::source::MemSource::from_samples(&samples, 2, 16, sample_rate as usize);
+    let stream = flacenc::encode_with_fixed_block_size(&config, source, config.block_size)
+        .map_err(|e| format!("{:?}", e))?;
+    let mut sink = flacenc::bitsink::ByteSink::new();
+    stream.write(&mut sink).map_err(|e| format!("{:?}", e))?;
+    Ok(sink.as_slice().to_vec())
+}
+
+#[cfg(feature = "ogg")]
+fn ogg(frames: &[[f32; 2]], sample_rate: u32) -> Result<Vec<u8>, String> {
+    use std::num::{NonZeroU32, NonZeroU8};
+    use vorbis_rs::VorbisEncoderBuilder;
+
+    let rate = NonZeroU32::new(sample_rate).ok_or("Sample rate has to be positive")?;
+    let channels = NonZeroU8::new(2).ok_or("No channels")?;
+    // A fixed stream serial instead of a random one, so the same groove always encodes to the same file.
+    let mut encoder = VorbisEncoderBuilder::new_with_serial(rate, channels, Vec::new(), 0x706f6c79)
+        .build()
+        .map_err(|e| e.to_string())?;
+    // Blocks of a reasonable size for libvorbis.
+    for block in frames.chunks(1024) {
+        let left: Vec<f32> = block.iter().map(|f| f[0].clamp(-1.0, 1.0)).collect();
+        let right: Vec<f32> = block.iter().map(|f| f[1].clamp(-1.0, 1.0)).collect();
+        encoder.encode_audio_block([left, right]).map_err(|e| e.to_string())?;
+    }
+    encoder.finish().map_err(|e| e.to_string())
+}
+
+#[cfg(feature = "mp3")]
+fn mp3(frames: &[[f32; 2]], sample_rate: u32) -> Result<Vec<u8>, String> {
+    use mp3lame_encoder::{max_required_buffer_size, Bitrate, Builder, FlushNoGap, InterleavedPcm, Quality};
+
+    let mut builder = Builder::new().ok_or("Can't start LAME")?;
+    builder.set_num_channels(2).map_err(|e| e.to_string())?;
+    builder.set_sample_rate(sample_rate).map_err(|e| e.to_string())?;
+    builder.set_brate(Bitrate::Kbps192).map_err(|e| e.to_string())?;
+    builder.set_quality(Quality::Best).map_err(|e| e.to_string())?;
+    let mut encoder = builder.build().map_err(|e| e.to_string())?;
+    let samples = to_i16(frames);
+    let mut out = Vec::with_capacity(max_required_buffer_size(frames.len()));
+    encoder
+        .encode_to_vec(InterleavedPcm(&samples), &mut out)
+        .map_err(|e| e.to_string())?;
+    encoder.flush_to_vec::<FlushNoGap>(&mut out).map_err(|e| e.to_string())?;
+    Ok(out)
+}
+
+#[cfg(test)]
+fn test_frames() -> Vec<[f32; 2]> {
+    (0..44100).map(|i| [(i as f32 / 20.0).sin() * 0.5, (i as f32 / 30.0).sin() * 0.5]).collect()
+}
+
+#[test]
+fn test_audio_format() {
+    assert_eq!(AudioFormat::from_path(Path::new("groove.FLAC")), Ok(AudioFormat::Flac));
+    assert_eq!(AudioFormat::from_path(Path::new("groove.ogg")), Ok(AudioFormat::Ogg));
+    assert_eq!(AudioFormat::from_path(Path::new("groove")), Ok(AudioFormat::Wav));
+    assert!(AudioFormat::from_path(Path::new("groove.aiff")).is_err());
+}
+
+#[test]
+fn test_encode_wav() {
+    let wav = encode(&test_frames(), 44100, AudioFormat::Wav).unwrap();
+    assert!(wav.starts_with(b"RIFF"));
+    assert_eq!(wav.len(), 44 + 44100 * 4);
+}
+
+#[test]
+fn test_encode() {
+    let frames = test_frames();
+    let encoded = |format| encode(&frames, 44100, format);
+    assert!(AudioFormat::Wav.is_supported());
+    assert_eq!(AudioFormat::Mp3.is_supported(), encoded(AudioFormat::Mp3).is_ok());
+    match encoded(AudioFormat::Flac) {
+        Ok(flac) => assert!(flac.starts_with(b"fLaC") && flac.len() < 44100 * 4),
+        Err(e) => assert!(cfg!(not(feature = "flac")) && e.contains("'flac'")),
+    }
+    match encoded(AudioFormat::Ogg) {
+        Ok(ogg) => assert!(ogg.starts_with(b"OggS")),
+        Err(e) => assert!(cfg!(not(feature = "ogg")) && e.contains("'ogg'")),
+    }
+    match encoded(AudioFormat::Mp3) {
+        // Either an ID3 tag or the sync word of the first frame.
+        Ok(mp3) => assert!(mp3.starts_with(b"ID3") || mp3[0] == 0xff),
+        Err(e) => assert!(cfg!(not(feature = "mp3")) && e.contains("'mp3'")),
+    }
+}
@@ -0,0 +1,343 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::str::FromStr;
+
+use serde::Deserialize;
+
+use crate::audio::mix::{from_db, Mix, Placement};
+use crate::midi::core::DrumPart;
+use crate::random::Rng;
+
+static DEFAULT_SAMPLE_RATE: u32 = 44100;
+
+/// A recorded hit, resampled to the rate of the kit. Mono recordings are spread over both channels.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Sample {
+    pub frames: Vec<[f32; 2]>,
+}
+
+/// Samples of a drum hit at about the same strength. `velocity` is the loudest note played with this layer.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Layer {
+    pub velocity: u8,
+    pub samples: Vec<Sample>,
+}
+
+/// How a sample is picked from a layer with several of them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Selection {
+    /// Every sample in turn.
+    RoundRobin,
+    /// Any sample but the one played last.
+    Random,
+}
+
+/// Velocity layers of a drum part, from the softest to the loudest, and where it's placed in the mix.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Instrument {
+    pub layers: Vec<Layer>,
+    pub placement: Placement,
+}
+
+/// Samples to render the drum parts with.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Kit {
+    pub sample_rate: u32,
+    pub selection: Selection,
+    pub parts: BTreeMap<DrumPart, Instrument>,
+    pub mix: Mix,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+struct KitFile {
+    sample_rate: Option<u32>,
+    selection: Option<Selection>,
+    #[serde(default)]
+    mix: MixFile,
+    parts: BTreeMap<String, InstrumentFile>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+struct MixFile {
+    overheads_delay: Option<f32>,
+    room_delay: Option<f32>,
+    room_decay: Option<f32>,
+    limiter: Option<bool>,
+    ceiling: Option<f32>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct InstrumentFile {
+    #[serde(default)]
+    samples: Vec<String>,
+    #[serde(default)]
+    layers: Vec<LayerFile>,
+    pan: Option<f32>,
+    overheads: Option<f32>,
+    room: Option<f32>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct LayerFile {
+    velocity: u8,
+    samples: Vec<String>,
+}
+
+impl Kit {
+    /// Reads a kit definition from TOML, the sample paths are relative to the kit file:
+    ///
+    /// ```toml
+    /// sample-rate = 44100
+    /// selection = "round-robin"
+    ///
+    /// [mix]
+    /// overheads-delay = 3.0
+    /// room-delay = 20.0
+    /// room-decay = 0.5
+    /// ceiling = -0.3
+    ///
+    /// [parts.kick]
+    /// samples = ["kick-1.wav", "kick-2.wav"]
+    /// room = 0.3
+    ///
+    /// [parts.snare]
+    /// pan = -0.2
+    /// overheads = 0.4
+    /// layers = [
+    ///   { velocity = 80, samples = ["snare-soft-1.wav", "snare-soft-2.wav"] },
+    ///   { velocity = 127, samples = ["snare-hard-1.wav", "snare-hard-2.wav", "snare-hard-3.wav"] },
+    /// ]
+    /// ```
+    ///
+    /// `samples` is a single layer for every velocity. The output is 44.1kHz and round-robin if not given. Delays
+    /// are in milliseconds and the ceiling of the limiter in dBFS, `limiter = false` turns it off. Parts are
+    /// centered and have no bleed unless `pan`, `overheads` and `room` say otherwise.
+    pub fn load(path: &Path) -> Result<Kit, String> {
+        let source = std::fs::read_to_string(path).map_err(|e| e.to_string())?;
+        let dir = path.parent().unwrap_or(Path::new("."));
+        Kit::from_toml(&source, |sample, rate| read_wav(&dir.join(sample), rate))
+    }
+
+    /// Reads a kit definition, `read` loads a sample by its path at the given rate.
+    fn from_toml<F>(source: &str, read: F) -> Result<Kit, String>
+    where
+        F: Fn(&str, u32) -> Result<Sample, String>,
+    {
+        let file: KitFile = toml::from_str(source).map_err(|e| e.to_string())?;
+        let sample_rate = file.sample_rate.unwrap_or(DEFAULT_SAMPLE_RATE);
+        if sample_rate == 0 {
+            return Err("Sample rate has to be positive".to_string());
+        }
+        let read_all = |part: DrumPart, samples: &[String]| {
+            if samples.is_empty() {
+                return Err(format!("{}: every layer needs a sample", part.name()));
+            }
+            samples
+                .iter()
+                .map(|s| read(s, sample_rate).map_err(|e| format!("{}: can't read {}: {}", part.name(), s, e)))
+                .collect::<Result<Vec<Sample>, String>>()
+        };
+        let parts = file
+            .parts
+            .iter()
+            .map(|(name, instrument)| {
+                let part = DrumPart::from_str(name)?;
+                let mut layers = match (instrument.samples.is_empty(), instrument.layers.is_empty()) {
+                    (false, true) => vec![Layer { velocity: 127, samples: read_all(part, &instrument.samples)? }],
+                    (true, false) => instrument
+                        .layers
+                        .iter()
+                        .map(|l| Ok(Layer { velocity: l.velocity, samples: read_all(part, &l.samples)? }))
+                        .collect::<Result<Vec<Layer>, String>>()?,
+                    _ => return Err(format!("{}: expected either samples or layers", part.name())),
+                };
+                layers.sort_by_key(|l| l.velocity);
+                let placement = Placement {
+                    pan: instrument.pan.unwrap_or(0.0),
+                    overheads: instrument.overheads.unwrap_or(0.0),
+                    room: instrument.room.unwrap_or(0.0),
+                };
+                if !(-1.0..=1.0).contains(&placement.pan) {
+                    return Err(format!("{}: pan has to be from -1 to 1", part.name()));
+                }
+                if placement.overheads < 0.0 || placement.room < 0.0 {
+                    return Err(format!("{}: bleed levels can't be negative", part.name()));
+                }
+                Ok((part, Instrument { layers, placement }))
+            })
+            .collect::<Result<BTreeMap<DrumPart, Instrument>, String>>()?;
+        Ok(Kit {
+            sample_rate,
+            selection: file.selection.unwrap_or(Selection::RoundRobin),
+            parts,
+            mix: file.mix.to_mix()?,
+        })
+    }
+}
+
+impl MixFile {
+    fn to_mix(&self) -> Result<Mix, String> {
+        let default = Mix::default();
+        let seconds = |ms: Option<f32>, default: f32| match ms {
+            Some(ms) if ms < 0.0 => Err("Delays can't be negative".to_string()),
+            Some(ms) => Ok(ms / 1000.0),
+            None => Ok(default),
+        };
+        let room_decay = self.room_decay.unwrap_or(default.room_decay);
+        if !(0.0..1.0).contains(&room_decay) {
+            return Err("Room decay has to be from 0 to 1".to_string());
+        }
+        Ok(Mix {
+            overheads_delay: seconds(self.overheads_delay, default.overheads_delay)?,
+            room_delay: seconds(self.room_delay, default.room_delay)?,
+            room_decay,
+            ceiling: match self.limiter {
+                Some(false) => None,
+                _ => self.ceiling.map(from_db).or(default.ceiling),
+            },
+        })
+    }
+}
+
+/// Picks the samples for the hits, keeping track of what was played last on every layer.
+pub(crate) struct Picker<'a> {
+    kit: &'a Kit,
+    last: BTreeMap<(DrumPart, usize), usize>,
+    rng: Rng,
+}
+
+impl<'a> Picker<'a> {
+    pub(crate) fn new(kit: &'a Kit, seed: u64) -> Self {
+        Picker { kit, last: BTreeMap::new(), rng: Rng::new(seed) }
+    }
+
+    /// The sample for a hit along with the gain to play it at, nothing if the kit has no samples for the part.
+    /// Notes louder than the loudest layer are played with it at full volume.
+    pub(crate) fn pick(&mut self, part: DrumPart, velocity: u8) -> Option<(&'a Sample, f32)> {
+        let layers = &self.kit.parts.get(&part)?.layers;
+        let index = layers.iter().position(|l| velocity <= l.velocity).unwrap_or(layers.len() - 1);
+        let layer = &layers[index];
+        let count = layer.samples.len();
+        let sample = match (self.last.get(&(part, index)), self.kit.selection) {
+            (None, _) => 0,
+            (Some(last), Selection::RoundRobin) => (last + 1) % count,
+            (Some(_), Selection::Random) if count == 1 => 0,
+            // Skipping the last sample so that no two hits in a row sound exactly the same.
+            (Some(last), Selection::Random) => (last + 1 + self.rng.below(count as u64 - 1) as usize) % count,
+        };
+        self.last.insert((part, index), sample);
+        let gain = (velocity as f32 / layer.velocity.max(1) as f32).min(1.0);
+        Some((&layer.samples[sample], gain))
+    }
+}
+
+/// Reads a WAV file into frames at `rate`, resampling it linearly if it was recorded at another rate.
+fn read_wav(path: &Path, rate: u32) -> Result<Sample, String> {
+    let mut reader = hound::WavReader::open(path).map_err(|e| e.to_string())?;
+    let spec = reader.spec();
+    let values: Vec<f32> = match spec.sample_format {
+        hound::SampleFormat::Float => reader.samples::<f32>().collect::<Result<_, _>>(),
+        hound::SampleFormat::Int => {
+            let scale = (1i64 << (spec.bits_per_sample - 1)) as f32;
+            reader.samples::<i32>().map(|s| s.map(|s| s as f32 / scale)).collect::<Result<_, _>>()
+        }
+    }
+    .map_err(|e| e.to_string())?;
+    // Only the first two channels are used.
+    let frames: Vec<[f32; 2]> = values
+        .chunks_exact(spec.channels as usize)
+        .map(|c| [c[0], *c.get(1).unwrap_or(&c[0])])
+        .collect();
+    Ok(Sample { frames: resample(&frames, spec.sample_rate, rate) })
+}
+
+fn resample(frames: &[[f32; 2]], from: u32, to: u32) -> Vec<[f32; 2]> {
+    if from == to || frames.is_empty() {
+        return frames.to_vec();
+    }
+    let length = (frames.len() as u64 * to as u64 / from as u64) as usize;
+    (0..length)
+        .map(|i| {
+            let position = i as f64 * from as f64 / to as f64;
+            let (index, fraction) = (position as usize, position.fract() as f32);
+            let (a, b) = (frames[index], *frames.get(index + 1).unwrap_or(&frames[index]));
+            [a[0] + (b[0] - a[0]) * fraction, a[1] + (b[1] - a[1]) * fraction]
+        })
+        .collect()
+}
+
+#[cfg(test)]
+use crate::midi::core::DrumPart::*;
+
+/// A kit reading every sample as a single frame holding its name's length, to tell them apart.
+#[cfg(test)]
+pub(crate) fn test_kit(source: &str) -> Result<Kit, String> {
+    Kit::from_toml(source, |sample, _| Ok(Sample { frames: vec![[sample.len() as f32; 2]] }))
+}
+
+#[test]
+fn test_parse_kit() {
+    let kit = test_kit(
+        "sample-rate = 48000\n[parts.kick]\nsamples = [\"k\"]\n[parts.snare]\nlayers = [\n  { velocity = 127, samples = [\"ss\"] },\n  { velocity = 80, samples = [\"s\"] },\n]",
+    )
+    .unwrap();
+    assert_eq!(kit.sample_rate, 48000);
+    assert_eq!(kit.selection, Selection::RoundRobin);
+    assert_eq!(kit.parts[&KickDrum].layers[0].velocity, 127);
+    // Layers are sorted from the softest.
+    assert_eq!(kit.parts[&SnareDrum].layers.iter().map(|l| l.velocity).collect::<Vec<u8>>(), vec![80, 127]);
+    assert_eq!(kit.mix, Mix::default());
+    assert_eq!(kit.parts[&KickDrum].placement, Placement::default());
+    assert!(test_kit("[parts.cowbell]\nsamples = [\"c\"]").is_err());
+    assert!(test_kit("[parts.kick]\nsamples = []").is_err());
+    assert!(test_kit("[parts.kick]\nsamples = [\"k\"]\nlayers = [{ velocity = 127, samples = [\"k\"] }]").is_err());
+}
+
+#[test]
+fn test_parse_mix() {
+    let kit = test_kit(
+        "[mix]\noverheads-delay = 5.0\nroom-decay = 0.25\nlimiter = false\n[parts.snare]\nsamples = [\"s\"]\npan = -0.5\nroom = 0.2",
+    )
+    .unwrap();
+    assert_eq!(kit.mix, Mix { overheads_delay: 0.005, room_delay: 0.02, room_decay: 0.25, ceiling: None });
+    assert_eq!(kit.parts[&SnareDrum].placement, Placement { pan: -0.5, overheads: 0.0, room: 0.2 });
+    assert_eq!(test_kit("[mix]\nceiling = 0.0\n[parts.kick]\nsamples = [\"k\"]").unwrap().mix.ceiling, Some(1.0));
+    assert!(test_kit("[parts.kick]\nsamples = [\"k\"]\npan = 2.0").is_err());
+    assert!(test_kit("[parts.kick]\nsamples = [\"k\"]\nroom = -1.0").is_err());
+    assert!(test_kit("[mix]\nroom-decay = 1.0\n[parts.kick]\nsamples = [\"k\"]").is_err());
+}
+
+#[test]
+fn test_pick_samples() {
+    let source = "[parts.snare]\nlayers = [\n  { velocity = 80, samples = [\"a\", \"bb\"] },\n  { velocity = 127, samples = [\"ccc\", \"dddd\", \"eeeee\"] },\n]";
+    let kit = test_kit(source).unwrap();
+    let mut picker = Picker::new(&kit, 0);
+    let mut picked = |velocity| picker.pick(SnareDrum, velocity).map(|(s, gain)| (s.frames[0][0] as u8, gain));
+    assert_eq!(picked(40), Some((1, 0.5)));
+    assert_eq!(picked(80), Some((2, 1.0)));
+    assert_eq!(picked(80), Some((1, 1.0)));
+    assert_eq!(picked(127), Some((3, 1.0)));
+    assert_eq!(picked(127), Some((4, 1.0)));
+    assert!(picker.pick(KickDrum, 100).is_none());
+
+    let kit = test_kit(&format!("selection = \"random\"\n{}", source)).unwrap();
+    let mut picker = Picker::new(&kit, 0);
+    let mut last = 0;
+    for _ in 0..100 {
+        let (sample, _) = picker.pick(SnareDrum, 100).unwrap();
+        assert_ne!(sample.frames[0][0] as u8, last);
+        last = sample.frames[0][0] as u8;
+    }
+}
+
+#[test]
+fn test_resample() {
+    let frames = vec![[0.0, 0.0], [1.0, -1.0]];
+    assert_eq!(resample(&frames, 1, 2), vec![[0.0, 0.0], [0.5, -0.5], [1.0, -1.0], [1.0, -1.0]]);
+    assert_eq!(resample(&frames, 2, 1), vec![[0.0, 0.0]]);
+}
@@ -0,0 +1,194 @@
+/// How the parts are put together once the hits are placed: the bleed into the overheads and the room mics, and
+/// the limiter on the master.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Mix {
+    /// In seconds, how much later a hit reaches the overheads than the close mic.
+    pub overheads_delay: f32,
+    /// In seconds, the time between the reflections of the room.
+    pub room_delay: f32,
+    /// Level of every next reflection of the room relative to the previous one, from 0 to 1.
+    pub room_decay: f32,
+    /// Peak level the master is kept under, linear. Nothing is limited if there's none.
+    pub ceiling: Option<f32>,
+}
+
+impl Default for Mix {
+    fn default() -> Self {
+        Mix { overheads_delay: 0.003, room_delay: 0.02, room_decay: 0.5, ceiling: Some(from_db(-0.3)) }
+    }
+}
+
+/// Where a part sits in the mix.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Placement {
+    /// From -1 (left) to 1 (right).
+    pub pan: f32,
+    /// Levels the part is heard at in the overheads and in the room, 0 for none.
+    pub overheads: f32,
+    pub room: f32,
+}
+
+impl Default for Placement {
+    fn default() -> Self {
+        Placement { pan: 0.0, overheads: 0.0, room: 0.0 }
+    }
+}
+
+impl Placement {
+    /// Gains of the left and the right channel. A centered part is at full level on both, panning only turns the
+    /// other side down.
+    pub fn gains(&self) -> [f32; 2] {
+        [(1.0 - self.pan).min(1.0), (1.0 + self.pan).min(1.0)]
+    }
+}
+
+pub fn from_db(db: f32) -> f32 {
+    10f32.powf(db / 20.0)
+}
+
+/// The buses the hits are mixed into.
+pub(crate) struct Buses {
+    pub close: Vec<[f32; 2]>,
+    pub overheads: Vec<[f32; 2]>,
+    pub room: Vec<f32>,
+}
+
+impl Buses {
+    pub(crate) fn new(length: usize) -> Self {
+        Buses { close: vec![[0.0; 2]; length], overheads: vec![[0.0; 2]; length], room: vec![0.0; length] }
+    }
+
+    /// Mixes a sample starting at `start` into the buses, growing them to fit it.
+    pub(crate) fn add(&mut self, start: usize, frames: &[[f32; 2]], gain: f32, placement: Placement) {
+        self.resize(start + frames.len());
+        let [left, right] = placement.gains();
+        for (i, frame) in frames.iter().enumerate() {
+            let (l, r) = (frame[0] * gain * left, frame[1] * gain * right);
+            self.close[start + i][0] += l;
+            self.close[start + i][1] += r;
+            if placement.overheads > 0.0 {
+                self.overheads[start + i][0] += l * placement.overheads;
+                self.overheads[start + i][1] += r * placement.overheads;
+            }
+            self.room[start + i] += (frame[0] + frame[1]) / 2.0 * gain * placement.room;
+        }
+    }
+
+    fn resize(&mut self, length: usize) {
+        if self.close.len() < length {
+            self.close.resize(length, [0.0; 2]);
+            self.overheads.resize(length, [0.0; 2]);
+            self.room.resize(length, 0.0);
+        }
+    }
+
+    /// Adds the other buses into these.
+    pub(crate) fn merge(&mut self, other: &Buses) {
+        self.resize(other.close.len());
+        for i in 0..other.close.len() {
+            for c in 0..2 {
+                self.close[i][c] += other.close[i][c];
+                self.overheads[i][c] += other.overheads[i][c];
+            }
+            self.room[i] += other.room[i];
+        }
+    }
+
+    /// Sums the buses into the master, which is extended for the room to ring out.
+    pub(crate) fn mix(self, mix: &Mix, sample_rate: u32) -> Vec<[f32; 2]> {
+        let mut out = self.mix_unlimited(mix, sample_rate);
+        if let Some(ceiling) = mix.ceiling {
+            limit(&mut out, ceiling, (0.05 * sample_rate as f32) as usize);
+        }
+        out
+    }
+
+    /// Sums the buses without limiting the result, the way they're heard before the master.
+    pub(crate) fn mix_unlimited(self, mix: &Mix, sample_rate: u32) -> Vec<[f32; 2]> {
+        let frames = |seconds: f32| (seconds.max(0.0) * sample_rate as f32).round() as usize;
+        let mut out = self.close;
+        let overheads_delay = frames(mix.overheads_delay);
+        let room_delay = frames(mix.room_delay).max(1);
+        let has_room = self.room.iter().any(|x| *x != 0.0) && mix.room_decay > 0.0;
+        // Until the reflections fade under -60dB.
+        let reflections = match has_room {
+            true => ((0.001f32).ln() / mix.room_decay.min(0.99).ln()).ceil() as usize,
+            false => 0,
+        };
+        out.resize(out.len() + overheads_delay.max(room_delay * reflections), [0.0; 2]);
+
+        for (i, frame) in self.overheads.iter().enumerate() {
+            out[i + overheads_delay][0] += frame[0];
+            out[i + overheads_delay][1] += frame[1];
+        }
+        if has_room {
+            // A feedback comb filter: every reflection repeats the previous one, quieter.
+            let mut room = self.room;
+            room.resize(out.len(), 0.0);
+            let mut reflected = vec![0.0; out.len()];
+            for i in room_delay..out.len() {
+                reflected[i] = room[i - room_delay] + mix.room_decay * reflected[i - room_delay];
+                out[i][0] += reflected[i];
+                out[i][1] += reflected[i];
+            }
+        }
+        out
+    }
+}
+
+/// Keeps the peaks under `ceiling`: the gain drops at once on a peak that would go over it and comes back
+/// exponentially over about `release` frames.
+fn limit(frames: &mut [[f32; 2]], ceiling: f32, release: usize) {
+    // What's left of the gain reduction after a frame, it's down to a thousandth after `release` frames.
+    let recovery = 0.001f32.powf(1.0 / release.max(1) as f32);
+    let mut gain: f32 = 1.0;
+    for frame in frames.iter_mut() {
+        let peak = frame[0].abs().max(frame[1].abs());
+        gain = (1.0 - (1.0 - gain) * recovery).min(1.0);
+        if peak * gain > ceiling {
+            gain = ceiling / peak;
+        }
+        frame[0] *= gain;
+        frame[1] *= gain;
+    }
+}
+
+#[test]
+fn test_placement() {
+    assert_eq!(Placement::default().gains(), [1.0, 1.0]);
+    assert_eq!(Placement { pan: -1.0, ..Default::default() }.gains(), [1.0, 0.0]);
+    assert_eq!(Placement { pan: 0.5, ..Default::default() }.gains(), [0.5, 1.0]);
+}
+
+#[test]
+fn test_buses() {
+    let mix = Mix { overheads_delay: 2.0, room_delay: 1.0, room_decay: 0.5, ceiling: None };
+    let mut buses = Buses::new(2);
+    buses.add(0, &[[1.0, 1.0]], 1.0, Placement { pan: 0.5, overheads: 0.5, room: 0.0 });
+    let out = buses.mix(&mix, 1);
+    assert_eq!(out, vec![[0.5, 1.0], [0.0, 0.0], [0.25, 0.5], [0.0, 0.0]]);
+
+    let mut buses = Buses::new(1);
+    buses.add(0, &[[1.0, 1.0]], 1.0, Placement { room: 1.0, ..Default::default() });
+    let out = buses.mix(&mix, 1);
+    // 0.5^10 is the first reflection under -60dB.
+    assert_eq!(out.len(), 11);
+    assert_eq!(out[..4].iter().map(|f| f[0]).collect::<Vec<f32>>(), vec![1.0, 1.0, 0.5, 0.25]);
+}
+
+#[test]
+fn test_limit() {
+    let mut frames = vec![[2.0, -0.5], [0.5, 0.5], [0.0, 0.0]];
+    limit(&mut frames, 1.0, 1);
+    assert_eq!(frames[0], [1.0, -0.25]);
+    // The gain recovers right away with a release of a single frame.
+    assert!(frames[1][0] > 0.499);
+    // As it does with no release at all, e.g. at a very low sample rate.
+    let mut frames = vec![[2.0, -0.5], [0.5, 0.5]];
+    limit(&mut frames, 1.0, 0);
+    assert_eq!(frames[0], [1.0, -0.25]);
+    assert!(frames[1][0] > 0.499 && frames[1][0] <= 0.5);
+    let mut frames = vec![[0.5, 0.5]; 4];
+    limit(&mut frames, 1.0, 100);
+    assert_eq!(frames, vec![[0.5, 0.5]; 4]);
+}
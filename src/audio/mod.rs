@@ -0,0 +1,143 @@
+pub mod encode;
+pub mod kit;
+pub mod mix;
+
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use midly::num::u7;
+use midly::Smf;
+
+use crate::audio::encode::{encode, AudioFormat};
+use crate::audio::kit::{Kit, Picker};
+use crate::audio::mix::Buses;
+use crate::midi::core::{DrumMap, DrumPart};
+use crate::midi::playback::{duration, schedule};
+
+/// Stereo audio, left and right sample of every frame.
+pub type Frames = Vec<[f32; 2]>;
+
+/// Note on, on the channel the drums are written to.
+static DRUMS_NOTE_ON: u8 = 0x9a;
+
+/// Plays the drum track of the file through the kit and mixes it. The output lasts as long as the file, plus
+/// however long the last hits and the room ring. Parts without samples in the kit are silent, `seed` drives the
+/// random choice of samples.
+pub fn render(smf: &Smf, kit: &Kit, drum_map: &DrumMap, seed: u64) -> Frames {
+    let (length, parts) = play_parts(smf, kit, drum_map, seed);
+    merge(length, &parts).mix(&kit.mix, kit.sample_rate)
+}
+
+/// The mix along with a stem per part that has samples in the kit, all of the same length. A stem is what the
+/// part adds to the mix before the limiter: its close mic, its bleed into the overheads and its room, so the stems
+/// sum up to the mix if it's not limited.
+pub fn render_stems(
+    smf: &Smf,
+    kit: &Kit,
+    drum_map: &DrumMap,
+    seed: u64,
+) -> (Frames, BTreeMap<DrumPart, Frames>) {
+    let (length, parts) = play_parts(smf, kit, drum_map, seed);
+    let mix = merge(length, &parts).mix(&kit.mix, kit.sample_rate);
+    let stems = parts
+        .into_iter()
+        .map(|(part, buses)| {
+            let mut stem = buses.mix_unlimited(&kit.mix, kit.sample_rate);
+            stem.resize(mix.len(), [0.0; 2]);
+            (part, stem)
+        })
+        .collect();
+    (mix, stems)
+}
+
+fn merge(length: usize, parts: &BTreeMap<DrumPart, Buses>) -> Buses {
+    let mut buses = Buses::new(length);
+    for part in parts.values() {
+        buses.merge(part);
+    }
+    buses
+}
+
+/// Places the hits of every part on its own buses. Samples are picked in the order of the hits across the parts,
+/// so the picks are the same whichever way the parts are mixed. Also returns the length of the file in frames.
+fn play_parts(smf: &Smf, kit: &Kit, drum_map: &DrumMap, seed: u64) -> (usize, BTreeMap<DrumPart, Buses>) {
+    // The other tracks are the bass and the clicks, which may share keys with the drums.
+    let drums = Smf { header: smf.header, tracks: smf.tracks.iter().take(1).cloned().collect() };
+    let to_frame = |microseconds: u64| (microseconds * kit.sample_rate as u64 / 1_000_000) as usize;
+    let length = to_frame(duration(&drums));
+    let mut parts: BTreeMap<DrumPart, Buses> = BTreeMap::new();
+    let mut picker = Picker::new(kit, seed);
+    for message in schedule(&drums, 1) {
+        let (status, key, velocity) = match message.message[..] {
+            [status, key, velocity] => (status, key, velocity),
+            _ => continue,
+        };
+        if status != DRUMS_NOTE_ON || velocity == 0 {
+            continue;
+        }
+        let Some(part) = drum_map.part(u7::from(key)) else { continue };
+        if let Some((sample, gain)) = picker.pick(part, velocity) {
+            let buses = parts.entry(part).or_insert_with(|| Buses::new(length));
+            buses.add(to_frame(message.at), &sample.frames, gain, kit.parts[&part].placement);
+        }
+    }
+    (length, parts)
+}
+
+/// Writes the frames to a 16-bit stereo audio file, in the format matching its extension.
+pub fn write_audio(path: &Path, frames: &[[f32; 2]], sample_rate: u32) -> Result<(), String> {
+    let format = AudioFormat::from_path(path)?;
+    let bytes = encode(frames, sample_rate, format)?;
+    std::fs::write(path, bytes).map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+use crate::audio::kit::test_kit;
+#[cfg(test)]
+use crate::dsl::dsl::groups;
+#[cfg(test)]
+use crate::midi::core::{generate, RenderOptions};
+#[cfg(test)]
+use DrumPart::*;
+
+#[test]
+fn test_render() {
+    // Samples are a single frame, 1 on the kick and 2 on the snare.
+    let source = "sample-rate = 8\n[mix]\nlimiter = false\n[parts.kick]\nsamples = [\"k\"]\n[parts.snare]\nsamples = [\"ss\"]";
+    let kit = test_kit(source).unwrap();
+    let parts = BTreeMap::from_iter([(KickDrum, groups("4x---").unwrap()), (SnareDrum, groups("4--x-").unwrap())]);
+    let options = RenderOptions { add_bass: true, ..Default::default() };
+    let smf = generate(parts, "", &options).unwrap();
+    let frames = render(&smf, &kit, &DrumMap::default(), 0);
+    // A bar of 4/4 at 120 BPM is 2 seconds, 4 frames per quarter note.
+    assert_eq!(frames.len(), 16);
+    let gain = 100.0 / 127.0;
+    assert_eq!(frames[0], [gain; 2]);
+    assert_eq!(frames[8], [2.0 * gain; 2]);
+    assert_eq!(frames.iter().filter(|f| f[0] != 0.0).count(), 2);
+
+    // The snare panned hard right and limited.
+    let kit = test_kit(&format!("{}\npan = 1.0", source.replace("limiter = false", "ceiling = -6.0"))).unwrap();
+    let frames = render(&smf, &kit, &DrumMap::default(), 0);
+    assert_eq!(frames[8][0], 0.0);
+    assert!((frames[8][1] - 0.5).abs() < 0.01);
+}
+
+#[test]
+fn test_render_stems() {
+    let source = "sample-rate = 8\n[mix]\nlimiter = false\nroom-delay = 250.0\n[parts.kick]\nsamples = [\"k\"]\nroom = 0.5\n[parts.snare]\nsamples = [\"ss\"]\n[parts.hi-hat]\nsamples = [\"h\"]";
+    let kit = test_kit(source).unwrap();
+    let parts = BTreeMap::from_iter([(KickDrum, groups("4x---").unwrap()), (SnareDrum, groups("4--x-").unwrap())]);
+    let smf = generate(parts, "", &RenderOptions::default()).unwrap();
+    let (mix, stems) = render_stems(&smf, &kit, &DrumMap::default(), 0);
+    assert_eq!(mix, render(&smf, &kit, &DrumMap::default(), 0));
+    // The hi-hat isn't played, so it has no stem.
+    assert_eq!(stems.keys().copied().collect::<Vec<DrumPart>>(), vec![KickDrum, SnareDrum]);
+    assert!(stems.values().all(|stem| stem.len() == mix.len()));
+    // The room of the kick rings on after the bar.
+    assert!(mix.len() > 16);
+    assert!(stems[&SnareDrum][16..].iter().all(|f| *f == [0.0; 2]));
+    for (i, frame) in mix.iter().enumerate() {
+        assert_eq!(stems[&KickDrum][i][0] + stems[&SnareDrum][i][0], frame[0]);
+    }
+}
@@ -0,0 +1,12 @@
+use std::io::{stdin, stdout};
+use std::process::exit;
+
+use polyrhythmix::lsp::run;
+
+/// Language server for pattern files, talking to the editor over stdin and stdout.
+fn main() {
+    if let Err(e) = run(stdin().lock(), stdout().lock()) {
+        eprintln!("poly-lsp: {}", e);
+        exit(1)
+    }
+}
@@ -1,12 +1,51 @@
 use std::collections::BTreeMap;
+use std::fs::{create_dir_all, read, read_to_string, write, OpenOptions};
+use std::io::{stdin, stdout, BufRead, ErrorKind, IsTerminal, Write};
+use std::iter;
+use std::path::{Path, PathBuf};
 use std::process::exit;
 use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Sender};
+use std::thread::available_parallelism;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
 
+use polyrhythmix::arrangement::Arrangement;
+use polyrhythmix::audio::{self, encode::AudioFormat, kit::Kit};
+use polyrhythmix::daily::{self, Constraints, Date};
+use polyrhythmix::etude::Etude;
 use polyrhythmix::dsl::dsl;
-use polyrhythmix::midi::core::{create_smf, DrumPart};
-use polyrhythmix::midi::time::TimeSignature;
+use polyrhythmix::dsl::file::{self as pattern_file, PatternFile};
+use polyrhythmix::dsl::highlight::{highlight, Editor};
+use polyrhythmix::dsl::session::Session;
+use polyrhythmix::dsl::variation::{self, variations, Variation};
+use polyrhythmix::dsl::version::DslVersion;
+use polyrhythmix::error::PolyError;
+use polyrhythmix::export::{self as notation, pack::{self, Manifest}, ExportFormat, Instruments};
+use polyrhythmix::midi::core::{generate_with_bars, parse_drum_mapping, DrumMap, DrumPart, RenderOptions, Sidechain, TrackEnd};
+use polyrhythmix::midi::doubling::Doubling;
+use polyrhythmix::midi::ensemble;
+use polyrhythmix::midi::filter::{Action, Filter, Filters};
+use polyrhythmix::midi::fingerprint::{fingerprint, hash};
+use polyrhythmix::midi::humanize::{Humanize, STRAIGHT_SWING};
+use polyrhythmix::midi::import;
+use polyrhythmix::midi::playback::{metronome, schedule, stream};
+use polyrhythmix::midi::markers::{add_markers, cycles, markers as bar_markers};
+use polyrhythmix::midi::mask::{parse_part_mask, Mask, Masks};
+use polyrhythmix::midi::provenance::{sidecar_path, Provenance};
+use polyrhythmix::midi::target::{retarget, Target};
+use polyrhythmix::midi::transform::{Automation, Crashes, Ending, IntensityArc, Transform};
+use polyrhythmix::midi::trigger::{Trigger, Triggers};
+use polyrhythmix::midi::variant::Variant;
+use polyrhythmix::midi::time::{tempo_from_taps, TempoSweep, TimeSignature};
+use polyrhythmix::random::Rng;
+use polyrhythmix::share::{Share, ShareEncoding};
+use polyrhythmix::template::{self, Variables, AUTO_NAME_TEMPLATE, DEFAULT_TEMPLATE};
+use polyrhythmix::video;
 
 use clap::*;
+use midly::num::u7;
+use midly::Smf;
 use DrumPart::*;
 
 #[derive(Debug, Parser, Clone)]
@@ -14,7 +53,12 @@ use DrumPart::*;
 #[command(author = "Denis Redozubov <denis.redozubov@gmail.com>")]
 #[command(version = "0.1")]
 #[command(about = "Polyrhythmically-inclinded Midi Drum generator", long_about = None)]
+#[command(args_conflicts_with_subcommands = true)]
+#[command(group(ArgGroup::new("destination").args(["output", "output_dir", "auto_name"]).multiple(true)))]
 struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+
     #[arg(short = 'K', long = "kick", default_value = None, help = "Kick drum pattern")]
     kick: Option<String>,
 
@@ -27,17 +71,682 @@ struct Cli {
     #[arg(short = 'C', long = "crash", default_value = None, help = "Crash cymbal pattern")]
     crash: Option<String>,
 
+    #[arg(long = "open-hi-hat", default_value = None, help = "Open Hi-Hat pattern")]
+    open_hihat: Option<String>,
+
+    #[arg(long = "ride", default_value = None, help = "Ride cymbal pattern")]
+    ride: Option<String>,
+
+    #[arg(long = "tom1", default_value = None, help = "High tom pattern")]
+    tom1: Option<String>,
+
+    #[arg(long = "tom2", default_value = None, help = "Mid tom pattern")]
+    tom2: Option<String>,
+
+    #[arg(long = "tom3", default_value = None, help = "Floor tom pattern")]
+    tom3: Option<String>,
+
     #[arg(short = 't', long = "tempo", default_value = "120", help = "Tempo value")]
     tempo: u16,
 
+    #[arg(long = "tap", conflicts_with = "tempo", help = "Set the tempo by tapping Enter on every beat")]
+    tap: bool,
+
+    #[arg(long = "tap-port", value_name = "PORT", requires = "tap", help = "MIDI input port whose notes are taps as well, by number or by a part of its name")]
+    tap_port: Option<String>,
+
+    #[arg(long = "tempo-sweep", value_parser = TempoSweep::from_str, conflicts_with_all = ["tempo", "tap"], help = "Render the pattern once per tempo in a 'from:to:step' BPM range")]
+    tempo_sweep: Option<TempoSweep>,
+
     #[arg(short = 's', long = "time-signature", default_value = "4/4", help = "Time signature")]
     time_signature: String,
 
-    #[arg(short = 'o', long = "output-file", default_value = None, help = "Output file path, make a dry run if omitted")]
+    #[arg(short = 'o', long = "output-file", default_value = None, value_parser = template::validate, help = "Output file path, make a dry run if omitted. {name}, {tempo}, {sig} and {hash} are filled in with the name of the pattern file, the tempo, the time signature and a hash of the patterns, e.g. '{name}_{tempo}bpm_{sig}.mid'")]
     output: Option<String>,
 
+    #[arg(long = "output-dir", help = "Directory to write the output file to, created if missing. The file is named '{name}_{tempo}bpm_{sig}.mid' unless -o names it")]
+    output_dir: Option<String>,
+
+    #[arg(long = "auto-name", conflicts_with = "output", help = "Name the output file after a hash of the patterns, the tempo and the time signature, e.g. '67639f84_120bpm_4-4.mid', instead of making a dry run")]
+    auto_name: bool,
+
+    #[arg(long = "force", help = "Overwrite existing files without asking")]
+    force: bool,
+
+    #[arg(long = "variants", value_name = "VARIANTS", value_parser = Variant::from_str, value_delimiter = ';', requires = "destination", conflicts_with = "arrangement", help = "Also render the groove with other settings to files of their own, suffixed with the settings, e.g. 'swing=60;tempo=90,halftime'. Settings are 'tempo=<BPM>', 'swing=<50..75>', 'halftime' and 'doubletime', which halve and double the tempo")]
+    variants: Vec<Variant>,
+
     #[clap(short = 'B', long = "follow-kick-drum-with-bass", help = "Generate a second MIDI track for the bass following the kick drum")]
     follow_kick_drum_with_bass: bool,
+
+    #[arg(long = "click-parts", help = "Generate an extra MIDI track clicking the start of every cycle of each drum part")]
+    click_parts: bool,
+
+    #[arg(long = "sidechain", value_name = "KEY", value_parser = value_parser!(u8).range(0..=127), help = "Generate an extra MIDI track playing this key on channel 16 at every kick drum hit, to trigger sidechain compression")]
+    sidechain: Option<u8>,
+
+    #[arg(long = "sidechain-length", value_name = "LENGTH", default_value = "16", requires = "sidechain", value_parser = dsl::Length::from_str, help = "Length of the sidechain trigger notes")]
+    sidechain_length: dsl::Length,
+
+    #[arg(long = "ensemble", help = "Give every drum part to a player of a percussion ensemble, each on a MIDI track of its own, and print who plays what")]
+    ensemble: bool,
+
+    #[arg(long = "double", value_name = "PART=INSTRUMENT:PITCHES", value_parser = Doubling::from_str, help = "Double a drum part with a melodic instrument playing the next pitch of a sequence at every hit, e.g. 'hi-hat=marimba:E4,G4,B4'. Can be given several times")]
+    double: Vec<Doubling>,
+
+    #[arg(long = "teach", help = "Render a layered lesson: the first drum part alone, then adding one part at a time")]
+    teach: bool,
+
+    #[arg(long = "prob", value_name = "PART=MASK", value_parser = parse_part_mask, value_delimiter = ',', help = "Play the hits of a drum part with a chance changing over time like a pattern, e.g. 'hi-hat=16 9999 5555': a step length and a digit from 0 (never) to 9 (always) per step")]
+    prob: Vec<(DrumPart, Mask)>,
+
+    #[arg(long = "rule", value_parser = Trigger::from_str, value_delimiter = ',', help = "Play the notes of a drum part only when another part is or isn't playing at the same time, e.g. 'snare.ghost unless kick' or 'open-hi-hat unless snare'")]
+    rule: Vec<Trigger>,
+
+    #[arg(long = "filter", value_parser = Filter::from_str, help = "Mute, thin or change the velocity of the notes matching an expression, e.g. 'part == hi-hat && beat % 2 == 0 -> velocity*0.5'. Can be given several times, see the README for the syntax")]
+    filter: Vec<Filter>,
+
+    #[arg(long = "variation", value_parser = parse_amount, help = "Mutate every repetition of the patterns a bit, from 0 (no changes) to 1 (every note)")]
+    variation: Option<f64>,
+
+    #[arg(long = "seed", help = "Seed for everything random, picked automatically if omitted")]
+    seed: Option<u64>,
+
+    #[arg(long = "arc", value_parser = Automation::from_str, help = "Shape the intensity over the whole output: 'build' or 'peak-at=<0..1>'")]
+    arc: Option<Automation>,
+
+    #[arg(long = "ending", value_parser = Ending::from_str, help = "Finish with a final bar instead of stopping mid-groove: 'crash', 'button' or 'fade'")]
+    ending: Option<Ending>,
+
+    #[arg(long = "crashes", value_name = "CHANCE", value_parser = parse_amount, help = "Place the crashes only on strong downbeats: where the parts line up again, after fills and at section starts. Each one gets a crash with this chance, from 0 to 1")]
+    crashes: Option<f64>,
+
+    #[arg(long = "humanize-timing", default_value = "0", help = "Move every note off the grid by up to this many ticks (48 per quarter note) either way")]
+    humanize_timing: u16,
+
+    #[arg(long = "humanize-velocity", default_value = "0", value_parser = value_parser!(u8).range(0..=127), help = "Change the velocity of every note by up to this much either way")]
+    humanize_velocity: u8,
+
+    #[arg(long = "swing", value_parser = parse_swing, help = "Delay the second half of every beat: 50 is straight, 66 is a triplet shuffle")]
+    swing: Option<f64>,
+
+    #[arg(long = "tail-rest", value_parser = dsl::Length::from_str, conflicts_with = "trim", help = "Leave a rest of this length after the last bar, e.g. '1' or '2+4'")]
+    tail_rest: Option<dsl::Length>,
+
+    #[arg(long = "trim", help = "End the output right after the last note instead of the end of the last bar")]
+    trim: bool,
+
+    #[arg(long = "arrangement", help = "Render a whole song from a TOML file with sections instead of the drum patterns")]
+    arrangement: Option<String>,
+
+    #[arg(long = "patterns", conflicts_with = "arrangement", help = "Read the drum patterns from a file with a 'part: pattern' line per part, the patterns given as options take precedence")]
+    patterns: Option<String>,
+
+    #[arg(long = "check", value_name = "FILE", exclusive = true, help = "Only check a pattern file, printing every problem as 'file:line:column: error|warning: message' and failing if there are errors")]
+    check: Option<String>,
+
+    #[arg(long = "share", value_name = "ENCODING", value_parser = ShareEncoding::from_str, num_args = 0..=1, default_missing_value = "plain", conflicts_with_all = ["tempo_sweep", "arrangement", "export"], help = "Print the groove as a single line to paste elsewhere and render it with --from-share: 'plain' (default) or 'base64'")]
+    share: Option<ShareEncoding>,
+
+    #[arg(long = "from-share", value_name = "GROOVE", value_parser = Share::from_str, conflicts_with_all = ["kick", "snare", "hihat", "crash", "open_hihat", "ride", "tom1", "tom2", "tom3", "tempo", "tap", "tempo_sweep", "time_signature", "map", "patterns", "arrangement"], help = "Render a groove printed by --share")]
+    from_share: Option<Share>,
+
+    #[arg(long = "qr", value_name = "PNG", requires = "share", num_args = 0..=1, default_missing_value = "", help = "Also show the shared groove as a QR code, or write it to a PNG image if a path is given")]
+    qr: Option<String>,
+
+    #[arg(long = "fingerprint", help = "Print a hash of the rendered notes, the same for every rendering of the same groove")]
+    fingerprint: bool,
+
+    #[arg(long = "provenance", requires = "destination", help = "Also write where every MIDI file came from next to it, e.g. 'groove.json' for 'groove.mid': the version, the seed, every option and hashes of the files read, to render it again exactly")]
+    provenance: bool,
+
+    #[arg(long = "markers", help = "Write a marker at every bar of the MIDI file, named after the bar and the section or the cycle of the groove it's in, e.g. 'Bar 5 - verse', for video editors to cut to")]
+    markers: bool,
+
+    #[arg(long = "map", value_parser = parse_drum_mapping, value_delimiter = ',', help = "Override the MIDI key of a drum part, e.g. 'ride=59'. Defaults to General MIDI")]
+    map: Vec<(DrumPart, u7)>,
+
+    #[arg(long = "target", value_parser = Target::from_str, help = "Family of hardware sound modules to write the MIDI file for, 'gm', 'gm2', 'gs' or 'xg': the drums go to channel 10 and to the keys of the family, banks are selected and the module is reset at the start")]
+    target: Option<Target>,
+
+    #[arg(long = "threads", value_parser = clap::value_parser!(u16).range(1..), help = "Number of threads to render the drum parts on, the output is the same for any number. Defaults to the number of CPUs")]
+    threads: Option<u16>,
+
+    #[arg(long = "export", value_parser = ExportFormat::from_str, conflicts_with = "arrangement", help = "Write the patterns as drum notation instead of MIDI: 'lilypond' or 'tab'. Printed out if there's no output file")]
+    export: Option<ExportFormat>,
+
+    #[arg(long = "instruments", value_parser = Instruments::from_str, help = "Who the notation of --export and --pack is written for: 'drums', 'ensemble' (the default with --ensemble) or 'body' to relabel the parts as body percussion")]
+    instruments: Option<Instruments>,
+
+    #[arg(long = "render-audio", value_name = "FILE", requires = "kit", conflicts_with = "export", help = "Also render the drums with the samples of a kit to an audio file: .wav, or .flac, .ogg and .mp3 if built with the features of the same names")]
+    render_audio: Option<String>,
+
+    #[arg(long = "render-stems", requires = "render_audio", help = "Also write an audio file per drum part next to the rendered audio, e.g. 'groove-kick.wav' for 'groove.wav'")]
+    render_stems: bool,
+
+    #[arg(long = "pack", value_name = "ZIP", conflicts_with_all = ["arrangement", "export"], help = "Also bundle the MIDI file, the notation, an ASCII tab, the pattern file and a manifest into a ZIP archive")]
+    pack: Option<String>,
+
+    #[arg(long = "render-video", value_name = "GIF", conflicts_with_all = ["arrangement", "export"], help = "Experimental: also draw the parts as rings of hits going around in time to an animated GIF")]
+    render_video: Option<String>,
+
+    #[arg(long = "kit", requires = "render_audio", help = "Kit definition with the samples to render the audio with, see 'Audio' in the README")]
+    kit: Option<String>,
+
+    #[arg(long = "play", help = "Play the output through a MIDI output port after rendering it")]
+    play: bool,
+
+    #[arg(long = "port", requires = "play", help = "MIDI output port to play through, by number or by a part of its name. Defaults to the first one")]
+    port: Option<String>,
+
+    #[arg(long = "device", value_name = "PATH", conflicts_with = "play", help = "Play the output by writing raw MIDI to a device file, e.g. an ALSA raw MIDI device such as /dev/snd/midiC1D0 or the one of a USB MIDI gadget, without the MIDI libraries")]
+    device: Option<String>,
+
+    #[arg(long = "metronome", conflicts_with_all = ["play", "device"], help = "Show a metronome scrolling a line per beat in time with the output, marking the beat of the bar and the parts played, to clap along without a synth")]
+    metronome: bool,
+
+    #[arg(long = "loops", default_value = "1", value_parser = clap::value_parser!(u32).range(1..), help = "Number of times to play the output in a row with --play, --device or --metronome")]
+    loops: u32,
+}
+
+#[derive(Debug, Subcommand, Clone)]
+enum Command {
+    /// Rewrite a pattern file written in an older version of the DSL to the latest one
+    Migrate {
+        #[arg(help = "Pattern file to migrate")]
+        file: String,
+
+        #[arg(short = 'o', long = "output-file", help = "Where to write the migrated file, printed out if omitted")]
+        output: Option<String>,
+
+        #[arg(long = "force", help = "Overwrite existing files without asking")]
+        force: bool,
+    },
+    /// Write the drum parts of a MIDI file down as a pattern file, quantized to a straight or a swung grid
+    Import {
+        #[arg(help = "MIDI file to import")]
+        file: String,
+
+        #[arg(short = 'o', long = "output-file", help = "Where to write the pattern file, printed out if omitted")]
+        output: Option<String>,
+
+        #[arg(long = "force", help = "Overwrite existing files without asking")]
+        force: bool,
+
+        #[arg(long = "grid", default_value = "16", value_parser = dsl::BasicLength::from_str, help = "Length of the notes to quantize to")]
+        grid: dsl::BasicLength,
+
+        #[arg(long = "swing", value_parser = parse_swing, help = "Quantize to the grid swung this much instead of detecting the swing: 50 is straight")]
+        swing: Option<f64>,
+
+        #[arg(long = "tempo", value_parser = value_parser!(u16).range(1..), help = "Tempo a file without one was played at, detected from the notes if omitted")]
+        tempo: Option<u16>,
+
+        #[arg(long = "names", default_value = "numbers", value_parser = dsl::LengthNames::from_str, help = "How to write the note lengths: 'numbers', 'us' for quarter, eighth, or 'uk' for crotchet, quaver")]
+        names: dsl::LengthNames,
+    },
+    /// Print variations of the patterns of a pattern file next to each other, to pick one from
+    Vary {
+        #[arg(help = "Pattern file to vary")]
+        file: String,
+
+        #[arg(long = "count", default_value = "8", value_parser = value_parser!(u16).range(1..), help = "Number of variations")]
+        count: u16,
+
+        #[arg(long = "amount", default_value = "0.2", value_parser = parse_amount, help = "Chance for every note to get mutated, from 0 to 1")]
+        amount: f64,
+
+        #[arg(long = "seed", help = "Seed for the mutations, picked automatically if omitted")]
+        seed: Option<u64>,
+    },
+    /// Print a syntax definition of pattern files for an editor
+    Highlight {
+        #[arg(long = "editor", value_parser = Editor::from_str, help = "Editor to write the definition for: 'vim', 'vscode' or 'sublime'")]
+        editor: Editor,
+
+        #[arg(short = 'o', long = "output-file", help = "Where to write the definition, printed out if omitted")]
+        output: Option<String>,
+
+        #[arg(long = "force", help = "Overwrite existing files without asking")]
+        force: bool,
+    },
+    /// Check the expectations declared in pattern files, e.g. 'expect bars = 12'
+    Test {
+        #[arg(required = true, help = "Pattern files to check")]
+        files: Vec<String>,
+
+        #[arg(short = 's', long = "time-signature", default_value = "4/4", value_parser = TimeSignature::from_str, help = "Time signature to render the patterns in")]
+        time_signature: TimeSignature,
+    },
+    /// Write a groove derived from the date to a pattern file and a MIDI file, the same one all day
+    Daily {
+        #[arg(long = "out-dir", default_value = ".", help = "Directory to write the files to, named after the date, e.g. '2024-03-17.poly' and '2024-03-17.mid'")]
+        out_dir: String,
+
+        #[arg(long = "date", value_parser = Date::from_str, help = "Date to write the groove of, e.g. '2024-03-17'. Defaults to today in UTC")]
+        date: Option<Date>,
+
+        #[arg(short = 's', long = "time-signatures", value_parser = TimeSignature::from_str, value_delimiter = ',', default_value = "4/4,3/4,5/4,7/8", help = "Time signatures to pick from")]
+        time_signatures: Vec<TimeSignature>,
+
+        #[arg(long = "tempo", default_value = "80:140", value_parser = parse_tempo_range, help = "Range of tempos to pick from, as 'slowest:fastest' in BPM")]
+        tempos: (u16, u16),
+
+        #[arg(long = "max-bars", default_value = "8", value_parser = value_parser!(u32).range(1..), help = "Number of bars the parts have to converge within")]
+        max_bars: u32,
+
+        #[arg(long = "parts", value_parser = DrumPart::from_str, value_delimiter = ',', default_value = "kick,snare,hi-hat", help = "Drum parts to write patterns for")]
+        parts: Vec<DrumPart>,
+
+        #[arg(long = "force", help = "Overwrite existing files without asking")]
+        force: bool,
+    },
+    /// Write an etude of every placement of a number of hits on the steps of a bar, a bar each, labelled with markers
+    Etude {
+        #[arg(long = "steps", value_parser = value_parser!(u32).range(1..=64), help = "Number of steps the bar is divided into, e.g. 16 for sixteenths or 12 for eighth triplets in 4/4")]
+        steps: u32,
+
+        #[arg(long = "hits", value_parser = value_parser!(u32).range(0..=64), help = "Number of hits to place on the steps")]
+        hits: u32,
+
+        #[arg(long = "up-to-rotation", help = "Leave out the placements that are rotations of one already played")]
+        up_to_rotation: bool,
+
+        #[arg(long = "part", default_value = "snare", value_parser = DrumPart::from_str, help = "Drum part to play the hits on")]
+        part: DrumPart,
+
+        #[arg(long = "repeat", default_value = "2", value_parser = value_parser!(u32).range(1..), help = "Times every bar is played before moving on to the next one")]
+        repeat: u32,
+
+        #[arg(short = 's', long = "time-signature", default_value = "4/4", value_parser = TimeSignature::from_str, help = "Time signature of the bars")]
+        time_signature: TimeSignature,
+
+        #[arg(short = 't', long = "tempo", default_value = "80", value_parser = value_parser!(u16).range(1..), help = "Tempo in BPM")]
+        tempo: u16,
+
+        #[arg(short = 'o', long = "output-file", help = "Where to write the MIDI file, make a dry run if omitted")]
+        output: Option<String>,
+
+        #[arg(long = "force", help = "Overwrite existing files without asking")]
+        force: bool,
+    },
+    /// Browse a library of pattern files
+    Lib {
+        #[command(subcommand)]
+        command: LibCommand,
+    },
+    /// Compose interactively, a part at a time, with undo and redo
+    Repl {
+        #[arg(long = "session", help = "Session file to pick up and to keep the history in, so the session can be resumed later")]
+        session: Option<String>,
+
+        #[arg(long = "force", help = "Overwrite existing files without asking")]
+        force: bool,
+    },
+}
+
+impl Command {
+    /// Whether `--force` was passed to a command that writes files, the others don't take it.
+    fn force(&self) -> bool {
+        match self {
+            Command::Migrate { force, .. }
+            | Command::Import { force, .. }
+            | Command::Highlight { force, .. }
+            | Command::Daily { force, .. }
+            | Command::Etude { force, .. }
+            | Command::Repl { force, .. } => *force,
+            Command::Vary { .. } | Command::Test { .. } | Command::Lib { .. } => false,
+        }
+    }
+}
+
+fn migrate_file(path: &str, output: Option<String>) {
+    let source = match read_to_string(path) {
+        Ok(x) => x,
+        Err(e) => {
+            println!("Can't read {}: {}", path, e);
+            exit(1)
+        }
+    };
+    match pattern_file::migrate(&source) {
+        Ok(migrated) => save_text(&migrated, output),
+        Err(errors) => {
+            println!("Can't migrate {}:", path);
+            for e in errors {
+                println!("  {}", e);
+            }
+            exit(1)
+        }
+    }
+}
+
+fn check_file(path: &str) {
+    let source = match read_to_string(path) {
+        Ok(x) => x,
+        Err(e) => {
+            println!("{}: error: can't read the file: {}", path, e);
+            exit(1)
+        }
+    };
+    let diagnostics = pattern_file::diagnostics(&source);
+    for d in &diagnostics {
+        let severity = if d.warning { "warning" } else { "error" };
+        println!("{}:{}:{}: {}: {}", path, d.line + 1, d.columns.start + 1, severity, d.message);
+    }
+    if diagnostics.iter().any(|d| !d.warning) {
+        exit(1)
+    }
+}
+
+#[derive(Debug, Subcommand, Clone)]
+enum LibCommand {
+    /// List the pattern files in the directories and below, along with their tags and parts
+    List {
+        #[arg(default_value = ".", help = "Directories to look for '.poly' files in")]
+        dirs: Vec<String>,
+
+        #[arg(long = "tag", help = "Only list the files with this tag, can be given several times for files with all of them")]
+        tags: Vec<String>,
+    },
+}
+
+/// Pattern files in the directory and the ones below it, in order.
+fn library(dir: &Path, files: &mut Vec<String>) -> std::io::Result<()> {
+    let mut entries = std::fs::read_dir(dir)?.map(|entry| entry.map(|e| e.path())).collect::<Result<Vec<_>, _>>()?;
+    entries.sort();
+    for path in entries {
+        if path.is_dir() {
+            library(&path, files)?;
+        } else if path.extension().is_some_and(|extension| extension == "poly") {
+            files.push(path.to_string_lossy().into_owned());
+        }
+    }
+    Ok(())
+}
+
+fn list_library(dirs: &[String], tags: &[String]) {
+    let mut files = Vec::new();
+    for dir in dirs {
+        if let Err(e) = library(Path::new(dir), &mut files) {
+            println!("Can't list {}: {}", dir, e);
+            exit(1)
+        }
+    }
+    for path in files {
+        match read_to_string(&path).map_err(|e| e.to_string()).and_then(|source| {
+            PatternFile::from_str(&source).map_err(|e| e.to_string())
+        }) {
+            Ok(file) if file.has_tags(tags) => {
+                let parts: Vec<&str> = file.patterns.keys().map(|part| part.name()).collect();
+                println!("{}: [{}] {}", path, file.tags.join(", "), parts.join(", "));
+            }
+            Ok(_) => {}
+            Err(e) if tags.is_empty() => println!("{}: can't read the file: {}", path, e),
+            Err(_) => {}
+        }
+    }
+}
+
+fn test_files(paths: &[String], time_signature: TimeSignature) {
+    let (mut checked, mut failed) = (0, 0);
+    for path in paths {
+        let file = match read_to_string(path).map_err(|e| e.to_string()).and_then(|source| {
+            PatternFile::from_str(&source).map_err(|e| e.to_string())
+        }) {
+            Ok(file) => file,
+            Err(e) => {
+                println!("{}: can't read the file: {}", path, e);
+                failed += 1;
+                continue;
+            }
+        };
+        for (line, expectation) in &file.expectations {
+            checked += 1;
+            if let Err(e) = expectation.check(&file.groups, time_signature) {
+                println!("{}:{}: expect {} failed: {}", path, line, expectation, e);
+                failed += 1;
+            }
+        }
+    }
+    println!("{} expectations checked, {} failed", checked, failed);
+    if failed > 0 {
+        exit(1)
+    }
+}
+
+fn print_converges(bars: u32) {
+    println!("Converges over {} bar{}", bars, if bars == 1 { "" } else { "s" });
+}
+
+fn write_daily(out_dir: &str, date: Option<Date>, constraints: Constraints) {
+    let date = date.unwrap_or_else(Date::today);
+    let daily = daily::groove(date, &constraints);
+    if let Err(e) = create_dir_all(out_dir) {
+        println!("Can't create {}: {}", out_dir, e);
+        exit(1)
+    }
+    let path = |extension| Path::new(out_dir).join(format!("{}.{}", date, extension)).to_string_lossy().into_owned();
+    let pattern_file = daily.to_pattern_file().into_bytes();
+    let options = RenderOptions { time_signature: daily.time_signature, tempos: vec![daily.tempo], ..Default::default() };
+    let text = format!("Groove of the day for {}", date);
+    let mut midi = Vec::new();
+    let rendered = generate_with_bars(daily.groups, &text, &options).map_err(|e| e.to_string());
+    if let Err(e) = rendered.and_then(|(smf, bars)| {
+        print_converges(bars);
+        smf.write_std(&mut midi).map_err(|e| e.to_string())
+    }) {
+        println!("Can't render the groove: {}", e);
+        exit(1)
+    }
+    // Running again on the same day finds the same groove already there, which is left as it is.
+    let files = [(path("poly"), pattern_file), (path("mid"), midi)];
+    let (unchanged, changed): (Vec<_>, Vec<_>) =
+        files.into_iter().partition(|(path, bytes)| read(path).is_ok_and(|written| written == *bytes));
+    check_outputs(&changed.iter().map(|(path, _)| path.clone()).collect::<Vec<_>>());
+    for (path, _) in unchanged {
+        println!("{} is up to date", path);
+    }
+    for (path, bytes) in changed {
+        save_bytes(&bytes, &path);
+    }
+}
+
+fn write_etude(etude: &Etude, part: DrumPart, tempo: u16, output: Option<String>) {
+    let labels = etude.labels();
+    for (_, label) in &labels {
+        println!("{}", label);
+    }
+    let text = format!("Etude of {} placements on the {}", labels.len(), part.name());
+    let mut smf = etude.to_timeline(part).to_smf(&text, tempo, &DrumMap::default());
+    add_markers(&mut smf, &labels);
+    save_smf(&smf, output, false);
+}
+
+fn repl(path: Option<String>) {
+    let mut session = match path.as_deref().map(read_to_string) {
+        Some(Ok(source)) => match Session::from_str(&source) {
+            Ok(session) => session,
+            Err(e) => {
+                println!("Can't read the session: {}", e);
+                exit(1)
+            }
+        },
+        // A session file that isn't there yet starts a new session, saved to it after the first change.
+        Some(Err(e)) if e.kind() == ErrorKind::NotFound => Session::default(),
+        Some(Err(e)) => {
+            println!("Can't read the session: {}", e);
+            exit(1)
+        }
+        None => Session::default(),
+    };
+    println!("Type '<part>: <pattern>' to set a part, ':clear <part>', ':undo', ':redo', ':show', ':export <file>', ':export! <file>' or ':quit'");
+    print!("{}", session.to_pattern_file());
+    for line in stdin().lock().lines() {
+        let Ok(line) = line else { break };
+        let line = line.trim();
+        let (command, argument) = line.split_once(' ').map_or((line, ""), |(c, a)| (c, a.trim()));
+        let changed = match command {
+            "" => false,
+            ":quit" | ":q" => break,
+            ":undo" => session.undo() || {
+                println!("Nothing to undo");
+                false
+            },
+            ":redo" => session.redo() || {
+                println!("Nothing to redo");
+                false
+            },
+            ":show" => {
+                print!("{}", session.to_pattern_file());
+                false
+            }
+            // Asking on the terminal would read the next line of the session, so overwriting takes a command of its own.
+            ":export" if Path::new(argument).exists() && !FORCE.load(Ordering::Relaxed) => {
+                println!("{} already exists, ':export! {}' overwrites it", argument, argument);
+                false
+            }
+            ":export" | ":export!" if !argument.is_empty() => {
+                write_bytes(session.to_pattern_file().as_bytes(), argument);
+                false
+            }
+            ":clear" => match DrumPart::from_str(argument) {
+                Ok(part) => session.set(part, None).is_ok(),
+                Err(e) => {
+                    println!("{}", e);
+                    false
+                }
+            },
+            _ => match line.split_once(':') {
+                Some((part, pattern)) if !part.is_empty() => {
+                    match DrumPart::from_str(part.trim()).map_err(|e| e.to_string()).and_then(|part| {
+                        session.set(part, Some(pattern.trim())).map_err(|e| e.to_string())
+                    }) {
+                        Ok(_) => true,
+                        Err(e) => {
+                            println!("{}", e);
+                            false
+                        }
+                    }
+                }
+                _ => {
+                    println!("Unknown command '{}'", line);
+                    false
+                }
+            },
+        };
+        if changed {
+            print!("{}", session.to_pattern_file());
+            if let Some(path) = &path {
+                if let Err(e) = write(path, session.to_string()) {
+                    println!("Failed to save the session to {}: {}", path, e);
+                }
+            }
+        }
+    }
+}
+
+fn vary_file(path: &str, count: u16, amount: f64, seed: Option<u64>) {
+    let file = match read_to_string(path).map_err(|e| e.to_string()).and_then(|source| {
+        PatternFile::from_str(&source).map_err(|e| e.to_string())
+    }) {
+        Ok(file) => file,
+        Err(e) => {
+            println!("Can't read {}: {}", path, e);
+            exit(1)
+        }
+    };
+    let seed = seed.unwrap_or_else(pick_seed);
+    let found = variations(&file.groups, count as usize, amount, &mut Rng::new(seed));
+    if found.len() < count as usize {
+        println!("Found only {} distinct variations, try a larger --amount", found.len());
+    }
+    let hits = |patterns: &BTreeMap<DrumPart, dsl::Groups>| {
+        patterns.values().flat_map(|g| g.0.iter().flat_map(|g| g.notes.iter())).filter(|n| **n != dsl::Note::Rest).count()
+    };
+    let mut rows = vec![["#".to_string(), "changes".to_string(), "hits".to_string()]
+        .into_iter()
+        .chain(file.groups.keys().map(|part| part.name().to_string()))
+        .collect::<Vec<String>>()];
+    let original = ["original".to_string(), "0".to_string(), hits(&file.groups).to_string()];
+    rows.push(original.into_iter().chain(file.patterns.values().cloned()).collect());
+    for (i, varied) in found.iter().enumerate() {
+        let changes: usize = file.groups.iter().map(|(part, g)| variation::changes(g, &varied[part])).sum();
+        let stats = [(i + 1).to_string(), changes.to_string(), hits(varied).to_string()];
+        rows.push(stats.into_iter().chain(varied.values().map(|g| g.to_string())).collect());
+    }
+    let widths: Vec<usize> = (0..rows[0].len()).map(|i| rows.iter().map(|row| row[i].len()).max().unwrap_or(0)).collect();
+    for row in rows {
+        let cells: Vec<String> = row.iter().zip(&widths).map(|(cell, width)| format!("{:<1$}", cell, width)).collect();
+        println!("{}", cells.join("  ").trim_end());
+    }
+}
+
+fn import_file(
+    path: &str,
+    output: Option<String>,
+    grid: dsl::BasicLength,
+    swing: Option<f64>,
+    tempo: Option<u16>,
+    names: dsl::LengthNames,
+) {
+    let result = read(path)
+        .map_err(|e| e.to_string())
+        .and_then(|bytes| {
+            let smf = Smf::parse(&bytes).map_err(|e| e.to_string())?;
+            import::import(&smf, &DrumMap::default(), grid, swing, tempo.map(f64::from), names)
+        });
+    match result {
+        Ok(imported) => {
+            save_text(&imported.to_pattern_file(), output);
+            if imported.tempo_detected {
+                println!(
+                    "{} has no tempo, detected {} BPM. If it's off, import again with --tempo",
+                    path, imported.tempo
+                );
+            }
+            if imported.skipped > 0 {
+                println!("{} notes on keys without a drum part were left out", imported.skipped);
+            }
+        }
+        Err(e) => {
+            println!("Can't import {}: {}", path, e);
+            exit(1)
+        }
+    }
+}
+
+fn parse_amount(s: &str) -> Result<f64, String> {
+    match f64::from_str(s) {
+        Ok(x) if (0.0..=1.0).contains(&x) => Ok(x),
+        Ok(_) => Err(format!("{} is not in the range from 0 to 1", s)),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+fn parse_swing(s: &str) -> Result<f64, String> {
+    match f64::from_str(s) {
+        Ok(x) if (STRAIGHT_SWING..=75.0).contains(&x) => Ok(x),
+        Ok(_) => Err(format!("{} is not in the range from {} to 75", s, STRAIGHT_SWING)),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+fn parse_tempo_range(s: &str) -> Result<(u16, u16), String> {
+    let error = || format!("Expected a tempo range like '80:140', got '{}'", s);
+    let (slowest, fastest) = s.split_once(':').ok_or_else(error)?;
+    match (u16::from_str(slowest), u16::from_str(fastest)) {
+        (Ok(slowest), Ok(fastest)) if 0 < slowest && slowest <= fastest => Ok((slowest, fastest)),
+        _ => Err(error()),
+    }
+}
+
+fn pick_seed() -> u64 {
+    let seed = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or(0);
+    println!("Using seed {}", seed);
+    seed
 }
 
 fn part_to_string(part: DrumPart) -> String {
@@ -46,113 +755,1019 @@ fn part_to_string(part: DrumPart) -> String {
         SnareDrum => String::from("Snare Drum"),
         HiHat => String::from("Hi-Hat"),
         CrashCymbal => String::from("Crash Cymbal"),
+        OpenHiHat => String::from("Open Hi-Hat"),
+        RideCymbal => String::from("Ride Cymbal"),
+        Tom1 => String::from("High Tom"),
+        Tom2 => String::from("Mid Tom"),
+        Tom3 => String::from("Floor Tom"),
     }
 }
 
 fn validate_and_parse_part(
     cli: Option<String>,
     part: DrumPart,
+    version: DslVersion,
     patterns: &mut BTreeMap<DrumPart, dsl::Groups>,
-) -> () {
+) {
     match cli {
         None => {}
-        Some(pattern) => match dsl::groups(pattern.as_str()) {
-            Ok((_, groups)) => {
+        Some(pattern) => match version.parse(pattern.as_str()) {
+            Ok(groups) => {
                 patterns.insert(part, groups);
             }
-            Err(_) => {
-                panic!("{} pattern is malformed.", part_to_string(part))
+            Err(PolyError::Pattern { pattern, position }) => {
+                println!("{} pattern is malformed:", part_to_string(part));
+                println!("  {}", pattern);
+                println!("  {}^", " ".repeat(pattern[..position].chars().count()));
+                exit(1)
+            }
+            Err(e) => {
+                println!("{} pattern is malformed: {}", part_to_string(part), e);
+                exit(1)
             }
         },
     }
 }
 
-fn create_text_description(
-    kick: &Option<String>,
-    snare: &Option<String>,
-    hihat: &Option<String>,
-    crash: &Option<String>,
-) -> String {
-    let mut parts: String = "".to_string();
-    if kick.is_some() {
-        parts.push_str(&format!("\nKick Drum - {}", kick.clone().unwrap()));
+fn create_text_description(parts: &[(DrumPart, Option<String>)], tags: &[String]) -> String {
+    let mut description: String = "".to_string();
+    for (part, pattern) in parts {
+        if let Some(pattern) = pattern {
+            description.push_str(&format!("\n{} - {}", part_to_string(*part), pattern));
+        }
+    }
+    if !tags.is_empty() {
+        description.push_str(&format!("\nTags: {}", tags.join(", ")));
+    }
+    format!("{}{}", "Created using Poly. Part blueprints:", description)
+}
+
+/// Maximum number of taps `--tap` listens to before settling on a tempo.
+const MAX_TAPS: usize = 8;
+
+fn tap_tempo(port: Option<&str>) -> u16 {
+    let (sender, notes) = channel();
+    // The notes played on the port come in while waiting for Enter, kept open until the tapping is done.
+    let _input = port.map(|port| listen_for_taps(port, sender));
+    println!(
+        "Tap Enter{} on every beat ({} taps max), type 'q' and press Enter when done:",
+        if port.is_some() { " or a note" } else { "" },
+        MAX_TAPS
+    );
+    let mut taps = Vec::new();
+    for line in stdin().lock().lines() {
+        taps.extend(notes.try_iter());
+        match line {
+            Ok(l) if l.trim() == "q" => break,
+            Ok(_) => {
+                taps.push(Instant::now());
+                if taps.len() >= MAX_TAPS {
+                    break;
+                }
+            }
+            Err(_) => break,
+        }
     }
-    if snare.is_some() {
-        parts.push_str(&format!("\nSnare Drum - {}", snare.clone().unwrap()));
+    taps.extend(notes.try_iter());
+    taps.sort();
+    taps.truncate(MAX_TAPS);
+    match tempo_from_taps(&taps) {
+        Some(tempo) => {
+            println!("Tapped tempo: {} BPM", tempo);
+            tempo
+        }
+        None => {
+            println!("At least two taps are needed to detect the tempo, exiting...");
+            exit(1)
+        }
     }
-    if hihat.is_some() {
-        parts.push_str(&format!("\nHi-Hat - {}", hihat.clone().unwrap()));
+}
+
+/// Sends the time of every note played on a MIDI input port as a tap.
+#[cfg(feature = "play")]
+fn listen_for_taps(port: &str, taps: Sender<Instant>) -> midir::MidiInputConnection<()> {
+    use midir::MidiInput;
+
+    let midi_input = match MidiInput::new("Polyrhythmix") {
+        Ok(x) => x,
+        Err(e) => {
+            println!("Can't open MIDI input: {}", e);
+            exit(1)
+        }
+    };
+    let ports = midi_input.ports();
+    let chosen = ports.iter().enumerate().find(|(i, p)| {
+        i.to_string() == port
+            || midi_input.port_name(p).unwrap_or_default().to_lowercase().contains(&port.to_lowercase())
+    });
+    let Some((_, chosen)) = chosen else {
+        println!("No MIDI input port matches '{}'", port);
+        exit(1)
+    };
+    let name = midi_input.port_name(chosen).unwrap_or_default();
+    // A note on with a velocity of 0 is a note off.
+    let on_note = move |_: u64, message: &[u8], _: &mut ()| {
+        if let [status, _, velocity] = message {
+            if status & 0xF0 == 0x90 && *velocity > 0 {
+                let _ = taps.send(Instant::now());
+            }
+        }
+    };
+    match midi_input.connect(chosen, "Polyrhythmix taps", on_note, ()) {
+        Ok(connection) => {
+            println!("Listening for taps on {}", name);
+            connection
+        }
+        Err(e) => {
+            println!("Can't connect to {}: {}", name, e);
+            exit(1)
+        }
     }
-    if crash.is_some() {
-        parts.push_str(&format!("\nCrash Cymbal - {}", crash.clone().unwrap()));
+}
+
+#[cfg(not(feature = "play"))]
+fn listen_for_taps(_port: &str, _taps: Sender<Instant>) {
+    println!("Poly was built without MIDI ports, reinstall it with `cargo install polyrhythmix --features play`");
+    exit(1)
+}
+
+fn save_text(text: &str, output: Option<String>) {
+    match output {
+        None => print!("{}", text),
+        Some(path) => save_bytes(text.as_bytes(), &path),
     }
-    format!("{}{}", "Created using Poly. Part blueprints:", parts)
 }
 
-fn main() {
-    let matches = Cli::parse();
-    match matches {
-        Cli {
-            kick,
-            snare,
-            hihat,
-            crash,
-            tempo,
-            time_signature,
-            output,
-            follow_kick_drum_with_bass,
-        } => {
-            if kick == None && snare == None && hihat == None && crash == None {
-                println!("No drum pattern was supplied, exiting...");
+fn show_qr(text: &str, path: &str) {
+    use polyrhythmix::share::qr;
+
+    let result = match path {
+        "" => qr::terminal(text).map(|code| print!("{}", code)),
+        _ => qr::png(text, 8).map(|image| save_bytes(&image, path)),
+    };
+    if let Err(e) = result {
+        println!("Can't make the QR code: {}", e);
+        exit(1)
+    }
+}
+
+/// Set by `--force`, or by answering to overwrite all the files.
+static FORCE: AtomicBool = AtomicBool::new(false);
+
+/// Whether to write to `path`: files that aren't there yet are written, existing ones only with `--force` or if the
+/// user says so. Without a terminal to ask on, Poly exits instead of overwriting anything.
+fn may_write(path: &Path) -> bool {
+    if FORCE.load(Ordering::Relaxed) || !path.exists() {
+        return true;
+    }
+    if !stdin().is_terminal() {
+        println!("{} already exists, pass --force to overwrite it", path.display());
+        exit(1)
+    }
+    print!("{} already exists, overwrite it? [y]es, [n]o, [a]ll: ", path.display());
+    let _ = stdout().flush();
+    let mut answer = String::new();
+    let _ = stdin().read_line(&mut answer);
+    match answer.trim() {
+        "y" | "yes" => true,
+        "a" | "all" => {
+            FORCE.store(true, Ordering::Relaxed);
+            true
+        }
+        _ => {
+            println!("Skipped {}", path.display());
+            false
+        }
+    }
+}
+
+/// Checks every file about to be written before any of them is. Without a terminal to ask on and without `--force`,
+/// Poly exits if any of them is already there, rather than halfway through writing the others. On a terminal every
+/// file is asked about as it's written instead, see `may_write`.
+fn check_outputs(paths: &[String]) {
+    if FORCE.load(Ordering::Relaxed) || stdin().is_terminal() {
+        return;
+    }
+    let existing: Vec<&String> = paths.iter().filter(|path| Path::new(path).exists()).collect();
+    if existing.is_empty() {
+        return;
+    }
+    for path in existing {
+        println!("{} already exists", path);
+    }
+    println!("Nothing was written, pass --force to overwrite them");
+    exit(1)
+}
+
+/// Files `save_audio` may write: the mix, and with `stems` the stem of every part that could be in it.
+fn audio_paths(output: &str, stems: bool) -> Vec<String> {
+    let mut paths = vec![output.to_string()];
+    if stems {
+        let parts = DrumPart::ALL.into_iter().map(|part| stem_path(Path::new(output), part));
+        paths.extend(parts.map(|path| path.to_string_lossy().into_owned()));
+    }
+    paths
+}
+
+/// The stem of the part next to the mix, e.g. `groove-kick.wav` next to `groove.wav`.
+fn stem_path(output: &Path, part: DrumPart) -> PathBuf {
+    let name = output.file_stem().unwrap_or_default().to_string_lossy();
+    let extension = output.extension().map_or(String::new(), |e| format!(".{}", e.to_string_lossy()));
+    output.with_file_name(format!("{}-{}{}", name, part.name(), extension))
+}
+
+/// Where the output goes: `-o` with its variables filled in, in `--output-dir` if there's one. Without `-o`, the file
+/// is named after the hash of the patterns with `--auto-name`, or after the default template in the directory, with
+/// the extension.
+fn output_path(
+    output: Option<String>,
+    dir: Option<&str>,
+    auto_name: bool,
+    extension: &str,
+    variables: &Variables,
+) -> Option<String> {
+    let template = match (output, dir) {
+        (Some(output), _) => output,
+        (None, _) if auto_name => format!("{}.{}", AUTO_NAME_TEMPLATE, extension),
+        (None, Some(_)) => format!("{}.{}", DEFAULT_TEMPLATE, extension),
+        (None, None) => return None,
+    };
+    let name = match template::expand(&template, variables) {
+        Ok(name) => name,
+        Err(e) => {
+            println!("Can't name the output file: {}", e);
+            exit(1)
+        }
+    };
+    let Some(dir) = dir else { return Some(name) };
+    if let Err(e) = create_dir_all(dir) {
+        println!("Can't create {}: {}", dir, e);
+        exit(1)
+    }
+    Some(Path::new(dir).join(name).to_string_lossy().into_owned())
+}
+
+fn save_bytes(bytes: &[u8], path: &str) {
+    if may_write(Path::new(path)) {
+        write_bytes(bytes, path);
+    }
+}
+
+fn write_bytes(bytes: &[u8], path: &str) {
+    match write(path, bytes) {
+        Ok(_) => println!("{} was written successfully", path),
+        Err(e) => {
+            println!("Failed to write {}: {}", path, e);
+            exit(1)
+        }
+    }
+}
+
+fn save_audio(smf: &Smf, kit: &str, output: &str, stems: bool, drum_map: &DrumMap, seed: u64) {
+    match AudioFormat::from_path(Path::new(output)) {
+        Ok(format) if !format.is_supported() => {
+            println!(
+                "Poly was built without {} encoding, reinstall it with `cargo install polyrhythmix --features {}`",
+                format.feature(),
+                format.feature()
+            );
+            exit(1)
+        }
+        Ok(_) => {}
+        Err(e) => {
+            println!("Can't render the audio to {}: {}", output, e);
+            exit(1)
+        }
+    }
+    let kit = match Kit::load(Path::new(kit)) {
+        Ok(x) => x,
+        Err(e) => {
+            println!("Can't read the kit from {}: {}", kit, e);
+            exit(1)
+        }
+    };
+    let output = Path::new(output);
+    let save = |path: &Path, frames: &[[f32; 2]]| {
+        if !may_write(path) {
+            return;
+        }
+        match audio::write_audio(path, frames, kit.sample_rate) {
+            Ok(_) => println!("{} was written successfully", path.display()),
+            Err(e) => {
+                println!("Failed to write {}: {}", path.display(), e);
                 exit(1)
+            }
+        }
+    };
+    if stems {
+        let (mix, stems) = audio::render_stems(smf, &kit, drum_map, seed);
+        save(output, &mix);
+        for (part, stem) in stems {
+            save(&stem_path(output, part), &stem);
+        }
+    } else {
+        save(output, &audio::render(smf, &kit, drum_map, seed));
+    }
+}
+
+fn save_video(groups: &BTreeMap<DrumPart, dsl::Groups>, signature: TimeSignature, tempo: u16, path: &str) {
+    if !path.to_lowercase().ends_with(".gif") {
+        println!("Can't render the video to {}: only GIF is supported", path);
+        exit(1)
+    }
+    match video::render_gif(groups, signature, tempo, 360) {
+        Ok(gif) => save_bytes(&gif, path),
+        Err(e) => {
+            println!("Can't render the video: {}", e);
+            exit(1)
+        }
+    }
+}
+
+/// Writes the groove pack, filling in the bars and the files of the manifest.
+fn save_pack(smf: &Smf, mut manifest: Manifest, instruments: Instruments, path: &str) {
+    let stem = Path::new(path).file_stem().map_or("groove".into(), |s| s.to_string_lossy());
+    let mut midi = Vec::new();
+    let notation = |format| {
+        notation::export(&manifest.groups, manifest.time_signature, &manifest.text, format, instruments)
+    };
+    let result = smf
+        .write_std(&mut midi)
+        .map_err(|e| e.to_string())
+        .and_then(|_| Ok((notation(ExportFormat::LilyPond)?, notation(ExportFormat::Tab)?)))
+        .and_then(|notes| {
+            let bars = manifest.time_signature.converges(manifest.groups.values()).map_err(|e| e.to_string())?;
+            Ok((notes, bars))
+        });
+    let ((lilypond, tab), bars) = match result {
+        Ok(x) => x,
+        Err(e) => {
+            println!("Can't pack the groove: {}", e);
+            exit(1)
+        }
+    };
+    let mut patterns: String = manifest.text.lines().map(|line| format!("# {}\n", line)).collect();
+    patterns.push_str(&format!("version: {}\n", DslVersion::LATEST.number()));
+    if !manifest.tags.is_empty() {
+        patterns.push_str(&format!("tags: [{}]\n", manifest.tags.join(", ")));
+    }
+    for (part, pattern) in &manifest.patterns {
+        patterns.push_str(&format!("{}: {}\n", part.name(), pattern));
+    }
+    let mut files = vec![
+        (format!("{}.mid", stem), midi),
+        (format!("{}.ly", stem), lilypond.into_bytes()),
+        (format!("{}-tab.txt", stem), tab.into_bytes()),
+        (format!("{}.txt", stem), patterns.into_bytes()),
+    ];
+    manifest.bars = bars;
+    manifest.files = files.iter().map(|(name, _)| name.clone()).collect();
+    files.push(("manifest.json".to_string(), manifest.to_json().into_bytes()));
+    save_bytes(&pack::zip(&files), path);
+}
+
+/// Output path of a variant: the settings go between the name of the file and its extension.
+fn variant_path(path: &str, variant: &Variant) -> String {
+    let path = Path::new(path);
+    let stem = path.file_stem().map_or("".into(), |s| s.to_string_lossy());
+    let name = match path.extension() {
+        Some(extension) => format!("{}-{}.{}", stem, variant, extension.to_string_lossy()),
+        None => format!("{}-{}", stem, variant),
+    };
+    path.with_file_name(name).to_string_lossy().into_owned()
+}
+
+/// The file as it's written and played with `--target`, see `retarget`. Rendering audio and the metronome read the
+/// file as it was rendered.
+fn for_target<'a>(smf: &Smf<'a>, target: Option<Target>) -> Smf<'a> {
+    let mut smf = smf.clone();
+    if let Some(target) = target {
+        for (from, to) in retarget(&mut smf, target) {
+            println!("{} modules have no sound at key {}, it's played at key {} instead", target, from, to);
+        }
+    }
+    smf
+}
+
+fn save_smf(smf: &Smf, output: Option<String>, print_fingerprint: bool) {
+    if print_fingerprint {
+        println!("Fingerprint: {:016x}", fingerprint(smf));
+    }
+    match output {
+        None => {
+            println!("No output file path was supplied, running a dry run...");
+        }
+        Some(path) if !may_write(Path::new(&path)) => {}
+        Some(path) => {
+            match smf.save(path.clone()) {
+                Ok(_) => println!("{} was written successfully", path),
+                Err(e) => {
+                    println!("Failed to write {}: {}", path, e);
+                    exit(1)
+                }
+            };
+        }
+    };
+}
+
+/// Writes where the MIDI file about to be saved to `path` came from next to it, see `Provenance`. `tapped` is the
+/// tempo tapped with `--tap`, which the command to render the file again has to give instead.
+fn save_provenance(smf: &Smf, path: &str, matches: &ArgMatches, seed: u64, tapped: Option<u16>) {
+    let mut options = Vec::new();
+    let mut command = Vec::new();
+    for arg in Cli::command().get_arguments() {
+        let (id, Some(name)) = (arg.get_id().as_str(), arg.get_long()) else { continue };
+        let mut values: Vec<String> = match matches.get_raw(id) {
+            Some(raw) => raw.map(|v| v.to_string_lossy().into_owned()).collect(),
+            None if id == "seed" => Vec::new(),
+            None => continue,
+        };
+        match (id, tapped) {
+            ("seed", _) => values = vec![seed.to_string()],
+            ("tempo", Some(tempo)) => values = vec![tempo.to_string()],
+            _ => {}
+        }
+        let given = matches.value_source(id) == Some(parser::ValueSource::CommandLine);
+        if given && !matches!(id, "seed" | "tap") {
+            if arg.get_action().takes_values() {
+                for value in &values {
+                    command.extend([format!("--{}", name), value.clone()]);
+                }
             } else {
-                let signature = match TimeSignature::from_str(&time_signature) {
-                    Err(e) => panic!("Can't parse the time signature: {}", e),
-                    Ok(x) => x,
-                };
-                let text_description = create_text_description(&kick, &snare, &hihat, &crash);
-
-                let mut groups = BTreeMap::new();
-                validate_and_parse_part(kick, KickDrum, &mut groups);
-                validate_and_parse_part(snare, SnareDrum, &mut groups);
-                validate_and_parse_part(hihat, HiHat, &mut groups);
-                validate_and_parse_part(crash, CrashCymbal, &mut groups);
-
-                let output_file = output.clone();
-
-                match output_file {
-                    None => {
-                        println!("No output file path was supplied, running a dry run...");
-                        create_smf(
-                            groups,
-                            signature,
-                            text_description.as_str(),
-                            tempo,
-                            matches.follow_kick_drum_with_bass,
-                        )
-                    }
-                    Some(path) => {
-                        match create_smf(
-                            groups,
-                            signature,
-                            text_description.as_str(),
-                            tempo,
-                            follow_kick_drum_with_bass,
-                        )
-                        .save(path.clone())
-                        {
-                            Ok(_) => {
-                                println!("{} was written successfully", path);
-                                exit(0)
-                            }
-                            Err(e) => {
-                                println!("Failed to write {}: {}", path, e);
-                                exit(1)
-                            }
-                        };
+                command.push(format!("--{}", name));
+            }
+        }
+        options.push((name.to_string(), values));
+    }
+    if let Some(tempo) = tapped {
+        command.extend(["--tempo".to_string(), tempo.to_string()]);
+    }
+    command.extend(["--seed".to_string(), seed.to_string()]);
+    let mut inputs = Vec::new();
+    for id in ["patterns", "arrangement", "kit"] {
+        if let Some(input) = matches.get_one::<String>(id) {
+            match read(input) {
+                Ok(bytes) => inputs.push((input.clone(), hash(&bytes))),
+                Err(e) => println!("Can't read {} to record its hash: {}", input, e),
+            }
+        }
+    }
+    let provenance = Provenance { seed, command, options, inputs, fingerprint: fingerprint(smf) };
+    save_bytes(provenance.to_json().as_bytes(), &sidecar_path(path));
+}
+
+/// Set on Ctrl-C during the playback, which then turns off the notes still playing and stops.
+static STOP: AtomicBool = AtomicBool::new(false);
+
+fn stop_on_interrupt() {
+    if let Err(e) = ctrlc::set_handler(|| STOP.store(true, Ordering::Relaxed)) {
+        println!("Can't catch Ctrl-C, notes may be left hanging if the playback is stopped: {}", e);
+    }
+}
+
+/// Lists the MIDI output ports and plays the file through the chosen one in real time.
+#[cfg(feature = "play")]
+fn play_smf(smf: &Smf, port: Option<&str>, loops: u32, drum_map: &DrumMap) {
+    use midir::MidiOutput;
+    use polyrhythmix::midi::playback::{all_notes_off, wait_until};
+    use std::time::Duration;
+
+    let midi_output = match MidiOutput::new("Polyrhythmix") {
+        Ok(x) => x,
+        Err(e) => {
+            println!("Can't open MIDI output: {}", e);
+            exit(1)
+        }
+    };
+    let ports: Vec<(usize, String, midir::MidiOutputPort)> = midi_output
+        .ports()
+        .into_iter()
+        .enumerate()
+        .map(|(i, p)| (i, midi_output.port_name(&p).unwrap_or_default(), p))
+        .collect();
+    if ports.is_empty() {
+        println!("No MIDI output ports are available, showing a metronome instead");
+        return show_metronome(smf, drum_map, loops);
+    }
+    println!("MIDI output ports:");
+    for (i, name, _) in &ports {
+        println!("  {}: {}", i, name);
+    }
+    let chosen = match port {
+        None => ports.first(),
+        Some(p) => ports
+            .iter()
+            .find(|(i, name, _)| i.to_string() == p || name.to_lowercase().contains(&p.to_lowercase())),
+    };
+    let Some((_, name, chosen)) = chosen else {
+        println!("No MIDI output port matches '{}'", port.unwrap_or_default());
+        exit(1)
+    };
+    let mut connection = match midi_output.connect(chosen, "Polyrhythmix preview") {
+        Ok(x) => x,
+        Err(e) => {
+            println!("Can't connect to {}: {}", name, e);
+            exit(1)
+        }
+    };
+    println!("Playing through {}, press Ctrl-C to stop", name);
+    stop_on_interrupt();
+    let messages = schedule(smf, loops);
+    let start = Instant::now();
+    for message in &messages {
+        if !wait_until(start + Duration::from_micros(message.at), &STOP) {
+            for off in all_notes_off(&messages) {
+                let _ = connection.send(&off);
+            }
+            return;
+        }
+        if let Err(e) = connection.send(&message.message) {
+            println!("Failed to send a MIDI message: {}", e);
+            exit(1)
+        }
+    }
+}
+
+#[cfg(not(feature = "play"))]
+fn play_smf(smf: &Smf, _port: Option<&str>, loops: u32, drum_map: &DrumMap) {
+    println!("Poly was built without playback, reinstall it with `cargo install polyrhythmix --features play`");
+    println!("Showing a metronome instead");
+    show_metronome(smf, drum_map, loops)
+}
+
+/// Plays the file in real time through a raw MIDI device file, see `stream`.
+fn play_device(smf: &Smf, path: &str, loops: u32) {
+    let mut device = match OpenOptions::new().write(true).open(path) {
+        Ok(x) => x,
+        Err(e) => {
+            println!("Can't open {}: {}", path, e);
+            exit(1)
+        }
+    };
+    println!("Playing through {}, press Ctrl-C to stop", path);
+    stop_on_interrupt();
+    if let Err(e) = stream(&schedule(smf, loops), &mut device, &STOP) {
+        println!("Failed to write to {}: {}", path, e);
+        exit(1)
+    }
+}
+
+/// Prints a line per beat of the file in real time, see `metronome`.
+fn show_metronome(smf: &Smf, drum_map: &DrumMap, loops: u32) {
+    use std::thread::sleep;
+    use std::time::Duration;
+
+    println!("Press Ctrl-C to stop");
+    let start = Instant::now();
+    for beat in metronome(smf, drum_map, loops) {
+        let at = start + Duration::from_micros(beat.at);
+        if let Some(wait) = at.checked_duration_since(Instant::now()) {
+            sleep(wait);
+        }
+        println!("{}", beat);
+    }
+}
+
+fn main() {
+    let matches = Cli::command().get_matches();
+    let cli = Cli::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+    let Cli {
+        command,
+        kick,
+        snare,
+        hihat,
+        crash,
+        open_hihat,
+        ride,
+        tom1,
+        tom2,
+        tom3,
+        tempo,
+        tap,
+        tap_port,
+        tempo_sweep,
+        time_signature,
+        output,
+        output_dir,
+        auto_name,
+        force,
+        variants,
+        follow_kick_drum_with_bass,
+        click_parts,
+        sidechain,
+        sidechain_length,
+        ensemble,
+        double,
+        teach,
+        variation,
+        seed,
+        arc,
+        ending,
+        crashes,
+        prob,
+        rule,
+        filter,
+        humanize_timing,
+        humanize_velocity,
+        swing,
+        tail_rest,
+        trim,
+        arrangement,
+        patterns,
+        check,
+        share,
+        from_share,
+        qr,
+        fingerprint,
+        provenance,
+        markers,
+        target,
+        map,
+        threads,
+        export,
+        instruments,
+        render_audio,
+        render_stems,
+        render_video,
+        pack,
+        kit,
+        play,
+        port,
+        device,
+        metronome,
+        loops,
+    } = cli;
+    FORCE.store(force || command.as_ref().is_some_and(Command::force), Ordering::Relaxed);
+    if let Some(path) = check {
+        return check_file(&path);
+    }
+    match command {
+        Some(Command::Migrate { file, output, .. }) => return migrate_file(&file, output),
+        Some(Command::Import { file, output, grid, swing, tempo, names, .. }) => {
+            return import_file(&file, output, grid, swing, tempo, names)
+        }
+        Some(Command::Vary { file, count, amount, seed }) => return vary_file(&file, count, amount, seed),
+        Some(Command::Highlight { editor, output, .. }) => return save_text(&highlight(editor), output),
+        Some(Command::Test { files, time_signature }) => return test_files(&files, time_signature),
+        Some(Command::Daily { out_dir, date, time_signatures, tempos, max_bars, parts, .. }) => {
+            return write_daily(&out_dir, date, Constraints { time_signatures, tempos, max_bars, parts })
+        }
+        Some(Command::Etude { steps, hits, up_to_rotation, part, repeat, time_signature, tempo, output, .. }) => {
+            let etude = match Etude::new(time_signature, steps, hits, up_to_rotation, repeat) {
+                Ok(etude) => etude,
+                Err(e) => {
+                    println!("Can't write the etude: {}", e);
+                    exit(1)
+                }
+            };
+            return write_etude(&etude, part, tempo, output);
+        }
+        Some(Command::Lib { command: LibCommand::List { dirs, tags } }) => return list_library(&dirs, &tags),
+        Some(Command::Repl { session, .. }) => return repl(session),
+        None => {}
+    }
+    let drum_map = match &from_share {
+        Some(groove) => groove.drum_map.clone(),
+        None => map.into_iter().fold(DrumMap::default(), |mut drum_map, (part, key)| {
+            drum_map.set(part, key);
+            drum_map
+        }),
+    };
+    if let Some(path) = arrangement {
+        let arrangement = match read_to_string(&path).map_err(|e| e.to_string()).and_then(|s| Arrangement::from_str(&s)) {
+            Ok(x) => x,
+            Err(e) => {
+                println!("Can't read the arrangement from {}: {}", path, e);
+                exit(1)
+            }
+        };
+        let text_description = format!("Created using Poly. Arrangement: {}", path);
+        let (tempo, time_signature) = match arrangement.order.first() {
+            Some((name, _)) => (arrangement.sections[name].tempo, arrangement.sections[name].time_signature),
+            None => match TimeSignature::from_str(&time_signature) {
+                Ok(x) => (tempo, x),
+                Err(e) => {
+                    println!("Can't parse the time signature: {}", e);
+                    exit(1)
+                }
+            },
+        };
+        let name = Path::new(&path).file_stem().map_or("arrangement".into(), |s| s.to_string_lossy());
+        let arrangement_hash = read(&path).map_or(0, |bytes| hash(&bytes));
+        let variables = Variables { name: &name, hash: arrangement_hash, tempo, time_signature };
+        let output = output_path(output, output_dir.as_deref(), auto_name, "mid", &variables);
+        let seed = match seed {
+            Some(seed) => seed,
+            None if crashes.is_some() => pick_seed(),
+            None => 0,
+        };
+        let crashes = crashes.map(|chance| Crashes { chance, seed });
+        let mut outputs: Vec<String> = output.iter().cloned().collect();
+        if let (true, Some(path)) = (provenance, &output) {
+            outputs.push(sidecar_path(path));
+        }
+        if let (Some(wav), Some(_)) = (&render_audio, &kit) {
+            outputs.extend(audio_paths(wav, render_stems));
+        }
+        check_outputs(&outputs);
+        let rendered = arrangement.to_smf(text_description.as_str(), &drum_map, crashes).and_then(|smf| {
+            let sections = if markers { arrangement.sections()? } else { Vec::new() };
+            Ok((smf, sections))
+        });
+        let (mut smf, sections) = match rendered {
+            Ok(x) => x,
+            Err(e) => {
+                println!("Can't render the arrangement: {}", e);
+                exit(1)
+            }
+        };
+        let cues = if markers { bar_markers(&smf, &sections) } else { Vec::new() };
+        add_markers(&mut smf, &cues);
+        let out = for_target(&smf, target);
+        if let (true, Some(path)) = (provenance, &output) {
+            save_provenance(&out, path, &matches, seed, None);
+        }
+        save_smf(&out, output, fingerprint);
+        if let (Some(wav), Some(kit)) = (&render_audio, &kit) {
+            save_audio(&smf, kit, wav, render_stems, &drum_map, seed);
+        }
+        if play {
+            play_smf(&out, port.as_deref(), loops, &drum_map);
+        } else if let Some(path) = &device {
+            play_device(&out, path, loops);
+        } else if metronome {
+            show_metronome(&smf, &drum_map, loops);
+        }
+        return;
+    }
+    let name = patterns.as_deref().and_then(|p| Path::new(p).file_stem()).map_or("groove".into(), |s| s.to_string_lossy().into_owned());
+    let file = patterns.map(|path| match read_to_string(&path).map_err(|e| e.to_string()).and_then(|s| {
+        PatternFile::from_str(&s).map_err(|e| e.to_string())
+    }) {
+        Ok(x) => x,
+        Err(e) => {
+            println!("Can't read the patterns from {}: {}", path, e);
+            exit(1)
+        }
+    });
+    let mut parts = vec![
+        (KickDrum, kick),
+        (SnareDrum, snare),
+        (HiHat, hihat),
+        (CrashCymbal, crash),
+        (OpenHiHat, open_hihat),
+        (RideCymbal, ride),
+        (Tom1, tom1),
+        (Tom2, tom2),
+        (Tom3, tom3),
+    ];
+    // Patterns from the file are written in the version it declares, the ones from the options in the latest one.
+    let mut versions = BTreeMap::new();
+    let saved = file
+        .as_ref()
+        .map(|file| (file.version, &file.patterns))
+        .or(from_share.as_ref().map(|groove| (groove.version, &groove.patterns)));
+    if let Some((version, patterns)) = saved {
+        for (part, pattern) in parts.iter_mut() {
+            if let (None, Some(saved)) = (&pattern, patterns.get(part)) {
+                *pattern = Some(saved.clone());
+                versions.insert(*part, version);
+            }
+        }
+    }
+    if parts.iter().all(|(_, pattern)| pattern.is_none()) {
+        println!("No drum pattern was supplied, exiting...");
+        exit(1)
+    } else {
+        let signature = match (&from_share, TimeSignature::from_str(&time_signature)) {
+            (Some(groove), _) => groove.time_signature,
+            (None, Err(e)) => panic!("Can't parse the time signature: {}", e),
+            (None, Ok(x)) => x,
+        };
+        let tags = file.as_ref().map(|file| file.tags.clone()).unwrap_or_default();
+        let text_description = create_text_description(&parts, &tags);
+        // Patterns as they're given, before they're parsed, so that `--auto-name` picks the same name next time.
+        let patterns_hash = hash(
+            parts
+                .iter()
+                .filter_map(|(part, pattern)| pattern.as_ref().map(|p| format!("{}: {}\n", part_to_string(*part), p)))
+                .collect::<String>()
+                .as_bytes(),
+        );
+
+        // Shared grooves are written in the latest version of the DSL, whatever the patterns were read from.
+        let mut shared = BTreeMap::new();
+        if share.is_some() || pack.is_some() {
+            for (part, pattern) in &parts {
+                let Some(pattern) = pattern else { continue };
+                let version = versions.get(part).copied().unwrap_or(DslVersion::LATEST);
+                match version.migrate(pattern) {
+                    Ok(migrated) => shared.insert(*part, migrated),
+                    Err(e) => {
+                        println!("Can't share the {} pattern: {}", part_to_string(*part), e);
+                        exit(1)
                     }
                 };
             }
         }
+
+        let mut groups = BTreeMap::new();
+        for (part, pattern) in parts {
+            let version = versions.get(&part).copied().unwrap_or(DslVersion::LATEST);
+            validate_and_parse_part(pattern, part, version, &mut groups);
+        }
+
+        let instruments = instruments.unwrap_or(if ensemble { Instruments::Ensemble } else { Instruments::Drums });
+        if let Some(format) = export {
+            let extension = match format {
+                ExportFormat::LilyPond => "ly",
+                ExportFormat::Tab => "txt",
+            };
+            let tempo = from_share.as_ref().map_or(tempo, |groove| groove.tempo);
+            let variables = Variables { name: &name, hash: patterns_hash, tempo, time_signature: signature };
+            let output = output_path(output, output_dir.as_deref(), auto_name, extension, &variables);
+            match notation::export(&groups, signature, &text_description, format, instruments) {
+                Ok(source) => save_text(&source, output),
+                Err(e) => {
+                    println!("Can't export the patterns: {}", e);
+                    exit(1)
+                }
+            }
+            return;
+        }
+
+        let tempos = match tempo_sweep {
+            Some(sweep) => {
+                let tempos = sweep.tempos();
+                println!("Sweeping over {} tempos from {} to {} BPM", tempos.len(), sweep.from, sweep.to);
+                tempos
+            }
+            None if tap => vec![tap_tempo(tap_port.as_deref())],
+            None => vec![from_share.as_ref().map_or(tempo, |groove| groove.tempo)],
+        };
+
+        let variables = Variables { name: &name, hash: patterns_hash, tempo: tempos[0], time_signature: signature };
+        let output = output_path(output, output_dir.as_deref(), auto_name, "mid", &variables);
+        let mut outputs = Vec::new();
+        if let (Some(_), Some(path)) = (share, &qr) {
+            outputs.extend((!path.is_empty()).then(|| path.clone()));
+        }
+        if let Some(path) = &output {
+            let variant_paths = variants.iter().map(|variant| variant_path(path, variant));
+            let midi: Vec<String> = iter::once(path.clone()).chain(variant_paths).collect();
+            if provenance {
+                outputs.extend(midi.iter().map(|path| sidecar_path(path)));
+            }
+            outputs.extend(midi);
+        }
+        if let (Some(wav), Some(_)) = (&render_audio, &kit) {
+            outputs.extend(audio_paths(wav, render_stems));
+        }
+        outputs.extend(render_video.iter().chain(&pack).cloned());
+        check_outputs(&outputs);
+        let groove = Share::new(DslVersion::LATEST, shared.clone(), tempos[0], signature, drum_map.clone());
+        if let Some(encoding) = share {
+            let line = groove.encode(encoding);
+            println!("Share: {}", line);
+            if let Some(path) = qr {
+                show_qr(&line, &path);
+            }
+        }
+
+        let mut transforms: Vec<Box<dyn Transform>> = Vec::new();
+
+        let humanize = humanize_timing > 0 || humanize_velocity > 0 || swing.is_some();
+        let seed = match seed {
+            Some(seed) => seed,
+            None if variation.is_some()
+                || crashes.is_some()
+                || !prob.is_empty()
+                || filter.iter().any(|f| matches!(f.action, Action::Thin(_)))
+                || humanize_timing > 0
+                || humanize_velocity > 0 =>
+            {
+                pick_seed()
+            }
+            None => 0,
+        };
+        if !prob.is_empty() {
+            transforms.push(Box::new(Masks { masks: prob.into_iter().collect(), seed }));
+        }
+        if !rule.is_empty() {
+            transforms.push(Box::new(Triggers(rule)));
+        }
+        if let Some(automation) = arc {
+            transforms.push(Box::new(IntensityArc { automation }));
+        }
+        if let Some(chance) = crashes {
+            transforms.push(Box::new(Crashes { chance, seed }));
+        }
+        if !filter.is_empty() {
+            transforms.push(Box::new(Filters { filters: filter, seed }));
+        }
+
+        let options = RenderOptions {
+            time_signature: signature,
+            tempos,
+            add_bass: follow_kick_drum_with_bass,
+            add_click: click_parts,
+            sidechain: sidechain.map(|key| Sidechain { key: key.into(), length: sidechain_length }),
+            ensemble,
+            doublings: double,
+            teach,
+            variation: variation.map(|amount| Variation { amount, seed }),
+            transforms,
+            ending,
+            humanize: humanize.then_some(Humanize {
+                timing: humanize_timing,
+                velocity: humanize_velocity,
+                swing: swing.unwrap_or(STRAIGHT_SWING),
+                seed,
+            }),
+            end: match tail_rest {
+                Some(length) => TrackEnd::Tail(length),
+                None if trim => TrackEnd::Trim,
+                None => TrackEnd::BarLine,
+            },
+            drum_map,
+            threads: threads.map_or_else(
+                || available_parallelism().map_or(1, |n| n.get()),
+                |n| n as usize,
+            ),
+        };
+
+        let kept_groups = match (&render_video, &pack) {
+            (None, None) if variants.is_empty() => BTreeMap::new(),
+            _ => groups.clone(),
+        };
+        if ensemble {
+            print!("{}", ensemble::legend(groups.keys().copied(), &options.drum_map));
+        }
+        // Markers go at the bars of every cycle of the converged pattern.
+        let (mut smf, cycle_bars) = match generate_with_bars(groups, text_description.as_str(), &options) {
+            Ok((smf, bars)) => {
+                print_converges(bars);
+                (smf, bars)
+            }
+            Err(e) => {
+                println!("Can't render the patterns: {}", e);
+                exit(1)
+            }
+        };
+        let cues = if markers { bar_markers(&smf, &cycles(&smf, cycle_bars)) } else { Vec::new() };
+        add_markers(&mut smf, &cues);
+        let out = for_target(&smf, target);
+        let tapped = tap.then_some(options.tempos[0]);
+        if let (true, Some(path)) = (provenance, &output) {
+            save_provenance(&out, path, &matches, seed, tapped);
+        }
+        save_smf(&out, output.clone(), fingerprint);
+        for variant in &variants {
+            match generate_with_bars(kept_groups.clone(), text_description.as_str(), &variant.apply(&options)) {
+                Ok((mut smf, bars)) => {
+                    print_converges(bars);
+                    let cues = if markers { bar_markers(&smf, &cycles(&smf, bars)) } else { Vec::new() };
+                    add_markers(&mut smf, &cues);
+                    let smf = for_target(&smf, target);
+                    let path = output.as_deref().map(|path| variant_path(path, variant));
+                    if let (true, Some(path)) = (provenance, &path) {
+                        save_provenance(&smf, path, &matches, seed, tapped);
+                    }
+                    save_smf(&smf, path, fingerprint)
+                }
+                Err(e) => {
+                    println!("Can't render the {} variant: {}", variant, e);
+                    exit(1)
+                }
+            }
+        }
+        let drum_map = if ensemble { ensemble::drum_map(&options.drum_map) } else { options.drum_map.clone() };
+        if let (Some(wav), Some(kit)) = (&render_audio, &kit) {
+            save_audio(&smf, kit, wav, render_stems, &drum_map, seed);
+        }
+        if let Some(path) = render_video {
+            save_video(&kept_groups, signature, options.tempos[0], &path);
+        }
+        if let Some(path) = pack {
+            let manifest = Manifest {
+                text: text_description.clone(),
+                tags,
+                tempo: options.tempos[0],
+                time_signature: signature,
+                bars: 0,
+                patterns: shared,
+                groups: kept_groups,
+                share: groove.to_string(),
+                files: Vec::new(),
+            };
+            save_pack(&out, manifest, instruments, &path);
+        }
+        if play {
+            play_smf(&out, port.as_deref(), loops, &drum_map);
+        } else if let Some(path) = &device {
+            play_device(&out, path, loops);
+        } else if metronome {
+            show_metronome(&smf, &drum_map, loops);
+        }
     }
 }
@@ -0,0 +1,15 @@
+/// CRC-32 as used by PNG and ZIP files.
+pub(crate) fn crc32(data: &[u8]) -> u32 {
+    !data.iter().fold(!0u32, |crc, byte| {
+        (0..8).fold(crc ^ *byte as u32, |crc, _| match crc & 1 {
+            1 => crc >> 1 ^ 0xedb88320,
+            _ => crc >> 1,
+        })
+    })
+}
+
+#[test]
+fn test_crc32() {
+    assert_eq!(crc32(b"IEND"), 0xae426082);
+    assert_eq!(crc32(b"123456789"), 0xcbf43926);
+}
@@ -0,0 +1,236 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::dsl::dsl::{groups, Groups, KnownLength};
+use crate::dsl::version::DslVersion;
+use crate::midi::core::DrumPart;
+use crate::midi::time::TimeSignature;
+use crate::random::Rng;
+
+/// A day of the Gregorian calendar, written as `2024-03-17`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Date {
+    pub year: i32,
+    pub month: u8,
+    pub day: u8,
+}
+
+impl Date {
+    /// The day it is in UTC.
+    pub fn today() -> Date {
+        let seconds = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+        Date::from_days((seconds / 86400) as i64)
+    }
+
+    /// The day this many days after 1970-01-01, see http://howardhinnant.github.io/date_algorithms.html.
+    pub fn from_days(days: i64) -> Date {
+        let z = days + 719468;
+        let era = z.div_euclid(146097);
+        let day_of_era = z - era * 146097;
+        let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        // Months counted from March, so that the leap day comes last.
+        let month = (5 * day_of_year + 2) / 153;
+        let day = day_of_year - (153 * month + 2) / 5 + 1;
+        let month = if month < 10 { month + 3 } else { month - 9 };
+        let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+        Date { year: year as i32, month: month as u8, day: day as u8 }
+    }
+
+    fn days_in_month(year: i32, month: u8) -> u8 {
+        match month {
+            2 if year % 4 == 0 && (year % 100 != 0 || year % 400 == 0) => 29,
+            2 => 28,
+            4 | 6 | 9 | 11 => 30,
+            _ => 31,
+        }
+    }
+}
+
+impl FromStr for Date {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let error = || format!("Expected a date like '2024-03-17', got '{}'", s);
+        let numbers: Vec<&str> = s.split('-').collect();
+        let [year, month, day] = numbers[..] else { return Err(error()) };
+        let year = i32::from_str(year).map_err(|_| error())?;
+        let month = u8::from_str(month).ok().filter(|m| (1..=12).contains(m)).ok_or_else(error)?;
+        let day = u8::from_str(day).map_err(|_| error())?;
+        if day == 0 || day > Date::days_in_month(year, month) {
+            return Err(error());
+        }
+        Ok(Date { year, month, day })
+    }
+}
+
+impl fmt::Display for Date {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:04}-{:02}-{:02}", self.year, self.month, self.day)
+    }
+}
+
+/// What a groove of the day may be.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Constraints {
+    /// One of them is picked for the day.
+    pub time_signatures: Vec<TimeSignature>,
+    /// Slowest and fastest tempo in BPM.
+    pub tempos: (u16, u16),
+    /// The parts have to converge within this many bars.
+    pub max_bars: u32,
+    pub parts: Vec<DrumPart>,
+}
+
+impl Default for Constraints {
+    fn default() -> Self {
+        Constraints {
+            time_signatures: ["4/4", "3/4", "5/4", "7/8"].iter().filter_map(|s| TimeSignature::from_str(s).ok()).collect(),
+            tempos: (80, 140),
+            max_bars: 8,
+            parts: vec![DrumPart::KickDrum, DrumPart::SnareDrum, DrumPart::HiHat],
+        }
+    }
+}
+
+impl Constraints {
+    /// Everything the groove is drawn from along with the date, so that other constraints give another groove.
+    fn seed_keys(&self) -> Vec<u64> {
+        let mut keys: Vec<u64> = self
+            .time_signatures
+            .iter()
+            .map(|t| t.to_midi())
+            .map(|(numerator, denominator)| ((numerator as u64) << 8) | denominator as u64)
+            .collect();
+        keys.extend([self.tempos.0 as u64, self.tempos.1 as u64, self.max_bars as u64]);
+        keys.extend(self.parts.iter().map(|p| p.seed_key()));
+        keys
+    }
+}
+
+/// A groove drawn from the date: the same date and constraints always give the same groove.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Daily {
+    pub date: Date,
+    pub time_signature: TimeSignature,
+    pub tempo: u16,
+    pub patterns: BTreeMap<DrumPart, String>,
+    pub groups: BTreeMap<DrumPart, Groups>,
+}
+
+/// Seed of the grooves of the day, mixed with the date and the constraints.
+static DAILY_SEED: u64 = 0x6461696c79;
+/// Draws of patterns that don't converge within the limit before settling for patterns that fill a bar.
+static ATTEMPTS: usize = 100;
+
+/// Share of the steps of a part's Euclidean rhythm that are hits: the hi-hat keeps time, the snare drum only
+/// answers.
+fn density(part: DrumPart) -> f64 {
+    match part {
+        DrumPart::HiHat | DrumPart::RideCymbal => 0.6,
+        DrumPart::SnareDrum | DrumPart::CrashCymbal | DrumPart::OpenHiHat => 0.2,
+        _ => 0.35,
+    }
+}
+
+/// A Euclidean rhythm for the part with `steps` steps of `length`, rotated at random.
+fn euclidean(part: DrumPart, length: u32, steps: u32, rng: &mut Rng) -> String {
+    let hits = ((steps as f64 * density(part)).round() as i64 + rng.below(3) as i64 - 1).clamp(1, steps as i64);
+    format!("{}E({},{},{})", length, hits, steps, rng.below(steps as u64))
+}
+
+/// The groove of the day. Every part gets a Euclidean rhythm of eighths or sixteenths over a number of steps that
+/// doesn't have to fill a bar, which is where the polyrhythms come from, as long as the parts converge within
+/// `max_bars`. Patterns filling a single bar are the fallback if they don't.
+pub fn groove(date: Date, constraints: &Constraints) -> Daily {
+    let mut keys = vec![date.year as u64, date.month as u64, date.day as u64];
+    keys.extend(constraints.seed_keys());
+    let mut rng = Rng::derive(DAILY_SEED, &keys);
+    let time_signature = match constraints.time_signatures.len() {
+        0 => Constraints::default().time_signatures[0],
+        n => constraints.time_signatures[rng.below(n as u64) as usize],
+    };
+    let (slowest, fastest) = constraints.tempos;
+    let tempo = slowest.min(fastest) + rng.below(slowest.abs_diff(fastest) as u64 + 1) as u16;
+    let converges = |groups: &[Groups]| time_signature.converges(groups).is_ok_and(|bars| bars <= constraints.max_bars);
+    // Lengths and numbers of steps that converge within the limit on their own, the parts are drawn from these.
+    let candidates: Vec<(u32, u32)> = [8, 16]
+        .into_iter()
+        .flat_map(|length| (3..=12).map(move |steps| (length, steps)))
+        .filter(|(length, steps)| groups(&format!("{}E(1,{})", length, steps)).is_ok_and(|g| converges(&[g])))
+        .collect();
+    let parse = |patterns: &BTreeMap<DrumPart, String>| {
+        patterns
+            .iter()
+            .map(|(part, pattern)| groups(pattern).map(|g| (*part, g)))
+            .collect::<Result<BTreeMap<DrumPart, Groups>, _>>()
+            .ok()
+    };
+    for _ in 0..ATTEMPTS.min(candidates.len() * ATTEMPTS) {
+        let patterns: BTreeMap<DrumPart, String> = constraints
+            .parts
+            .iter()
+            .map(|part| {
+                let (length, steps) = candidates[rng.below(candidates.len() as u64) as usize];
+                (*part, euclidean(*part, length, steps, &mut rng))
+            })
+            .collect();
+        let Some(groups) = parse(&patterns) else { continue };
+        if converges(&groups.values().cloned().collect::<Vec<Groups>>()) {
+            return Daily { date, time_signature, tempo, patterns, groups };
+        }
+    }
+    let steps = time_signature.to_128th() / 8;
+    let patterns: BTreeMap<DrumPart, String> =
+        constraints.parts.iter().map(|part| (*part, euclidean(*part, 16, steps, &mut rng))).collect();
+    let groups = parse(&patterns).unwrap_or_default();
+    Daily { date, time_signature, tempo, patterns, groups }
+}
+
+impl Daily {
+    /// The groove as a pattern file, with the tempo and the time signature to render it with on top.
+    pub fn to_pattern_file(&self) -> String {
+        let mut file = format!(
+            "# Groove of the day for {}, render with --tempo {} --time-signature {}\nversion: {}\n",
+            self.date,
+            self.tempo,
+            self.time_signature,
+            DslVersion::LATEST.number()
+        );
+        for (part, pattern) in &self.patterns {
+            file.push_str(&format!("{}: {}\n", part.name(), pattern));
+        }
+        file
+    }
+}
+
+#[test]
+fn test_date() {
+    assert_eq!(Date::from_days(0).to_string(), "1970-01-01");
+    assert_eq!(Date::from_days(19799), Date { year: 2024, month: 3, day: 17 });
+    assert_eq!(Date::from_days(19782).to_string(), "2024-02-29");
+    assert_eq!(Date::from_str("2024-02-29"), Ok(Date { year: 2024, month: 2, day: 29 }));
+    assert!(Date::from_str("2023-02-29").is_err());
+    assert!(Date::from_str("2024-13-01").is_err());
+    assert!(Date::from_str("17.03.2024").is_err());
+}
+
+#[test]
+fn test_daily_groove() {
+    let constraints = Constraints::default();
+    let date = Date::from_str("2024-03-17").unwrap();
+    let daily = groove(date, &constraints);
+    assert_eq!(daily, groove(date, &constraints));
+    assert!(constraints.time_signatures.contains(&daily.time_signature));
+    assert!((80..=140).contains(&daily.tempo));
+    assert_eq!(daily.patterns.keys().copied().collect::<Vec<_>>(), constraints.parts);
+    assert!(daily.time_signature.converges(daily.groups.values()).unwrap() <= 8);
+    assert_ne!(daily, groove(Date::from_str("2024-03-18").unwrap(), &constraints));
+    let strict = Constraints { max_bars: 1, tempos: (100, 100), ..Default::default() };
+    let daily = groove(date, &strict);
+    assert_eq!(daily.tempo, 100);
+    assert_eq!(daily.time_signature.converges(daily.groups.values()), Ok(1));
+    assert!(daily.to_pattern_file().starts_with("# Groove of the day for 2024-03-17, render with --tempo 100"));
+}
@@ -1,15 +1,22 @@
-use std::num::ParseIntError;
-use std::ops::Add;
-use std::str::{self, FromStr};
-use std::vec::Vec;
+use core::fmt;
+use core::num::ParseIntError;
+use core::ops::Range;
+use core::ops::Add;
+use core::str::{self, FromStr};
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
 
 use nom::branch::alt;
-pub use nom::character::complete::{char, digit1};
+pub use nom::character::complete::{char, digit1, multispace0};
 use nom::multi::many1;
-use nom::sequence::{delimited, separated_pair, tuple};
+use nom::sequence::{delimited, preceded, separated_pair, terminated, tuple};
 use nom::{Err, IResult};
 
-use nom::combinator::{all_consuming, map, map_res};
+use nom::bytes::complete::tag;
+use nom::combinator::{all_consuming, map, map_res, opt, verify};
+
+use crate::error::PolyError;
 
 /// Allows measurement in 128th notes.
 pub trait KnownLength {
@@ -30,13 +37,109 @@ pub enum BasicLength {
 impl FromStr for BasicLength {
     type Err = String;
 
+    /// Reads the length as a number, e.g. `8`, or as any of its names, e.g. `e`, `eighth` or `quaver`.
     fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some((_, length)) = NAMED_LENGTHS.iter().find(|(name, _)| *name == s) {
+            return Ok(*length);
+        }
         let result: Result<u16, ParseIntError> = s.parse();
         match result {
             Ok(n) => Self::from_num(n),
-            Result::Err(e) => panic!("{}", e),
+            Result::Err(e) => Err(e.to_string()),
+        }
+    }
+}
+
+/// Names a note length can be written with instead of its number, American and British alike. Longer names
+/// come before the ones they start with, so they're tried first.
+pub(crate) static NAMED_LENGTHS: [(&str, BasicLength); 19] = [
+    ("hemidemisemiquaver", BasicLength::SixtyFourth),
+    ("demisemiquaver", BasicLength::ThirtySecond),
+    ("semiquaver", BasicLength::Sixteenth),
+    ("semibreve", BasicLength::Whole),
+    ("crotchet", BasicLength::Fourth),
+    ("quaver", BasicLength::Eighth),
+    ("minim", BasicLength::Half),
+    ("sixtyfourth", BasicLength::SixtyFourth),
+    ("thirtysecond", BasicLength::ThirtySecond),
+    ("sixteenth", BasicLength::Sixteenth),
+    ("eighth", BasicLength::Eighth),
+    ("quarter", BasicLength::Fourth),
+    ("half", BasicLength::Half),
+    ("whole", BasicLength::Whole),
+    ("s", BasicLength::Sixteenth),
+    ("e", BasicLength::Eighth),
+    ("q", BasicLength::Fourth),
+    ("h", BasicLength::Half),
+    ("w", BasicLength::Whole),
+];
+
+/// How note lengths are written in the patterns Poly writes itself.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LengthNames {
+    /// `4`, `8`, `16`.
+    #[default]
+    Numbers,
+    /// `quarter`, `eighth`, `sixteenth`.
+    American,
+    /// `crotchet`, `quaver`, `semiquaver`.
+    British,
+}
+
+impl FromStr for LengthNames {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "numbers" => Ok(LengthNames::Numbers),
+            "us" => Ok(LengthNames::American),
+            "uk" => Ok(LengthNames::British),
+            _ => Err(format!("Expected 'numbers', 'us' or 'uk', got '{}'", s)),
+        }
+    }
+}
+
+impl LengthNames {
+    /// Writes the length followed by the notes. Names are set apart from the notes with a space.
+    pub fn write(self, length: BasicLength, notes: &str) -> String {
+        let name = match (self, length) {
+            (LengthNames::Numbers, _) => return format!("{}{}", 128 / length.to_128th(), notes),
+            (LengthNames::American, BasicLength::Whole) => "whole",
+            (LengthNames::American, BasicLength::Half) => "half",
+            (LengthNames::American, BasicLength::Fourth) => "quarter",
+            (LengthNames::American, BasicLength::Eighth) => "eighth",
+            (LengthNames::American, BasicLength::Sixteenth) => "sixteenth",
+            (LengthNames::American, BasicLength::ThirtySecond) => "thirtysecond",
+            (LengthNames::American, BasicLength::SixtyFourth) => "sixtyfourth",
+            (LengthNames::British, BasicLength::Whole) => "semibreve",
+            (LengthNames::British, BasicLength::Half) => "minim",
+            (LengthNames::British, BasicLength::Fourth) => "crotchet",
+            (LengthNames::British, BasicLength::Eighth) => "quaver",
+            (LengthNames::British, BasicLength::Sixteenth) => "semiquaver",
+            (LengthNames::British, BasicLength::ThirtySecond) => "demisemiquaver",
+            (LengthNames::British, BasicLength::SixtyFourth) => "hemidemisemiquaver",
+        };
+        format!("{} {}", name, notes)
+    }
+}
+
+#[test]
+fn test_length_names() {
+    assert_eq!(BasicLength::from_str("quaver"), Ok(BasicLength::Eighth));
+    assert_eq!(BasicLength::from_str("q"), Ok(BasicLength::Fourth));
+    assert_eq!(BasicLength::from_str("16"), Ok(BasicLength::Sixteenth));
+    assert!(BasicLength::from_str("crotchets").is_err());
+    for (name, length) in NAMED_LENGTHS {
+        assert_eq!(length_basic(name), Ok(("", length)));
+    }
+    for names in [LengthNames::Numbers, LengthNames::American, LengthNames::British] {
+        for length in NAMED_LENGTHS.map(|(_, length)| length) {
+            let written = names.write(length, "x-");
+            assert_eq!(groups(&written), groups(&format!("{}x-", 128 / length.to_128th())));
         }
     }
+    assert_eq!(LengthNames::British.write(BasicLength::Eighth, "xg"), "quaver xg");
+    assert_eq!(LengthNames::Numbers.write(BasicLength::Eighth, "xg"), "8xg");
 }
 
 impl KnownLength for BasicLength {
@@ -54,6 +157,16 @@ impl KnownLength for BasicLength {
 }
 
 impl BasicLength {
+    pub const ALL: [BasicLength; 7] = [
+        BasicLength::Whole,
+        BasicLength::Half,
+        BasicLength::Fourth,
+        BasicLength::Eighth,
+        BasicLength::Sixteenth,
+        BasicLength::ThirtySecond,
+        BasicLength::SixtyFourth,
+    ];
+
     pub fn from_num(n: u16) -> Result<Self, String> {
         match n {
             64 => Ok(BasicLength::SixtyFourth),
@@ -208,6 +321,25 @@ impl KnownLength for Length {
     }
 }
 
+impl FromStr for Length {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match all_consuming(length)(s) {
+            Ok((_, l)) => Ok(l),
+            Err(_) => Err(format!("Can't parse note length: {}", s)),
+        }
+    }
+}
+
+#[test]
+fn test_length_from_str() {
+    assert_eq!(Length::from_str("4+8"), Ok(Length::Tied(ModdedLength::Plain(BasicLength::Fourth), ModdedLength::Plain(BasicLength::Eighth))));
+    assert_eq!(Length::from_str("2."), Ok(Length::Simple(ModdedLength::Dotted(BasicLength::Half))));
+    assert!(Length::from_str("3").is_err());
+    assert!(Length::from_str("4x").is_err());
+}
+
 #[test]
 fn test_known_length_of_length() {
     let dotted_eighth = ModdedLength::Dotted(BasicLength::Eighth);
@@ -220,6 +352,61 @@ fn test_known_length_of_length() {
 pub enum Note {
     Hit,
     Rest,
+    /// Louder than a regular hit.
+    Accent,
+    /// Much softer than a regular hit.
+    Ghost,
+}
+
+/// Dynamic marking of a note group, it sets the velocity of all the hits within the group.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Dynamic {
+    Pianissimo,
+    Piano,
+    MezzoPiano,
+    MezzoForte,
+    Forte,
+    Fortissimo,
+}
+
+impl Dynamic {
+    /// MIDI velocity of a regular hit.
+    pub fn velocity(self) -> u8 {
+        match self {
+            Dynamic::Pianissimo => 33,
+            Dynamic::Piano => 49,
+            Dynamic::MezzoPiano => 64,
+            Dynamic::MezzoForte => 80,
+            Dynamic::Forte => 100,
+            Dynamic::Fortissimo => 112,
+        }
+    }
+}
+
+/// Groups without a dynamic marking are played `f`, that leaves some headroom for accents.
+pub static DEFAULT_DYNAMIC: Dynamic = Dynamic::Forte;
+
+impl Note {
+    /// MIDI velocity of the note played with the `dynamic`, `None` for rests.
+    pub fn velocity(self, dynamic: Dynamic) -> Option<u8> {
+        let base = dynamic.velocity();
+        match self {
+            Note::Rest => None,
+            Note::Hit => Some(base),
+            Note::Accent => Some(u8::min(base + 27, 127)),
+            Note::Ghost => Some(u8::max(base * 2 / 5, 1)),
+        }
+    }
+}
+
+#[test]
+fn test_note_velocity() {
+    assert_eq!(Note::Rest.velocity(Dynamic::Forte), None);
+    assert_eq!(Note::Hit.velocity(Dynamic::Forte), Some(100));
+    assert_eq!(Note::Accent.velocity(Dynamic::Forte), Some(127));
+    assert_eq!(Note::Accent.velocity(Dynamic::Fortissimo), Some(127));
+    assert_eq!(Note::Ghost.velocity(Dynamic::Forte), Some(40));
+    assert_eq!(Note::Ghost.velocity(Dynamic::Pianissimo), Some(13));
 }
 
 #[allow(unused_imports)]
@@ -318,13 +505,16 @@ pub struct Group<T, R> {
     pub notes: Vec<T>,
     pub length: Length,
     pub times: R,
+    /// `None` means the group inherits the dynamic of the enclosing group, or `DEFAULT_DYNAMIC` at the top level.
+    pub dynamic: Option<Dynamic>,
 }
 
 impl<T> Group<T, Times> {
     pub fn empty() -> Self {
         Group {
+            dynamic: None,
             notes: Vec::new(),
-            length: FOURTH.clone(),
+            length: *FOURTH,
             times: Times(1),
         }
     }
@@ -380,6 +570,7 @@ impl KnownLength for Group<Note, ()> {
 #[test]
 fn test_known_length_group() {
     let group = Group {
+        dynamic: None,
         notes: vec![
             SingleNote(Hit),
             SingleNote(Hit),
@@ -390,7 +581,7 @@ fn test_known_length_group() {
             SingleNote(Hit),
             SingleNote(Rest),
         ],
-        length: SIXTEENTH.clone(),
+        length: *SIXTEENTH,
         times: Times(1),
     };
     assert_eq!(group.to_128th(), 64);
@@ -402,7 +593,7 @@ pub struct Groups(pub Vec<Group<Note, ()>>);
 impl IntoIterator for Groups {
     type Item = Group<Note, ()>;
 
-    type IntoIter = std::vec::IntoIter<Self::Item>;
+    type IntoIter = alloc::vec::IntoIter<Self::Item>;
 
     fn into_iter(self) -> Self::IntoIter {
         self.0.into_iter()
@@ -421,35 +612,123 @@ impl KnownLength for Groups {
     }
 }
 
+impl fmt::Display for BasicLength {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", 128 / self.to_128th())
+    }
+}
+
+impl fmt::Display for ModdedLength {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ModdedLength::Plain(length) => write!(f, "{}", length),
+            ModdedLength::Dotted(length) => write!(f, "{}.", length),
+        }
+    }
+}
+
+impl fmt::Display for Length {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Length::Simple(length) => write!(f, "{}", length),
+            Length::Tied(first, second) => write!(f, "{}+{}", first, second),
+            Length::Triplet(length) => write!(f, "{}t", length),
+        }
+    }
+}
+
+/// Writes the groups back in the DSL, a group of repetitions of the same notes as a repeated group.
+impl fmt::Display for Groups {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, group) in self.0.iter().enumerate() {
+            let dynamic = group.dynamic.and_then(|d| DYNAMICS.iter().find(|(_, x)| *x == d)).map_or("", |(name, _)| name);
+            let n = group.notes.len();
+            let period = (1..=n)
+                .find(|p| n.is_multiple_of(*p) && group.notes.iter().enumerate().all(|(j, note)| *note == group.notes[j % p]))
+                .unwrap_or(n);
+            let notes: String = group.notes[..period]
+                .iter()
+                .filter_map(|note| NOTES.iter().find(|(_, x)| x == note).map(|(c, _)| *c))
+                .collect();
+            let separator = if i == 0 { "" } else { " " };
+            match n / period.max(1) {
+                0 | 1 => write!(f, "{}{}{}{}", separator, dynamic, group.length, notes)?,
+                times => write!(f, "{}({},{}{}{})", separator, times, dynamic, group.length, notes)?,
+            }
+        }
+        Ok(())
+    }
+}
+
 impl KnownLength for &Groups {
     fn to_128th(&self) -> u32 {
         self.0.iter().fold(0, |acc, x| acc + x.to_128th())
     }
 }
 
+#[test]
+fn test_display_groups() {
+    for pattern in ["8x--x-- mf16xgXg", "(3,8.x-) 4tx-x 4+16x", "(7,8xx)"] {
+        let parsed = groups(pattern).unwrap();
+        assert_eq!(groups(&parsed.to_string()), Ok(parsed));
+    }
+    assert_eq!(groups("8x-x-x- (2,pp16xXg)").unwrap().to_string(), "(3,8x-) (2,pp16xXg)");
+    assert_eq!(groups("(2,8x-(2,16xg))").unwrap().to_string(), "8x- (2,16xg) 8x- (2,16xg)");
+}
+
 #[test]
 fn test_known_length_groups() {
     let groups = Groups(vec![Group {
+        dynamic: None,
         notes: vec![Hit, Hit, Rest, Hit, Rest, Hit, Hit, Rest],
-        length: SIXTEENTH.clone(),
+        length: *SIXTEENTH,
         times: (),
     }]);
     assert_eq!(groups.to_128th(), 64);
 }
 
-fn hit(input: &str) -> IResult<&str, Note> {
-    map(char('x'), |_| Note::Hit)(input)
+/// Characters standing for the notes.
+pub(crate) static NOTES: [(char, Note); 4] = [('x', Note::Hit), ('-', Note::Rest), ('X', Note::Accent), ('g', Note::Ghost)];
+
+/// Dynamic markings, the ones starting with others first so they're tried first.
+pub(crate) static DYNAMICS: [(&str, Dynamic); 6] = [
+    ("pp", Dynamic::Pianissimo),
+    ("p", Dynamic::Piano),
+    ("mp", Dynamic::MezzoPiano),
+    ("mf", Dynamic::MezzoForte),
+    ("ff", Dynamic::Fortissimo),
+    ("f", Dynamic::Forte),
+];
+
+fn note(input: &str) -> IResult<&str, Note> {
+    NOTES
+        .iter()
+        .find_map(|(c, note)| input.strip_prefix(*c).map(|rest| (rest, *note)))
+        .ok_or(Err::Error(nom::error::make_error(input, nom::error::ErrorKind::Char)))
 }
 
-fn rest(input: &str) -> IResult<&str, Note> {
-    map(char('-'), |_| Note::Rest)(input)
+/// A dynamic, which may be set apart from a named length after it with whitespace, as in `mf quaver`.
+fn dynamic(input: &str) -> IResult<&str, Dynamic> {
+    let (rest, dynamic) = DYNAMICS
+        .iter()
+        .find_map(|(name, dynamic)| input.strip_prefix(name).map(|rest| (rest, *dynamic)))
+        .ok_or(Err::Error(nom::error::make_error(input, nom::error::ErrorKind::Tag)))?;
+    let (rest, _) = multispace0(rest)?;
+    Ok((rest, dynamic))
 }
 
-fn note(input: &str) -> IResult<&str, Note> {
-    alt((hit, rest))(input)
+/// A named length, e.g. `q`, `quarter` or `crotchet`.
+fn named_length(input: &str) -> IResult<&str, BasicLength> {
+    NAMED_LENGTHS
+        .iter()
+        .find_map(|(name, length)| input.strip_prefix(name).map(|rest| (rest, *length)))
+        .ok_or(Err::Error(nom::error::make_error(input, nom::error::ErrorKind::Tag)))
 }
 
 fn length_basic(input: &str) -> IResult<&str, BasicLength> {
+    if let Ok(named) = named_length(input) {
+        return Ok(named);
+    }
     match map_res(digit1, str::parse)(input) {
         Ok((r, 1)) => Ok((r, BasicLength::Whole)),
         Ok((r, 2)) => Ok((r, BasicLength::Half)),
@@ -473,7 +752,7 @@ fn dotted_length(input: &str) -> IResult<&str, ModdedLength> {
 }
 
 fn modded_length(input: &str) -> IResult<&str, ModdedLength> {
-    alt((dotted_length, map(length_basic, |x| ModdedLength::Plain(x))))(input)
+    alt((dotted_length, map(length_basic, ModdedLength::Plain)))(input)
 }
 
 fn triplet_length(input: &str) -> IResult<&str, Length> {
@@ -493,12 +772,13 @@ fn length(input: &str) -> IResult<&str, Length> {
     alt((
         triplet_length,
         tied_length,
-        map(modded_length, |x| Length::Simple(x)),
+        map(modded_length, Length::Simple),
     ))(input)
 }
 
+/// A group is played at least once, a group played no times would have no length to repeat.
 fn times(input: &str) -> IResult<&str, Times> {
-    map(map_res(digit1, str::parse), |x| Times(x))(input)
+    map(verify(map_res(digit1, str::parse), |n: &u16| *n > 0), Times)(input)
 }
 
 fn group(input: &str) -> IResult<&str, Group<GroupOrNote<Times>, Times>> {
@@ -506,26 +786,23 @@ fn group(input: &str) -> IResult<&str, Group<GroupOrNote<Times>, Times>> {
         tuple((
             times,
             char(','),
+            opt(dynamic),
             length,
-            many1(alt((
-                map_res(note, |x| -> Result<GroupOrNote<Times>, &str> { Ok(SingleNote(x))}),
-                map_res(delimited_group, |x| -> Result<GroupOrNote<Times>, &str> { Ok(SingleGroup(x))}),
-            ))),
+            group_contents,
         )),
-        |(t, _, l, n)| (t, l, n),
+        |(t, _, d, l, n)| (t, d, l, n),
     );
     let single_syntax = map(
         tuple((
+            opt(dynamic),
             length,
-            many1(alt((
-                map_res(note, |x| -> Result<GroupOrNote<Times>, &str> { Ok(SingleNote(x))}),
-                map_res(delimited_group, |x| -> Result<GroupOrNote<Times>, &str> { Ok(SingleGroup(x))}),
-            ))),
-        )), |(l, vn)| (Times(1), l, vn));
-    let (rem, (t, l, n)) = alt((repeated_syntax, single_syntax))(input)?;
+            group_contents,
+        )), |(d, l, vn)| (Times(1), d, l, vn));
+    let (rem, (t, d, l, n)) = alt((repeated_syntax, single_syntax))(input)?;
     Ok((
         rem,
         Group {
+            dynamic: d,
             notes: n
                 .into_iter()
                 .collect(),
@@ -535,20 +812,102 @@ fn group(input: &str) -> IResult<&str, Group<GroupOrNote<Times>, Times>> {
     ))
 }
 
+/// Notes and nested groups, which may be nested arbitrarily deep. Whitespace between them is ignored.
+fn group_contents(input: &str) -> IResult<&str, Vec<GroupOrNote<Times>>> {
+    many1(preceded(
+        multispace0,
+        alt((
+            map(note, SingleNote),
+            map(delimited_group, SingleGroup),
+        )),
+    ))(input)
+}
+
+/// Spreads `hits` over `steps` as evenly as possible, starting `rotation` steps into the pattern.
+pub fn euclidean_rhythm(hits: u16, steps: u16, rotation: u16) -> Vec<Note> {
+    let (k, n) = (hits as u32, steps as u32);
+    (0..n)
+        .map(|i| {
+            let step = (i + rotation as u32) % n;
+            if (step * k) % n < k { Hit } else { Rest }
+        })
+        .collect()
+}
+
+/// `E(hits,steps)` or `E(hits,steps,rotation)`, with the same prefixes as a regular group.
+/// The length may be omitted, then the steps are 16th notes.
+fn euclidean(input: &str) -> IResult<&str, Group<GroupOrNote<Times>, Times>> {
+    let number = |input| map_res(digit1, str::parse::<u16>)(input);
+    let arguments = map_res(
+        delimited(
+            tag("E("),
+            tuple((number, preceded(char(','), number), opt(preceded(char(','), number)))),
+            char(')'),
+        ),
+        |(hits, steps, rotation)| -> Result<Vec<Note>, &str> {
+            if steps == 0 || hits > steps {
+                Err("Euclidean rhythm needs at least as many steps as hits")
+            } else {
+                Ok(euclidean_rhythm(hits, steps, rotation.unwrap_or(0)))
+            }
+        },
+    );
+    map(
+        tuple((opt(terminated(times, char(','))), opt(dynamic), opt(length), arguments)),
+        |(t, d, l, notes)| Group {
+            dynamic: d,
+            notes: notes.into_iter().map(SingleNote).collect(),
+            length: l.unwrap_or(*SIXTEENTH),
+            times: t.unwrap_or(Times(1)),
+        },
+    )(input)
+}
+
 fn delimited_group(input: &str) -> IResult<&str, Group<GroupOrNote<Times>, Times>> {
-    delimited(char('('), group, char(')'))(input)
+    delimited(char('('), alt((euclidean, group)), preceded(multispace0, char(')')))(input)
 }
 
 pub fn group_or_delimited_group(input: &str) -> IResult<&str, Group<GroupOrNote<Times>, Times>> {
-    alt((delimited_group, group))(input)
+    alt((delimited_group, euclidean, group))(input)
 }
 
-pub fn groups(input: &str) -> IResult<&str, Groups> {
-    map_res(
-        all_consuming(many1(group_or_delimited_group)),
-        |gs| -> Result<Groups, &str> {
-            Ok(flatten_groups(gs))
-        })(input)
+/// Parses a whole pattern, such as `8x-x(3,16x-)`, into flat note groups.
+pub fn groups(input: &str) -> Result<Groups, PolyError> {
+    let result = all_consuming(terminated(
+        many1(preceded(multispace0, group_or_delimited_group)),
+        multispace0,
+    ))(input);
+    match result {
+        Ok((_, gs)) => Ok(flatten_groups(gs)),
+        Err(Err::Error(e) | Err::Failure(e)) => Err(PolyError::Pattern {
+            pattern: input.to_string(),
+            position: input.len() - e.input.len(),
+        }),
+        Err(Err::Incomplete(_)) => Err(PolyError::Pattern { pattern: input.to_string(), position: input.len() }),
+    }
+}
+
+/// Parses a pattern like `groups`, keeping the byte range of the pattern each top-level group is written at. Groups
+/// nested in a group are a part of it, e.g. `8x-(3,16xg)` is a single group.
+pub fn spanned_groups(input: &str) -> Result<Vec<(Range<usize>, Groups)>, PolyError> {
+    let error = |rest: &str| PolyError::Pattern { pattern: input.to_string(), position: input.len() - rest.len() };
+    let mut spans = Vec::new();
+    let mut rest = input.trim_start();
+    while !rest.is_empty() {
+        let start = input.len() - rest.len();
+        match group_or_delimited_group(rest) {
+            Ok((remaining, group)) => {
+                spans.push((start..input.len() - remaining.len(), flatten_group(group)));
+                rest = remaining.trim_start();
+            }
+            Err(Err::Error(e) | Err::Failure(e)) => return Err(error(e.input)),
+            Err(Err::Incomplete(_)) => return Err(error("")),
+        }
+    }
+    if spans.is_empty() {
+        return Err(error(rest));
+    }
+    Ok(spans)
 }
 
 pub fn flatten_groups<I>(input_groups: I) -> Groups
@@ -570,18 +929,26 @@ pub fn flatten_group(input: Group<GroupOrNote<Times>, Times>) -> Groups {
 fn flatten_group_(input: &Group<GroupOrNote<Times>, Times>, out_groups: &mut Vec<Group<Note, ()>>) -> Groups {
     let mut note_group = Vec::new();
     let inlined_notes = input.notes.iter().cycle().take(input.notes.len() * input.times.0 as usize);
-    let group = Group { notes: inlined_notes.collect(), times: (), length: input.length };
+    let group = Group { dynamic: input.dynamic, notes: inlined_notes.collect(), times: (), length: input.length };
     group.notes.iter().for_each(|&g| {
         match g {
             SingleGroup(group) => {
-                let isolated_group = Group {
-                    notes: note_group.clone(),
-                    length: input.length,
-                    times: (),
+                if !note_group.is_empty() {
+                    let isolated_group = Group {
+                        dynamic: input.dynamic,
+                        notes: note_group.clone(),
+                        length: input.length,
+                        times: (),
+                    };
+                    out_groups.push(isolated_group);
+                    note_group.clear();
+                }
+                // Nested groups without a dynamic marking inherit it from the enclosing group.
+                let inheriting_group = Group {
+                    dynamic: group.dynamic.or(input.dynamic),
+                    ..group.clone()
                 };
-                out_groups.push(isolated_group);
-                note_group.clear();
-                flatten_group_(&group, out_groups);
+                flatten_group_(&inheriting_group, out_groups);
             }
             SingleNote(note) => {
                 note_group.push(*note);
@@ -590,29 +957,32 @@ fn flatten_group_(input: &Group<GroupOrNote<Times>, Times>, out_groups: &mut Vec
     });
     if !note_group.is_empty() {
         let isolated_group = Group {
+            dynamic: group.dynamic,
             notes: note_group.clone(),
             length: group.length,
             times: (),
         };
         out_groups.push(isolated_group);
     }
-    Groups(out_groups.iter().cloned().collect())
+    Groups(out_groups.to_vec())
 }
 
 #[test]
 fn test_flatten_group() {
     let output = Groups(vec![
-        Group { notes: vec![Hit], length: *SIXTEENTH, times: () },
-        Group { notes: vec![Rest, Hit, Rest, Hit], length: *EIGHTH, times: () },
-        Group { notes: vec![Hit], length: *SIXTEENTH, times: () },
-        Group { notes: vec![Rest, Hit, Rest, Hit], length: *EIGHTH, times: () },
-        Group { notes: vec![Hit], length: *SIXTEENTH, times: () },
-        Group { notes: vec![Rest, Hit, Rest, Hit], length: *EIGHTH, times: () },
+        Group { dynamic: None, notes: vec![Hit], length: *SIXTEENTH, times: () },
+        Group { dynamic: None, notes: vec![Rest, Hit, Rest, Hit], length: *EIGHTH, times: () },
+        Group { dynamic: None, notes: vec![Hit], length: *SIXTEENTH, times: () },
+        Group { dynamic: None, notes: vec![Rest, Hit, Rest, Hit], length: *EIGHTH, times: () },
+        Group { dynamic: None, notes: vec![Hit], length: *SIXTEENTH, times: () },
+        Group { dynamic: None, notes: vec![Rest, Hit, Rest, Hit], length: *EIGHTH, times: () },
     ]);
     // basically it's 3,16x(2,8-x)
     let input = Group {
+        dynamic: None,
         notes: vec![
             SingleNote(Hit), SingleGroup(Group {
+                dynamic: None,
                 notes: vec![SingleNote(Rest), SingleNote(Hit)],
                 length: *EIGHTH,
                 times: Times(2),
@@ -638,44 +1008,72 @@ fn parse_length() {
     );
     assert_eq!(length("8t"), Ok(("", *EIGHTH_TRIPLET)));
     assert_eq!(length("4.t"), Ok(("", *FOURTH_DOTTED_TRIPLET)));
+    assert_eq!(length("et"), Ok(("", *EIGHTH_TRIPLET)));
+    assert_eq!(length("crotchet.t"), Ok(("", *FOURTH_DOTTED_TRIPLET)));
+    assert_eq!(
+        length("quarter+s"),
+        Ok((
+            "",
+            Length::Tied(
+                ModdedLength::Plain(BasicLength::Fourth),
+                ModdedLength::Plain(BasicLength::Sixteenth)
+            )
+        ))
+    );
 }
 
 #[test]
 fn test_parse_groups() {
+    assert_eq!(groups("e x- (3,s xg) (E(3,8))"), groups("8x-(3,16xg)(E(3,8))"));
+    assert_eq!(groups("crotchet x quaver xx"), groups("4x8xx"));
     assert_eq!(
         groups("8x-(7,8xx)"),
-        Ok((
-            "",
-            Groups(vec![
+        Ok(Groups(vec![
                 Group {
+                    dynamic: None,
                     notes: vec![Hit, Rest],
                     length: *EIGHTH,
                     times: ()
                 },
                 Group {
+                    dynamic: None,
                     notes: vec![Hit, Hit, Hit, Hit, Hit, Hit, Hit, Hit, Hit, Hit, Hit, Hit, Hit, Hit],
                     length: *EIGHTH,
                     times: ()
                 }
-            ])
-        ))
+            ]))
     );
     assert_eq!(
         groups("8x-(7,8xx"),
-        Err(Err::Error(nom::error::make_error(
-            "(7,8xx",
-            nom::error::ErrorKind::Eof
-        )))
+        Err(PolyError::Pattern { pattern: "8x-(7,8xx".to_string(), position: 3 })
+    );
+}
+
+#[test]
+fn test_spanned_groups() {
+    let pattern = " 8x- (3,16xg) (2,4x) E(3,8) ";
+    let spans = spanned_groups(pattern).unwrap();
+    assert_eq!(
+        spans.iter().map(|(span, _)| &pattern[span.clone()]).collect::<Vec<&str>>(),
+        ["8x- (3,16xg) (2,4x)", "E(3,8)"]
     );
+    assert_eq!(Groups(spans.into_iter().flat_map(|(_, g)| g.0).collect()), groups(pattern).unwrap());
+    assert_eq!(
+        spanned_groups("8x-(7,8xx"),
+        Err(PolyError::Pattern { pattern: "8x-(7,8xx".to_string(), position: 3 })
+    );
+    assert!(spanned_groups("  ").is_err());
 }
 
 #[test]
 fn test_parse_group() {
     let expectation = Group {
+        dynamic: None,
         times: *TWICE,
         notes: vec![
             SingleNote(Hit),
             SingleGroup(Group {
+                dynamic: None,
                 times: *ONCE,
                 notes: vec![SingleNote(Rest), SingleNote(Hit)],
                 length: *EIGHTH,
@@ -684,11 +1082,14 @@ fn test_parse_group() {
         length: *SIXTEENTH,
     };
     assert_eq!(group("2,16x(8-x)"), Ok(("", expectation)));
+    assert!(group("0,8x").is_err());
+    assert!(groups("0,8x").is_err());
     assert_eq!(
         group("16x--x-"),
         Ok((
             "",
             Group {
+                dynamic: None,
                 times: *ONCE,
                 notes: vec![
                     HIT.clone(),
@@ -706,6 +1107,7 @@ fn test_parse_group() {
         Ok((
             "",
             Group {
+                dynamic: None,
                 times: *ONCE,
                 notes: vec![HIT.clone(), HIT.clone(), HIT.clone()],
                 length: *EIGHTH_TRIPLET
@@ -717,6 +1119,7 @@ fn test_parse_group() {
         Ok((
             "",
             Group {
+                dynamic: None,
                 times: *ONCE,
                 notes: vec![HIT.clone(), REST.clone(), HIT.clone(), HIT.clone()],
                 length: Length::Tied(
@@ -731,6 +1134,7 @@ fn test_parse_group() {
         Ok((
             "",
             Group {
+                dynamic: None,
                 times: *THRICE,
                 length: *SIXTEENTH,
                 notes: vec![HIT.clone(), HIT.clone()]
@@ -739,6 +1143,52 @@ fn test_parse_group() {
     );
 }
 
+#[test]
+fn test_parse_dynamics() {
+    assert_eq!(group("mf quaver x-X"), group("mf8x-X"));
+    assert_eq!(
+        group("16xX-g"),
+        Ok((
+            "",
+            Group {
+                dynamic: None,
+                times: *ONCE,
+                notes: vec![HIT.clone(), SingleNote(Accent), REST.clone(), SingleNote(Ghost)],
+                length: *SIXTEENTH
+            }
+        ))
+    );
+    assert_eq!(
+        group("3,pp8xX"),
+        Ok((
+            "",
+            Group {
+                dynamic: Some(Dynamic::Pianissimo),
+                times: *THRICE,
+                notes: vec![HIT.clone(), SingleNote(Accent)],
+                length: *EIGHTH
+            }
+        ))
+    );
+    assert_eq!(
+        groups("mf8x-ff4X"),
+        Ok(Groups(vec![
+                Group { dynamic: Some(Dynamic::MezzoForte), notes: vec![Hit, Rest], length: *EIGHTH, times: () },
+                Group { dynamic: Some(Dynamic::Fortissimo), notes: vec![Accent], length: *FOURTH, times: () },
+            ]))
+    );
+    // nested groups inherit the dynamic of the enclosing group unless they have their own
+    assert_eq!(
+        groups("p8x(16xx)x(f16x)"),
+        Ok(Groups(vec![
+                Group { dynamic: Some(Dynamic::Piano), notes: vec![Hit], length: *EIGHTH, times: () },
+                Group { dynamic: Some(Dynamic::Piano), notes: vec![Hit, Hit], length: *SIXTEENTH, times: () },
+                Group { dynamic: Some(Dynamic::Piano), notes: vec![Hit], length: *EIGHTH, times: () },
+                Group { dynamic: Some(Dynamic::Forte), notes: vec![Hit], length: *SIXTEENTH, times: () },
+            ]))
+    );
+}
+
 #[test]
 fn parse_delimited_group() {
     assert_eq!(
@@ -746,6 +1196,7 @@ fn parse_delimited_group() {
         Ok((
             "",
             Group {
+                dynamic: None,
                 times: *THRICE,
                 notes: vec![
                     HIT.clone(),
@@ -767,6 +1218,7 @@ fn test_parse_group_or_delimited_group() {
         Ok((
             "",
             Group {
+                dynamic: None,
                 times: *THRICE,
                 notes: vec![
                     HIT.clone(),
@@ -784,6 +1236,7 @@ fn test_parse_group_or_delimited_group() {
         Ok((
             "",
             Group {
+                dynamic: None,
                 times: *ONCE,
                 notes: vec![
                     HIT.clone(),
@@ -805,6 +1258,66 @@ fn test_parse_group_or_delimited_group() {
     );
 }
 
+#[test]
+fn test_parse_nested_groups() {
+    let sixteenth = |notes: Vec<Note>| Group { dynamic: None, notes, length: *SIXTEENTH, times: () };
+    let eighth_triplet = |notes: Vec<Note>| Group { dynamic: None, notes, length: *EIGHTH_TRIPLET, times: () };
+    assert_eq!(
+        groups("16x-(3,8t x-x)x-"),
+        Ok(Groups(vec![
+                sixteenth(vec![Hit, Rest]),
+                eighth_triplet(vec![Hit, Rest, Hit, Hit, Rest, Hit, Hit, Rest, Hit]),
+                sixteenth(vec![Hit, Rest]),
+            ]))
+    );
+    // groups nested in groups nested in groups, starting right away with a nested group
+    assert_eq!(
+        groups("(2,16(8t(32xx)x)-)"),
+        Ok(Groups(vec![
+                Group { dynamic: None, notes: vec![Hit, Hit], length: *THIRTY_SECOND, times: () },
+                eighth_triplet(vec![Hit]),
+                sixteenth(vec![Rest]),
+                Group { dynamic: None, notes: vec![Hit, Hit], length: *THIRTY_SECOND, times: () },
+                eighth_triplet(vec![Hit]),
+                sixteenth(vec![Rest]),
+            ]))
+    );
+    assert_eq!(
+        groups(" 8x-x 16xxxx "),
+        Ok(Groups(vec![
+                Group { dynamic: None, notes: vec![Hit, Rest, Hit], length: *EIGHTH, times: () },
+                sixteenth(vec![Hit, Hit, Hit, Hit]),
+            ]))
+    );
+}
+
+#[test]
+fn test_euclidean_rhythm() {
+    assert_eq!(euclidean_rhythm(3, 8, 0), vec![Hit, Rest, Rest, Hit, Rest, Rest, Hit, Rest]);
+    assert_eq!(euclidean_rhythm(3, 8, 1), vec![Rest, Rest, Hit, Rest, Rest, Hit, Rest, Hit]);
+    assert_eq!(euclidean_rhythm(0, 4, 0), vec![Rest; 4]);
+    assert_eq!(euclidean_rhythm(4, 4, 0), vec![Hit; 4]);
+    assert_eq!(euclidean_rhythm(5, 16, 0).iter().filter(|n| **n == Hit).count(), 5);
+}
+
+#[test]
+fn test_parse_euclidean() {
+    assert_eq!(groups("E(3,8)"), groups("16x--x--x-"));
+    assert_eq!(groups("2,8E(3,8)"), groups("(2,8x--x--x-)"));
+    assert_eq!(groups("p4E(2,4,1)"), groups("p4-x-x"));
+    assert_eq!(groups("16x-(8tE(2,3))x"), groups("16x-8tx-x16x"));
+    assert_eq!(groups("E(3,8) 8E(1,2)"), groups("16x--x--x-8x-"));
+    assert!(groups("E(5,4)").is_err());
+    assert!(groups("E(1,0)").is_err());
+}
+
+#[test]
+fn test_nested_groups_known_length() {
+    let (_, nested) = group_or_delimited_group("(3,16x(2,8-x))").unwrap();
+    assert_eq!(nested.to_128th(), 3 * (8 + 2 * 2 * 16));
+    assert_eq!(flatten_group(nested).to_128th(), 3 * (8 + 2 * 2 * 16));
+}
+
 // “x” hit
 // “-“ rest
 // 16x-- => 16th hit and 16th rests
@@ -0,0 +1,164 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::str::FromStr;
+
+use midly::{MidiMessage, TrackEventKind};
+
+use crate::dsl::dsl::Groups;
+use crate::midi::core::{generate, DrumPart, RenderOptions};
+use crate::midi::time::TimeSignature;
+
+/// How a number measured from the patterns compares with the expected one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Comparison {
+    Equal,
+    NotEqual,
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual,
+}
+
+/// Longest first, so that `<=` isn't read as `<`.
+static COMPARISONS: [(&str, Comparison); 6] = [
+    ("!=", Comparison::NotEqual),
+    ("<=", Comparison::LessOrEqual),
+    (">=", Comparison::GreaterOrEqual),
+    ("=", Comparison::Equal),
+    ("<", Comparison::Less),
+    (">", Comparison::Greater),
+];
+
+impl Comparison {
+    fn holds(self, actual: u32, expected: u32) -> bool {
+        match self {
+            Comparison::Equal => actual == expected,
+            Comparison::NotEqual => actual != expected,
+            Comparison::Less => actual < expected,
+            Comparison::LessOrEqual => actual <= expected,
+            Comparison::Greater => actual > expected,
+            Comparison::GreaterOrEqual => actual >= expected,
+        }
+    }
+}
+
+impl fmt::Display for Comparison {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (symbol, _) = COMPARISONS.iter().find(|(_, c)| c == self).unwrap_or(&("=", Comparison::Equal));
+        write!(f, "{}", symbol)
+    }
+}
+
+/// Something a pattern file promises about the groove, checked by `poly test` to catch a groove changing as
+/// Poly evolves. Written on a line of its own after `expect`:
+///
+/// ```text
+/// expect converge
+/// expect bars = 12
+/// expect hits(kick) >= 16
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Expectation {
+    /// The parts line up again within the 1000 bars Poly renders at most.
+    Converge,
+    /// Number of bars the parts take to converge.
+    Bars(Comparison, u32),
+    /// Number of notes the part plays over the converged groove, as rendered.
+    Hits(DrumPart, Comparison, u32),
+}
+
+impl FromStr for Expectation {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if s == "converge" {
+            return Ok(Expectation::Converge);
+        }
+        let error = || format!("Expected 'converge', 'bars <comparison> <number>' or 'hits(<part>) <comparison> <number>', got '{}'", s);
+        let (subject, comparison, number) = COMPARISONS
+            .iter()
+            .find_map(|(symbol, comparison)| {
+                s.split_once(symbol).map(|(subject, number)| (subject.trim(), *comparison, number.trim()))
+            })
+            .ok_or_else(error)?;
+        let number = u32::from_str(number).map_err(|_| format!("Expected a number, got '{}'", number))?;
+        if subject == "bars" {
+            return Ok(Expectation::Bars(comparison, number));
+        }
+        let part = subject.strip_prefix("hits(").and_then(|p| p.strip_suffix(')')).ok_or_else(error)?;
+        Ok(Expectation::Hits(DrumPart::from_str(part.trim())?, comparison, number))
+    }
+}
+
+impl fmt::Display for Expectation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Expectation::Converge => write!(f, "converge"),
+            Expectation::Bars(comparison, number) => write!(f, "bars {} {}", comparison, number),
+            Expectation::Hits(part, comparison, number) => write!(f, "hits({}) {} {}", part.name(), comparison, number),
+        }
+    }
+}
+
+impl Expectation {
+    /// Checks the expectation against the patterns in the time signature, telling what was found instead if it
+    /// doesn't hold.
+    pub fn check(&self, groups: &BTreeMap<DrumPart, Groups>, time_signature: TimeSignature) -> Result<(), String> {
+        let converged = time_signature.converges(groups.values()).map_err(|e| e.to_string());
+        let (comparison, expected, actual) = match *self {
+            Expectation::Converge => return converged.map(|_| ()),
+            Expectation::Bars(comparison, expected) => (comparison, expected, converged?),
+            Expectation::Hits(part, comparison, expected) => {
+                let options = RenderOptions { time_signature, ..Default::default() };
+                let smf = generate(groups.clone(), "", &options).map_err(|e| e.to_string())?;
+                let hits = smf.tracks[0]
+                    .iter()
+                    .filter(|e| match e.kind {
+                        TrackEventKind::Midi { message: MidiMessage::NoteOn { key, vel }, .. } => {
+                            vel > 0 && options.drum_map.part(key) == Some(part)
+                        }
+                        _ => false,
+                    })
+                    .count();
+                (comparison, expected, hits as u32)
+            }
+        };
+        if comparison.holds(actual, expected) {
+            Ok(())
+        } else {
+            Err(format!("got {}", actual))
+        }
+    }
+}
+
+#[cfg(test)]
+use crate::dsl::dsl::groups;
+
+#[test]
+fn test_parse_expectation() {
+    assert_eq!(Expectation::from_str("converge"), Ok(Expectation::Converge));
+    assert_eq!(Expectation::from_str("bars = 12"), Ok(Expectation::Bars(Comparison::Equal, 12)));
+    let hits = Expectation::from_str(" hits(kick)>=16").unwrap();
+    assert_eq!(hits, Expectation::Hits(DrumPart::KickDrum, Comparison::GreaterOrEqual, 16));
+    assert_eq!(hits.to_string(), "hits(kick) >= 16");
+    assert!(Expectation::from_str("bars = twelve").is_err());
+    assert!(Expectation::from_str("hits(cowbell) = 1").is_err());
+    assert!(Expectation::from_str("notes = 1").is_err());
+}
+
+#[test]
+fn test_check_expectation() {
+    let patterns = BTreeMap::from([
+        (DrumPart::KickDrum, groups("8x--x--").unwrap()),
+        (DrumPart::SnareDrum, groups("4-x").unwrap()),
+    ]);
+    let four_four = TimeSignature::from_str("4/4").unwrap();
+    let check = |s| Expectation::from_str(s).unwrap().check(&patterns, four_four);
+    assert_eq!(check("converge"), Ok(()));
+    assert_eq!(check("bars = 3"), Ok(()));
+    assert_eq!(check("bars < 3"), Err("got 3".to_string()));
+    assert_eq!(check("hits(kick) = 8"), Ok(()));
+    assert_eq!(check("hits(snare) = 6"), Ok(()));
+    assert_eq!(check("hits(hi-hat) != 0"), Err("got 0".to_string()));
+}
@@ -0,0 +1,367 @@
+use std::collections::BTreeMap;
+use std::ops::Range;
+use std::str::FromStr;
+
+use crate::dsl::dsl::{Groups, Note};
+use crate::dsl::expect::Expectation;
+use crate::dsl::version::DslVersion;
+use crate::error::PolyError;
+use crate::midi::core::DrumPart;
+
+/// Drum parts saved to a file along with the version of the DSL they're written in:
+///
+/// ```text
+/// # 3 against 4
+/// version: 1
+/// tags: [polyrhythm, beginner]
+/// kick: 8x--x--
+/// snare: 4-x
+/// ```
+///
+/// Lines starting with `#` are comments. Files without a version are read as version 1. Tags, to find the groove by
+/// in a library of them, are optional. Lines starting with `expect` hold expectations about the groove, see
+/// `Expectation`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PatternFile {
+    pub version: DslVersion,
+    pub tags: Vec<String>,
+    /// Patterns as written in the file.
+    pub patterns: BTreeMap<DrumPart, String>,
+    pub groups: BTreeMap<DrumPart, Groups>,
+    /// Expectations in the order they're written, along with their lines counting from 1.
+    pub expectations: Vec<(usize, Expectation)>,
+}
+
+/// Reads the tags of a `tags` line, a list like `[afrocuban, 7-8, intermediate]`. The brackets can be left out.
+pub fn parse_tags(s: &str) -> Result<Vec<String>, String> {
+    let s = s.trim();
+    let list = s.strip_prefix('[').and_then(|s| s.strip_suffix(']')).unwrap_or(s);
+    if list.trim().is_empty() {
+        return Ok(Vec::new());
+    }
+    list.split(',')
+        .map(|tag| match tag.trim() {
+            "" => Err(format!("expected tags separated by commas, got '{}'", s)),
+            tag if tag.contains(|c: char| c.is_whitespace() || "[]".contains(c)) => {
+                Err(format!("tags can't hold spaces or brackets, got '{}'", tag))
+            }
+            tag => Ok(tag.to_string()),
+        })
+        .collect()
+}
+
+/// What follows `expect` on a line holding an expectation.
+fn expectation(line: &str) -> Option<&str> {
+    line.strip_prefix("expect").filter(|rest| rest.is_empty() || rest.starts_with(char::is_whitespace))
+}
+
+impl FromStr for PatternFile {
+    type Err = PolyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let error = |line: usize, message: String| PolyError::PatternFile { line: line + 1, message };
+        let mut version = None;
+        let mut tags = None;
+        let mut patterns = BTreeMap::new();
+        let mut expectations = Vec::new();
+        // Patterns are parsed once the version is known, wherever it's declared.
+        let mut lines = BTreeMap::new();
+        for (i, line) in s.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            if let Some(expected) = expectation(line) {
+                expectations.push((i + 1, Expectation::from_str(expected).map_err(|e| error(i, e))?));
+                continue;
+            }
+            let Some((key, value)) = line.split_once(':') else {
+                return Err(error(i, "expected 'key: value'".to_string()));
+            };
+            let (key, value) = (key.trim(), value.trim());
+            if key == "version" {
+                let number = u32::from_str(value).map_err(|e| error(i, e.to_string()))?;
+                if version.replace(DslVersion::try_from(number).map_err(|e| error(i, e.to_string()))?).is_some() {
+                    return Err(error(i, "the version is declared twice".to_string()));
+                }
+            } else if key == "tags" {
+                if tags.replace(parse_tags(value).map_err(|e| error(i, e))?).is_some() {
+                    return Err(error(i, "the tags are declared twice".to_string()));
+                }
+            } else {
+                let part = DrumPart::from_str(key).map_err(|e| error(i, e))?;
+                if patterns.insert(part, value.to_string()).is_some() {
+                    return Err(error(i, format!("{} is declared twice", part.name())));
+                }
+                lines.insert(part, i);
+            }
+        }
+        let version = version.unwrap_or(DslVersion::V1);
+        let groups = patterns
+            .iter()
+            .map(|(part, pattern)| match version.parse(pattern) {
+                Ok(groups) => Ok((*part, groups)),
+                Err(e) => Err(error(lines[part], e.to_string())),
+            })
+            .collect::<Result<BTreeMap<DrumPart, Groups>, PolyError>>()?;
+        Ok(PatternFile { version, tags: tags.unwrap_or_default(), patterns, groups, expectations })
+    }
+}
+
+impl PatternFile {
+    /// Whether the file has every one of the tags, ignoring case.
+    pub fn has_tags(&self, tags: &[String]) -> bool {
+        tags.iter().all(|tag| self.tags.iter().any(|t| t.eq_ignore_ascii_case(tag)))
+    }
+}
+
+/// Rewrites a pattern file to the latest version of the DSL, keeping the comments and the order of the lines. The
+/// version is declared once, before the first line that isn't a comment, wherever the file declared it. Patterns
+/// that can't be converted are all reported, along with their lines.
+pub fn migrate(s: &str) -> Result<String, Vec<PolyError>> {
+    let file = PatternFile::from_str(s).map_err(|e| vec![e])?;
+    let latest = format!("version: {}", DslVersion::LATEST.number());
+    let mut out = Vec::new();
+    let mut errors = Vec::new();
+    let mut declared = false;
+    for (i, line) in s.lines().enumerate() {
+        let trimmed = line.trim();
+        if trimmed.is_empty() || trimmed.starts_with('#') {
+            out.push(line.to_string());
+            continue;
+        }
+        if !declared {
+            out.push(latest.clone());
+            declared = true;
+        }
+        if expectation(trimmed).is_some() {
+            out.push(line.to_string());
+            continue;
+        }
+        // Already validated by the parsing above.
+        let (key, pattern) = trimmed.split_once(':').unwrap_or_default();
+        match key.trim() {
+            // Declared above already.
+            "version" => {}
+            "tags" => out.push(line.to_string()),
+            key => match file.version.migrate(pattern.trim()) {
+                Ok(pattern) => out.push(format!("{}: {}", key, pattern)),
+                Err(message) => errors.push(PolyError::PatternFile { line: i + 1, message }),
+            },
+        }
+    }
+    if !declared {
+        out.push(latest);
+    }
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+    let mut result = out.join("\n");
+    result.push('\n');
+    Ok(result)
+}
+
+/// A problem with a pattern file, within a line. Lines count from 0, columns are byte offsets in the line.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub line: usize,
+    pub columns: Range<usize>,
+    /// The file can still be read, but probably doesn't do what was meant.
+    pub warning: bool,
+    pub message: String,
+}
+
+/// A `key: value` line of a pattern file, with the byte ranges of both within the line.
+pub(crate) struct Entry<'a> {
+    pub line: usize,
+    pub key: &'a str,
+    pub key_columns: Range<usize>,
+    pub value: &'a str,
+    pub value_columns: Range<usize>,
+}
+
+/// Splits the lines holding a key and a value, leaving out the blank ones, the comments and the expectations.
+/// Malformed expectations and lines that aren't any of these are reported.
+pub(crate) fn entries(text: &str) -> (Vec<Entry<'_>>, Vec<Diagnostic>) {
+    let mut entries = Vec::new();
+    let mut problems = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+        let trimmed = line.trim();
+        if trimmed.is_empty() || trimmed.starts_with('#') {
+            continue;
+        }
+        let offset = |s: &str| s.as_ptr() as usize - line.as_ptr() as usize;
+        if let Some(expected) = expectation(trimmed) {
+            if let Err(message) = Expectation::from_str(expected) {
+                let start = offset(trimmed);
+                problems.push(Diagnostic { line: i, columns: start..start + trimmed.len(), warning: false, message });
+            }
+            continue;
+        }
+        let Some((key, value)) = line.split_once(':') else {
+            let start = offset(trimmed);
+            problems.push(Diagnostic {
+                line: i,
+                columns: start..start + trimmed.len(),
+                warning: false,
+                message: "expected 'key: value'".to_string(),
+            });
+            continue;
+        };
+        let (key, value) = (key.trim(), value.trim());
+        entries.push(Entry {
+            line: i,
+            key,
+            key_columns: offset(key)..offset(key) + key.len(),
+            value,
+            value_columns: offset(value)..offset(value) + value.len(),
+        });
+    }
+    (entries, problems)
+}
+
+/// Everything wrong with a pattern file, unlike reading it which stops at the first problem.
+pub fn diagnostics(text: &str) -> Vec<Diagnostic> {
+    let (entries, mut problems) = entries(text);
+    let error = |line, columns, message| Diagnostic { line, columns, warning: false, message };
+    let mut version = None;
+    let mut tagged = false;
+    let mut parts = BTreeMap::new();
+    for entry in &entries {
+        if entry.key == "version" {
+            if version.is_some() {
+                problems.push(error(entry.line, entry.key_columns.clone(), "the version is declared twice".to_string()));
+                continue;
+            }
+            match u32::from_str(entry.value).map_err(|e| e.to_string()).and_then(|number| {
+                DslVersion::try_from(number).map_err(|e| e.to_string())
+            }) {
+                Ok(v) => version = Some(v),
+                Err(e) => problems.push(error(entry.line, entry.value_columns.clone(), e)),
+            }
+        } else if entry.key == "tags" {
+            if tagged {
+                problems.push(error(entry.line, entry.key_columns.clone(), "the tags are declared twice".to_string()));
+            } else if let Err(e) = parse_tags(entry.value) {
+                problems.push(error(entry.line, entry.value_columns.clone(), e));
+            }
+            tagged = true;
+        } else {
+            match DrumPart::from_str(entry.key) {
+                Ok(part) if parts.contains_key(&part) => problems.push(error(
+                    entry.line,
+                    entry.key_columns.clone(),
+                    format!("{} is declared twice", part.name()),
+                )),
+                Ok(part) => {
+                    parts.insert(part, entry);
+                }
+                Err(e) => problems.push(error(entry.line, entry.key_columns.clone(), e)),
+            }
+        }
+    }
+    let version = version.unwrap_or(DslVersion::V1);
+    for entry in parts.values() {
+        match version.parse(entry.value) {
+            Ok(groups) if groups.0.iter().all(|g| g.notes.iter().all(|n| *n == Note::Rest)) => {
+                problems.push(Diagnostic {
+                    line: entry.line,
+                    columns: entry.value_columns.clone(),
+                    warning: true,
+                    message: "the pattern has no hits".to_string(),
+                })
+            }
+            Ok(_) => {}
+            Err(PolyError::Pattern { position, .. }) => problems.push(error(
+                entry.line,
+                entry.value_columns.start + position..entry.value_columns.end,
+                format!("the pattern is malformed here: '{}'", &entry.value[position..]),
+            )),
+            Err(e) => problems.push(error(entry.line, entry.value_columns.clone(), e.to_string())),
+        }
+    }
+    problems.sort_by_key(|p| (p.line, p.columns.start));
+    problems
+}
+
+#[cfg(test)]
+use crate::dsl::dsl::groups;
+#[cfg(test)]
+use crate::dsl::expect::Comparison;
+
+#[test]
+fn test_parse_pattern_file() {
+    let file = PatternFile::from_str("# 3 against 4\nversion: 1\n\nkick: 8x--x--\nsnare: 4-x\n").unwrap();
+    assert_eq!(file.version, DslVersion::V1);
+    assert_eq!(file.patterns[&DrumPart::KickDrum], "8x--x--");
+    assert_eq!(file.groups[&DrumPart::SnareDrum], groups("4-x").unwrap());
+    assert_eq!(PatternFile::from_str("kick: 4x").unwrap().version, DslVersion::V1);
+    let expecting = PatternFile::from_str("kick: 8x--x--\nsnare: 4-x\nexpect converge\n\nexpect bars = 3\n").unwrap();
+    assert_eq!(expecting.expectations, vec![(3, Expectation::Converge), (5, Expectation::Bars(Comparison::Equal, 3))]);
+    let tagged = PatternFile::from_str("tags: [afrocuban, 7-8, Intermediate]\nkick: 8x--x--\n").unwrap();
+    assert_eq!(tagged.tags, vec!["afrocuban", "7-8", "Intermediate"]);
+    assert!(tagged.has_tags(&["intermediate".to_string(), "7-8".to_string()]));
+    assert!(!tagged.has_tags(&["afro".to_string()]));
+    assert!(file.tags.is_empty() && file.has_tags(&[]));
+}
+
+#[test]
+fn test_parse_tags() {
+    assert_eq!(parse_tags(" [a, b-c ,d] "), Ok(vec!["a".to_string(), "b-c".to_string(), "d".to_string()]));
+    assert_eq!(parse_tags("a,b"), Ok(vec!["a".to_string(), "b".to_string()]));
+    assert_eq!(parse_tags("[]"), Ok(vec![]));
+    assert!(parse_tags("[a,,b]").is_err());
+    assert!(parse_tags("[a b]").is_err());
+    assert!(parse_tags("[a, [b]]").is_err());
+}
+
+#[test]
+fn test_pattern_file_errors() {
+    let line = |s: &str| match PatternFile::from_str(s) {
+        Err(PolyError::PatternFile { line, .. }) => Some(line),
+        _ => None,
+    };
+    assert_eq!(line("version: 2\nkick: 4x"), Some(1));
+    assert_eq!(line("kick: 4x\n\nsnare: 4-x(\nversion: 1"), Some(3));
+    assert_eq!(line("kick: 4x\ncowbell: 4x"), Some(2));
+    assert_eq!(line("kick: 4x\nkick: 8x"), Some(2));
+    assert_eq!(line("kick 4x"), Some(1));
+    assert_eq!(line("kick: 4x\nexpect bars"), Some(2));
+    assert_eq!(line("expected: 4x"), Some(1));
+    assert_eq!(line("tags: [a]\nkick: 4x\ntags: [b]"), Some(3));
+    assert_eq!(line("tags: [a b]"), Some(1));
+}
+
+#[test]
+fn test_migrate() {
+    assert_eq!(
+        migrate("# 3 against 4\n\nkick: 8x--x--\n  snare:4-x\n"),
+        Ok("# 3 against 4\n\nversion: 1\nkick: 8x--x--\nsnare: 4-x\n".to_string())
+    );
+    assert_eq!(migrate("version: 1\nkick: 4x"), Ok("version: 1\nkick: 4x\n".to_string()));
+    assert!(migrate("version: 2\nkick: 4x").is_err());
+    assert_eq!(migrate("kick: 4x\nexpect bars = 1"), Ok("version: 1\nkick: 4x\nexpect bars = 1\n".to_string()));
+    assert_eq!(migrate("tags: [a]\nkick: 4x"), Ok("version: 1\ntags: [a]\nkick: 4x\n".to_string()));
+    // A version declared after the patterns moves to the top, and the result reads back the same.
+    let migrated = migrate("kick: 4x\nversion: 1\n").unwrap();
+    assert_eq!(migrated, "version: 1\nkick: 4x\n");
+    assert_eq!(PatternFile::from_str(&migrated), PatternFile::from_str("kick: 4x\nversion: 1\n"));
+    assert_eq!(migrate(&migrated), Ok(migrated));
+}
+
+#[test]
+fn test_diagnostics() {
+    assert_eq!(diagnostics("# groove\nversion: 1\nkick: 8x-- (3,16xg) E(3,8)\n"), vec![]);
+    let text = "version: 1\nkick 4x\nkick: 8x-(x\nkick: 4x\ncowbell: 4x\nsnare: 4--\nversion: 7\n";
+    let problems: Vec<(usize, Range<usize>, bool)> =
+        diagnostics(text).into_iter().map(|d| (d.line, d.columns, d.warning)).collect();
+    assert_eq!(
+        problems,
+        vec![(1, 0..7, false), (2, 9..11, false), (3, 0..4, false), (4, 0..7, false), (5, 7..10, true), (6, 0..7, false)]
+    );
+    assert_eq!(diagnostics("version: 7\n")[0].message, "DSL version 7 is not supported");
+    assert_eq!(diagnostics("kick: 4x\nexpect converge\n"), vec![]);
+    assert_eq!(diagnostics("tags: [a, b]\nkick: 4x\n"), vec![]);
+    assert_eq!(diagnostics("tags: [a,]\ntags: [b]\n").iter().map(|d| d.columns.clone()).collect::<Vec<_>>(), [6..10, 0..4]);
+    assert_eq!(diagnostics("kick: 4x\n  expect hits(kick)\n")[0].columns, 2..19);
+}
@@ -0,0 +1,142 @@
+use crate::dsl::dsl::{Dynamic, Group, Groups, KnownLength, Length, ModdedLength, Note, DEFAULT_DYNAMIC};
+
+use Note::*;
+
+/// Pattern as a series of equally long steps, each one either silent or played with a velocity.
+/// That's how most step sequencers and pattern generators see a drum part.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Grid {
+    /// Length of a single step.
+    pub resolution: Length,
+    /// MIDI velocity of every step, `None` for silent ones.
+    pub steps: Vec<Option<u8>>,
+}
+
+/// Exact length in 384ths of a whole note, so triplets of every supported length are whole numbers too.
+pub(crate) fn to_384th(length: Length) -> u32 {
+    let modded = |ml: ModdedLength| match ml {
+        ModdedLength::Plain(bl) => bl.to_128th() * 3,
+        ModdedLength::Dotted(bl) => bl.to_128th() * 3 * 3 / 2,
+    };
+    match length {
+        Length::Simple(ml) => modded(ml),
+        Length::Tied(ml1, ml2) => modded(ml1) + modded(ml2),
+        Length::Triplet(ml) => modded(ml) * 2 / 3,
+    }
+}
+
+impl Groups {
+    /// Lays the pattern out on a grid with steps of `resolution`. Fails if a note doesn't start right on a step,
+    /// a note that lasts longer than a step is followed by silent steps.
+    pub fn to_grid(&self, resolution: Length) -> Result<Grid, String> {
+        let step = to_384th(resolution);
+        let mut steps = Vec::new();
+        let mut time: u32 = 0;
+        for group in &self.0 {
+            let note_length = to_384th(group.length);
+            let dynamic = group.dynamic.unwrap_or(DEFAULT_DYNAMIC);
+            for note in &group.notes {
+                if !time.is_multiple_of(step) || !note_length.is_multiple_of(step) {
+                    return Err(format!(
+                        "Notes don't fall on the steps of {:?}, try a shorter resolution",
+                        resolution
+                    ));
+                }
+                steps.push(note.velocity(dynamic));
+                steps.extend((1..note_length / step).map(|_| None));
+                time += note_length;
+            }
+        }
+        Ok(Grid { resolution, steps })
+    }
+}
+
+/// Dynamics in the order they're tried when turning a velocity back into a note: the default one goes first,
+/// so the patterns are marked up as little as possible.
+static DYNAMICS: [Dynamic; 6] = [
+    DEFAULT_DYNAMIC,
+    Dynamic::MezzoForte,
+    Dynamic::Fortissimo,
+    Dynamic::MezzoPiano,
+    Dynamic::Piano,
+    Dynamic::Pianissimo,
+];
+
+/// A note and a dynamic that give exactly this velocity, or the closest regular hit if there are none.
+fn note_for_velocity(velocity: u8) -> (Note, Dynamic) {
+    DYNAMICS
+        .iter()
+        .flat_map(|d| [(Hit, *d), (Accent, *d), (Ghost, *d)])
+        .find(|(note, dynamic)| note.velocity(*dynamic) == Some(velocity))
+        .unwrap_or_else(|| {
+            let closest = DYNAMICS
+                .iter()
+                .min_by_key(|d| d.velocity().abs_diff(velocity))
+                .unwrap_or(&DEFAULT_DYNAMIC);
+            (Hit, *closest)
+        })
+}
+
+impl Grid {
+    /// Turns the grid back into note groups, every step becomes a note of `resolution` length.
+    /// Velocities the DSL can't express are rounded to the closest dynamic.
+    pub fn to_groups(&self) -> Groups {
+        let mut groups: Vec<Group<Note, ()>> = Vec::new();
+        for step in &self.steps {
+            let (note, dynamic) = match step {
+                None => (Rest, None),
+                Some(velocity) => {
+                    let (note, dynamic) = note_for_velocity(*velocity);
+                    (note, Some(dynamic).filter(|d| *d != DEFAULT_DYNAMIC))
+                }
+            };
+            match groups.last_mut() {
+                // Rests don't depend on the dynamic, so they go to the current group.
+                Some(group) if note == Rest || group.dynamic == dynamic => group.notes.push(note),
+                _ => groups.push(Group {
+                    dynamic,
+                    notes: vec![note],
+                    length: self.resolution,
+                    times: (),
+                }),
+            }
+        }
+        Groups(groups)
+    }
+}
+
+#[cfg(test)]
+use std::str::FromStr;
+#[cfg(test)]
+use crate::dsl::dsl::groups;
+
+#[test]
+fn test_to_grid() {
+    let sixteenth = Length::from_str("16").unwrap();
+    let pattern = groups("16xX-g8x-").unwrap();
+    assert_eq!(
+        pattern.to_grid(sixteenth),
+        Ok(Grid {
+            resolution: sixteenth,
+            steps: vec![Some(100), Some(127), None, Some(40), Some(100), None, None, None],
+        })
+    );
+    assert!(pattern.to_grid(Length::from_str("8").unwrap()).is_err());
+    assert!(groups("8tx-x").unwrap().to_grid(sixteenth).is_err());
+    assert_eq!(
+        groups("8tx-x").unwrap().to_grid(Length::from_str("16t").unwrap()).map(|g| g.steps.len()),
+        Ok(6)
+    );
+}
+
+#[test]
+fn test_grid_round_trip() {
+    let sixteenth = Length::from_str("16").unwrap();
+    for pattern in ["16xX-g", "16x-p16xXg-", "16x-xx-x(3,mf16x-)"] {
+        let groups = groups(pattern).unwrap();
+        assert_eq!(groups.to_grid(sixteenth).unwrap().to_groups(), groups);
+    }
+    // Velocities the DSL can't express snap to the closest dynamic.
+    let grid = Grid { resolution: sixteenth, steps: vec![Some(90), None, Some(30)] };
+    assert_eq!(grid.to_groups(), groups("16x-pp16x").unwrap());
+}
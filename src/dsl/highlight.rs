@@ -0,0 +1,171 @@
+use std::str::FromStr;
+
+use crate::dsl::dsl::{BasicLength, KnownLength, Note, DYNAMICS, NAMED_LENGTHS, NOTES};
+use crate::json::json_string;
+use crate::midi::core::DrumPart;
+
+/// Editors syntax definitions can be written for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Editor {
+    Vim,
+    VsCode,
+    Sublime,
+}
+
+impl FromStr for Editor {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "vim" => Ok(Editor::Vim),
+            "vscode" => Ok(Editor::VsCode),
+            "sublime" => Ok(Editor::Sublime),
+            _ => Err(format!("Expected 'vim', 'vscode' or 'sublime', got '{}'", s)),
+        }
+    }
+}
+
+/// Extension of pattern files.
+static EXTENSION: &str = "poly";
+
+/// A kind of token in a pattern file, matched with a regular expression that reads the same in TextMate grammars,
+/// Sublime Text and Vim's very magic mode.
+struct Rule {
+    /// Vim syntax group, without the `poly` prefix.
+    group: &'static str,
+    /// Vim highlight group the syntax group is linked to.
+    vim: &'static str,
+    /// TextMate scope, without the `.poly` suffix.
+    scope: &'static str,
+    regex: String,
+}
+
+fn escape(s: &str) -> String {
+    s.chars()
+        .map(|c| match c {
+            '.' | '+' | '(' | ')' | '-' | '|' | '?' | '*' => format!("\\{}", c),
+            c => c.to_string(),
+        })
+        .collect()
+}
+
+fn one_of<I: IntoIterator<Item = String>>(alternatives: I) -> String {
+    format!("({})", alternatives.into_iter().map(|a| escape(&a)).collect::<Vec<String>>().join("|"))
+}
+
+/// Token rules built from the vocabulary of the parser, so they follow it when it changes. When rules could match
+/// at the same place, the earlier one wins.
+fn rules() -> Vec<Rule> {
+    // Numbers with more digits go first, so `16` isn't taken for `1`.
+    let mut numbers: Vec<String> = BasicLength::ALL.iter().map(|l| (128 / l.to_128th()).to_string()).collect();
+    numbers.sort_by_key(|n| std::cmp::Reverse(n.len()));
+    let lengths = one_of(NAMED_LENGTHS.iter().map(|(name, _)| name.to_string()).chain(numbers));
+    let note = |note| NOTES.iter().find(|(_, n)| *n == note).map(|(c, _)| escape(&c.to_string())).unwrap();
+    let rule = |group, vim, scope, regex: String| Rule { group, vim, scope, regex };
+    vec![
+        rule("Comment", "Comment", "comment.line.number-sign", r"^\s*#.*$".to_string()),
+        rule("Version", "PreProc", "keyword.other.version", r"^\s*version\s*:\s*\d+".to_string()),
+        rule(
+            "Part",
+            "Identifier",
+            "entity.name.tag.part",
+            format!(r"^\s*{}\s*:", one_of(DrumPart::ALL.map(|p| p.name().to_string()))),
+        ),
+        rule("Repeat", "Number", "constant.numeric.repeat", r"\d+,".to_string()),
+        rule("Euclidean", "Function", "support.function.euclidean", r"E\(\d+,\d+(,\d+)?\)".to_string()),
+        rule("Length", "Type", "storage.type.length", format!(r"{}\.?t?\+?", lengths)),
+        rule("Dynamic", "Special", "keyword.other.dynamic", one_of(DYNAMICS.map(|(name, _)| name.to_string()))),
+        rule("Accent", "Statement", "keyword.control.accent", note(Note::Accent)),
+        rule("Hit", "Constant", "constant.character.hit", note(Note::Hit)),
+        rule("Ghost", "Comment", "comment.block.ghost", note(Note::Ghost)),
+        rule("Rest", "Delimiter", "punctuation.separator.rest", note(Note::Rest)),
+    ]
+}
+
+fn vim(rules: &[Rule]) -> String {
+    let mut out = format!(
+        "\" Polyrhythmix pattern files, generated by `poly highlight --editor vim`.\n\
+         \" Save as ~/.vim/syntax/{0}.vim and add to your vimrc:\n\
+         \" autocmd BufRead,BufNewFile *.{0} set filetype={0}\n\
+         if exists(\"b:current_syntax\")\n  finish\nendif\n\n",
+        EXTENSION
+    );
+    // Vim prefers the match defined last when several start at the same place.
+    for rule in rules.iter().rev() {
+        out.push_str(&format!("syntax match poly{} \"\\v{}\"\n", rule.group, rule.regex));
+    }
+    out.push('\n');
+    for rule in rules {
+        out.push_str(&format!("highlight default link poly{} {}\n", rule.group, rule.vim));
+    }
+    out.push_str(&format!("\nlet b:current_syntax = \"{}\"\n", EXTENSION));
+    out
+}
+
+fn vscode(rules: &[Rule]) -> String {
+    let patterns: Vec<String> = rules
+        .iter()
+        .map(|rule| {
+            format!(
+                "    {{ \"name\": {}, \"match\": {} }}",
+                json_string(&format!("{}.{}", rule.scope, EXTENSION)),
+                json_string(&rule.regex)
+            )
+        })
+        .collect();
+    format!(
+        "{{\n  \"name\": \"Polyrhythmix\",\n  \"scopeName\": \"source.{0}\",\n  \"fileTypes\": [\"{0}\"],\n  \
+         \"patterns\": [\n{1}\n  ]\n}}\n",
+        EXTENSION,
+        patterns.join(",\n")
+    )
+}
+
+fn sublime(rules: &[Rule]) -> String {
+    let mut out = format!(
+        "%YAML 1.2\n---\nname: Polyrhythmix\nfile_extensions: [{0}]\nscope: source.{0}\ncontexts:\n  main:\n",
+        EXTENSION
+    );
+    for rule in rules {
+        out.push_str(&format!(
+            "    - match: '{}'\n      scope: {}.{}\n",
+            rule.regex.replace('\'', "''"),
+            rule.scope,
+            EXTENSION
+        ));
+    }
+    out
+}
+
+/// Syntax definition of pattern files for the editor: a Vim syntax file, a TextMate grammar for VS Code or a
+/// Sublime Text syntax.
+pub fn highlight(editor: Editor) -> String {
+    let rules = rules();
+    match editor {
+        Editor::Vim => vim(&rules),
+        Editor::VsCode => vscode(&rules),
+        Editor::Sublime => sublime(&rules),
+    }
+}
+
+#[test]
+fn test_rules() {
+    let rules = rules();
+    let regex = |group| rules.iter().find(|r| r.group == group).unwrap().regex.clone();
+    assert_eq!(regex("Dynamic"), "(pp|p|mp|mf|ff|f)");
+    assert!(regex("Part").contains(r"open\-hi\-hat|"));
+    assert!(regex("Length").starts_with("(hemidemisemiquaver|"));
+    assert!(regex("Length").contains("|16|32|64|1|2|4|8)"));
+    assert_eq!(regex("Rest"), r"\-");
+}
+
+#[test]
+fn test_highlight() {
+    let vim = highlight(Editor::Vim);
+    assert!(vim.contains("syntax match polyRest \"\\v\\-\"\nsyntax match polyGhost \"\\vg\"\n"));
+    assert!(vim.contains("highlight default link polyComment Comment\n"));
+    let vscode = highlight(Editor::VsCode);
+    assert!(vscode.contains("{ \"name\": \"constant.numeric.repeat.poly\", \"match\": \"\\\\d+,\" },\n"));
+    let sublime = highlight(Editor::Sublime);
+    assert!(sublime.contains("    - match: '^\\s*#.*$'\n      scope: comment.line.number-sign.poly\n"));
+}
@@ -1 +1,15 @@
-pub mod dsl;
\ No newline at end of file
+#[allow(clippy::module_inception)]
+pub mod dsl;
+#[cfg(feature = "std")]
+pub mod expect;
+#[cfg(feature = "std")]
+pub mod file;
+#[cfg(feature = "std")]
+pub mod grid;
+#[cfg(feature = "std")]
+pub mod highlight;
+#[cfg(feature = "std")]
+pub mod session;
+#[cfg(feature = "std")]
+pub mod variation;
+pub mod version;
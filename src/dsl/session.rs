@@ -0,0 +1,188 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::str::FromStr;
+
+use crate::dsl::version::DslVersion;
+use crate::error::PolyError;
+use crate::midi::core::DrumPart;
+
+/// The patterns of every part at some point of a session.
+pub type State = BTreeMap<DrumPart, String>;
+
+/// An interactive composing session: every state the patterns went through, so that changes can be undone and
+/// redone, and the session picked up later from a file. Changing the patterns after undoing something drops the
+/// states that could have been redone, like in any editor.
+///
+/// Saved, a session lists its states in order, each one starting with a `state:` line:
+///
+/// ```text
+/// version: 1
+/// current: 1
+/// state:
+/// kick: 8x--x--
+/// state:
+/// kick: 8x--x--
+/// snare: 4-x
+/// ```
+///
+/// `current` counts the states from 0, and the first state is always there, even if it has no patterns.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Session {
+    pub version: DslVersion,
+    states: Vec<State>,
+    current: usize,
+}
+
+impl Default for Session {
+    fn default() -> Self {
+        Session { version: DslVersion::LATEST, states: vec![State::new()], current: 0 }
+    }
+}
+
+impl Session {
+    /// The patterns as they are now.
+    pub fn patterns(&self) -> &State {
+        &self.states[self.current]
+    }
+
+    /// Sets the pattern of a part, or takes the part out without one. Patterns that can't be read are refused and
+    /// leave the session as it was.
+    pub fn set(&mut self, part: DrumPart, pattern: Option<&str>) -> Result<(), PolyError> {
+        let mut state = self.patterns().clone();
+        match pattern {
+            Some(pattern) => {
+                self.version.parse(pattern)?;
+                state.insert(part, pattern.to_string());
+            }
+            None => {
+                state.remove(&part);
+            }
+        }
+        if state != *self.patterns() {
+            self.states.truncate(self.current + 1);
+            self.states.push(state);
+            self.current += 1;
+        }
+        Ok(())
+    }
+
+    /// Steps back to the previous state, returns false if there's none.
+    pub fn undo(&mut self) -> bool {
+        let undone = self.current > 0;
+        self.current = self.current.saturating_sub(1);
+        undone
+    }
+
+    /// Steps forward to the state last undone, returns false if there's none.
+    pub fn redo(&mut self) -> bool {
+        let redone = self.current + 1 < self.states.len();
+        if redone {
+            self.current += 1;
+        }
+        redone
+    }
+
+    /// The current state as a pattern file.
+    pub fn to_pattern_file(&self) -> String {
+        let mut file = format!("version: {}\n", self.version.number());
+        for (part, pattern) in self.patterns() {
+            file.push_str(&format!("{}: {}\n", part.name(), pattern));
+        }
+        file
+    }
+}
+
+impl fmt::Display for Session {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "version: {}", self.version.number())?;
+        writeln!(f, "current: {}", self.current)?;
+        for state in &self.states {
+            writeln!(f, "state:")?;
+            for (part, pattern) in state {
+                writeln!(f, "{}: {}", part.name(), pattern)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Session {
+    type Err = PolyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let error = |line: usize, message: String| PolyError::PatternFile { line: line + 1, message };
+        let mut version = DslVersion::V1;
+        let mut current = None;
+        let mut states: Vec<State> = Vec::new();
+        for (i, line) in s.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let Some((key, value)) = line.split_once(':') else {
+                return Err(error(i, "expected 'key: value'".to_string()));
+            };
+            let (key, value) = (key.trim(), value.trim());
+            match (key, states.last_mut()) {
+                ("state", _) => states.push(State::new()),
+                ("version", None) => {
+                    let number = u32::from_str(value).map_err(|e| error(i, e.to_string()))?;
+                    version = DslVersion::try_from(number).map_err(|e| error(i, e.to_string()))?;
+                }
+                ("current", None) => current = Some(usize::from_str(value).map_err(|e| error(i, e.to_string()))?),
+                (_, None) => return Err(error(i, format!("expected 'version', 'current' or 'state', got '{}'", key))),
+                (_, Some(state)) => {
+                    let part = DrumPart::from_str(key).map_err(|e| error(i, e))?;
+                    version.parse(value).map_err(|e| error(i, e.to_string()))?;
+                    if state.insert(part, value.to_string()).is_some() {
+                        return Err(error(i, format!("{} is declared twice", part.name())));
+                    }
+                }
+            }
+        }
+        if states.is_empty() {
+            states.push(State::new());
+        }
+        let current = current.unwrap_or(states.len() - 1);
+        if current >= states.len() {
+            return Err(error(0, format!("the current state is {}, but there are only {}", current, states.len())));
+        }
+        Ok(Session { version, states, current })
+    }
+}
+
+#[test]
+fn test_session_undo_redo() {
+    let mut session = Session::default();
+    session.set(DrumPart::KickDrum, Some("8x--x--")).unwrap();
+    session.set(DrumPart::SnareDrum, Some("4-x")).unwrap();
+    assert!(session.set(DrumPart::HiHat, Some("8x(")).is_err());
+    assert_eq!(session.patterns().len(), 2);
+    assert!(session.undo());
+    assert_eq!(session.patterns().keys().collect::<Vec<_>>(), vec![&DrumPart::KickDrum]);
+    assert!(session.undo());
+    assert!(!session.undo());
+    assert!(session.redo());
+    // Changing the patterns drops the snare drum that could have been redone.
+    session.set(DrumPart::HiHat, Some("8x")).unwrap();
+    assert!(!session.redo());
+    session.set(DrumPart::KickDrum, None).unwrap();
+    assert_eq!(session.to_pattern_file(), "version: 1\nhi-hat: 8x\n");
+}
+
+#[test]
+fn test_session_file() {
+    let mut session = Session::default();
+    session.set(DrumPart::KickDrum, Some("8x--x--")).unwrap();
+    session.set(DrumPart::SnareDrum, Some("4-x")).unwrap();
+    session.undo();
+    let saved = session.to_string();
+    assert_eq!(saved, "version: 1\ncurrent: 1\nstate:\nstate:\nkick: 8x--x--\nstate:\nkick: 8x--x--\nsnare: 4-x\n");
+    let mut restored = Session::from_str(&saved).unwrap();
+    assert_eq!(restored, session);
+    assert!(restored.redo());
+    assert_eq!(restored.patterns().len(), 2);
+    assert!(Session::from_str("current: 3\nstate:\n").is_err());
+    assert!(Session::from_str("kick: 8x\n").is_err());
+    assert!(Session::from_str("state:\nkick: 8x(\n").is_err());
+}
@@ -0,0 +1,170 @@
+use std::collections::BTreeMap;
+
+use crate::dsl::dsl::{Group, Groups, Note};
+use crate::random::Rng;
+
+use Note::*;
+
+/// Tries per variation asked for before `variations` settles for fewer.
+static VARIATION_TRIES: usize = 20;
+
+/// Settings for `vary`: how much of the pattern gets mutated and the seed that drives the mutations.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Variation {
+    /// Chance for every single note of the pattern to get mutated, from 0 to 1.
+    pub amount: f64,
+    pub seed: u64,
+}
+
+/// Applies small rule-based mutations to the pattern, so repetitions of it don't sound copy-pasted:
+/// * a hit or a ghost note may be dropped,
+/// * a hit may be moved to a neighbouring rest,
+/// * an accent may be moved to a neighbouring hit,
+/// * a rest may be filled with a ghost note.
+///
+/// Group lengths are preserved, so the varied pattern converges the same way as the original.
+pub fn vary(groups: &Groups, amount: f64, rng: &mut Rng) -> Groups {
+    groups
+        .0
+        .iter()
+        .map(|group| Group {
+            dynamic: group.dynamic,
+            notes: vary_notes(&group.notes, amount, rng),
+            length: group.length,
+            times: (),
+        })
+        .collect()
+}
+
+fn vary_notes(notes: &[Note], amount: f64, rng: &mut Rng) -> Vec<Note> {
+    let mut out = notes.to_vec();
+    // A note moved onto the next one has had its mutation already.
+    let mut moved = None;
+    for i in 0..out.len() {
+        if moved == Some(i) || !rng.chance(amount) {
+            continue;
+        }
+        let neighbours = |out: &[Note], note: Note| -> Vec<usize> {
+            [i.checked_sub(1), Some(i + 1)]
+                .into_iter()
+                .flatten()
+                .filter(|&j| out.get(j) == Some(&note))
+                .collect()
+        };
+        match out[i] {
+            Hit => {
+                let rests = neighbours(&out, Rest);
+                if rests.is_empty() || rng.chance(0.5) {
+                    out[i] = Rest;
+                } else {
+                    let j = rests[rng.below(rests.len() as u64) as usize];
+                    out.swap(i, j);
+                    moved = Some(j);
+                }
+            }
+            Accent => {
+                let hits = neighbours(&out, Hit);
+                if !hits.is_empty() {
+                    let j = hits[rng.below(hits.len() as u64) as usize];
+                    out.swap(i, j);
+                    moved = Some(j);
+                }
+            }
+            Ghost => out[i] = Rest,
+            Rest => out[i] = Ghost,
+        }
+    }
+    out
+}
+
+/// Number of notes that differ between a pattern and its variation.
+pub fn changes(original: &Groups, varied: &Groups) -> usize {
+    let notes = |groups: &Groups| groups.0.iter().flat_map(|g| g.notes.clone()).collect::<Vec<Note>>();
+    notes(original).iter().zip(notes(varied)).filter(|(a, b)| **a != *b).count()
+}
+
+/// Up to `count` variations of a set of patterns, e.g. the drum parts of a groove, each one different from the
+/// patterns and from the other variations. Gives up on finding more after a number of tries, which matters for
+/// short patterns or small amounts.
+pub fn variations<K: Ord + Clone>(
+    patterns: &BTreeMap<K, Groups>,
+    count: usize,
+    amount: f64,
+    rng: &mut Rng,
+) -> Vec<BTreeMap<K, Groups>> {
+    let mut found: Vec<BTreeMap<K, Groups>> = Vec::new();
+    for _ in 0..count * VARIATION_TRIES {
+        if found.len() == count {
+            break;
+        }
+        let varied = patterns.iter().map(|(key, groups)| (key.clone(), vary(groups, amount, rng))).collect();
+        if varied != *patterns && !found.contains(&varied) {
+            found.push(varied);
+        }
+    }
+    found
+}
+
+#[cfg(test)]
+use crate::dsl::dsl::{groups, KnownLength};
+
+#[test]
+fn test_vary_keeps_length() {
+    let pattern = groups("16x-x-x--x8x-x").unwrap();
+    let mut rng = Rng::new(3);
+    for _ in 0..20 {
+        let varied = vary(&pattern, 0.3, &mut rng);
+        assert_eq!(varied.to_128th(), pattern.to_128th());
+        assert_eq!(varied.0.len(), pattern.0.len());
+    }
+}
+
+#[test]
+fn test_vary_amount_bounds() {
+    let pattern = groups("16x-x-x--x").unwrap();
+    let mut rng = Rng::new(3);
+    assert_eq!(vary(&pattern, 0.0, &mut rng), pattern);
+    assert_ne!(vary(&pattern, 1.0, &mut rng), pattern);
+}
+
+#[test]
+fn test_vary_moves_accents() {
+    let pattern = groups("16Xx").unwrap();
+    let varied = vary(&pattern, 1.0, &mut Rng::new(5));
+    assert_eq!(varied.0[0].notes.iter().filter(|n| **n == Accent).count(), 1);
+}
+
+#[test]
+fn test_vary_mutates_a_note_once() {
+    // The hit is either dropped, leaving a rest to fill, or moved onto the rest, and then left alone.
+    let pattern = groups("16x-").unwrap();
+    let outcomes = [groups("16-g").unwrap(), groups("16-x").unwrap()];
+    for seed in 0..50 {
+        assert!(outcomes.contains(&vary(&pattern, 1.0, &mut Rng::new(seed))));
+    }
+}
+
+#[test]
+fn test_vary_is_reproducible() {
+    let pattern = groups("16x-x-x--x8x-x").unwrap();
+    assert_eq!(
+        vary(&pattern, 0.5, &mut Rng::new(11)),
+        vary(&pattern, 0.5, &mut Rng::new(11))
+    );
+}
+
+#[test]
+fn test_variations() {
+    let patterns = BTreeMap::from([("kick", groups("8x--x--").unwrap()), ("snare", groups("4-x").unwrap())]);
+    let found = variations(&patterns, 8, 0.2, &mut Rng::new(7));
+    assert_eq!(found.len(), 8);
+    for (i, varied) in found.iter().enumerate() {
+        assert_ne!(*varied, patterns);
+        assert!(!found[..i].contains(varied));
+        assert_eq!(varied["snare"].to_128th(), patterns["snare"].to_128th());
+    }
+    assert_eq!(changes(&groups("16x-xX").unwrap(), &groups("16xgx-").unwrap()), 2);
+    // A single rest has only one variation.
+    let rest = BTreeMap::from([("kick", groups("4-").unwrap())]);
+    assert_eq!(variations(&rest, 3, 1.0, &mut Rng::new(7)), vec![BTreeMap::from([("kick", groups("4g").unwrap())])]);
+}
@@ -0,0 +1,59 @@
+use alloc::string::{String, ToString};
+
+use crate::dsl::dsl::{groups, Groups};
+use crate::error::PolyError;
+
+/// Version of the pattern grammar. Saved patterns declare the version they're written in, so they keep being read
+/// the same way when the grammar changes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[non_exhaustive]
+pub enum DslVersion {
+    /// Notes, lengths, dynamics, repeated and nested groups, Euclidean rhythms.
+    V1,
+}
+
+impl DslVersion {
+    /// The version patterns given on the command line are written in.
+    pub const LATEST: DslVersion = DslVersion::V1;
+
+    pub fn number(self) -> u32 {
+        match self {
+            DslVersion::V1 => 1,
+        }
+    }
+
+    /// Parses a pattern with the grammar of this version.
+    pub fn parse(self, pattern: &str) -> Result<Groups, PolyError> {
+        match self {
+            DslVersion::V1 => groups(pattern),
+        }
+    }
+
+    /// Rewrites a pattern written in this version so it's read the same way by the latest one. Fails with the
+    /// reason if the pattern uses something the latest version can't express.
+    pub fn migrate(self, pattern: &str) -> Result<String, String> {
+        match self {
+            DslVersion::V1 => Ok(pattern.to_string()),
+        }
+    }
+}
+
+impl TryFrom<u32> for DslVersion {
+    type Error = PolyError;
+
+    fn try_from(number: u32) -> Result<Self, Self::Error> {
+        match number {
+            1 => Ok(DslVersion::V1),
+            _ => Err(PolyError::DslVersion(number)),
+        }
+    }
+}
+
+#[test]
+fn test_dsl_version() {
+    assert_eq!(DslVersion::try_from(1), Ok(DslVersion::V1));
+    assert_eq!(DslVersion::try_from(2), Err(PolyError::DslVersion(2)));
+    assert_eq!(DslVersion::LATEST.number(), 1);
+    assert_eq!(DslVersion::V1.parse("8x-"), groups("8x-"));
+    assert_eq!(DslVersion::V1.migrate("8x-"), Ok("8x-".to_string()));
+}
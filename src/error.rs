@@ -0,0 +1,64 @@
+use core::fmt;
+
+use alloc::string::String;
+
+/// Everything that can go wrong on the way from the patterns to a MIDI file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum PolyError {
+    /// The pattern doesn't follow the DSL. `position` is the byte offset in `pattern` where it stops making sense.
+    Pattern { pattern: String, position: usize },
+    TimeSignature(String),
+    /// Tempo in BPM, it has to be positive.
+    Tempo(u16),
+    /// At least one tempo is required to render anything.
+    NoTempo,
+    /// No drum parts were given, or none of them has a single note to play.
+    NoNotes,
+    /// The patterns don't line up within the limit of bars.
+    DoesNotConverge,
+    /// Patterns are declared to be written in a version of the DSL this release doesn't know.
+    DslVersion(u32),
+    /// A pattern file can't be read, `line` counts from 1.
+    PatternFile { line: usize, message: String },
+    /// A shared groove can't be read.
+    Share(String),
+    /// More parts are doubled by melodic instruments than there are MIDI channels for them.
+    Doublings(usize),
+    /// A pattern would be repeated more times than a grid of events can hold.
+    TooManyRepeats(u128),
+    /// A bar of an arrangement's section is swapped for another one, but the section isn't played that long.
+    NoBar(u32),
+    /// The bass is added, but there's no kick drum for it to follow.
+    NoKick,
+}
+
+impl fmt::Display for PolyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PolyError::Pattern { pattern, position } => write!(
+                f,
+                "Pattern '{}' is malformed at position {}: '{}'",
+                pattern,
+                position,
+                pattern.get(*position..).unwrap_or_default()
+            ),
+            PolyError::TimeSignature(message) => write!(f, "{}", message),
+            PolyError::Tempo(tempo) => write!(f, "Tempo of {} BPM is not supported, it has to be positive", tempo),
+            PolyError::NoTempo => write!(f, "At least one tempo is required"),
+            PolyError::NoNotes => write!(f, "Result has no midi notes"),
+            PolyError::DoesNotConverge => write!(f, "Does not converge"),
+            PolyError::DslVersion(version) => write!(f, "DSL version {} is not supported", version),
+            PolyError::PatternFile { line, message } => write!(f, "Line {}: {}", line, message),
+            PolyError::Share(message) => write!(f, "Can't read the shared groove: {}", message),
+            PolyError::Doublings(count) => write!(f, "{} doublings are too many, there are MIDI channels for 12", count),
+            PolyError::TooManyRepeats(times) => {
+                write!(f, "A pattern would be repeated {} times, more than {} are too many", times, u16::MAX)
+            }
+            PolyError::NoBar(bar) => write!(f, "Bar {} is swapped for another one, but the section ends before", bar),
+            PolyError::NoKick => write!(f, "The bass follows the kick drum, which has no notes"),
+        }
+    }
+}
+
+impl core::error::Error for PolyError {}
@@ -0,0 +1,175 @@
+use std::str::FromStr;
+
+use crate::dsl::dsl::{KnownLength, Length, Note, DEFAULT_DYNAMIC};
+use crate::dsl::grid::{to_384th, Grid};
+use crate::midi::core::{DrumPart, Event, EventType, Part, Tick, Velocity};
+use crate::midi::markers::Marker;
+use crate::midi::time::TimeSignature;
+use crate::midi::timeline::Timeline;
+
+/// Etudes with more placements than this are refused, their number grows fast with the steps.
+pub static MAX_PERMUTATIONS: u64 = 1024;
+
+/// Note lengths a step of the bar may have, in the order they're tried.
+static STEP_LENGTHS: [&str; 17] =
+    ["1", "2", "4", "8", "16", "32", "64", "1t", "2t", "4t", "8t", "16t", "32t", "1.", "2.", "4.", "8."];
+
+/// Every placement of a number of hits on the steps of a bar, a bar each, for practicing them one after the other.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Etude {
+    pub time_signature: TimeSignature,
+    /// One bar each, in the order they're played.
+    pub grids: Vec<Grid>,
+    /// Times every bar is played before moving on to the next one.
+    pub repeat: u32,
+}
+
+/// The note length dividing a bar of the time signature into `steps` steps, e.g. eighth triplets for 12 steps of
+/// 4/4 or dotted quarters for 4 steps of 12/8.
+pub fn step_length(time_signature: TimeSignature, steps: u32) -> Result<Length, String> {
+    let bar = time_signature.to_128th() * 3;
+    STEP_LENGTHS
+        .iter()
+        .filter_map(|s| Length::from_str(s).ok())
+        .find(|length| to_384th(*length) * steps == bar)
+        .ok_or_else(|| format!("{} steps don't divide a bar of {} into notes of one length", steps, time_signature))
+}
+
+/// Number of ways to pick `k` of `n`, saturating.
+fn binomial(n: u32, k: u32) -> u64 {
+    if k > n {
+        return 0;
+    }
+    (0..k.min(n - k)).fold(1u64, |acc, i| acc.saturating_mul((n - i) as u64) / (i as u64 + 1))
+}
+
+/// Whether the placement comes first among its rotations, hits before rests. Every placement that's a rotation of
+/// another one has exactly one of them coming first.
+fn is_first_rotation(steps: &[bool]) -> bool {
+    (1..steps.len()).all(|r| steps.iter().cycle().skip(r).take(steps.len()).le(steps.iter()))
+}
+
+/// Every placement of `hits` hits on `steps` steps, the ones with the earlier hits first. With `up_to_rotation`,
+/// only the first of the placements that are rotations of each other, which starts with a hit.
+pub fn placements(steps: u32, hits: u32, up_to_rotation: bool) -> Result<Vec<Vec<bool>>, String> {
+    if hits > steps {
+        return Err(format!("Can't place {} hits on {} steps", hits, steps));
+    }
+    let too_many = || format!("Too many placements of {} hits on {} steps, over {}", hits, steps, MAX_PERMUTATIONS);
+    // Rotations are told apart by going through all of the placements, but there are at most `steps` of each.
+    let limit = if up_to_rotation { MAX_PERMUTATIONS * steps as u64 } else { MAX_PERMUTATIONS };
+    if binomial(steps, hits) > limit {
+        return Err(too_many());
+    }
+    let mut placements = Vec::new();
+    // Positions of the hits, moved on like the digits of a counter.
+    let mut positions: Vec<u32> = (0..hits).collect();
+    loop {
+        let mut placement = vec![false; steps as usize];
+        positions.iter().for_each(|p| placement[*p as usize] = true);
+        if !up_to_rotation || is_first_rotation(&placement) {
+            placements.push(placement);
+        }
+        let Some(i) = (0..hits as usize).rev().find(|i| positions[*i] < steps - hits + *i as u32) else { break };
+        positions[i] += 1;
+        for j in i + 1..hits as usize {
+            positions[j] = positions[j - 1] + 1;
+        }
+    }
+    if placements.len() as u64 > MAX_PERMUTATIONS {
+        return Err(too_many());
+    }
+    Ok(placements)
+}
+
+impl Etude {
+    /// The etude of every placement of `hits` hits on `steps` steps of a bar.
+    pub fn new(
+        time_signature: TimeSignature,
+        steps: u32,
+        hits: u32,
+        up_to_rotation: bool,
+        repeat: u32,
+    ) -> Result<Etude, String> {
+        let resolution = step_length(time_signature, steps)?;
+        let velocity = Note::Hit.velocity(DEFAULT_DYNAMIC);
+        let grids = placements(steps, hits, up_to_rotation)?
+            .into_iter()
+            .map(|placement| Grid {
+                resolution,
+                steps: placement.into_iter().map(|hit| velocity.filter(|_| hit)).collect(),
+            })
+            .collect();
+        Ok(Etude { time_signature, grids, repeat })
+    }
+
+    /// The bars one after the other, played on the part. The steps are laid out in ticks right away, patterns of
+    /// triplets would be rounded to 128th notes on the way and take hundreds of bars to line up with the bar again.
+    pub fn to_timeline(&self, part: DrumPart) -> Timeline {
+        let bar = self.bar_length();
+        let mut events = Vec::new();
+        let bars = self.grids.iter().flat_map(|grid| (0..self.repeat).map(move |_| grid));
+        for (i, grid) in bars.enumerate() {
+            // Two 384th notes to a tick.
+            let step = Tick(to_384th(grid.resolution) as u128 / 2);
+            for (j, velocity) in grid.steps.iter().enumerate() {
+                let Some(velocity) = velocity else { continue };
+                let start = bar * i as u128 + step * j as u128;
+                events.push(Event::new(start, EventType::NoteOn(Part::Drum(part), Velocity(*velocity))));
+                events.push(Event::new(start + step, EventType::NoteOff(Part::Drum(part))));
+            }
+        }
+        let length = bar * (self.grids.len() as u128 * self.repeat as u128);
+        Timeline::new(self.time_signature, events, length)
+    }
+
+    fn bar_length(&self) -> Tick {
+        Timeline::new(self.time_signature, Vec::new(), Tick(0)).bar_length()
+    }
+
+    /// A marker at the start of every placement, numbered and drawn as steps, e.g. `3: x--x--x-`.
+    pub fn labels(&self) -> Vec<Marker> {
+        let bar = self.bar_length().0 as u32;
+        self.grids
+            .iter()
+            .enumerate()
+            .map(|(i, grid)| {
+                let steps: String = grid.steps.iter().map(|s| if s.is_some() { 'x' } else { '-' }).collect();
+                (i as u32 * bar * self.repeat, format!("{}: {}", i + 1, steps))
+            })
+            .collect()
+    }
+}
+
+#[test]
+fn test_placements() {
+    assert_eq!(binomial(8, 3), 56);
+    assert_eq!(binomial(3, 5), 0);
+    assert_eq!(placements(8, 3, false).unwrap().len(), 56);
+    assert_eq!(placements(4, 2, false).unwrap()[..2], [vec![true, true, false, false], vec![true, false, true, false]]);
+    // Necklaces of 3 hits on 8 steps.
+    let necklaces = placements(8, 3, true).unwrap();
+    assert_eq!(necklaces.len(), 7);
+    assert!(necklaces.iter().all(|p| p[0]));
+    assert_eq!(placements(4, 0, true).unwrap(), vec![vec![false; 4]]);
+    assert!(placements(3, 4, false).is_err());
+    assert!(placements(32, 16, false).is_err());
+}
+
+#[test]
+fn test_etude() {
+    let four_four = TimeSignature::from_str("4/4").unwrap();
+    assert_eq!(step_length(four_four, 12), Length::from_str("8t"));
+    assert_eq!(step_length(TimeSignature::from_str("12/8").unwrap(), 4), Length::from_str("4."));
+    assert_eq!(step_length(TimeSignature::from_str("6/8").unwrap(), 4), Length::from_str("8."));
+    assert!(step_length(four_four, 7).is_err());
+    let etude = Etude::new(four_four, 8, 3, true, 2).unwrap();
+    assert_eq!(etude.to_timeline(DrumPart::SnareDrum).bars(), 14);
+    assert_eq!(etude.labels()[1], (384, "2: xx-x----".to_string()));
+    assert_eq!(etude.grids[1].to_groups().to_string(), "8xx-x----");
+    // Triplets fill the bar exactly, and the etude is as long as it takes.
+    let etude = Etude::new(four_four, 12, 6, true, 1).unwrap();
+    let timeline = etude.to_timeline(DrumPart::SnareDrum);
+    assert_eq!(timeline.bars() as usize, etude.grids.len());
+    assert_eq!(timeline.events()[..4].iter().map(|e| e.tick.0).collect::<Vec<_>>(), [0, 16, 16, 32]);
+}
@@ -0,0 +1,185 @@
+use crate::dsl::dsl::{BasicLength, Dynamic, Length, ModdedLength, Note};
+use crate::export::{Instruments, NotatedNote};
+use crate::midi::core::DrumPart;
+use crate::midi::time::TimeSignature;
+
+static VERSION: &str = "2.24.0";
+
+/// Name of the drum in LilyPond's `\drummode`, matching the General MIDI key the part is written to.
+fn drum_name(part: DrumPart) -> &'static str {
+    match part {
+        DrumPart::KickDrum => "bd",
+        DrumPart::SnareDrum => "sn",
+        DrumPart::HiHat => "hhc",
+        DrumPart::CrashCymbal => "cymc",
+        DrumPart::OpenHiHat => "hho",
+        DrumPart::RideCymbal => "cymr",
+        DrumPart::Tom1 => "tommh",
+        DrumPart::Tom2 => "toml",
+        DrumPart::Tom3 => "tomfh",
+    }
+}
+
+fn instrument_name(part: DrumPart) -> &'static str {
+    match part {
+        DrumPart::KickDrum => "Kick",
+        DrumPart::SnareDrum => "Snare",
+        DrumPart::HiHat => "Hi-Hat",
+        DrumPart::CrashCymbal => "Crash",
+        DrumPart::OpenHiHat => "Open Hi-Hat",
+        DrumPart::RideCymbal => "Ride",
+        DrumPart::Tom1 => "Tom 1",
+        DrumPart::Tom2 => "Tom 2",
+        DrumPart::Tom3 => "Tom 3",
+    }
+}
+
+fn basic_length(length: BasicLength) -> u8 {
+    match length {
+        BasicLength::Whole => 1,
+        BasicLength::Half => 2,
+        BasicLength::Fourth => 4,
+        BasicLength::Eighth => 8,
+        BasicLength::Sixteenth => 16,
+        BasicLength::ThirtySecond => 32,
+        BasicLength::SixtyFourth => 64,
+    }
+}
+
+/// Duration as written in LilyPond, triplets are written with their regular value inside of `\tuplet`.
+fn duration(length: Length) -> String {
+    let modded = |ml: ModdedLength| match ml {
+        ModdedLength::Plain(bl) => basic_length(bl).to_string(),
+        ModdedLength::Dotted(bl) => format!("{}.", basic_length(bl)),
+    };
+    match length {
+        Length::Simple(ml) | Length::Triplet(ml) => modded(ml),
+        Length::Tied(ml1, ml2) => format!("{}~ {}", modded(ml1), modded(ml2)),
+    }
+}
+
+fn dynamic(dynamic: Dynamic) -> &'static str {
+    match dynamic {
+        Dynamic::Pianissimo => "\\pp",
+        Dynamic::Piano => "\\p",
+        Dynamic::MezzoPiano => "\\mp",
+        Dynamic::MezzoForte => "\\mf",
+        Dynamic::Forte => "\\f",
+        Dynamic::Fortissimo => "\\ff",
+    }
+}
+
+/// Writes a bar of a drum part. `current` is the dynamic marked last, a new one is only marked when it changes.
+fn bar(part: DrumPart, notes: &[NotatedNote], current: &mut Option<Dynamic>) -> String {
+    let mut out: Vec<String> = Vec::new();
+    let mut in_tuplet = false;
+    // Ties to the previous note don't repeat the articulation.
+    let mut continued = false;
+    for n in notes {
+        let triplet = matches!(n.length, Length::Triplet(_));
+        if triplet && !in_tuplet {
+            out.push("\\tuplet 3/2 {".to_string());
+        } else if !triplet && in_tuplet {
+            out.push("}".to_string());
+        }
+        in_tuplet = triplet;
+        let mut note = match n.note {
+            Note::Rest => format!("r{}", duration(n.length)),
+            Note::Ghost if !continued => format!("\\parenthesize {}{}", drum_name(part), duration(n.length)),
+            _ => format!("{}{}", drum_name(part), duration(n.length)),
+        };
+        if n.note != Note::Rest && !continued {
+            if n.note == Note::Accent {
+                note.push_str("->");
+            }
+            if *current != Some(n.dynamic) {
+                note.push_str(dynamic(n.dynamic));
+                *current = Some(n.dynamic);
+            }
+        }
+        if n.tied {
+            note.push('~');
+        }
+        continued = n.tied;
+        out.push(note);
+    }
+    if in_tuplet {
+        out.push("}".to_string());
+    }
+    out.join(" ")
+}
+
+/// LilyPond source of the score with a drum staff per part, `text` goes to a comment on top.
+/// Parts played by anything but the drums are named after it, but keep the notes of the drums they stand for.
+pub(crate) fn score(
+    parts: &[(DrumPart, Vec<Vec<NotatedNote>>)],
+    time_signature: TimeSignature,
+    text: &str,
+    instruments: Instruments,
+) -> String {
+    let mut out = String::new();
+    for line in text.lines() {
+        out.push_str(&format!("% {}\n", line));
+    }
+    out.push_str(&format!("\\version \"{}\"\n\n", VERSION));
+    out.push_str("\\header {\n  tagline = ##f\n}\n\n");
+    out.push_str("\\score {\n  <<\n");
+    for (part, bars) in parts {
+        out.push_str(&format!(
+            "    \\new DrumStaff \\with {{ instrumentName = \"{}\" }} \\drummode {{\n",
+            instruments.name(*part).unwrap_or_else(|| instrument_name(*part))
+        ));
+        out.push_str(&format!(
+            "      \\time {}/{}\n",
+            time_signature.numerator,
+            basic_length(time_signature.denominator)
+        ));
+        let mut current = None;
+        for notes in bars {
+            out.push_str(&format!("      {} |\n", bar(*part, notes, &mut current)));
+        }
+        out.push_str("    }\n");
+    }
+    out.push_str("  >>\n  \\layout { }\n}\n");
+    out
+}
+
+#[cfg(test)]
+use std::collections::BTreeMap;
+#[cfg(test)]
+use std::str::FromStr;
+#[cfg(test)]
+use crate::dsl::dsl::groups;
+#[cfg(test)]
+use crate::export::{export, ExportFormat};
+
+#[test]
+fn test_lilypond_bar() {
+    let four_four = TimeSignature::from_str("4/4").unwrap();
+    let written = |pattern: &str| {
+        let bars = crate::export::to_bars(&groups(pattern).unwrap(), four_four, 1).unwrap();
+        bar(DrumPart::SnareDrum, &bars[0], &mut None)
+    };
+    assert_eq!(written("4x-X-"), "sn4\\f r4 sn4-> r4");
+    assert_eq!(written("8g-p8x--4.x"), "\\parenthesize sn8\\f r8 sn8\\p r4 sn4.\\f");
+    assert_eq!(written("8tx-x-x-4x-"), "\\tuplet 3/2 { sn8\\f r8 sn8 r8 sn8 r8 } sn4 r4");
+}
+
+#[test]
+fn test_lilypond_score() {
+    let parts = BTreeMap::from_iter([
+        (DrumPart::KickDrum, groups("8x--x--").unwrap()),
+        (DrumPart::SnareDrum, groups("4-x").unwrap()),
+    ]);
+    let score = export(&parts, TimeSignature::from_str("4/4").unwrap(), "Kick and snare", ExportFormat::LilyPond, Instruments::Drums)
+        .unwrap();
+    assert!(score.starts_with("% Kick and snare\n\\version"));
+    assert!(score.contains("\\new DrumStaff \\with { instrumentName = \"Kick\" } \\drummode {"));
+    // The groove converges over 3 bars on both staves.
+    assert_eq!(score.matches(" |\n").count(), 6);
+    assert!(score.contains("      bd8\\f r4 bd8 r4 bd8 r8 |\n      r8 bd8 r4 bd8 r4 bd8 |\n      r4 bd8 r4 bd8 r4 |\n"));
+    let score = export(&parts, TimeSignature::from_str("4/4").unwrap(), "", ExportFormat::LilyPond, Instruments::Ensemble)
+        .unwrap();
+    assert!(score.contains("\\new DrumStaff \\with { instrumentName = \"Surdo\" } \\drummode {\n"));
+    assert!(score.contains("      bd8\\f r4 bd8 r4 bd8 r8 |\n"));
+}
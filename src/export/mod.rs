@@ -0,0 +1,272 @@
+pub mod lilypond;
+pub mod pack;
+pub mod tab;
+
+use std::collections::BTreeMap;
+use std::str::FromStr;
+
+use crate::dsl::dsl::{BasicLength, Dynamic, Groups, KnownLength, Length, ModdedLength, Note, DEFAULT_DYNAMIC};
+use crate::dsl::grid::to_384th;
+use crate::midi::core::DrumPart;
+use crate::midi::ensemble;
+use crate::midi::time::TimeSignature;
+
+/// Notation formats the patterns can be exported to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExportFormat {
+    LilyPond,
+    /// ASCII drum tab.
+    Tab,
+}
+
+impl FromStr for ExportFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "lilypond" | "ly" => Ok(ExportFormat::LilyPond),
+            "tab" => Ok(ExportFormat::Tab),
+            _ => Err(format!("Unknown export format '{}', expected 'lilypond' or 'tab'", s)),
+        }
+    }
+}
+
+/// Who the parts are written for. The drum kit keeps the names of the drums, the others relabel every part with
+/// what plays it instead.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Instruments {
+    #[default]
+    Drums,
+    /// The hand percussion of `--ensemble`, a player per part.
+    Ensemble,
+    /// Sounds made with the body, for teaching a groove to a class without any instruments.
+    BodyPercussion,
+}
+
+impl FromStr for Instruments {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "drums" => Ok(Instruments::Drums),
+            "ensemble" => Ok(Instruments::Ensemble),
+            "body" => Ok(Instruments::BodyPercussion),
+            _ => Err(format!("Unknown instruments '{}', expected 'drums', 'ensemble' or 'body'", s)),
+        }
+    }
+}
+
+impl Instruments {
+    /// Name of what plays the part, `None` for the drums as every format names them its own way.
+    pub(crate) fn name(self, part: DrumPart) -> Option<&'static str> {
+        match self {
+            Instruments::Drums => None,
+            Instruments::Ensemble => Some(ensemble::instrument(part).name),
+            Instruments::BodyPercussion => Some(match part {
+                DrumPart::KickDrum => "Stomp",
+                DrumPart::SnareDrum => "Clap",
+                // Pats on the thighs, alternating hands, keep up with the fastest hi-hat patterns.
+                DrumPart::HiHat => "Pat",
+                DrumPart::CrashCymbal => "Hey",
+                DrumPart::OpenHiHat => "Rub",
+                DrumPart::RideCymbal => "Snap",
+                DrumPart::Tom1 => "Tongue click",
+                DrumPart::Tom2 => "Chest",
+                DrumPart::Tom3 => "Slap",
+            }),
+        }
+    }
+}
+
+/// A note or a rest as it's written in the score. Notes longer than any single note value, or ringing over
+/// the bar line, are written as several notes tied together.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NotatedNote {
+    pub note: Note,
+    pub dynamic: Dynamic,
+    /// Either a simple or a triplet length, never a tied one.
+    pub length: Length,
+    /// Tied to the next note.
+    pub tied: bool,
+}
+
+/// Note values the durations are written with, from the longest to the shortest.
+static BASIC_LENGTHS: [BasicLength; 7] = [
+    BasicLength::Whole,
+    BasicLength::Half,
+    BasicLength::Fourth,
+    BasicLength::Eighth,
+    BasicLength::Sixteenth,
+    BasicLength::ThirtySecond,
+    BasicLength::SixtyFourth,
+];
+
+/// Splits a duration in 384ths into note values to tie together, using as few of them as possible and preferring
+/// regular notes to triplets. The longest values go first.
+fn note_values(duration: u32) -> Result<Vec<Length>, String> {
+    let candidates: Vec<(Length, u32)> = BASIC_LENGTHS
+        .iter()
+        .flat_map(|bl| {
+            [
+                (Length::Simple(ModdedLength::Dotted(*bl)), 2),
+                (Length::Simple(ModdedLength::Plain(*bl)), 2),
+                (Length::Triplet(ModdedLength::Plain(*bl)), 3),
+            ]
+        })
+        .collect();
+    // Cheapest way to write every duration up to `duration`, along with the last note value it's written with.
+    let mut best: Vec<Option<(u32, usize)>> = vec![None; duration as usize + 1];
+    best[0] = Some((0, 0));
+    for d in 1..=duration as usize {
+        best[d] = candidates
+            .iter()
+            .enumerate()
+            .filter_map(|(i, (length, cost))| {
+                let rest = d.checked_sub(to_384th(*length) as usize)?;
+                best[rest].map(|(c, _)| (c + cost, i))
+            })
+            .min_by_key(|(cost, _)| *cost);
+    }
+    let mut values = Vec::new();
+    let mut d = duration as usize;
+    while d > 0 {
+        match best[d] {
+            Some((_, i)) => {
+                let length = candidates[i].0;
+                values.push(length);
+                d -= to_384th(length) as usize;
+            }
+            None => return Err(format!("Can't write a duration of {}/384 with regular note values", duration)),
+        }
+    }
+    values.sort_by_key(|l| std::cmp::Reverse(to_384th(*l)));
+    Ok(values)
+}
+
+/// Lays out the pattern repeated over `bars` bars, the part that's left after the last full repetition is filled
+/// with rests. Notes are split at the bar lines and consecutive rests are merged.
+pub fn to_bars(groups: &Groups, time_signature: TimeSignature, bars: u32) -> Result<Vec<Vec<NotatedNote>>, String> {
+    let bar_length = time_signature.to_128th() * 3;
+    let total = bar_length * bars;
+    let pattern: Vec<(Note, Dynamic, u32)> = groups
+        .0
+        .iter()
+        .flat_map(|group| {
+            let dynamic = group.dynamic.unwrap_or(DEFAULT_DYNAMIC);
+            group.notes.iter().map(move |note| (*note, dynamic, to_384th(group.length)))
+        })
+        .collect();
+    let pattern_length: u32 = pattern.iter().map(|(_, _, length)| length).sum();
+    if pattern_length == 0 {
+        return Err("Pattern is empty".to_string());
+    }
+    let mut notes: Vec<(Note, Dynamic, u32)> = Vec::new();
+    for (note, dynamic, length) in pattern.iter().cycle().take(pattern.len() * (total / pattern_length) as usize) {
+        match notes.last_mut() {
+            Some((Note::Rest, _, rest)) if *note == Note::Rest => *rest += length,
+            _ => notes.push((*note, *dynamic, *length)),
+        }
+    }
+    let padding = total % pattern_length;
+    if padding > 0 {
+        match notes.last_mut() {
+            Some((Note::Rest, _, rest)) => *rest += padding,
+            _ => notes.push((Note::Rest, DEFAULT_DYNAMIC, padding)),
+        }
+    }
+
+    let mut result = vec![Vec::new(); bars as usize];
+    let mut time = 0;
+    for (note, dynamic, length) in notes {
+        let end = time + length;
+        while time < end {
+            let bar = time / bar_length;
+            let piece_end = end.min((bar + 1) * bar_length);
+            let values = note_values(piece_end - time)?;
+            let count = values.len();
+            result[bar as usize].extend(values.into_iter().enumerate().map(|(i, length)| NotatedNote {
+                note,
+                dynamic,
+                length,
+                tied: note != Note::Rest && (i + 1 < count || piece_end < end),
+            }));
+            time = piece_end;
+        }
+    }
+    Ok(result)
+}
+
+/// Renders the drum parts over the bars they take to converge as notation source code.
+pub fn export(
+    groups: &BTreeMap<DrumPart, Groups>,
+    time_signature: TimeSignature,
+    text: &str,
+    format: ExportFormat,
+    instruments: Instruments,
+) -> Result<String, String> {
+    let bars = time_signature.converges(groups.values()).map_err(|e| e.to_string())?;
+    let parts = groups
+        .iter()
+        .map(|(part, groups)| Ok((*part, to_bars(groups, time_signature, bars)?)))
+        .collect::<Result<Vec<(DrumPart, Vec<Vec<NotatedNote>>)>, String>>()?;
+    match format {
+        ExportFormat::LilyPond => Ok(lilypond::score(&parts, time_signature, text, instruments)),
+        ExportFormat::Tab => Ok(tab::tab(&parts, time_signature, text, instruments)),
+    }
+}
+
+#[cfg(test)]
+use crate::dsl::dsl::groups;
+
+#[test]
+fn test_note_values() {
+    let values = |d: u32| note_values(d).unwrap();
+    assert_eq!(values(96), vec![Length::Simple(ModdedLength::Plain(BasicLength::Fourth))]);
+    assert_eq!(values(72), vec![Length::Simple(ModdedLength::Dotted(BasicLength::Eighth))]);
+    assert_eq!(values(64), vec![Length::Triplet(ModdedLength::Plain(BasicLength::Fourth))]);
+    assert_eq!(
+        values(120),
+        vec![
+            Length::Simple(ModdedLength::Plain(BasicLength::Fourth)),
+            Length::Simple(ModdedLength::Plain(BasicLength::Sixteenth)),
+        ]
+    );
+    assert_eq!(values(0), vec![]);
+}
+
+#[test]
+fn test_to_bars() {
+    let four_four = TimeSignature::from_str("4/4").unwrap();
+    let quarter = Length::Simple(ModdedLength::Plain(BasicLength::Fourth));
+    let note = |note, length, tied| NotatedNote { note, dynamic: DEFAULT_DYNAMIC, length, tied };
+    // Three quarters against the bar line: the second hit is split between the bars and tied.
+    let bars = to_bars(&groups("2.x").unwrap(), four_four, 3).unwrap();
+    assert_eq!(bars.len(), 3);
+    assert_eq!(
+        bars[0],
+        vec![
+            note(Note::Hit, Length::Simple(ModdedLength::Dotted(BasicLength::Half)), false),
+            note(Note::Hit, quarter, true),
+        ]
+    );
+    let half = Length::Simple(ModdedLength::Plain(BasicLength::Half));
+    assert_eq!(bars[1], vec![note(Note::Hit, half, false), note(Note::Hit, half, true)]);
+    // Rests are merged and never tied.
+    let bars = to_bars(&groups("4x---").unwrap(), four_four, 1).unwrap();
+    assert_eq!(
+        bars[0],
+        vec![
+            note(Note::Hit, quarter, false),
+            note(Note::Rest, Length::Simple(ModdedLength::Dotted(BasicLength::Half)), false),
+        ]
+    );
+    // What's left after the last full repetition is a rest.
+    let bars = to_bars(&groups("4x--").unwrap(), four_four, 1).unwrap();
+    assert_eq!(
+        bars[0],
+        vec![
+            note(Note::Hit, quarter, false),
+            note(Note::Rest, Length::Simple(ModdedLength::Dotted(BasicLength::Half)), false),
+        ]
+    );
+}
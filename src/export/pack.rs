@@ -0,0 +1,169 @@
+use std::collections::BTreeMap;
+
+use crate::checksum::crc32;
+use crate::dsl::dsl::{Groups, KnownLength};
+use crate::json::json_string;
+use crate::midi::core::DrumPart;
+use crate::midi::time::TimeSignature;
+
+/// Modification date of every file in the archive, 1980-01-01 in MS-DOS format, so the same pack always comes
+/// out the same.
+static DOS_DATE: u16 = 1 << 5 | 1;
+/// Version 2.0 of the ZIP format, the first one with folders, is all that's needed for stored files.
+static ZIP_VERSION: u16 = 20;
+/// File names are UTF-8.
+static UTF8_NAMES: u16 = 1 << 11;
+
+/// Writes the files to a ZIP archive. MIDI files and text are small, so they're stored as they are,
+/// without compression.
+pub fn zip(files: &[(String, Vec<u8>)]) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut directory = Vec::new();
+    for (name, contents) in files {
+        let offset = out.len() as u32;
+        let crc = crc32(contents);
+        // Fields shared by the local header and the central directory entry: version needed, flags, method,
+        // time, date, CRC-32, sizes and the length of the name.
+        let mut common = Vec::new();
+        common.extend_from_slice(&ZIP_VERSION.to_le_bytes());
+        common.extend_from_slice(&UTF8_NAMES.to_le_bytes());
+        common.extend_from_slice(&0u16.to_le_bytes());
+        common.extend_from_slice(&0u16.to_le_bytes());
+        common.extend_from_slice(&DOS_DATE.to_le_bytes());
+        common.extend_from_slice(&crc.to_le_bytes());
+        common.extend_from_slice(&(contents.len() as u32).to_le_bytes());
+        common.extend_from_slice(&(contents.len() as u32).to_le_bytes());
+        common.extend_from_slice(&(name.len() as u16).to_le_bytes());
+
+        out.extend_from_slice(&0x04034b50u32.to_le_bytes());
+        out.extend_from_slice(&common);
+        out.extend_from_slice(&0u16.to_le_bytes());
+        out.extend_from_slice(name.as_bytes());
+        out.extend_from_slice(contents);
+
+        directory.extend_from_slice(&0x02014b50u32.to_le_bytes());
+        directory.extend_from_slice(&ZIP_VERSION.to_le_bytes());
+        directory.extend_from_slice(&common);
+        // Lengths of the extra field and the comment, disk number, attributes.
+        directory.extend_from_slice(&[0; 12]);
+        directory.extend_from_slice(&offset.to_le_bytes());
+        directory.extend_from_slice(name.as_bytes());
+    }
+    let directory_offset = out.len() as u32;
+    out.extend_from_slice(&directory);
+    out.extend_from_slice(&0x06054b50u32.to_le_bytes());
+    out.extend_from_slice(&[0; 4]);
+    out.extend_from_slice(&(files.len() as u16).to_le_bytes());
+    out.extend_from_slice(&(files.len() as u16).to_le_bytes());
+    out.extend_from_slice(&(directory.len() as u32).to_le_bytes());
+    out.extend_from_slice(&directory_offset.to_le_bytes());
+    out.extend_from_slice(&0u16.to_le_bytes());
+    out
+}
+
+/// What's in a groove pack and how it's played, written to `manifest.json`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Manifest {
+    pub text: String,
+    /// Tags of the pattern file the groove was read from.
+    pub tags: Vec<String>,
+    pub tempo: u16,
+    pub time_signature: TimeSignature,
+    /// Bars the parts take to converge.
+    pub bars: u32,
+    pub patterns: BTreeMap<DrumPart, String>,
+    pub groups: BTreeMap<DrumPart, Groups>,
+    /// The groove as printed by `--share`.
+    pub share: String,
+    /// Names of the other files in the pack.
+    pub files: Vec<String>,
+}
+
+impl Manifest {
+    /// The manifest as JSON. Every part comes with the number of times its pattern is played until the parts
+    /// converge, the plan to practice it by.
+    pub fn to_json(&self) -> String {
+        let total = self.time_signature.to_128th() * self.bars;
+        let parts: Vec<String> = self
+            .patterns
+            .iter()
+            .map(|(part, pattern)| {
+                let length = self.groups.get(part).map_or(0, |g| g.to_128th());
+                let repeats = total.checked_div(length).unwrap_or(0);
+                format!(
+                    "    {{ \"part\": {}, \"pattern\": {}, \"repeats\": {} }}",
+                    json_string(part.name()),
+                    json_string(pattern),
+                    repeats
+                )
+            })
+            .collect();
+        let tags: Vec<String> = self.tags.iter().map(|t| json_string(t)).collect();
+        let files: Vec<String> = self.files.iter().map(|f| json_string(f)).collect();
+        format!(
+            "{{\n  \"generator\": {},\n  \"description\": {},\n  \"tags\": [{}],\n  \"tempo\": {},\n  \"time_signature\": {},\n  \
+             \"bars\": {},\n  \"parts\": [\n{}\n  ],\n  \"share\": {},\n  \"files\": [{}]\n}}\n",
+            json_string(&format!("Polyrhythmix {}", env!("CARGO_PKG_VERSION"))),
+            json_string(&self.text),
+            tags.join(", "),
+            self.tempo,
+            json_string(&self.time_signature.to_string()),
+            self.bars,
+            parts.join(",\n"),
+            json_string(&self.share),
+            files.join(", ")
+        )
+    }
+}
+
+#[cfg(test)]
+use std::str::FromStr;
+#[cfg(test)]
+use crate::dsl::dsl::groups;
+
+#[test]
+fn test_zip() {
+    let zip = zip(&[("a.txt".to_string(), b"123456789".to_vec()), ("b.mid".to_string(), vec![])]);
+    // A local header of 30 bytes and the name before the contents of the first file.
+    assert_eq!(zip[0..4], [0x50, 0x4b, 0x03, 0x04]);
+    assert_eq!(zip[14..18], 0xcbf43926u32.to_le_bytes());
+    assert_eq!(zip[30..35], *b"a.txt");
+    assert_eq!(zip[35..44], *b"123456789");
+    // The second file starts right after, then go two central directory entries of 46 bytes and their names.
+    assert_eq!(zip[44..48], [0x50, 0x4b, 0x03, 0x04]);
+    assert_eq!(zip[79..83], [0x50, 0x4b, 0x01, 0x02]);
+    let end = &zip[zip.len() - 22..];
+    assert_eq!(end[0..4], [0x50, 0x4b, 0x05, 0x06]);
+    assert_eq!(end[10..12], 2u16.to_le_bytes());
+    assert_eq!(end[12..16], (46 * 2 + 10u32).to_le_bytes());
+    assert_eq!(end[16..20], 79u32.to_le_bytes());
+    assert_eq!(zip.len(), 79 + 102 + 22);
+}
+
+#[test]
+fn test_manifest() {
+    let manifest = Manifest {
+        text: "3 against \"4\"".to_string(),
+        tags: vec!["polyrhythm".to_string(), "3-4".to_string()],
+        tempo: 90,
+        time_signature: TimeSignature::from_str("3/4").unwrap(),
+        bars: 4,
+        patterns: BTreeMap::from([
+            (DrumPart::KickDrum, "4x".to_string()),
+            (DrumPart::SnareDrum, "1x".to_string()),
+        ]),
+        groups: BTreeMap::from([
+            (DrumPart::KickDrum, groups("4x").unwrap()),
+            (DrumPart::SnareDrum, groups("1x").unwrap()),
+        ]),
+        share: "poly=1;tempo=90;signature=3/4;kick=4x;snare=1x".to_string(),
+        files: vec!["groove.mid".to_string(), "groove.ly".to_string()],
+    };
+    let json = manifest.to_json();
+    assert!(json.contains("\"description\": \"3 against \\\"4\\\"\",\n"));
+    assert!(json.contains("\"tags\": [\"polyrhythm\", \"3-4\"],\n"));
+    assert!(json.contains("\"time_signature\": \"3/4\",\n"));
+    assert!(json.contains("{ \"part\": \"kick\", \"pattern\": \"4x\", \"repeats\": 12 },\n"));
+    assert!(json.contains("{ \"part\": \"snare\", \"pattern\": \"1x\", \"repeats\": 3 }\n"));
+    assert!(json.ends_with("\"files\": [\"groove.mid\", \"groove.ly\"]\n}\n"));
+}
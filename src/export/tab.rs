@@ -0,0 +1,188 @@
+use crate::dsl::dsl::{KnownLength, Note};
+use crate::dsl::grid::to_384th;
+use crate::export::{Instruments, NotatedNote};
+use crate::midi::core::DrumPart;
+use crate::midi::time::TimeSignature;
+
+/// Bars written on a single line of the tab.
+static BARS_PER_LINE: usize = 4;
+
+/// Order of the parts in the tab, from the top line to the bottom one, as drum tabs usually go.
+static ORDER: [DrumPart; 9] = [
+    DrumPart::CrashCymbal,
+    DrumPart::RideCymbal,
+    DrumPart::HiHat,
+    DrumPart::OpenHiHat,
+    DrumPart::Tom1,
+    DrumPart::Tom2,
+    DrumPart::Tom3,
+    DrumPart::SnareDrum,
+    DrumPart::KickDrum,
+];
+
+/// Two letters on the left of the part's line: the usual abbreviations for the drums, the start of the name for
+/// anything else.
+fn label(part: DrumPart, instruments: Instruments) -> String {
+    if let Some(name) = instruments.name(part) {
+        return name.chars().take(2).collect();
+    }
+    let label = match part {
+        DrumPart::KickDrum => "BD",
+        DrumPart::SnareDrum => "SD",
+        DrumPart::HiHat => "HH",
+        DrumPart::CrashCymbal => "CC",
+        DrumPart::OpenHiHat => "OH",
+        DrumPart::RideCymbal => "Rd",
+        DrumPart::Tom1 => "T1",
+        DrumPart::Tom2 => "T2",
+        DrumPart::Tom3 => "FT",
+    };
+    label.to_string()
+}
+
+/// Cymbals are written with `x`, drums with `o`.
+fn symbol(part: DrumPart, note: Note) -> char {
+    let cymbal = matches!(
+        part,
+        DrumPart::CrashCymbal | DrumPart::RideCymbal | DrumPart::HiHat | DrumPart::OpenHiHat
+    );
+    match note {
+        Note::Rest => '-',
+        Note::Ghost => 'g',
+        Note::Hit if cymbal => 'x',
+        Note::Hit => 'o',
+        Note::Accent if cymbal => 'X',
+        Note::Accent => 'O',
+    }
+}
+
+fn gcd(a: u32, b: u32) -> u32 {
+    if b == 0 { a } else { gcd(b, a % b) }
+}
+
+/// Notes starting in a bar, with their times in 384ths from the start of the bar.
+type Onsets = Vec<(u32, Note)>;
+
+/// Starts of the notes within their bars, the notes tied to the previous ones left out.
+fn onsets(bars: &[Vec<NotatedNote>]) -> Vec<Onsets> {
+    let mut continued = false;
+    bars.iter()
+        .map(|notes| {
+            let mut time = 0;
+            let mut onsets = Vec::new();
+            for n in notes {
+                if n.note != Note::Rest && !continued {
+                    onsets.push((time, n.note));
+                }
+                continued = n.tied;
+                time += to_384th(n.length);
+            }
+            onsets
+        })
+        .collect()
+}
+
+/// ASCII drum tab with a line per part, every character standing for the shortest step between the notes.
+/// `text` goes on top.
+pub(crate) fn tab(
+    parts: &[(DrumPart, Vec<Vec<NotatedNote>>)],
+    time_signature: TimeSignature,
+    text: &str,
+    instruments: Instruments,
+) -> String {
+    let bar_length = time_signature.to_128th() * 3;
+    let parts: Vec<(DrumPart, Vec<Onsets>)> = ORDER
+        .iter()
+        .filter_map(|part| parts.iter().find(|(p, _)| p == part))
+        .map(|(part, bars)| (*part, onsets(bars)))
+        .collect();
+    let step = parts
+        .iter()
+        .flat_map(|(_, bars)| bars.iter().flatten())
+        .fold(bar_length, |step, (time, _)| gcd(step, *time));
+    let cells = (bar_length / step) as usize;
+    let bars = parts.first().map_or(0, |(_, bars)| bars.len());
+
+    let mut out = String::new();
+    for line in text.lines() {
+        out.push_str(line);
+        out.push('\n');
+    }
+    out.push_str(&format!("Time signature {}, a character is 1/{}\n", time_signature, 384 / step));
+    out.push_str("x o: hit, X O: accent, g: ghost note\n");
+    // Labels of anything but the drums can't be told from the abbreviations alone.
+    let legend: Vec<String> = parts
+        .iter()
+        .filter_map(|(part, _)| {
+            let name = instruments.name(*part)?;
+            Some(format!("{}: {} ({})", label(*part, instruments), name.to_lowercase(), part.name()))
+        })
+        .collect();
+    if !legend.is_empty() {
+        out.push_str(&legend.join(", "));
+        out.push('\n');
+    }
+    for first in (0..bars).step_by(BARS_PER_LINE) {
+        out.push('\n');
+        for (part, part_bars) in &parts {
+            out.push_str(&label(*part, instruments));
+            out.push('|');
+            for onsets in &part_bars[first..bars.min(first + BARS_PER_LINE)] {
+                let mut line = vec!['-'; cells];
+                for (time, note) in onsets {
+                    line[(time / step) as usize] = symbol(*part, *note);
+                }
+                out.extend(line);
+                out.push('|');
+            }
+            out.push('\n');
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+use std::collections::BTreeMap;
+#[cfg(test)]
+use std::str::FromStr;
+#[cfg(test)]
+use crate::dsl::dsl::groups;
+#[cfg(test)]
+use crate::export::{export, ExportFormat};
+
+#[test]
+fn test_tab() {
+    let parts = BTreeMap::from([
+        (DrumPart::KickDrum, groups("8X--x--").unwrap()),
+        (DrumPart::SnareDrum, groups("4-x").unwrap()),
+        (DrumPart::HiHat, groups("8xg").unwrap()),
+    ]);
+    let four_four = TimeSignature::from_str("4/4").unwrap();
+    assert_eq!(
+        export(&parts, four_four, "Groove", ExportFormat::Tab, Instruments::Drums).unwrap(),
+        "Groove\n\
+         Time signature 4/4, a character is 1/8\n\
+         x o: hit, X O: accent, g: ghost note\n\
+         \n\
+         HH|xgxgxgxg|xgxgxgxg|xgxgxgxg|\n\
+         SD|--o---o-|--o---o-|--o---o-|\n\
+         BD|O--o--O-|-o--O--o|--O--o--|\n"
+    );
+    // Eighth triplets take steps of 1/12, dotted halves ringing over the bar line are only written where they start.
+    let parts = BTreeMap::from([
+        (DrumPart::Tom1, groups("8tx-x").unwrap()),
+        (DrumPart::RideCymbal, groups("2.x").unwrap()),
+    ]);
+    let tab = export(&parts, four_four, "", ExportFormat::Tab, Instruments::Drums).unwrap();
+    assert!(tab.contains("a character is 1/12\n"));
+    assert!(tab.contains("\nRd|x--------x--|------x-----|---x--------|\n"));
+    // Body percussion for a class: the parts are relabeled and explained under the key.
+    let parts = BTreeMap::from([
+        (DrumPart::KickDrum, groups("4x-").unwrap()),
+        (DrumPart::SnareDrum, groups("4-x").unwrap()),
+        (DrumPart::HiHat, groups("8x").unwrap()),
+    ]);
+    let tab = export(&parts, four_four, "", ExportFormat::Tab, Instruments::BodyPercussion).unwrap();
+    assert!(tab.contains("g: ghost note\nPa: pat (hi-hat), Cl: clap (snare), St: stomp (kick)\n"));
+    assert!(tab.ends_with("\nPa|xxxxxxxx|\nCl|--o---o-|\nSt|o---o---|\n"));
+}
@@ -0,0 +1,271 @@
+#[cfg(feature = "lsp")]
+use std::fmt;
+
+/// Quotes a string for JSON, escaping what has to be escaped.
+pub(crate) fn json_string(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// Arrays and objects nested deeper than this are refused rather than parsed recursively until the stack runs out.
+#[cfg(feature = "lsp")]
+static MAX_DEPTH: usize = 128;
+
+/// A JSON value, just enough for the messages of the language server. Objects keep the order of their members.
+#[cfg(feature = "lsp")]
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Json {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+#[cfg(feature = "lsp")]
+impl Json {
+    /// An object with the members in the given order.
+    pub(crate) fn object<const N: usize>(members: [(&str, Json); N]) -> Json {
+        Json::Object(members.into_iter().map(|(k, v)| (k.to_string(), v)).collect())
+    }
+
+    /// Member of an object, `Null` if there's no such member or this isn't an object.
+    pub(crate) fn get(&self, key: &str) -> &Json {
+        match self {
+            Json::Object(members) => members.iter().find(|(k, _)| k == key).map_or(&Json::Null, |(_, v)| v),
+            _ => &Json::Null,
+        }
+    }
+
+    /// Follows the path of members, e.g. `["textDocument", "uri"]`.
+    pub(crate) fn at(&self, path: &[&str]) -> &Json {
+        path.iter().fold(self, |json, key| json.get(key))
+    }
+
+    pub(crate) fn as_str(&self) -> Option<&str> {
+        match self {
+            Json::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn as_u32(&self) -> Option<u32> {
+        match self {
+            Json::Number(n) if *n >= 0.0 && n.fract() == 0.0 => Some(*n as u32),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn parse(s: &str) -> Result<Json, String> {
+        let mut parser = Parser { chars: s.char_indices().peekable(), source: s, depth: 0 };
+        let json = parser.value()?;
+        parser.whitespace();
+        match parser.chars.peek() {
+            None => Ok(json),
+            Some((i, _)) => Err(format!("Unexpected input at {}", i)),
+        }
+    }
+}
+
+#[cfg(feature = "lsp")]
+impl fmt::Display for Json {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Json::Null => write!(f, "null"),
+            Json::Bool(b) => write!(f, "{}", b),
+            Json::Number(n) => write!(f, "{}", n),
+            Json::String(s) => write!(f, "{}", json_string(s)),
+            Json::Array(values) => {
+                write!(f, "[")?;
+                for (i, value) in values.iter().enumerate() {
+                    write!(f, "{}{}", if i == 0 { "" } else { "," }, value)?;
+                }
+                write!(f, "]")
+            }
+            Json::Object(members) => {
+                write!(f, "{{")?;
+                for (i, (key, value)) in members.iter().enumerate() {
+                    write!(f, "{}{}:{}", if i == 0 { "" } else { "," }, json_string(key), value)?;
+                }
+                write!(f, "}}")
+            }
+        }
+    }
+}
+
+#[cfg(feature = "lsp")]
+struct Parser<'a> {
+    chars: std::iter::Peekable<std::str::CharIndices<'a>>,
+    source: &'a str,
+    /// Arrays and objects the parser is in.
+    depth: usize,
+}
+
+#[cfg(feature = "lsp")]
+impl Parser<'_> {
+    fn whitespace(&mut self) {
+        while self.chars.next_if(|(_, c)| c.is_ascii_whitespace()).is_some() {}
+    }
+
+    fn expect(&mut self, expected: char) -> Result<(), String> {
+        match self.chars.next() {
+            Some((_, c)) if c == expected => Ok(()),
+            Some((i, c)) => Err(format!("Expected '{}' at {}, got '{}'", expected, i, c)),
+            None => Err(format!("Expected '{}', got the end", expected)),
+        }
+    }
+
+    fn value(&mut self) -> Result<Json, String> {
+        self.whitespace();
+        match self.chars.peek().copied() {
+            None => Err("Expected a value, got the end".to_string()),
+            Some((i, '{' | '[')) if self.depth >= MAX_DEPTH => {
+                Err(format!("Nested deeper than {} at {}", MAX_DEPTH, i))
+            }
+            Some((_, '{')) => self.nested(Parser::object),
+            Some((_, '[')) => self.nested(Parser::array),
+            Some((_, '"')) => self.string().map(Json::String),
+            Some((i, c)) if c == '-' || c.is_ascii_digit() => {
+                let mut end = i;
+                while let Some((j, c)) = self.chars.next_if(|(_, c)| "+-.eE".contains(*c) || c.is_ascii_digit()) {
+                    end = j + c.len_utf8();
+                }
+                let number = &self.source[i..end];
+                number.parse().map(Json::Number).map_err(|_| format!("Malformed number '{}'", number))
+            }
+            Some((i, _)) => {
+                let rest = &self.source[i..];
+                let (word, json) = [("null", Json::Null), ("true", Json::Bool(true)), ("false", Json::Bool(false))]
+                    .into_iter()
+                    .find(|(word, _)| rest.starts_with(word))
+                    .ok_or_else(|| format!("Unexpected input at {}", i))?;
+                for _ in 0..word.len() {
+                    self.chars.next();
+                }
+                Ok(json)
+            }
+        }
+    }
+
+    fn nested(&mut self, parse: fn(&mut Self) -> Result<Json, String>) -> Result<Json, String> {
+        self.depth += 1;
+        let json = parse(self);
+        self.depth -= 1;
+        json
+    }
+
+    /// Four hexadecimal digits of a `\u` escape.
+    fn code_unit(&mut self) -> Result<u32, String> {
+        let hex: String = (0..4).filter_map(|_| self.chars.next().map(|(_, c)| c)).collect();
+        u32::from_str_radix(&hex, 16).map_err(|_| format!("Malformed escape '\\u{}'", hex))
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        self.expect('"')?;
+        let mut out = String::new();
+        loop {
+            match self.chars.next().map(|(_, c)| c) {
+                None => return Err("Unterminated string".to_string()),
+                Some('"') => return Ok(out),
+                Some('\\') => match self.chars.next().map(|(_, c)| c) {
+                    Some('n') => out.push('\n'),
+                    Some('t') => out.push('\t'),
+                    Some('r') => out.push('\r'),
+                    Some('b') => out.push('\u{8}'),
+                    Some('f') => out.push('\u{c}'),
+                    Some('u') => {
+                        let high = self.code_unit()?;
+                        let code = if (0xd800..0xdc00).contains(&high) {
+                            self.expect('\\')?;
+                            self.expect('u')?;
+                            let low = self.code_unit()?;
+                            0x10000 + ((high - 0xd800) << 10) + low.saturating_sub(0xdc00)
+                        } else {
+                            high
+                        };
+                        out.push(char::from_u32(code).unwrap_or(char::REPLACEMENT_CHARACTER));
+                    }
+                    Some(c) => out.push(c),
+                    None => return Err("Unterminated string".to_string()),
+                },
+                Some(c) => out.push(c),
+            }
+        }
+    }
+
+    fn array(&mut self) -> Result<Json, String> {
+        self.expect('[')?;
+        let mut values = Vec::new();
+        self.whitespace();
+        if self.chars.next_if(|(_, c)| *c == ']').is_some() {
+            return Ok(Json::Array(values));
+        }
+        loop {
+            values.push(self.value()?);
+            self.whitespace();
+            if self.chars.next_if(|(_, c)| *c == ',').is_none() {
+                self.expect(']')?;
+                return Ok(Json::Array(values));
+            }
+        }
+    }
+
+    fn object(&mut self) -> Result<Json, String> {
+        self.expect('{')?;
+        let mut members = Vec::new();
+        self.whitespace();
+        if self.chars.next_if(|(_, c)| *c == '}').is_some() {
+            return Ok(Json::Object(members));
+        }
+        loop {
+            self.whitespace();
+            let key = self.string()?;
+            self.whitespace();
+            self.expect(':')?;
+            members.push((key, self.value()?));
+            self.whitespace();
+            if self.chars.next_if(|(_, c)| *c == ',').is_none() {
+                self.expect('}')?;
+                return Ok(Json::Object(members));
+            }
+        }
+    }
+}
+
+#[test]
+fn test_json_string() {
+    assert_eq!(json_string("a \"b\"\\c\nd\t"), "\"a \\\"b\\\"\\\\c\\nd\\u0009\"");
+}
+
+#[cfg(feature = "lsp")]
+#[test]
+fn test_json() {
+    let text = r#" {"id": 1, "params": {"text": "kick: 4x\n\u00e9\ud83e\udd41", "list": [true, null, -2.5e1]}, "e": {}} "#;
+    let json = Json::parse(text).unwrap();
+    assert_eq!(json.get("id").as_u32(), Some(1));
+    assert_eq!(json.at(&["params", "text"]).as_str(), Some("kick: 4x\né🥁"));
+    assert_eq!(json.at(&["params", "list"]), &Json::Array(vec![Json::Bool(true), Json::Null, Json::Number(-25.0)]));
+    assert_eq!(json.at(&["params", "missing", "deeper"]), &Json::Null);
+    assert_eq!(
+        json.to_string(),
+        "{\"id\":1,\"params\":{\"text\":\"kick: 4x\\né🥁\",\"list\":[true,null,-25]},\"e\":{}}"
+    );
+    assert_eq!(Json::parse(&json.to_string()), Ok(json));
+    assert!(Json::parse("{\"a\": }").is_err());
+    assert!(Json::parse("[1, 2").is_err());
+    assert!(Json::parse("1 2").is_err());
+    let nested = |depth: usize| format!("{}{}", "[".repeat(depth), "]".repeat(depth));
+    assert!(Json::parse(&nested(MAX_DEPTH)).is_ok());
+    assert_eq!(Json::parse(&nested(100_000)), Err("Nested deeper than 128 at 128".to_string()));
+}
@@ -1,2 +1,32 @@
+//! Without the `std` feature, only the DSL parser, the timing of the patterns and the random generators are
+//! built, on `core` and `alloc`, so that embedded MIDI hardware can read the same patterns.
+#![cfg_attr(not(any(feature = "std", test)), no_std)]
+
+extern crate alloc;
+
+#[cfg(feature = "arrangement")]
+pub mod arrangement;
+#[cfg(feature = "audio")]
+pub mod audio;
+#[cfg(any(feature = "export", feature = "qr"))]
+mod checksum;
+#[cfg(feature = "std")]
+pub mod daily;
 pub mod dsl;
-pub mod midi;
\ No newline at end of file
+pub mod error;
+#[cfg(feature = "std")]
+pub mod etude;
+#[cfg(feature = "export")]
+pub mod export;
+#[cfg(feature = "std")]
+mod json;
+#[cfg(feature = "lsp")]
+pub mod lsp;
+pub mod midi;
+pub mod random;
+#[cfg(feature = "std")]
+pub mod share;
+#[cfg(feature = "std")]
+pub mod template;
+#[cfg(feature = "video")]
+pub mod video;
@@ -0,0 +1,411 @@
+use std::collections::BTreeMap;
+use std::io::{self, BufRead, Write};
+use std::ops::Range;
+use std::str::FromStr;
+
+use crate::dsl::dsl::{spanned_groups, Groups};
+use crate::dsl::file::{diagnostics, entries};
+use crate::dsl::grid::to_384th;
+use crate::dsl::version::DslVersion;
+use crate::json::Json;
+use crate::midi::core::DrumPart::{self, *};
+
+/// Patterns offered when completing the pattern of a part.
+static PRESETS: [(&str, DrumPart, &str); 10] = [
+    ("four on the floor", KickDrum, "4x"),
+    ("tresillo", KickDrum, "8x--x--x-"),
+    ("Bleed", KickDrum, "16xx-xx-xx-xx-xx-xx-xx-xx-"),
+    ("backbeat", SnareDrum, "4-x"),
+    ("ghosted backbeat", SnareDrum, "16--g-x-g--g-x-g"),
+    ("eighths", HiHat, "8x"),
+    ("accented sixteenths", HiHat, "16Xxxx"),
+    ("offbeats", OpenHiHat, "8-x"),
+    ("quarters", RideCymbal, "4x"),
+    ("every other bar", CrashCymbal, "1x-"),
+];
+
+/// Messages longer than this are refused before anything is allocated for them, pattern files are nowhere near it.
+static MAX_MESSAGE_LENGTH: usize = 16 * 1024 * 1024;
+
+/// Quarter notes in 384ths, the length of a beat on hover.
+static BEAT: u32 = 96;
+
+/// LSP severities.
+static ERROR: f64 = 1.0;
+static WARNING: f64 = 2.0;
+
+/// LSP completion item kinds.
+static KEYWORD: f64 = 14.0;
+static PROPERTY: f64 = 10.0;
+static SNIPPET: f64 = 15.0;
+
+fn beats(groups: &Groups) -> String {
+    let length: u32 = groups.0.iter().map(|g| to_384th(g.length) * g.notes.len() as u32).sum();
+    let beats = length as f64 / BEAT as f64;
+    let beats = format!("{:.2}", beats).trim_end_matches('0').trim_end_matches('.').to_string();
+    match beats.as_str() {
+        "1" => "1 beat".to_string(),
+        _ => format!("{} beats", beats),
+    }
+}
+
+/// The innermost group in parentheses around the byte `column` of the pattern. Euclidean rhythms are left to the
+/// groups they start.
+fn enclosing_group(pattern: &str, column: usize) -> Option<Range<usize>> {
+    let mut open = Vec::new();
+    let mut innermost = None;
+    for (i, c) in pattern.char_indices() {
+        match c {
+            '(' => open.push(i),
+            ')' => {
+                let start = open.pop()?;
+                let euclidean = pattern[..start].ends_with('E');
+                if !euclidean && (start..=i).contains(&column) && innermost.is_none() {
+                    innermost = Some(start..i + 1);
+                }
+            }
+            _ => {}
+        }
+    }
+    innermost
+}
+
+/// Markdown shown when hovering over the byte `column` of the line: the length of the group of the pattern under
+/// it, or of the whole pattern when hovering over its part.
+fn hover(text: &str, line: usize, column: usize) -> Option<String> {
+    let (entries, _) = entries(text);
+    let entry = entries.into_iter().find(|e| e.line == line && e.key != "version")?;
+    let part = DrumPart::from_str(entry.key).ok()?;
+    let spans = spanned_groups(entry.value).ok()?;
+    let whole = Groups(spans.iter().flat_map(|(_, groups)| groups.0.clone()).collect());
+    if entry.key_columns.contains(&column) {
+        return Some(format!("**{}**: the pattern takes {}", part.name(), beats(&whole)));
+    }
+    let column = column.checked_sub(entry.value_columns.start)?;
+    let (span, groups) = match enclosing_group(entry.value, column) {
+        Some(span) => {
+            let (_, groups) = spanned_groups(&entry.value[span.clone()]).ok()?.pop()?;
+            (span, groups)
+        }
+        None => spans.into_iter().find(|(span, _)| span.contains(&column))?,
+    };
+    Some(format!("`{}` takes {}, the whole pattern {}", &entry.value[span], beats(&groups), beats(&whole)))
+}
+
+/// Completions at the byte `column` of the line: the parts, the version and the tags not declared yet while typing a
+/// key, the presets of the part while typing its pattern.
+fn completions(text: &str, line: usize, column: usize) -> Vec<Json> {
+    let current = text.lines().nth(line).unwrap_or_default();
+    let before = &current[..column.min(current.len())];
+    let item = |label: &str, kind, detail: &str, insert: &str| {
+        Json::object([
+            ("label", Json::String(label.to_string())),
+            ("kind", Json::Number(kind)),
+            ("detail", Json::String(detail.to_string())),
+            ("insertText", Json::String(insert.to_string())),
+        ])
+    };
+    match before.split_once(':') {
+        None => {
+            let (entries, _) = entries(text);
+            let declared: Vec<&str> = entries.iter().filter(|e| e.line != line).map(|e| e.key).collect();
+            let mut items: Vec<Json> = DrumPart::ALL
+                .iter()
+                .filter(|part| !declared.contains(&part.name()))
+                .map(|part| item(part.name(), PROPERTY, "drum part", &format!("{}: ", part.name())))
+                .collect();
+            if !declared.contains(&"version") {
+                let latest = DslVersion::LATEST.number();
+                items.push(item("version", KEYWORD, "DSL version", &format!("version: {}", latest)));
+            }
+            if !declared.contains(&"tags") {
+                items.push(item("tags", KEYWORD, "tags to find the groove by", "tags: ["));
+            }
+            items
+        }
+        Some((key, _)) => match DrumPart::from_str(key.trim()) {
+            Ok(part) => PRESETS
+                .iter()
+                .filter(|(_, p, _)| *p == part)
+                .map(|(name, _, pattern)| item(name, SNIPPET, pattern, pattern))
+                .collect(),
+            Err(_) => Vec::new(),
+        },
+    }
+}
+
+/// LSP counts the characters of a line in UTF-16 code units.
+fn utf16_column(line: &str, column: usize) -> u32 {
+    line[..column.min(line.len())].encode_utf16().count() as u32
+}
+
+fn byte_column(line: &str, character: u32) -> usize {
+    let mut units = 0;
+    for (i, c) in line.char_indices() {
+        if units >= character {
+            return i;
+        }
+        units += c.len_utf16() as u32;
+    }
+    line.len()
+}
+
+fn position(line: usize, character: u32) -> Json {
+    Json::object([("line", Json::Number(line as f64)), ("character", Json::Number(character as f64))])
+}
+
+/// A language server for pattern files, answering the messages of an editor one by one.
+#[derive(Debug, Default)]
+pub struct Server {
+    documents: BTreeMap<String, String>,
+}
+
+impl Server {
+    fn publish_diagnostics(&self, uri: &str) -> Json {
+        let text = self.documents.get(uri).map_or("", String::as_str);
+        let lines: Vec<&str> = text.lines().collect();
+        let diagnostics = diagnostics(text)
+            .into_iter()
+            .map(|d| {
+                let line = lines.get(d.line).copied().unwrap_or_default();
+                Json::object([
+                    (
+                        "range",
+                        Json::object([
+                            ("start", position(d.line, utf16_column(line, d.columns.start))),
+                            ("end", position(d.line, utf16_column(line, d.columns.end))),
+                        ]),
+                    ),
+                    ("severity", Json::Number(if d.warning { WARNING } else { ERROR })),
+                    ("source", Json::String("poly".to_string())),
+                    ("message", Json::String(d.message)),
+                ])
+            })
+            .collect();
+        Json::object([
+            ("jsonrpc", Json::String("2.0".to_string())),
+            ("method", Json::String("textDocument/publishDiagnostics".to_string())),
+            (
+                "params",
+                Json::object([("uri", Json::String(uri.to_string())), ("diagnostics", Json::Array(diagnostics))]),
+            ),
+        ])
+    }
+
+    /// The document, line and byte column a request is about.
+    fn cursor<'a>(&'a self, params: &Json) -> Option<(&'a str, usize, usize)> {
+        let text = self.documents.get(params.at(&["textDocument", "uri"]).as_str()?)?;
+        let line = params.at(&["position", "line"]).as_u32()? as usize;
+        let character = params.at(&["position", "character"]).as_u32()?;
+        let column = byte_column(text.lines().nth(line).unwrap_or_default(), character);
+        Some((text, line, column))
+    }
+
+    fn respond(&mut self, method: &str, params: &Json) -> Result<Json, (i32, String)> {
+        match method {
+            "initialize" => Ok(Json::object([
+                (
+                    "capabilities",
+                    Json::object([
+                        ("textDocumentSync", Json::Number(1.0)),
+                        ("hoverProvider", Json::Bool(true)),
+                        (
+                            "completionProvider",
+                            Json::object([("triggerCharacters", Json::Array(vec![Json::String(":".to_string())]))]),
+                        ),
+                    ]),
+                ),
+                (
+                    "serverInfo",
+                    Json::object([
+                        ("name", Json::String("poly-lsp".to_string())),
+                        ("version", Json::String(env!("CARGO_PKG_VERSION").to_string())),
+                    ]),
+                ),
+            ])),
+            "shutdown" => Ok(Json::Null),
+            "textDocument/hover" => Ok(self
+                .cursor(params)
+                .and_then(|(text, line, column)| hover(text, line, column))
+                .map_or(Json::Null, |markdown| {
+                    Json::object([(
+                        "contents",
+                        Json::object([("kind", Json::String("markdown".to_string())), ("value", Json::String(markdown))]),
+                    )])
+                })),
+            "textDocument/completion" => Ok(Json::Array(
+                self.cursor(params).map_or(Vec::new(), |(text, line, column)| completions(text, line, column)),
+            )),
+            _ => Err((-32601, format!("Unsupported method '{}'", method))),
+        }
+    }
+
+    /// Handles a message from the editor and returns the messages to send back, or `None` once the editor asks
+    /// the server to exit.
+    pub fn handle(&mut self, message: &str) -> Option<Vec<String>> {
+        let message = match Json::parse(message) {
+            Ok(message) => message,
+            Err(e) => {
+                let error = Json::object([("code", Json::Number(-32700.0)), ("message", Json::String(e))]);
+                let reply = Json::object([
+                    ("jsonrpc", Json::String("2.0".to_string())),
+                    ("id", Json::Null),
+                    ("error", error),
+                ]);
+                return Some(vec![reply.to_string()]);
+            }
+        };
+        let method = message.get("method").as_str().unwrap_or_default();
+        let params = message.get("params");
+        let uri = params.at(&["textDocument", "uri"]).as_str().unwrap_or_default().to_string();
+        match (method, message.get("id")) {
+            ("exit", _) => return None,
+            ("textDocument/didOpen", Json::Null) => {
+                let text = params.at(&["textDocument", "text"]).as_str().unwrap_or_default();
+                self.documents.insert(uri.clone(), text.to_string());
+            }
+            ("textDocument/didChange", Json::Null) => {
+                // Documents are synced in full, so the last change holds the whole text.
+                if let Json::Array(changes) = params.get("contentChanges") {
+                    if let Some(text) = changes.last().and_then(|c| c.get("text").as_str()) {
+                        self.documents.insert(uri.clone(), text.to_string());
+                    }
+                }
+            }
+            ("textDocument/didClose", Json::Null) => {
+                self.documents.remove(&uri);
+            }
+            (_, Json::Null) => return Some(Vec::new()),
+            (_, id) => {
+                let id = id.clone();
+                let reply = match self.respond(method, params) {
+                    Ok(result) => ("result", result),
+                    Err((code, message)) => (
+                        "error",
+                        Json::object([("code", Json::Number(code as f64)), ("message", Json::String(message))]),
+                    ),
+                };
+                let reply = Json::object([("jsonrpc", Json::String("2.0".to_string())), ("id", id), reply]);
+                return Some(vec![reply.to_string()]);
+            }
+        }
+        Some(vec![self.publish_diagnostics(&uri).to_string()])
+    }
+}
+
+/// Reads a message framed with a `Content-Length` header, `None` at the end of the input.
+fn read_message<R: BufRead>(input: &mut R) -> io::Result<Option<String>> {
+    let mut length = None;
+    loop {
+        let mut header = String::new();
+        if input.read_line(&mut header)? == 0 {
+            return Ok(None);
+        }
+        let header = header.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.eq_ignore_ascii_case("content-length") {
+                length = value.trim().parse::<usize>().ok();
+            }
+        }
+    }
+    let length = length.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "No Content-Length header"))?;
+    if length > MAX_MESSAGE_LENGTH {
+        let message = format!("Content-Length {} is over the limit of {} bytes", length, MAX_MESSAGE_LENGTH);
+        return Err(io::Error::new(io::ErrorKind::InvalidData, message));
+    }
+    let mut body = vec![0; length];
+    input.read_exact(&mut body)?;
+    String::from_utf8(body).map(Some).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// Serves an editor over the input and the output, usually stdin and stdout, until it asks to exit.
+pub fn run<R: BufRead, W: Write>(mut input: R, mut output: W) -> io::Result<()> {
+    let mut server = Server::default();
+    while let Some(message) = read_message(&mut input)? {
+        let Some(replies) = server.handle(&message) else { break };
+        for reply in replies {
+            write!(output, "Content-Length: {}\r\n\r\n{}", reply.len(), reply)?;
+        }
+        output.flush()?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+static FILE: &str = "# groove\nversion: 1\nkick: 8x-- (3,16xg) E(3,8)\nsnare: 4-x\n";
+
+#[test]
+fn test_hover() {
+    assert_eq!(hover(FILE, 2, 13).unwrap(), "`(3,16xg)` takes 1.5 beats, the whole pattern 5 beats");
+    assert_eq!(hover(FILE, 2, 8).unwrap(), "`8x-- (3,16xg)` takes 3 beats, the whole pattern 5 beats");
+    assert_eq!(hover(FILE, 2, 22).unwrap(), "`E(3,8)` takes 2 beats, the whole pattern 5 beats");
+    assert_eq!(hover(FILE, 3, 1).unwrap(), "**snare**: the pattern takes 2 beats");
+    assert_eq!(hover("tom1: 8tx-x(2,4t(8-x))", 0, 17).unwrap(), "`(8-x)` takes 1 beat, the whole pattern 3 beats");
+    assert_eq!(hover(FILE, 2, 40), None);
+    assert_eq!(hover(FILE, 0, 2), None);
+}
+
+#[test]
+fn test_completions() {
+    let labels = |items: Vec<Json>| -> Vec<String> {
+        items.iter().map(|i| i.get("label").as_str().unwrap().to_string()).collect()
+    };
+    let parts = labels(completions(FILE, 4, 0));
+    assert!(!parts.contains(&"kick".to_string()) && !parts.contains(&"version".to_string()));
+    assert_eq!(parts[0], "hi-hat");
+    assert_eq!(labels(completions(FILE, 3, 6)), ["backbeat", "ghosted backbeat"]);
+    assert_eq!(completions(FILE, 3, 6)[0].get("insertText").as_str(), Some("4-x"));
+    assert!(labels(completions("", 0, 0)).contains(&"version".to_string()));
+}
+
+#[test]
+fn test_server() {
+    let mut server = Server::default();
+    let initialize = server.handle(r#"{"jsonrpc":"2.0","id":1,"method":"initialize","params":{}}"#).unwrap();
+    assert!(initialize[0].starts_with(r#"{"jsonrpc":"2.0","id":1,"result":{"capabilities":"#));
+    let open = r#"{"jsonrpc":"2.0","method":"textDocument/didOpen","params":{"textDocument":
+        {"uri":"file:///g.poly","languageId":"poly","version":1,"text":"kick: 8x-(x\n"}}}"#;
+    assert_eq!(
+        server.handle(open).unwrap(),
+        vec![
+            "{\"jsonrpc\":\"2.0\",\"method\":\"textDocument/publishDiagnostics\",\"params\":{\"uri\":\"file:///g.poly\",\
+             \"diagnostics\":[{\"range\":{\"start\":{\"line\":0,\"character\":9},\"end\":{\"line\":0,\"character\":11}},\
+             \"severity\":1,\"source\":\"poly\",\"message\":\"the pattern is malformed here: '(x'\"}]}}"
+        ]
+    );
+    let change = r#"{"jsonrpc":"2.0","method":"textDocument/didChange","params":{"textDocument":{"uri":"file:///g.poly"},
+        "contentChanges":[{"text":"kick: 8x-x\n"}]}}"#;
+    assert!(server.handle(change).unwrap()[0].ends_with("\"diagnostics\":[]}}"));
+    let hover = r#"{"jsonrpc":"2.0","id":2,"method":"textDocument/hover","params":{"textDocument":{"uri":"file:///g.poly"},
+        "position":{"line":0,"character":7}}}"#;
+    assert_eq!(
+        server.handle(hover).unwrap(),
+        vec![
+            "{\"jsonrpc\":\"2.0\",\"id\":2,\"result\":{\"contents\":{\"kind\":\"markdown\",\
+             \"value\":\"`8x-x` takes 1.5 beats, the whole pattern 1.5 beats\"}}}"
+        ]
+    );
+    let unknown = server.handle(r#"{"jsonrpc":"2.0","id":3,"method":"textDocument/rename","params":{}}"#).unwrap();
+    assert!(unknown[0].contains("\"error\":{\"code\":-32601"));
+    assert_eq!(server.handle(r#"{"jsonrpc":"2.0","method":"initialized","params":{}}"#), Some(vec![]));
+    assert_eq!(server.handle(r#"{"jsonrpc":"2.0","method":"exit"}"#), None);
+}
+
+#[test]
+fn test_run() {
+    let message = |body: &str| format!("Content-Length: {}\r\n\r\n{}", body.len(), body);
+    let input = [
+        message(r#"{"jsonrpc":"2.0","id":1,"method":"shutdown"}"#),
+        message(r#"{"jsonrpc":"2.0","method":"exit"}"#),
+        message(r#"{"jsonrpc":"2.0","id":2,"method":"shutdown"}"#),
+    ]
+    .concat();
+    let mut output = Vec::new();
+    run(input.as_bytes(), &mut output).unwrap();
+    assert_eq!(String::from_utf8(output).unwrap(), message(r#"{"jsonrpc":"2.0","id":1,"result":null}"#));
+    let huge = "Content-Length: 99999999999\r\n\r\n{}";
+    assert_eq!(run(huge.as_bytes(), &mut Vec::new()).unwrap_err().kind(), io::ErrorKind::InvalidData);
+}
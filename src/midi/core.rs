@@ -1,9 +1,10 @@
 extern crate derive_more;
 use std::cmp::Ordering;
 use std::cmp::Ordering::*;
+use std::cmp::min;
 use std::collections::BTreeMap;
 use std::iter::Peekable;
-use std::{str::FromStr};
+use std::str::FromStr;
 
 use midly::{
     num::u24, num::u28, num::u4, num::u7, Header, MidiMessage, Smf, TrackEventKind,
@@ -12,12 +13,19 @@ use midly::{MetaMessage, TrackEvent};
 
 use crate::dsl::dsl::{
     BasicLength, Group, GroupOrNote, Groups,
-    KnownLength, Length, ModdedLength, Note, Times,
-    groups, group_or_delimited_group, flatten_group,
-    EIGHTH, FOURTH, SIXTEENTH
+    KnownLength, Length, ModdedLength, Note, Times, DEFAULT_DYNAMIC,
 };
-
+#[cfg(test)]
+use crate::dsl::dsl::{groups, group_or_delimited_group, flatten_group, HALF, SIXTEENTH};
+
+use crate::dsl::variation::{vary, Variation};
+use crate::error::PolyError;
+use crate::midi::doubling::{self, doubling_track, Doubling};
+use crate::midi::ensemble;
+use crate::midi::humanize::Humanize;
 use crate::midi::time::TimeSignature;
+use crate::midi::transform::{pair_notes, unpair_notes, Ending, PairedNote, Transform, TransformContext};
+use crate::random::{Domain, Streams};
 #[allow(unused_imports)]
 use GroupOrNote::*;
 #[allow(unused_imports)]
@@ -64,23 +72,34 @@ fn test_add_tick() {
 #[repr(transparent)]
 pub struct Delta(pub u128);
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd)]
+/// MIDI velocity of a note.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[repr(transparent)]
+pub struct Velocity(pub u8);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum EventType {
-    NoteOn(Part),
+    NoteOn(Part, Velocity),
     NoteOff(Part),
 }
 
 use EventType::*;
 
+impl PartialOrd for EventType {
+    fn partial_cmp(&self, other: &EventType) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
 impl Ord for EventType {
     fn cmp(&self, other: &EventType) -> Ordering {
         match (self, other) {
-            (NoteOn(a), NoteOn(b)) => a.cmp(b),
-            (NoteOn(a), NoteOff(b)) => match a.cmp(b) {
+            (NoteOn(a, va), NoteOn(b, vb)) => a.cmp(b).then(va.cmp(vb)),
+            (NoteOn(a, _), NoteOff(b)) => match a.cmp(b) {
                 Equal => Greater,
                 ord => ord,
             },
-            (NoteOff(a), NoteOn(b)) => match a.cmp(b) {
+            (NoteOff(a), NoteOn(b, _)) => match a.cmp(b) {
                 Equal => Less,
                 ord => ord,
             },
@@ -93,13 +112,60 @@ impl Ord for EventType {
 pub enum DrumPart {
     KickDrum,
     SnareDrum,
+    /// Closed hi-hat.
     HiHat,
-    CrashCymbal
+    CrashCymbal,
+    OpenHiHat,
+    RideCymbal,
+    /// High tom.
+    Tom1,
+    /// Mid tom.
+    Tom2,
+    /// Floor tom.
+    Tom3,
 }
 
 #[allow(unused_imports)]
 use DrumPart::*;
 
+impl DrumPart {
+    pub const ALL: [DrumPart; 9] = [
+        KickDrum, SnareDrum, HiHat, CrashCymbal, OpenHiHat, RideCymbal, Tom1, Tom2, Tom3,
+    ];
+
+    /// Identifies the part among the keys of the random generators it draws from, see `Rng::derive`. Fixed, so
+    /// the same seed keeps giving the same results as parts are added or removed.
+    pub fn seed_key(self) -> u64 {
+        self as u64 + 1
+    }
+
+    /// Short name, as used in the command line.
+    pub fn name(self) -> &'static str {
+        match self {
+            KickDrum => "kick",
+            SnareDrum => "snare",
+            HiHat => "hi-hat",
+            CrashCymbal => "crash",
+            OpenHiHat => "open-hi-hat",
+            RideCymbal => "ride",
+            Tom1 => "tom1",
+            Tom2 => "tom2",
+            Tom3 => "tom3",
+        }
+    }
+}
+
+impl FromStr for DrumPart {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        DrumPart::ALL
+            .into_iter()
+            .find(|part| part.name() == s)
+            .ok_or_else(|| format!("Unknown drum part '{}'", s))
+    }
+}
+
 trait ToMidi {
     fn to_midi_key(&self) -> u7;
 }
@@ -110,8 +176,14 @@ impl ToMidi for DrumPart {
         match self {
             KickDrum => u7::from(36),
             SnareDrum => u7::from(38),
-            HiHat => u7::from(46),
+            // The closed hi-hat, it was written to 46 until the open hi-hat had a part of its own.
+            HiHat => u7::from(42),
             CrashCymbal => u7::from(49),
+            OpenHiHat => u7::from(46),
+            RideCymbal => u7::from(51),
+            Tom1 => u7::from(48),
+            Tom2 => u7::from(45),
+            Tom3 => u7::from(43),
         }
     }
 }
@@ -121,20 +193,93 @@ impl ToMidi for Part {
         match self {
             Drum(dp) => dp.to_midi_key(),
             Bass => 28.into(), // low E
-        }       
+            // Percussion sounds that are easy to tell apart from the drum kit and each other.
+            Click(KickDrum) => 77.into(), // low wood block
+            Click(SnareDrum) => 76.into(), // high wood block
+            Click(HiHat) => 75.into(), // claves
+            Click(CrashCymbal) => 56.into(), // cowbell
+            Click(OpenHiHat) => 54.into(), // tambourine
+            Click(RideCymbal) => 81.into(), // open triangle
+            Click(Tom1) => 60.into(), // high bongo
+            Click(Tom2) => 61.into(), // low bongo
+            Click(Tom3) => 64.into(), // low conga
+        }
     }
 }
 
+/// MIDI keys of the drum parts. Defaults to the General MIDI percussion key map, but not every drum
+/// sampler follows it, so any part can be moved to another key.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DrumMap(BTreeMap<DrumPart, u7>);
+
+impl DrumMap {
+    pub fn set(&mut self, part: DrumPart, key: u7) {
+        self.0.insert(part, key);
+    }
+
+    /// The drum part played with the key, if any.
+    pub fn part(&self, key: u7) -> Option<DrumPart> {
+        DrumPart::ALL.into_iter().find(|part| self.key(Drum(*part)) == key)
+    }
+
+    /// Parts moved off their default keys.
+    pub fn entries(&self) -> impl Iterator<Item = (DrumPart, u7)> + '_ {
+        self.0.iter().map(|(part, key)| (*part, *key))
+    }
+
+    /// The same map without the parts set to their default keys, which play just like parts left alone.
+    pub fn without_defaults(&self) -> DrumMap {
+        DrumMap(self.0.iter().filter(|(part, key)| **key != part.to_midi_key()).map(|(p, k)| (*p, *k)).collect())
+    }
+
+    fn key(&self, part: Part) -> u7 {
+        match part {
+            Drum(dp) => self.0.get(&dp).copied().unwrap_or_else(|| dp.to_midi_key()),
+            _ => part.to_midi_key(),
+        }
+    }
+}
+
+/// Parses a single drum map entry like `ride=59`.
+pub fn parse_drum_mapping(s: &str) -> Result<(DrumPart, u7), String> {
+    let (part, key) = s
+        .split_once('=')
+        .ok_or_else(|| format!("Expected '<part>=<key>', got '{}'", s))?;
+    let part = DrumPart::from_str(part)?;
+    match key.parse::<u8>() {
+        Ok(k) if k <= 127 => Ok((part, u7::from(k))),
+        _ => Err(format!("{} is not a MIDI key from 0 to 127", key)),
+    }
+}
+
+#[test]
+fn test_drum_map() {
+    let mut map = DrumMap::default();
+    assert_eq!(map.key(Drum(RideCymbal)), u7::from(51));
+    let (part, key) = parse_drum_mapping("ride=59").unwrap();
+    map.set(part, key);
+    assert_eq!(map.key(Drum(RideCymbal)), u7::from(59));
+    assert_eq!(map.key(Drum(HiHat)), u7::from(42));
+    assert_eq!(map.key(Click(RideCymbal)), u7::from(81));
+    assert_eq!(map.part(u7::from(59)), Some(RideCymbal));
+    assert_eq!(map.part(u7::from(51)), None);
+    assert!(parse_drum_mapping("cowbell=56").is_err());
+    assert!(parse_drum_mapping("ride=128").is_err());
+    assert!(parse_drum_mapping("ride").is_err());
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, PartialOrd, Ord, Eq, Hash)]
 pub enum Part {
     Drum(DrumPart),
-    Bass
+    Bass,
+    /// Marks cycles of the drum part's pattern.
+    Click(DrumPart),
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct Event<T> {
-    tick: T,
-    event_type: EventType,
+    pub tick: T,
+    pub event_type: EventType,
 }
 
 impl<T> Event<T> {
@@ -183,10 +328,11 @@ where
 }
 
 #[test]
+#[allow(clippy::unnecessary_sort_by)]
 fn test_ord_event_t() {
     let first_on = Event {
         tick: Tick(0),
-        event_type: NoteOn(Drum(KickDrum)),
+        event_type: NoteOn(Drum(KickDrum), Velocity(100)),
     };
     let first_off = Event {
         tick: Tick(24),
@@ -194,7 +340,7 @@ fn test_ord_event_t() {
     };
     let second_on = Event {
         tick: Tick(24),
-        event_type: NoteOn(Drum(KickDrum)),
+        event_type: NoteOn(Drum(KickDrum), Velocity(100)),
     };
     assert_eq!(first_on.cmp(&first_off), Less);
     assert_eq!(first_off.cmp(&second_on), Less);
@@ -221,19 +367,11 @@ pub struct EventGrid<T> {
 
 impl EventGrid<Tick> {
     pub fn new(events: Vec<Event<Tick>>, end: Tick) -> EventGrid<Tick> {
-        let start = if events.len() == 0 {
-            Tick(0)
-        } else {
-            match events.first() {
-                Some(x) => x.tick,
-                None => Tick(0),
-            }
+        let start = match events.first() {
+            Some(x) => x.tick,
+            None => Tick(0),
         };
-        EventGrid {
-            events,
-            start: start,
-            end: end,
-        }
+        EventGrid { events, start, end }
     }
 }
 
@@ -252,23 +390,26 @@ impl<T> EventGrid<T> {
     }
 }
 
-// FIXME: add a mutable version for use in `groups_to_event_grid`
 /// Adds two EventGrids together, manipulates the time of the right `EventGrid` by
 /// adding the length of the left one to timings.
 impl EventGrid<Tick> {
     pub fn concat(&self, other: EventGrid<Tick>) -> EventGrid<Tick> {
-        // FIXME: get rid of unnecessary cloning
-        let other_events = other.clone().events.into_iter().map(|mut e| {
-            e.tick = e.tick + self.length();
+        let mut events = Vec::with_capacity(self.events.len() + other.events.len());
+        events.extend_from_slice(&self.events);
+        let mut grid = EventGrid { events, start: self.start, end: self.end };
+        grid.append(other);
+        grid
+    }
+
+    /// Same as `concat`, but appends `other` in place instead of copying both grids.
+    pub fn append(&mut self, other: EventGrid<Tick>) {
+        let offset = self.length();
+        let other_length = other.length();
+        self.events.extend(other.events.into_iter().map(|mut e| {
+            e.tick = e.tick + offset;
             e
-        });
-        let mut self_event_copy = self.events.clone();
-        self_event_copy.extend(other_events);
-        EventGrid {
-            events: self_event_copy,
-            start: self.start,
-            end: self.start + self.length() + other.length(),
-                }
+        }));
+        self.end = self.start + offset + other_length;
     }
 }
 
@@ -277,7 +418,7 @@ fn test_concat_event_grid() {
     let empty: EventGrid<Tick> = EventGrid::empty();
     let kick_on = Event {
         tick: Tick(0),
-        event_type: NoteOn(Drum(KickDrum)),
+        event_type: NoteOn(Drum(KickDrum), Velocity(100)),
     };
     let kick_off = Event {
         tick: Tick(24),
@@ -296,7 +437,7 @@ fn test_concat_event_grid() {
         events: vec![
             Event {
                 tick: Tick(12),
-                event_type: NoteOn(Drum(HiHat)),
+                event_type: NoteOn(Drum(HiHat), Velocity(100)),
             },
             Event {
                 tick: Tick(24),
@@ -309,7 +450,7 @@ fn test_concat_event_grid() {
     assert_eq!(
         input.concat(input.clone()),
         EventGrid {
-            events: vec![Event { tick: Tick(12), event_type: NoteOn(Drum(HiHat)) }, Event { tick: Tick(24), event_type: NoteOff(Drum(HiHat)) }, Event { tick: Tick(24), event_type: NoteOn(Drum(HiHat)) }, Event { tick: Tick(36), event_type: NoteOff(Drum(HiHat)) }],
+            events: vec![Event { tick: Tick(12), event_type: NoteOn(Drum(HiHat), Velocity(100)) }, Event { tick: Tick(24), event_type: NoteOff(Drum(HiHat)) }, Event { tick: Tick(24), event_type: NoteOn(Drum(HiHat), Velocity(100)) }, Event { tick: Tick(36), event_type: NoteOff(Drum(HiHat)) }],
             start: Tick(12),
             end: Tick(36)
         }
@@ -370,7 +511,7 @@ impl BasicLength {
 
 impl ModdedLength {
     /// `ModdedLength` to MIDI Ticks
-    fn to_ticks(&self) -> Tick {
+    fn to_ticks(self) -> Tick {
         match self {
             ModdedLength::Plain(blen) => blen.to_ticks(),
             ModdedLength::Dotted(blen) => {
@@ -390,15 +531,15 @@ impl Length {
     /// Arguments:
     ///
     /// * `length`: `length` is a variable of type `Length`, which is an enum that represents different
-    /// types of musical note lengths. The function `length_to_ticks` takes a `Length` as input and returns
-    /// a `Tick`, which is a struct representing the number of ticks (a unit of time in music
+    ///   types of musical note lengths. The function `length_to_ticks` takes a `Length` as input and returns
+    ///   a `Tick`, which is a struct representing the number of ticks (a unit of time in music
     ///
     /// Returns:
     ///
     /// The function `length_to_ticks` takes a `Length` enum as input and returns a `Tick` value. The `Tick`
     /// value represents the duration of the note in ticks, which is a unit of time used in music notation
     /// software.
-    fn to_ticks(&self) -> Tick {
+    fn to_ticks(self) -> Tick {
         match self {
             Length::Simple(mlen) => mlen.to_ticks(),
             Length::Tied(first, second) => first.to_ticks() + second.to_ticks(),
@@ -412,7 +553,7 @@ impl Length {
 }
 
 #[allow(dead_code)]
-static MICROSECONDS_PER_MINUTE: u128 = 60000000 as u128;
+static MICROSECONDS_PER_MINUTE: u128 = 60000000;
 
 #[allow(dead_code)]
 static MIDI_CLOCKS_PER_CLICK: u8 = 24;
@@ -431,10 +572,10 @@ static MIDI_CLOCKS_PER_CLICK: u8 = 24;
     derive_more::Mul,
     derive_more::Display,
 )]
-pub struct MidiTempo(u24);
+pub struct MidiTempo(pub(crate) u24);
 
 impl MidiTempo {
-    fn from_tempo(tempo: u16) -> Self {
+    pub(crate) fn from_tempo(tempo: u16) -> Self {
         let mt = MICROSECONDS_PER_MINUTE as u32 / tempo as u32;
         Self(mt.into())
     }
@@ -446,27 +587,29 @@ fn group_to_event_grid(
     Group {
         notes,
         length,
+        dynamic,
         ..
     }: &Group<Note, ()>,
     part: Part,
     start: &Tick,
 ) -> EventGrid<Tick> {
-    let mut time = start.clone();
+    let mut time = *start;
     let note_length = length.to_ticks();
+    let dynamic = dynamic.unwrap_or(DEFAULT_DYNAMIC);
     let mut grid = EventGrid::empty();
     grid.start = *start;
     notes.iter().for_each(|entry| {
-        match entry {
-            Note::Rest => {
+        match entry.velocity(dynamic) {
+            None => {
                 let rest_end = time + note_length;
                 time = rest_end;
                 grid.end = rest_end;
             }
-            Note::Hit => {
+            Some(velocity) => {
                 let note_end = time + note_length;
                 let note_on = Event {
                     tick: time,
-                    event_type: NoteOn(part),
+                    event_type: NoteOn(part, Velocity(velocity)),
                 };
                 let note_off = Event {
                     tick: note_end,
@@ -486,15 +629,16 @@ fn group_to_event_grid(
 fn test_group_to_event_grid() {
     let start_time = Tick(12);
     let group = Group {
+        dynamic: None,
         notes: vec![Hit, Hit],
-        length: SIXTEENTH.clone(),
+        length: *SIXTEENTH,
         times: (),
     };
     let grid = EventGrid {
         events: vec![
-            Event { tick: Tick(12), event_type: NoteOn(Drum(HiHat)) },
+            Event { tick: Tick(12), event_type: NoteOn(Drum(HiHat), Velocity(100)) },
             Event { tick: Tick(24), event_type: NoteOff(Drum(HiHat)) },
-            Event { tick: Tick(24), event_type: NoteOn(Drum(HiHat)) },
+            Event { tick: Tick(24), event_type: NoteOn(Drum(HiHat), Velocity(100)) },
             Event { tick: Tick(36), event_type: NoteOff(Drum(HiHat)) }
         ],
         start: start_time,
@@ -507,18 +651,23 @@ fn test_group_to_event_grid() {
     //         KickDrum,
     //         &start_time
     //     ),
-    //     EventGrid { events: vec![Event { tick: Tick(0), event_type: NoteOn(Drum(KickDrum)) }, Event { tick: Tick(24), event_type: NoteOff(Drum(KickDrum)) }, Event { tick: Tick(72), event_type: NoteOn(Drum(KickDrum)) }, Event { tick: Tick(96), event_type: NoteOff(Drum(KickDrum)) }], length: Tick(144) }
+    //     EventGrid { events: vec![Event { tick: Tick(0), event_type: NoteOn(Drum(KickDrum), Velocity(100)) }, Event { tick: Tick(24), event_type: NoteOff(Drum(KickDrum)) }, Event { tick: Tick(72), event_type: NoteOn(Drum(KickDrum), Velocity(100)) }, Event { tick: Tick(96), event_type: NoteOff(Drum(KickDrum)) }], length: Tick(144) }
     // );
 }
 
 fn concat_grid(event_grid: EventGrid<Tick>, times: Times) -> EventGrid<Tick> {
-    if times.0 <= 0 {
+    if times.0 == 0 {
         EventGrid::empty()
     } else {
-        // FIXME: think about unnecessary cloning
-        (0..)
-            .take((times.0 - 1) as usize)
-            .fold(event_grid.clone(), |acc, _| acc.concat(event_grid.clone()))
+        let mut grid = EventGrid {
+            events: Vec::with_capacity(event_grid.events.len() * times.0 as usize),
+            start: event_grid.start,
+            end: event_grid.start,
+        };
+        for _ in 0..times.0 {
+            grid.append(event_grid.clone());
+        }
+        grid
     }
 }
 
@@ -530,7 +679,7 @@ fn test_concat_grid() {
                 events: vec![
                     Event {
                         tick: Tick(12),
-                        event_type: NoteOn(Drum(HiHat))
+                        event_type: NoteOn(Drum(HiHat), Velocity(100))
                     },
                     Event {
                         tick: Tick(24),
@@ -542,7 +691,7 @@ fn test_concat_grid() {
             },
             Times(2)
         ),
-        EventGrid { events: vec![Event { tick: Tick(12), event_type: NoteOn(Drum(HiHat)) }, Event { tick: Tick(24), event_type: NoteOff(Drum(HiHat)) }, Event { tick: Tick(24), event_type: NoteOn(Drum(HiHat)) }, Event { tick: Tick(36), event_type: NoteOff(Drum(HiHat)) }], start: Tick(12), end: Tick(36) }
+        EventGrid { events: vec![Event { tick: Tick(12), event_type: NoteOn(Drum(HiHat), Velocity(100)) }, Event { tick: Tick(24), event_type: NoteOff(Drum(HiHat)) }, Event { tick: Tick(24), event_type: NoteOn(Drum(HiHat), Velocity(100)) }, Event { tick: Tick(36), event_type: NoteOff(Drum(HiHat)) }], start: Tick(12), end: Tick(36) }
     );
 }
 
@@ -565,32 +714,26 @@ fn groups_to_event_grid(part: Part, groups: &Groups) -> EventGrid<Tick> {
 
 #[derive(Clone, Debug)]
 pub(crate) struct EventIterator {
-    kick: Peekable<std::vec::IntoIter<Event<Tick>>>,
-    snare: Peekable<std::vec::IntoIter<Event<Tick>>>,
-    hihat: Peekable<std::vec::IntoIter<Event<Tick>>>,
-    crash: Peekable<std::vec::IntoIter<Event<Tick>>>,
+    parts: BTreeMap<DrumPart, Peekable<std::vec::IntoIter<Event<Tick>>>>,
+    #[allow(dead_code)]
     time_signature: TimeSignature,
-    bars: u32
+    pub(crate) bars: u32
 }
 
 impl EventIterator {
     fn new(
-        kick_grid: EventGrid<Tick>,
-        snare_grid: EventGrid<Tick>,
-        hihat_grid: EventGrid<Tick>,
-        crash_grid: EventGrid<Tick>,
+        grids: BTreeMap<DrumPart, EventGrid<Tick>>,
         time_signature: TimeSignature,
         bars: u32
     ) -> EventIterator {
-        let event_iterator = EventIterator {
-            kick: kick_grid.into_iter().peekable(),
-            snare: snare_grid.into_iter().peekable(),
-            hihat: hihat_grid.into_iter().peekable(),
-            crash: crash_grid.into_iter().peekable(),
+        EventIterator {
+            parts: grids
+                .into_iter()
+                .map(|(part, grid)| (part, grid.into_iter().peekable()))
+                .collect(),
             time_signature,
             bars
-        };
-        event_iterator
+        }
     }
 }
 
@@ -599,30 +742,20 @@ impl Iterator for EventIterator {
 
     #[inline]
     fn next(&mut self) -> Option<Self::Item> {
-        let candidates: BTreeMap<DrumPart, Event<Tick>> = [
-            (KickDrum, self.kick.peek()),
-            (SnareDrum, self.snare.peek()),
-            (HiHat, self.hihat.peek()),
-            (CrashCymbal, self.crash.peek()),
-        ]
-        .into_iter()
-        .filter_map(|(p, x)| match x {
-            Some(x) => Some((p, *x)),
-            None => None,
-        })
-        .collect();
+        let min_part = self
+            .parts
+            .iter_mut()
+            .filter_map(|(p, x)| x.peek().map(|x| (*p, *x)))
+            .min_by_key(|(_, x)| *x)
+            .map(|(p, _)| p);
+
+        min_part.and_then(|p| self.parts.get_mut(&p).and_then(|x| x.next()))
+    }
 
-        if let Some((min_part, min_event)) = candidates.iter().min_by_key(|(_, x)| *x) {
-            match min_part {
-                KickDrum => self.kick.next(),
-                SnareDrum => self.snare.next(),
-                HiHat => self.hihat.next(),
-                CrashCymbal => self.crash.next(),
-            };
-            Some(*min_event)
-        } else {
-            None
-        }
+    /// Exact, so collecting the events allocates just once.
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let remaining = self.parts.values().map(|x| x.len()).sum();
+        (remaining, Some(remaining))
     }
 }
 
@@ -630,37 +763,37 @@ impl Iterator for EventIterator {
 fn test_event_iterator_impl() {
     let empty = EventGrid::empty();
     let kick1 = group_to_event_grid(
-        &flatten_group(group_or_delimited_group("(4x-)").unwrap().1)
+        flatten_group(group_or_delimited_group("(4x-)").unwrap().1)
             .0
             .first()
             .unwrap(),
         Drum(KickDrum),
-        &mut Tick(0),
+        &Tick(0),
     );
     let snare1 = group_to_event_grid(
-        &flatten_group(group_or_delimited_group("(4-x)").unwrap().1)
+        flatten_group(group_or_delimited_group("(4-x)").unwrap().1)
             .0
             .first()
             .unwrap(),
         Drum(SnareDrum),
-        &mut Tick(0),
+        &Tick(0),
     );
 
     assert_eq!(
         EventIterator::new(
-            kick1.clone(),
-            snare1.clone(),
-            empty.clone(),
-            empty.clone(),
+            BTreeMap::from_iter([
+                (KickDrum, kick1.clone()),
+                (SnareDrum, snare1.clone()),
+                (HiHat, empty.clone()),
+            ]),
             TimeSignature::from_str("4/4").unwrap(),
             1
         )
-        .into_iter()
         .collect::<Vec<Event<Tick>>>(),
         vec![
             Event {
                 tick: Tick(0),
-                event_type: NoteOn(Drum(KickDrum))
+                event_type: NoteOn(Drum(KickDrum), Velocity(100))
             },
             Event {
                 tick: Tick(48),
@@ -668,7 +801,7 @@ fn test_event_iterator_impl() {
             },
             Event {
                 tick: Tick(48),
-                event_type: NoteOn(Drum(SnareDrum))
+                event_type: NoteOn(Drum(SnareDrum), Velocity(100))
             },
             Event {
                 tick: Tick(96),
@@ -679,19 +812,15 @@ fn test_event_iterator_impl() {
 
     assert_eq!(
         EventIterator::new(
-            kick1.clone(),
-            empty.clone(),
-            empty.clone(),
-            empty.clone(),
+            BTreeMap::from_iter([(KickDrum, kick1.clone()), (SnareDrum, empty.clone())]),
             TimeSignature::from_str("4/4").unwrap(),
             1
         )
-        .into_iter()
         .collect::<Vec<Event<Tick>>>(),
         [
             Event {
                 tick: Tick(0),
-                event_type: NoteOn(Drum(KickDrum))
+                event_type: NoteOn(Drum(KickDrum), Velocity(100))
             },
             Event {
                 tick: Tick(48),
@@ -705,52 +834,93 @@ fn test_event_iterator_impl() {
 /// Calling .collect() on this EventIterator should produce an `EventGrid`.
 ///
 /// Returns time as a number of ticks from beginning, has to be turned into the midi delta-time.
-fn merge_into_iterator(
+///
+/// With `variation`, every repetition of a drum part's pattern but the first one is mutated with `vary`.
+///
+/// Drum parts are laid out on up to `threads` threads. The result is the same for any number of threads:
+/// every bar of every part draws from a random generator of its own, see `Rng::derive`, and the parts are merged
+/// in the order of drum parts whichever thread finishes first. Changing one part doesn't change how the others vary.
+pub(crate) fn merge_into_iterator(
     groups: &BTreeMap<DrumPart, Groups>,
     time_signature: TimeSignature,
-) -> EventIterator {
-    // Maps a drum part to a number of 128th notes
-    let length_map: BTreeMap<DrumPart, u32> = groups.iter().map(|(k, x)| (*k, x.to_128th())).collect();
-
+    variation: Option<Variation>,
+    threads: usize,
+) -> Result<EventIterator, PolyError> {
     // We want exactly length_limit or BAR_LIMIT
     let converges_over_bars = time_signature
         .converges(groups.values())
-        .unwrap_or(BAR_LIMIT.clone());
-
-    if converges_over_bars == 1 {
-        println!("Converges over {} bar", converges_over_bars);
-    } else {
-        println!("Converges over {} bars", converges_over_bars);
-    }
+        .unwrap_or(BAR_LIMIT);
 
     // length limit in 128th notes
     let length_limit = converges_over_bars * time_signature.to_128th();
 
-    let to_event_grid = |part: &DrumPart| {
-        match groups.get(part) {
-            Some(groups) => {
-                let length_128th = length_map.get(part).unwrap();
-                let times = length_limit / length_128th;
-                let event_grid = groups_to_event_grid(Drum(*part), groups);
-                (event_grid, times)
+    let jobs: Vec<(DrumPart, &Groups)> = groups.iter().map(|(part, groups)| (*part, groups)).collect();
+    let grids = render_in_parallel(jobs, threads, |(part, groups)| {
+        let times = length_limit / groups.to_128th();
+        let grid = match variation {
+            Some(Variation { amount, seed }) if times > 0 => {
+                let mut streams = Streams::new(seed);
+                let mut grid = groups_to_event_grid(Drum(part), groups);
+                for i in 1..times {
+                    let bar = i * groups.to_128th() / time_signature.to_128th();
+                    let rng = streams.get(&[Domain::Variation.key(), part.seed_key(), bar as u64]);
+                    grid.append(groups_to_event_grid(Drum(part), &vary(groups, amount, rng)));
+                }
+                grid
             }
-            None => (EventGrid::empty(), 0),
-        }
-    };
+            _ => concat_grid(groups_to_event_grid(Drum(part), groups), repeats(times)?),
+        };
+        Ok((part, grid))
+    });
 
-    let (kick_grid, kick_repeats) = to_event_grid(&KickDrum);
-    let (snare_grid, snare_repeats) = to_event_grid(&SnareDrum);
-    let (hihat_grid, hihat_repeats) = to_event_grid(&HiHat);
-    let (crash_grid, crash_repeats) = to_event_grid(&CrashCymbal);
+    Ok(EventIterator::new(grids.into_iter().collect::<Result<_, _>>()?, time_signature, converges_over_bars))
+}
 
-    EventIterator::new(
-        concat_grid(kick_grid, Times(kick_repeats as u16)),
-        concat_grid(snare_grid, Times(snare_repeats as u16)),
-        concat_grid(hihat_grid, Times(hihat_repeats as u16)),
-        concat_grid(crash_grid, Times(crash_repeats as u16)),
-        time_signature,
-        converges_over_bars
-    )
+/// `Times` a grid is repeated, if it fits.
+fn repeats<T: Copy + Into<u128>>(times: T) -> Result<Times, PolyError>
+where
+    u16: TryFrom<T>,
+{
+    u16::try_from(times).map(Times).map_err(|_| PolyError::TooManyRepeats(times.into()))
+}
+
+/// Runs `render` over the jobs on up to `threads` scoped threads, each one taking a contiguous chunk of them.
+/// Results come back in the order of the jobs, so they don't depend on the number of threads or on scheduling.
+fn render_in_parallel<J, R, F>(jobs: Vec<J>, threads: usize, render: F) -> Vec<R>
+where
+    J: Send,
+    R: Send,
+    F: Fn(J) -> R + Sync,
+{
+    if threads <= 1 || jobs.len() <= 1 {
+        return jobs.into_iter().map(render).collect();
+    }
+    let chunk_size = jobs.len().div_ceil(threads);
+    let mut chunks: Vec<Vec<J>> = Vec::new();
+    let mut jobs = jobs.into_iter().peekable();
+    while jobs.peek().is_some() {
+        chunks.push(jobs.by_ref().take(chunk_size).collect());
+    }
+    let render = &render;
+    std::thread::scope(|scope| {
+        let handles: Vec<_> = chunks
+            .into_iter()
+            .map(|chunk| scope.spawn(move || chunk.into_iter().map(render).collect::<Vec<R>>()))
+            .collect();
+        handles
+            .into_iter()
+            .flat_map(|handle| handle.join().expect("Rendering thread panicked"))
+            .collect()
+    })
+}
+
+#[test]
+fn test_render_in_parallel() {
+    let jobs: Vec<u32> = (0..10).collect();
+    let squares: Vec<u32> = jobs.iter().map(|x| x * x).collect();
+    for threads in [0, 1, 3, 4, 10, 16] {
+        assert_eq!(render_in_parallel(jobs.clone(), threads, |x| x * x), squares);
+    }
 }
 
 #[test]
@@ -761,7 +931,7 @@ fn test_merge_into_iterator() {
     let kick_events = vec![
         Event {
             tick: Tick(0),
-            event_type: NoteOn(Drum(KickDrum)),
+            event_type: NoteOn(Drum(KickDrum), Velocity(100)),
         },
         Event {
             tick: Tick(12),
@@ -769,7 +939,7 @@ fn test_merge_into_iterator() {
         },
         Event {
             tick: Tick(12),
-            event_type: NoteOn(Drum(KickDrum)),
+            event_type: NoteOn(Drum(KickDrum), Velocity(100)),
         },
         Event {
             tick: Tick(24),
@@ -777,7 +947,7 @@ fn test_merge_into_iterator() {
         },
         Event {
             tick: Tick(36),
-            event_type: NoteOn(Drum(KickDrum)),
+            event_type: NoteOn(Drum(KickDrum), Velocity(100)),
         },
         Event {
             tick: Tick(48),
@@ -785,7 +955,7 @@ fn test_merge_into_iterator() {
         },
         Event {
             tick: Tick(60),
-            event_type: NoteOn(Drum(KickDrum)),
+            event_type: NoteOn(Drum(KickDrum), Velocity(100)),
         },
         Event {
             tick: Tick(72),
@@ -793,7 +963,7 @@ fn test_merge_into_iterator() {
         },
         Event {
             tick: Tick(72),
-            event_type: NoteOn(Drum(KickDrum)),
+            event_type: NoteOn(Drum(KickDrum), Velocity(100)),
         },
         Event {
             tick: Tick(84),
@@ -801,7 +971,7 @@ fn test_merge_into_iterator() {
         },
         Event {
             tick: Tick(96),
-            event_type: NoteOn(Drum(KickDrum)),
+            event_type: NoteOn(Drum(KickDrum), Velocity(100)),
         },
         Event {
             tick: Tick(108),
@@ -809,7 +979,7 @@ fn test_merge_into_iterator() {
         },
         Event {
             tick: Tick(108),
-            event_type: NoteOn(Drum(KickDrum)),
+            event_type: NoteOn(Drum(KickDrum), Velocity(100)),
         },
         Event {
             tick: Tick(120),
@@ -817,7 +987,7 @@ fn test_merge_into_iterator() {
         },
         Event {
             tick: Tick(132),
-            event_type: NoteOn(Drum(KickDrum)),
+            event_type: NoteOn(Drum(KickDrum), Velocity(100)),
         },
         Event {
             tick: Tick(144),
@@ -825,7 +995,7 @@ fn test_merge_into_iterator() {
         },
         Event {
             tick: Tick(156),
-            event_type: NoteOn(Drum(KickDrum)),
+            event_type: NoteOn(Drum(KickDrum), Velocity(100)),
         },
         Event {
             tick: Tick(168),
@@ -833,7 +1003,7 @@ fn test_merge_into_iterator() {
         },
         Event {
             tick: Tick(168),
-            event_type: NoteOn(Drum(KickDrum)),
+            event_type: NoteOn(Drum(KickDrum), Velocity(100)),
         },
         Event {
             tick: Tick(180),
@@ -843,7 +1013,7 @@ fn test_merge_into_iterator() {
     let snare_events = vec![
         Event {
             tick: Tick(24),
-            event_type: NoteOn(Drum(SnareDrum)),
+            event_type: NoteOn(Drum(SnareDrum), Velocity(100)),
         },
         Event {
             tick: Tick(48),
@@ -851,7 +1021,7 @@ fn test_merge_into_iterator() {
         },
         Event {
             tick: Tick(96),
-            event_type: NoteOn(Drum(SnareDrum)),
+            event_type: NoteOn(Drum(SnareDrum), Velocity(100)),
         },
         Event {
             tick: Tick(120),
@@ -859,7 +1029,7 @@ fn test_merge_into_iterator() {
         },
         Event {
             tick: Tick(24 + 144),
-            event_type: NoteOn(Drum(SnareDrum)),
+            event_type: NoteOn(Drum(SnareDrum), Velocity(100)),
         },
         Event {
             tick: Tick(48 + 144),
@@ -867,7 +1037,7 @@ fn test_merge_into_iterator() {
         },
         Event {
             tick: Tick(96 + 144),
-            event_type: NoteOn(Drum(SnareDrum)),
+            event_type: NoteOn(Drum(SnareDrum), Velocity(100)),
         },
         Event {
             tick: Tick(120 + 144),
@@ -875,7 +1045,7 @@ fn test_merge_into_iterator() {
         },
         Event {
             tick: Tick(24 + 288),
-            event_type: NoteOn(Drum(SnareDrum)),
+            event_type: NoteOn(Drum(SnareDrum), Velocity(100)),
         },
         Event {
             tick: Tick(48 + 288),
@@ -883,7 +1053,7 @@ fn test_merge_into_iterator() {
         },
         Event {
             tick: Tick(96 + 288),
-            event_type: NoteOn(Drum(SnareDrum)),
+            event_type: NoteOn(Drum(SnareDrum), Velocity(100)),
         },
         Event {
             tick: Tick(120 + 288),
@@ -891,7 +1061,7 @@ fn test_merge_into_iterator() {
         },
         Event {
             tick: Tick(24 + 144 * 3),
-            event_type: NoteOn(Drum(SnareDrum)),
+            event_type: NoteOn(Drum(SnareDrum), Velocity(100)),
         },
         Event {
             tick: Tick(48 + 144 * 3),
@@ -899,7 +1069,7 @@ fn test_merge_into_iterator() {
         },
         Event {
             tick: Tick(96 + 144 * 3),
-            event_type: NoteOn(Drum(SnareDrum)),
+            event_type: NoteOn(Drum(SnareDrum), Velocity(100)),
         },
         Event {
             tick: Tick(120 + 144 * 3),
@@ -909,40 +1079,163 @@ fn test_merge_into_iterator() {
     let four_fourth = TimeSignature::from_str("4/4").unwrap();
     let flattened_kick_and_snare = merge_into_iterator(
         &BTreeMap::from_iter([
-            (KickDrum, groups("16xx-x-xx-").unwrap().1),
-            (SnareDrum, groups("8-x--x-").unwrap().1),
+            (KickDrum, groups("16xx-x-xx-").unwrap()),
+            (SnareDrum, groups("8-x--x-").unwrap()),
         ]),
         four_fourth,
+        None,
+        1,
     )
+    .unwrap()
     .collect::<Vec<Event<Tick>>>();
 
     assert_eq!(
         merge_into_iterator(
-            &BTreeMap::from_iter([(KickDrum, groups(kick_group).unwrap().1)]),
-            four_fourth
+            &BTreeMap::from_iter([(KickDrum, groups(kick_group).unwrap())]),
+            four_fourth,
+            None,
+            1
         )
+        .unwrap()
         .collect::<Vec<Event<Tick>>>(),
         kick_events
     );
     assert_eq!(
         merge_into_iterator(
-            &BTreeMap::from_iter([(SnareDrum, groups(snare_group).unwrap().1)]),
-            four_fourth
+            &BTreeMap::from_iter([(SnareDrum, groups(snare_group).unwrap())]),
+            four_fourth,
+            None,
+            1
         )
+        .unwrap()
         .collect::<Vec<Event<Tick>>>(),
         snare_events
     );
-    assert_eq!(
+    assert!(
         kick_events
             .iter()
             .all(|x| flattened_kick_and_snare.contains(x))
             && snare_events
                 .iter()
-                .all(|x| flattened_kick_and_snare.contains(x)),
-        true
+                .all(|x| flattened_kick_and_snare.contains(x))
     );
 }
 
+/// Where the MIDI tracks end.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrackEnd {
+    /// Exactly at the end of the last bar.
+    BarLine,
+    /// Leave a rest after the last bar, so cymbals can ring out.
+    Tail(Length),
+    /// Right after the last note, dropping any trailing silence.
+    Trim,
+}
+
+/// Everything besides the drum patterns that affects how the MIDI file is rendered.
+#[derive(Debug, Clone)]
+pub struct RenderOptions {
+    pub time_signature: TimeSignature,
+    /// The converged pattern is rendered once per tempo, changing the tempo at the start of every repetition.
+    /// The first one is the initial tempo.
+    pub tempos: Vec<u16>,
+    /// Generate a second MIDI track for the bass following the kick drum.
+    pub add_bass: bool,
+    /// Generate an extra MIDI track marking the start of every cycle of each drum part's pattern.
+    pub add_click: bool,
+    /// Generate an extra MIDI track with a note at every kick drum hit, to trigger sidechain compression.
+    pub sidechain: Option<Sidechain>,
+    /// Give every drum part to a player of a percussion ensemble: each one gets a track of its own and is played
+    /// with the instrument suggested by `ensemble::instrument`.
+    pub ensemble: bool,
+    /// Melodic instruments playing along with drum parts, each one on a track of its own.
+    pub doublings: Vec<Doubling>,
+    /// Render a layered lesson instead of the plain groove: the converged pattern is played with the first
+    /// drum part only, then the second one joins in and so on until all of them are playing, finally
+    /// accents, ghost notes and dynamics are added.
+    pub teach: bool,
+    /// Mutate every repetition of the drum parts' patterns, see `vary`.
+    pub variation: Option<Variation>,
+    /// Post-processing steps applied to the drum track in order, after the events are laid out.
+    pub transforms: Vec<Box<dyn Transform>>,
+    /// Finish the track with an ending instead of just stopping after the last repetition.
+    pub ending: Option<Ending>,
+    /// Applied to the drum and the bass tracks last, once everything else is in place.
+    pub humanize: Option<Humanize>,
+    pub end: TrackEnd,
+    pub drum_map: DrumMap,
+    /// Number of threads to lay the drum parts out on. Doesn't change the output, only how fast it's rendered.
+    pub threads: usize,
+}
+
+impl Default for RenderOptions {
+    fn default() -> Self {
+        RenderOptions {
+            time_signature: TimeSignature {
+                numerator: 4,
+                denominator: BasicLength::Fourth,
+            },
+            tempos: vec![120],
+            add_bass: false,
+            add_click: false,
+            sidechain: None,
+            ensemble: false,
+            doublings: Vec::new(),
+            teach: false,
+            variation: None,
+            transforms: Vec::new(),
+            ending: None,
+            humanize: None,
+            end: TrackEnd::BarLine,
+            drum_map: DrumMap::default(),
+            threads: 1,
+        }
+    }
+}
+
+/// Notes of the sidechain trigger track, played along with the kick drum as it ends up in the drum track, after
+/// the transforms and humanization.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Sidechain {
+    pub key: u7,
+    /// Notes are cut short where the next kick drum hit comes sooner.
+    pub length: Length,
+}
+
+/// MIDI channel of the sidechain trigger track, out of the way of the drums and the bass.
+static SIDECHAIN_CHANNEL: u8 = 15;
+/// Triggers are all played at the same velocity, so the ducking doesn't depend on the dynamics of the kick drum.
+static SIDECHAIN_VELOCITY: u8 = 127;
+
+/// The sidechain trigger track for kick drum hits starting at `kicks`, along with the time of its last event.
+fn sidechain_track<'a>(kicks: &[Tick], sidechain: Sidechain) -> (Vec<TrackEvent<'a>>, Tick) {
+    let mut track = vec![
+        TrackEvent {
+            delta: 0.into(),
+            kind: TrackEventKind::Meta(MetaMessage::TrackName(b"Sidechain")),
+        },
+        TrackEvent {
+            delta: 0.into(),
+            kind: TrackEventKind::Meta(MetaMessage::InstrumentName(b"Sidechain")),
+        },
+    ];
+    let mut time = Tick(0);
+    let mut push = |tick: Tick, message| {
+        track.push(TrackEvent {
+            delta: u28::from((tick - time).0 as u32),
+            kind: TrackEventKind::Midi { channel: u4::from(SIDECHAIN_CHANNEL), message },
+        });
+        time = tick;
+    };
+    for (i, start) in kicks.iter().enumerate() {
+        let end = *start + sidechain.length.to_ticks();
+        let end = kicks.get(i + 1).map_or(end, |next| min(end, *next));
+        push(*start, MidiMessage::NoteOn { key: sidechain.key, vel: SIDECHAIN_VELOCITY.into() });
+        push(end, MidiMessage::NoteOff { key: sidechain.key, vel: 0.into() });
+    }
+    (track, time)
+}
+
 // The length of a beat is not standard, so in order to fully describe the length of a MIDI tick the MetaMessage::Tempo event should be present.
 pub fn create_smf<'a>(
     groups: BTreeMap<DrumPart, Groups>,
@@ -950,19 +1243,33 @@ pub fn create_smf<'a>(
     text: &'a str,
     tempo: u16,
     add_bass: bool
-) -> Smf<'a> {
-    let tracks = create_tracks(groups, time_signature, text, MidiTempo::from_tempo(tempo), add_bass);
-    // https://majicdesigns.github.io/MD_MIDIFile/page_timing.html
-    // says " If it is not specified the MIDI default is 48 ticks per quarter note."
-    // As it's required in `Header`, let's use the same value.
-    let metrical = midly::Timing::Metrical(TICKS_PER_QUARTER_NOTE.into());
-    Smf {
-        header: Header {
-            format: midly::Format::Parallel,
-            timing: metrical,
-        },
-        tracks: tracks,
-    }
+) -> Result<Smf<'a>, PolyError> {
+    let options = RenderOptions {
+        time_signature,
+        tempos: vec![tempo],
+        add_bass,
+        ..Default::default()
+    };
+    generate(groups, text, &options)
+}
+
+/// Same as `create_smf`, but takes all the rendering settings as `RenderOptions`.
+pub fn generate<'a>(
+    groups: BTreeMap<DrumPart, Groups>,
+    text: &'a str,
+    options: &RenderOptions,
+) -> Result<Smf<'a>, PolyError> {
+    generate_with_bars(groups, text, options).map(|(smf, _)| smf)
+}
+
+/// Same as `generate`, along with the number of bars the patterns take to converge, a single cycle of the track.
+pub fn generate_with_bars<'a>(
+    groups: BTreeMap<DrumPart, Groups>,
+    text: &'a str,
+    options: &RenderOptions,
+) -> Result<(Smf<'a>, u32), PolyError> {
+    let (tracks, bars) = create_tracks(groups, text, options)?;
+    Ok((tracks_to_smf(tracks), bars))
 }
 
 /// Translates drum parts to a single MIDI track.
@@ -970,113 +1277,149 @@ pub fn create_smf<'a>(
 /// /// # Arguments
 ///
 /// * `parts_and_groups` - Drum parts parsed from the command line.
-/// * `time_signature` - Time signature parsed from the command line.
 /// * `text_event` - Text message to be embedded into the MIDI file.
+/// * `options` - Time signature, tempos and extra tracks to render.
 ///
 /// # Returns
 ///
-/// Multi-track vectors of MIDI events in `midly` format.
+/// Multi-track vectors of MIDI events in `midly` format, along with the bars the patterns converge over.
 ///
 fn create_tracks<'a>(
     parts_and_groups: BTreeMap<DrumPart, Groups>,
-    time_signature: TimeSignature,
     text_event: &'a str,
-    midi_tempo: MidiTempo,
-    add_bass: bool
-) -> Vec<Vec<midly::TrackEvent<'a>>> {
-    let events_iter = merge_into_iterator(&parts_and_groups, time_signature);
-    let bars = events_iter.bars.clone();
+    options: &RenderOptions,
+) -> Result<(Vec<Vec<midly::TrackEvent<'a>>>, u32), PolyError> {
+    let time_signature = options.time_signature;
+    if let Some(tempo) = options.tempos.iter().find(|t| **t == 0) {
+        return Err(PolyError::Tempo(*tempo));
+    }
+    // Patterns without any length can't be repeated until they converge.
+    if parts_and_groups.is_empty() || parts_and_groups.values().any(|g| g.to_128th() == 0) {
+        return Err(PolyError::NoNotes);
+    }
+    if options.doublings.len() > doubling::CHANNELS.len() {
+        return Err(PolyError::Doublings(options.doublings.len()));
+    }
+    let midi_tempos: Vec<MidiTempo> = options.tempos.iter().map(|t| MidiTempo::from_tempo(*t)).collect();
+    let events_iter = merge_into_iterator(&parts_and_groups, time_signature, options.variation, options.threads)?;
+    let bars = events_iter.bars;
     let events: Vec<Event<Tick>> = events_iter.collect();
 
-    let track_time = match events.last() {
-        Some(ev) => ev.tick,
-        None => {
-            panic!("Result has no midi notes")
-        }
+    if events.is_empty() {
+        return Err(PolyError::NoNotes);
+    }
+    // The bass plays the kick drum's notes.
+    if options.add_bass && !events.iter().any(|e| matches!(e.event_type, NoteOn(Drum(KickDrum), _))) {
+        return Err(PolyError::NoKick);
+    }
+    let (midi_tempo, tempo_changes) = midi_tempos.split_first().ok_or(PolyError::NoTempo)?;
+    let cycle_length = time_signature.denominator.to_ticks()
+        * (time_signature.numerator as u128 * bars as u128);
+    let stages = if options.teach {
+        teaching_stages(&events, parts_and_groups.keys())
+    } else {
+        vec![events]
+    };
+    let stage_count = stages.len() as u128;
+    let mut lesson = EventGrid::empty();
+    for stage in stages {
+        lesson.append(EventGrid {
+            events: stage,
+            start: Tick(0),
+            end: cycle_length,
+        });
+    }
+    let lesson_length = cycle_length * stage_count;
+    let tempo_repeats = repeats(midi_tempos.len() as u128)?;
+    let event_grid = concat_grid(lesson, tempo_repeats);
+    // Converged pattern is repeated this many times over the whole track.
+    let cycles = tempo_repeats.0 as u128 * stage_count;
+    let transform_context = TransformContext {
+        time_signature,
+        length: cycle_length * cycles,
+        cycle: cycle_length,
+    };
+    let events = options
+        .transforms
+        .iter()
+        .fold(event_grid.events, |events, t| t.apply(events, &transform_context));
+    let (events, length) = match options.ending {
+        Some(ending) => ending.apply(events, &transform_context),
+        None => (events, transform_context.length),
+    };
+    // The bass follows the kick drum of the ending too, it isn't part of the kick drum's pattern.
+    let ending_bass: Vec<PairedNote> = if options.add_bass && options.ending.is_some() {
+        pair_notes(&events)
+            .into_iter()
+            .filter(|n| n.part == Drum(KickDrum) && n.start >= transform_context.length)
+            .map(|n| PairedNote { part: Bass, ..n })
+            .collect()
+    } else {
+        Vec::new()
+    };
+    let humanize = |events: Vec<Event<Tick>>| match options.humanize {
+        Some(humanize) => humanize.apply(events, &transform_context),
+        None => events,
+    };
+    let events = humanize(events);
+    let mut kicks: Vec<Tick> = events
+        .iter()
+        .filter(|e| matches!(e.event_type, NoteOn(Drum(KickDrum), _)))
+        .map(|e| e.tick)
+        .collect();
+    kicks.sort();
+    kicks.dedup();
+    let doublings: Vec<(Vec<TrackEvent<'a>>, Tick)> = options
+        .doublings
+        .iter()
+        .zip(doubling::CHANNELS)
+        .map(|(d, channel)| doubling_track(&events, d, channel))
+        .collect();
+    let event_grid = EventGrid::new(events, length);
+    // Every subsequent tempo takes over at the start of the next repetition of the lesson (or the converged pattern).
+    let tempo_changes: Vec<(Tick, MetaMessage)> = tempo_changes
+        .iter()
+        .enumerate()
+        .map(|(i, t)| (lesson_length * (i as u128 + 1), MetaMessage::Tempo(t.0)))
+        .collect();
+    let drum_map = if options.ensemble {
+        ensemble::drum_map(&options.drum_map)
+    } else {
+        options.drum_map.clone()
+    };
+    let map_notes = |grid: EventGrid<Tick>, tempo_changes: &[(Tick, MetaMessage<'a>)], track: &mut Vec<TrackEvent<'a>>| {
+        write_events(grid, tempo_changes, &drum_map, track)
     };
-    let event_grid_tick = EventGrid::new(events, track_time);
-    let event_grid = event_grid_tick.to_delta();
-    let mut drums_track = Vec::new();
-
-    // This is likely to be specific to Guitar Pro. Tested with Guitar Pro 7.
-    drums_track.push(TrackEvent {
-        delta: 0.into(),
-        kind: TrackEventKind::Midi {
-            channel: 9.into(),
-            message: MidiMessage::ProgramChange { program: 0.into() },
-        },
-    });
-    drums_track.push(TrackEvent {
-        delta: 0.into(),
-        kind: TrackEventKind::Meta(MetaMessage::TrackName(b"Drumkit")),
-    });
-    drums_track.push(TrackEvent {
-        delta: 0.into(),
-        kind: TrackEventKind::Meta(MetaMessage::InstrumentName(b"Drumkit")),
-    });
-    drums_track.push(TrackEvent {
-        delta: 0.into(),
-        kind: TrackEventKind::Meta(MetaMessage::MidiChannel(10.into())),
-    });
-    drums_track.push(TrackEvent {
-        delta: 0.into(),
-        kind: TrackEventKind::Meta(MetaMessage::MidiPort(10.into())),
-    });
-
-    drums_track.push(TrackEvent {
-        delta: 0.into(),
-        kind: TrackEventKind::Meta(MetaMessage::Tempo(midi_tempo.0)),
-    });
-
-    let (midi_time_signature_numerator, midi_time_signature_denominator) = time_signature.to_midi();
-    drums_track.push(TrackEvent {
-        delta: 0.into(),
-        kind: TrackEventKind::Meta(MetaMessage::TimeSignature(
-            midi_time_signature_numerator,
-            midi_time_signature_denominator,
-            MIDI_CLOCKS_PER_CLICK.clone(),
-            8,
-        )),
-    });
-
-    drums_track.push(TrackEvent {
-        delta: 0.into(),
-        kind: TrackEventKind::Meta(MetaMessage::Text(text_event.as_bytes())),
-    });
 
-    let map_notes = |grid: EventGrid<Delta>, track: &mut Vec<TrackEvent>| {
-        for event in grid.events {
-            let midi_message = match event.event_type {
-                NoteOn(part) => MidiMessage::NoteOn {
-                    key: part.to_midi_key(),
-                    vel: 127.into(),
-                },
-                NoteOff(part) => MidiMessage::NoteOff {
-                    key: part.to_midi_key(),
-                    vel: 127.into(),
-                },
+    let mut tracks = Vec::new();
+    if options.ensemble {
+        // The first player's track takes the tempo, the time signature and the text, like the drum track does.
+        for (i, part) in parts_and_groups.keys().enumerate() {
+            let name = ensemble::instrument(*part).name;
+            let mut track = if i == 0 {
+                drums_track_header(time_signature, *midi_tempo, text_event, name)
+            } else {
+                percussion_track_header(name)
             };
-            track.push(TrackEvent {
-                delta: u28::from(event.tick.0 as u32),
-                kind: TrackEventKind::Midi {
-                    channel: u4::from(10),
-                    message: midi_message,
-                },
-            })
+            let events = event_grid
+                .events
+                .iter()
+                .filter(|e| matches!(e.event_type, NoteOn(Drum(p), _) | NoteOff(Drum(p)) if p == *part))
+                .cloned()
+                .collect();
+            let changes = if i == 0 { tempo_changes.as_slice() } else { &[] };
+            let end = map_notes(EventGrid::new(events, length), changes, &mut track);
+            tracks.push((track, end));
         }
-    };
-
-    map_notes(event_grid, &mut drums_track);
-
-    drums_track.push(TrackEvent {
-        delta: drums_track.last().unwrap().delta,
-        kind: TrackEventKind::Meta(MetaMessage::EndOfTrack),
-    });
+    } else {
+        let mut drums_track = drums_track_header(time_signature, *midi_tempo, text_event, "Drumkit");
+        let drums_end = map_notes(event_grid, &tempo_changes, &mut drums_track);
+        tracks.push((drums_track, drums_end));
+    }
 
-    if add_bass {
+    if options.add_bass {
         let mut bass_track = Vec::new();
-        let empty_groups = Groups(Vec::new());
-        let kick = parts_and_groups.get(&KickDrum).unwrap_or(&empty_groups);
+        let kick = parts_and_groups.get(&KickDrum).filter(|kick| kick.to_128th() > 0).ok_or(PolyError::NoKick)?;
         let bass = groups_to_event_grid(Bass, kick);
         // This is likely to be specific to Guitar Pro. Tested with Guitar Pro 7.
         bass_track.push(TrackEvent {
@@ -1094,10 +1437,570 @@ fn create_tracks<'a>(
             delta: 0.into(),
             kind: TrackEventKind::Meta(MetaMessage::InstrumentName(b"Bass")),
         });
-        let times = bars * time_signature.to_128th() / kick.to_128th();
-        map_notes(concat_grid(bass, Times(times as u16)).to_delta(), &mut bass_track);
-        vec![drums_track, bass_track]
-    } else {
-        vec![drums_track]
+        let times = cycles as u32 * bars * time_signature.to_128th() / kick.to_128th();
+        let mut bass = concat_grid(bass, repeats(times)?).events;
+        bass.extend(unpair_notes(&ending_bass));
+        bass.sort();
+        let bass_end = map_notes(EventGrid::new(humanize(bass), length), &[], &mut bass_track);
+        tracks.push((bass_track, bass_end));
+    }
+
+    if options.add_click {
+        let mut click_track = vec![
+            TrackEvent {
+                delta: 0.into(),
+                kind: TrackEventKind::Meta(MetaMessage::TrackName(b"Click")),
+            },
+            TrackEvent {
+                delta: 0.into(),
+                kind: TrackEventKind::Meta(MetaMessage::InstrumentName(b"Click")),
+            },
+        ];
+        // Clicks go on through the ending, which starts a cycle of every part.
+        let mut clicks: Vec<Event<Tick>> = Vec::new();
+        for (part, groups) in &parts_and_groups {
+            clicks.extend(cycle_click_grid(*part, groups, length)?);
+        }
+        clicks.sort();
+        let click_end = map_notes(EventGrid::new(clicks, length), &[], &mut click_track);
+        tracks.push((click_track, click_end));
+    }
+
+    if let Some(sidechain) = options.sidechain {
+        tracks.push(sidechain_track(&kicks, sidechain));
+    }
+    tracks.extend(doublings);
+
+    let end = match options.end {
+        TrackEnd::BarLine => length,
+        TrackEnd::Tail(tail) => length + tail.to_ticks(),
+        TrackEnd::Trim => tracks.iter().map(|(_, last)| *last).max().unwrap_or(Tick(0)),
+    };
+    Ok((end_tracks(tracks, end), bars))
+}
+
+/// Meta events every drum track starts with.
+pub(crate) fn drums_track_header<'a>(
+    time_signature: TimeSignature,
+    midi_tempo: MidiTempo,
+    text_event: &'a str,
+    name: &'a str,
+) -> Vec<TrackEvent<'a>> {
+    let mut track = percussion_track_header(name);
+    track.extend([
+        TrackEvent {
+            delta: 0.into(),
+            kind: TrackEventKind::Meta(MetaMessage::Tempo(midi_tempo.0)),
+        },
+        TrackEvent {
+            delta: 0.into(),
+            kind: TrackEventKind::Meta(time_signature_event(time_signature)),
+        },
+        TrackEvent {
+            delta: 0.into(),
+            kind: TrackEventKind::Meta(MetaMessage::Text(text_event.as_bytes())),
+        },
+    ]);
+    track
+}
+
+/// Meta events naming a track played on the percussion channel.
+fn percussion_track_header(name: &str) -> Vec<TrackEvent<'_>> {
+    vec![
+        // This is likely to be specific to Guitar Pro. Tested with Guitar Pro 7.
+        TrackEvent {
+            delta: 0.into(),
+            kind: TrackEventKind::Midi {
+                channel: 9.into(),
+                message: MidiMessage::ProgramChange { program: 0.into() },
+            },
+        },
+        TrackEvent {
+            delta: 0.into(),
+            kind: TrackEventKind::Meta(MetaMessage::TrackName(name.as_bytes())),
+        },
+        TrackEvent {
+            delta: 0.into(),
+            kind: TrackEventKind::Meta(MetaMessage::InstrumentName(name.as_bytes())),
+        },
+        TrackEvent {
+            delta: 0.into(),
+            kind: TrackEventKind::Meta(MetaMessage::MidiChannel(10.into())),
+        },
+        TrackEvent {
+            delta: 0.into(),
+            kind: TrackEventKind::Meta(MetaMessage::MidiPort(10.into())),
+        },
+    ]
+}
+
+pub(crate) fn time_signature_event(time_signature: TimeSignature) -> MetaMessage<'static> {
+    let (numerator, denominator) = time_signature.to_midi();
+    MetaMessage::TimeSignature(numerator, denominator, MIDI_CLOCKS_PER_CLICK, 8)
+}
+
+/// Writes the events to the track, along with the meta events such as tempo changes. Returns the time of the last
+/// event written to the track.
+pub(crate) fn write_events<'a>(
+    grid: EventGrid<Tick>,
+    meta_events: &[(Tick, MetaMessage<'a>)],
+    drum_map: &DrumMap,
+    track: &mut Vec<TrackEvent<'a>>,
+) -> Tick {
+    track.reserve(grid.events.len() + meta_events.len());
+    let mut time = Tick(0);
+    let mut meta_events = meta_events.iter().peekable();
+    for event in grid.events {
+        while let Some((tick, meta)) = meta_events.next_if(|(tick, _)| *tick <= event.tick) {
+            track.push(TrackEvent {
+                delta: u28::from((*tick - time).0 as u32),
+                kind: TrackEventKind::Meta(*meta),
+            });
+            time = *tick;
+        }
+        let midi_message = match event.event_type {
+            NoteOn(part, velocity) => MidiMessage::NoteOn {
+                key: drum_map.key(part),
+                vel: velocity.0.into(),
+            },
+            NoteOff(part) => MidiMessage::NoteOff {
+                key: drum_map.key(part),
+                vel: 127.into(),
+            },
+        };
+        track.push(TrackEvent {
+            delta: u28::from((event.tick - time).0 as u32),
+            kind: TrackEventKind::Midi {
+                channel: u4::from(10),
+                message: midi_message,
+            },
+        });
+        time = event.tick;
+    }
+    // Meta events after the last note, e.g. a tempo change at the start of a section that rests.
+    for (tick, meta) in meta_events {
+        track.push(TrackEvent {
+            delta: u28::from((*tick - time).0 as u32),
+            kind: TrackEventKind::Meta(*meta),
+        });
+        time = *tick;
+    }
+    time
+}
+
+/// Closes every track at `end`, or right after its last event if that comes later.
+pub(crate) fn end_tracks<'a>(tracks: Vec<(Vec<TrackEvent<'a>>, Tick)>, end: Tick) -> Vec<Vec<TrackEvent<'a>>> {
+    tracks
+        .into_iter()
+        .map(|(mut track, last)| {
+            let delta = if end > last { end - last } else { Tick(0) };
+            track.push(TrackEvent {
+                delta: u28::from(delta.0 as u32),
+                kind: TrackEventKind::Meta(MetaMessage::EndOfTrack),
+            });
+            track
+        })
+        .collect()
+}
+
+/// Wraps the tracks into a MIDI file.
+pub(crate) fn tracks_to_smf(tracks: Vec<Vec<TrackEvent>>) -> Smf {
+    // https://majicdesigns.github.io/MD_MIDIFile/page_timing.html
+    // says " If it is not specified the MIDI default is 48 ticks per quarter note."
+    // As it's required in `Header`, let's use the same value.
+    let metrical = midly::Timing::Metrical(TICKS_PER_QUARTER_NOTE.into());
+    Smf {
+        header: Header {
+            format: midly::Format::Parallel,
+            timing: metrical,
+        },
+        tracks,
+    }
+}
+
+/// Splits the converged pattern into cumulative stages: the first stage contains only the first drum part,
+/// every next stage adds another one. All of these are played with the same velocity, the last stage
+/// brings in the dynamics, unless the pattern is played with the same velocity anyway.
+fn teaching_stages<'a, I>(events: &[Event<Tick>], parts: I) -> Vec<Vec<Event<Tick>>>
+where
+    I: IntoIterator<Item = &'a DrumPart>,
+{
+    let flat_velocity = Velocity(DEFAULT_DYNAMIC.velocity());
+    let mut playing = Vec::new();
+    let mut stages: Vec<Vec<Event<Tick>>> = parts
+        .into_iter()
+        .map(|part| {
+            playing.push(Drum(*part));
+            events
+                .iter()
+                .filter(|e| match e.event_type {
+                    NoteOn(p, _) | NoteOff(p) => playing.contains(&p),
+                })
+                .map(|e| match e.event_type {
+                    NoteOn(p, _) => Event::new(e.tick, NoteOn(p, flat_velocity)),
+                    NoteOff(_) => *e,
+                })
+                .collect()
+        })
+        .collect();
+    if stages.last().map(|s| s.as_slice()) != Some(events) {
+        stages.push(events.to_vec());
+    }
+    stages
+}
+
+#[test]
+fn test_teaching_stages() {
+    let kick_on = Event::new(Tick(0), NoteOn(Drum(KickDrum), Velocity(100)));
+    let kick_off = Event::new(Tick(24), NoteOff(Drum(KickDrum)));
+    let snare_on = Event::new(Tick(24), NoteOn(Drum(SnareDrum), Velocity(100)));
+    let snare_off = Event::new(Tick(48), NoteOff(Drum(SnareDrum)));
+    let events = vec![kick_on, kick_off, snare_on, snare_off];
+    assert_eq!(
+        teaching_stages(&events, &[KickDrum, SnareDrum]),
+        vec![vec![kick_on, kick_off], events.clone()]
+    );
+
+    let accent_on = Event::new(Tick(48), NoteOn(Drum(SnareDrum), Velocity(127)));
+    let accent_off = Event::new(Tick(72), NoteOff(Drum(SnareDrum)));
+    let flattened_accent_on = Event::new(Tick(48), NoteOn(Drum(SnareDrum), Velocity(100)));
+    let accented_events = vec![kick_on, kick_off, snare_on, snare_off, accent_on, accent_off];
+    assert_eq!(
+        teaching_stages(&accented_events, &[KickDrum, SnareDrum]),
+        vec![
+            vec![kick_on, kick_off],
+            vec![kick_on, kick_off, snare_on, snare_off, flattened_accent_on, accent_off],
+            accented_events.clone()
+        ]
+    );
+}
+
+/// Marks the beginning of every repetition of the drum part's pattern within `total_length` with a click.
+fn cycle_click_grid(part: DrumPart, groups: &Groups, total_length: Tick) -> Result<EventGrid<Tick>, PolyError> {
+    let pattern_length = groups_to_event_grid(Drum(part), groups).length();
+    if pattern_length == Tick(0) {
+        return Ok(EventGrid::empty());
     }
+    let click_length = min(BasicLength::Sixteenth.to_ticks(), pattern_length);
+    let cycle = EventGrid {
+        events: vec![
+            Event::new(Tick(0), NoteOn(Click(part), Velocity(100))),
+            Event::new(click_length, NoteOff(Click(part))),
+        ],
+        start: Tick(0),
+        end: pattern_length,
+    };
+    Ok(concat_grid(cycle, repeats(total_length.0 / pattern_length.0)?))
+}
+
+#[test]
+fn test_cycle_click_grid() {
+    let grid = cycle_click_grid(KickDrum, &groups("8x----").unwrap(), Tick(480)).unwrap();
+    assert_eq!(
+        grid.events,
+        vec![
+            Event::new(Tick(0), NoteOn(Click(KickDrum), Velocity(100))),
+            Event::new(Tick(12), NoteOff(Click(KickDrum))),
+            Event::new(Tick(120), NoteOn(Click(KickDrum), Velocity(100))),
+            Event::new(Tick(132), NoteOff(Click(KickDrum))),
+            Event::new(Tick(240), NoteOn(Click(KickDrum), Velocity(100))),
+            Event::new(Tick(252), NoteOff(Click(KickDrum))),
+            Event::new(Tick(360), NoteOn(Click(KickDrum), Velocity(100))),
+            Event::new(Tick(372), NoteOff(Click(KickDrum))),
+        ]
+    );
+}
+
+#[test]
+fn test_generate_too_many_repeats() {
+    // The snare drum takes 521 bars of 12/4 to line up with the bar line, over which the kick drum repeats 100032 times.
+    let parts = BTreeMap::from_iter([(KickDrum, groups("64x").unwrap()), (SnareDrum, groups("(521,2x)").unwrap())]);
+    let options = RenderOptions { time_signature: TimeSignature::from_str("12/4").unwrap(), ..Default::default() };
+    assert_eq!(generate(parts, "", &options).unwrap_err(), PolyError::TooManyRepeats(100032));
+}
+
+#[test]
+fn test_generate_tempo_sweep() {
+    let options = RenderOptions {
+        tempos: vec![100, 110, 120],
+        ..Default::default()
+    };
+    let smf = generate(
+        BTreeMap::from_iter([(KickDrum, groups("4x---").unwrap())]),
+        "",
+        &options,
+    ).unwrap();
+    let mut time = 0;
+    let mut tempo_changes = Vec::new();
+    let mut note_ons = 0;
+    for event in smf.tracks[0].iter() {
+        time += event.delta.as_int();
+        match event.kind {
+            TrackEventKind::Meta(MetaMessage::Tempo(t)) => tempo_changes.push((time, t.as_int())),
+            TrackEventKind::Midi { message: MidiMessage::NoteOn { .. }, .. } => note_ons += 1,
+            _ => {}
+        }
+    }
+    assert_eq!(tempo_changes, vec![(0, 600000), (192, 545454), (384, 500000)]);
+    assert_eq!(note_ons, 3);
+}
+
+#[test]
+fn test_generate_velocities() {
+    let smf = generate(
+        BTreeMap::from_iter([(SnareDrum, groups("16xXg-p16x").unwrap())]),
+        "",
+        &RenderOptions::default(),
+    ).unwrap();
+    let velocities: Vec<u8> = smf.tracks[0]
+        .iter()
+        .filter_map(|event| match event.kind {
+            TrackEventKind::Midi { message: MidiMessage::NoteOn { vel, .. }, .. } => Some(vel.as_int()),
+            _ => None,
+        })
+        .take(4)
+        .collect();
+    assert_eq!(velocities, vec![100, 127, 40, 49]);
+}
+
+#[test]
+fn test_generate_track_end() {
+    let track_end = |end: TrackEnd| {
+        let options = RenderOptions { end, add_bass: true, ..Default::default() };
+        let smf = generate(BTreeMap::from_iter([(KickDrum, groups("4x---").unwrap())]), "", &options).unwrap();
+        smf.tracks
+            .iter()
+            .map(|track| track.iter().map(|e| e.delta.as_int()).sum::<u32>())
+            .collect::<Vec<u32>>()
+    };
+    assert_eq!(track_end(TrackEnd::BarLine), vec![192, 192]);
+    assert_eq!(track_end(TrackEnd::Tail(*HALF)), vec![288, 288]);
+    assert_eq!(track_end(TrackEnd::Trim), vec![48, 48]);
+}
+
+#[test]
+fn test_generate_ending() {
+    let options = RenderOptions { ending: Some(Ending::Crash), ..Default::default() };
+    let smf = generate(BTreeMap::from_iter([(SnareDrum, groups("4-x").unwrap())]), "", &options).unwrap();
+    let mut time = 0;
+    let mut note_ons = Vec::new();
+    for event in smf.tracks[0].iter() {
+        time += event.delta.as_int();
+        if let TrackEventKind::Midi { message: MidiMessage::NoteOn { key, .. }, .. } = event.kind {
+            note_ons.push((time, key.as_int()));
+        }
+    }
+    assert_eq!(note_ons[note_ons.len() - 2..], [(192, 36), (192, 49)]);
+    assert_eq!(time, 384);
+}
+
+#[test]
+fn test_generate_ending_bass_and_click() {
+    let options = RenderOptions { ending: Some(Ending::Crash), add_bass: true, add_click: true, ..Default::default() };
+    let parts = BTreeMap::from_iter([(KickDrum, groups("4x---").unwrap()), (SnareDrum, groups("4-x").unwrap())]);
+    let smf = generate(parts, "", &options).unwrap();
+    let note_ons = |track: &[TrackEvent]| {
+        let mut time = 0;
+        let mut note_ons = Vec::new();
+        for event in track {
+            time += event.delta.as_int();
+            if let TrackEventKind::Midi { message: MidiMessage::NoteOn { key, .. }, .. } = event.kind {
+                note_ons.push((time, key.as_int()));
+            }
+        }
+        note_ons
+    };
+    // The bass plays along with the kick drum of the ending, and the clicks go on through its bar.
+    assert_eq!(note_ons(&smf.tracks[1]), [(0, 28), (192, 28)]);
+    assert_eq!(note_ons(&smf.tracks[2])[3..], [(192, 77), (192, 76), (288, 76)]);
+}
+
+#[test]
+fn test_generate_drum_map() {
+    let mut drum_map = DrumMap::default();
+    drum_map.set(RideCymbal, 59.into());
+    let options = RenderOptions { drum_map, ..Default::default() };
+    let smf = generate(
+        BTreeMap::from_iter([(RideCymbal, groups("1x").unwrap()), (Tom3, groups("2-x").unwrap())]),
+        "",
+        &options,
+    ).unwrap();
+    let keys: Vec<u8> = smf.tracks[0]
+        .iter()
+        .filter_map(|event| match event.kind {
+            TrackEventKind::Midi { message: MidiMessage::NoteOn { key, .. }, .. } => Some(key.as_int()),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(keys, vec![59, 43]);
+}
+
+#[test]
+fn test_generate_humanize_bass() {
+    let humanize = Humanize { timing: 4, velocity: 0, swing: 60.0, seed: 3 };
+    let options = RenderOptions { humanize: Some(humanize), add_bass: true, ..Default::default() };
+    let smf = generate(BTreeMap::from_iter([(KickDrum, groups("8x-xx").unwrap())]), "", &options).unwrap();
+    let note_on_times = |track: &[TrackEvent]| {
+        let mut time = 0;
+        let mut times = Vec::new();
+        for event in track {
+            time += event.delta.as_int();
+            if let TrackEventKind::Midi { message: MidiMessage::NoteOn { .. }, .. } = event.kind {
+                times.push(time);
+            }
+        }
+        times
+    };
+    let kick = note_on_times(&smf.tracks[0]);
+    assert_ne!(kick[..3], [0, 48, 72]);
+    assert_eq!(kick, note_on_times(&smf.tracks[1]));
+}
+
+#[test]
+fn test_generate_sidechain() {
+    let sidechain = Sidechain { key: 24.into(), length: Length::Simple(ModdedLength::Plain(BasicLength::Eighth)) };
+    let options = RenderOptions { sidechain: Some(sidechain), ..Default::default() };
+    let parts = BTreeMap::from_iter([(KickDrum, groups("16xx--x---").unwrap()), (SnareDrum, groups("4-x").unwrap())]);
+    let smf = generate(parts, "", &options).unwrap();
+    let mut time = 0;
+    let mut notes = Vec::new();
+    for event in &smf.tracks[1] {
+        time += event.delta.as_int();
+        match event.kind {
+            TrackEventKind::Midi { channel, message: MidiMessage::NoteOn { key, vel } } => {
+                assert_eq!((channel.as_int(), key.as_int(), vel.as_int()), (15, 24, 127));
+                notes.push((time, true));
+            }
+            TrackEventKind::Midi { message: MidiMessage::NoteOff { .. }, .. } => notes.push((time, false)),
+            _ => {}
+        }
+    }
+    // The first trigger is cut short by the second kick drum hit a 16th later.
+    assert_eq!(notes[..6], [(0, true), (12, false), (12, true), (36, false), (48, true), (72, false)]);
+    let kicks = smf.tracks[0]
+        .iter()
+        .filter(|e| matches!(e.kind, TrackEventKind::Midi { message: MidiMessage::NoteOn { key, .. }, .. } if key == KickDrum.to_midi_key()))
+        .count();
+    assert_eq!(notes.len(), 2 * kicks);
+}
+
+#[test]
+fn test_generate_ensemble() {
+    let options = RenderOptions { ensemble: true, add_bass: true, ..Default::default() };
+    let parts = BTreeMap::from_iter([(KickDrum, groups("4x--").unwrap()), (HiHat, groups("8x").unwrap())]);
+    let smf = generate(parts, "", &options).unwrap();
+    assert_eq!(smf.tracks.len(), 3);
+    let notes = |track: &[TrackEvent]| {
+        let mut keys = track
+            .iter()
+            .filter_map(|e| match e.kind {
+                TrackEventKind::Midi { message: MidiMessage::NoteOn { key, .. }, .. } => Some(key.as_int()),
+                _ => None,
+            })
+            .collect::<Vec<u8>>();
+        keys.dedup();
+        keys
+    };
+    assert_eq!(smf.tracks[0][1].kind, TrackEventKind::Meta(MetaMessage::TrackName(b"Surdo")));
+    assert!(smf.tracks[0].iter().any(|e| matches!(e.kind, TrackEventKind::Meta(MetaMessage::Tempo(_)))));
+    assert_eq!(notes(&smf.tracks[0]), [41]);
+    assert_eq!(smf.tracks[1][1].kind, TrackEventKind::Meta(MetaMessage::TrackName(b"Shaker")));
+    assert_eq!(notes(&smf.tracks[1]), [70]);
+    // The bass keeps following the kick drum after the players.
+    assert_eq!(notes(&smf.tracks[2]), [28]);
+}
+
+#[test]
+fn test_generate_doublings() {
+    let doubling = |s| Doubling::from_str(s).unwrap();
+    let options = RenderOptions { doublings: vec![doubling("hi-hat=marimba:E4,G4,B4")], ..Default::default() };
+    let parts = BTreeMap::from_iter([(HiHat, groups("8x-Xx").unwrap()), (KickDrum, groups("4x").unwrap())]);
+    let smf = generate(parts, "", &options).unwrap();
+    assert_eq!(smf.tracks.len(), 2);
+    let mut time = 0;
+    let mut notes = Vec::new();
+    for event in &smf.tracks[1] {
+        time += event.delta.as_int();
+        if let TrackEventKind::Midi { channel, message: MidiMessage::NoteOn { key, vel } } = event.kind {
+            notes.push((time, channel.as_int(), key.as_int(), vel.as_int()));
+        }
+    }
+    // Three hits a cycle of the hi-hat and three pitches: the accent moves to another pitch every time.
+    assert_eq!(notes[..4], [(0, 1, 64, 100), (48, 1, 67, 127), (72, 1, 71, 100), (96, 1, 64, 100)]);
+    assert_eq!(notes.len(), 6);
+    let options = RenderOptions { doublings: vec![doubling("kick=piano:C4"); 13], ..Default::default() };
+    let parts = BTreeMap::from_iter([(KickDrum, groups("4x").unwrap())]);
+    assert_eq!(generate(parts, "", &options).unwrap_err(), PolyError::Doublings(13));
+}
+
+#[test]
+fn test_variation_of_a_part_keeps_with_other_parts_changing() {
+    let four_four = TimeSignature::from_str("4/4").unwrap();
+    let variation = Some(Variation { amount: 0.5, seed: 7 });
+    let snares = |parts: BTreeMap<DrumPart, Groups>| {
+        merge_into_iterator(&parts, four_four, variation, 1)
+            .unwrap()
+            .filter(|e| matches!(e.event_type, NoteOn(Drum(SnareDrum), _) | NoteOff(Drum(SnareDrum))))
+            .filter(|e| e.tick < Tick(960))
+            .collect::<Vec<Event<Tick>>>()
+    };
+    let snare = groups("8-x-x--x-x").unwrap();
+    let before = snares(BTreeMap::from_iter([(SnareDrum, snare.clone()), (HiHat, groups("4x").unwrap())]));
+    // A new part and another hi-hat pattern don't change how the snare drum varies over the same 5 bars.
+    let after = snares(BTreeMap::from_iter([
+        (KickDrum, groups("4x--x").unwrap()),
+        (SnareDrum, snare.clone()),
+        (HiHat, groups("1x-").unwrap()),
+    ]));
+    assert_eq!(before, after);
+    let plain = merge_into_iterator(&BTreeMap::from_iter([(SnareDrum, snare)]), four_four, None, 1).unwrap();
+    assert_ne!(before, plain.take_while(|e| e.tick < Tick(960)).collect::<Vec<Event<Tick>>>());
+}
+
+#[test]
+fn test_generate_threads_are_deterministic() {
+    let parts = BTreeMap::from_iter([
+        (KickDrum, groups("16xx-x-xx-").unwrap()),
+        (SnareDrum, groups("8-x--x-").unwrap()),
+        (HiHat, groups("8.tx-X").unwrap()),
+        (RideCymbal, groups("4xg").unwrap()),
+        (Tom3, groups("16-----x").unwrap()),
+    ]);
+    let render = |threads: usize| {
+        let options = RenderOptions {
+            tempos: vec![100, 120],
+            add_bass: true,
+            add_click: true,
+            variation: Some(Variation { amount: 0.3, seed: 42 }),
+            humanize: Some(Humanize { timing: 3, velocity: 10, swing: 58.0, seed: 42 }),
+            threads,
+            ..Default::default()
+        };
+        let mut bytes = Vec::new();
+        generate(parts.clone(), "", &options).unwrap().write(&mut bytes).unwrap();
+        bytes
+    };
+    let single_threaded = render(1);
+    for threads in [2, 3, 5, 8] {
+        for _ in 0..4 {
+            assert_eq!(render(threads), single_threaded);
+        }
+    }
+}
+
+#[test]
+fn test_generate_errors() {
+    let kick = |pattern: &str| BTreeMap::from_iter([(KickDrum, groups(pattern).unwrap())]);
+    let options = RenderOptions::default();
+    assert_eq!(generate(BTreeMap::new(), "", &options).err(), Some(PolyError::NoNotes));
+    assert_eq!(generate(kick("4-"), "", &options).err(), Some(PolyError::NoNotes));
+    let no_tempo = RenderOptions { tempos: vec![], ..Default::default() };
+    assert_eq!(generate(kick("4x"), "", &no_tempo).err(), Some(PolyError::NoTempo));
+    let bass = RenderOptions { add_bass: true, ..Default::default() };
+    let snare = BTreeMap::from_iter([(SnareDrum, groups("4-x").unwrap())]);
+    assert_eq!(generate(snare.clone(), "", &bass).err(), Some(PolyError::NoKick));
+    let resting_kick = BTreeMap::from_iter([(KickDrum, groups("4-").unwrap()), (SnareDrum, groups("4-x").unwrap())]);
+    assert_eq!(generate(resting_kick, "", &bass).err(), Some(PolyError::NoKick));
+    let zero_tempo = RenderOptions { tempos: vec![120, 0], ..Default::default() };
+    assert_eq!(generate(kick("4x"), "", &zero_tempo).err(), Some(PolyError::Tempo(0)));
 }
@@ -0,0 +1,189 @@
+use std::str::FromStr;
+
+use midly::num::{u28, u4, u7};
+use midly::{MetaMessage, MidiMessage, TrackEvent, TrackEventKind};
+
+use crate::midi::core::{DrumPart, Event, EventType, Part, Tick};
+use crate::midi::transform::pair_notes;
+
+/// General MIDI programs of the melodic instruments parts can be doubled with, by name.
+static PROGRAMS: [(&str, u8); 16] = [
+    ("piano", 0),
+    ("electric-piano", 4),
+    ("harpsichord", 6),
+    ("celesta", 8),
+    ("glockenspiel", 9),
+    ("music-box", 10),
+    ("vibraphone", 11),
+    ("marimba", 12),
+    ("xylophone", 13),
+    ("tubular-bells", 14),
+    ("guitar", 24),
+    ("bass", 33),
+    ("pizzicato", 45),
+    ("harp", 46),
+    ("kalimba", 108),
+    ("steel-drums", 114),
+];
+
+/// Semitones of the natural notes above C.
+static NATURALS: [(char, i16); 7] = [('C', 0), ('D', 2), ('E', 4), ('F', 5), ('G', 7), ('A', 9), ('B', 11)];
+
+/// Reads a pitch like `E4`, `F#3` or `Bb2`, with middle C written as `C4`, or a plain MIDI key.
+pub fn parse_pitch(s: &str) -> Result<u7, String> {
+    let error = || format!("Expected a pitch like 'E4', 'F#3' or a MIDI key from 0 to 127, got '{}'", s);
+    if let Ok(key) = s.parse::<u8>() {
+        return if key <= 127 { Ok(key.into()) } else { Err(error()) };
+    }
+    let mut chars = s.chars();
+    let letter = chars.next().map(|c| c.to_ascii_uppercase()).ok_or_else(error)?;
+    let (_, natural) = NATURALS.iter().find(|(c, _)| *c == letter).ok_or_else(error)?;
+    let rest = chars.as_str();
+    let (accidental, octave) = match rest.strip_prefix('#') {
+        Some(octave) => (1, octave),
+        None => match rest.strip_prefix('b') {
+            Some(octave) => (-1, octave),
+            None => (0, rest),
+        },
+    };
+    // MIDI keys go from C-1 to G9, checking the octave first keeps huge ones from overflowing.
+    let octave: i16 = match octave.parse() {
+        Ok(octave) if (-1..=9).contains(&octave) => octave,
+        _ => return Err(error()),
+    };
+    let key = (octave + 1) * 12 + natural + accidental;
+    if (0..=127).contains(&key) {
+        Ok((key as u8).into())
+    } else {
+        Err(error())
+    }
+}
+
+/// A drum part doubled by a melodic instrument: every hit of the part is played on the instrument as well, with
+/// the next pitch of the sequence, which starts over once it runs out. Sequences that don't fit the number of
+/// hits of the pattern shift against it, like the interlocking mallet patterns of Steve Reich.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Doubling {
+    pub part: DrumPart,
+    /// General MIDI program of the instrument.
+    pub program: u7,
+    pub pitches: Vec<u7>,
+}
+
+impl FromStr for Doubling {
+    type Err = String;
+
+    /// Reads `<part>=<instrument>:<pitch>,<pitch>,...`, e.g. `hi-hat=marimba:E4,G4,B4`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let error = || format!("Expected '<part>=<instrument>:<pitch>,<pitch>,...', got '{}'", s);
+        let (part, rest) = s.split_once('=').ok_or_else(error)?;
+        let (instrument, pitches) = rest.split_once(':').ok_or_else(error)?;
+        let program = match PROGRAMS.iter().find(|(name, _)| *name == instrument) {
+            Some((_, program)) => *program,
+            None => match instrument.parse::<u8>() {
+                Ok(program) if program <= 127 => program,
+                _ => {
+                    let names: Vec<&str> = PROGRAMS.iter().map(|(name, _)| *name).collect();
+                    return Err(format!(
+                        "Unknown instrument '{}', expected one of {} or a General MIDI program from 0 to 127",
+                        instrument,
+                        names.join(", ")
+                    ));
+                }
+            },
+        };
+        Ok(Doubling {
+            part: DrumPart::from_str(part)?,
+            program: program.into(),
+            pitches: pitches.split(',').map(|p| parse_pitch(p.trim())).collect::<Result<Vec<u7>, String>>()?,
+        })
+    }
+}
+
+impl Doubling {
+    /// Name of the track, the instrument's if it's one of the named ones.
+    fn name(&self) -> &'static str {
+        PROGRAMS.iter().find(|(_, program)| *program == self.program.as_int()).map_or("Doubling", |(name, _)| name)
+    }
+}
+
+/// MIDI channels left for the doublings, the drums, the bass and the sidechain triggers have their own.
+pub(crate) static CHANNELS: [u8; 12] = [1, 2, 3, 4, 5, 6, 7, 8, 11, 12, 13, 14];
+
+/// The track of the instrument doubling the part in `events` on `channel`, along with the time of its last event.
+/// Every note lasts as long as the drum hit it doubles, at the same velocity.
+pub(crate) fn doubling_track<'a>(events: &[Event<Tick>], doubling: &Doubling, channel: u8) -> (Vec<TrackEvent<'a>>, Tick) {
+    let channel = u4::from(channel);
+    let name = doubling.name().as_bytes();
+    let mut track = vec![
+        TrackEvent {
+            delta: 0.into(),
+            kind: TrackEventKind::Midi { channel, message: MidiMessage::ProgramChange { program: doubling.program } },
+        },
+        TrackEvent {
+            delta: 0.into(),
+            kind: TrackEventKind::Meta(MetaMessage::TrackName(name)),
+        },
+        TrackEvent {
+            delta: 0.into(),
+            kind: TrackEventKind::Meta(MetaMessage::InstrumentName(name)),
+        },
+    ];
+    let part = Part::Drum(doubling.part);
+    let hits: Vec<Event<Tick>> = events
+        .iter()
+        .filter(|e| matches!(e.event_type, EventType::NoteOn(p, _) | EventType::NoteOff(p) if p == part))
+        .cloned()
+        .collect();
+    let mut notes: Vec<(Tick, MidiMessage)> = pair_notes(&hits)
+        .into_iter()
+        .zip(doubling.pitches.iter().cycle())
+        .flat_map(|(note, key)| {
+            [
+                (note.start, MidiMessage::NoteOn { key: *key, vel: note.velocity.0.into() }),
+                (note.end, MidiMessage::NoteOff { key: *key, vel: 0.into() }),
+            ]
+        })
+        .collect();
+    // A note ends before the next one starts when they meet at the same tick, even if it's on the same key.
+    notes.sort_by_key(|(tick, message)| (*tick, matches!(message, MidiMessage::NoteOn { .. })));
+    let mut time = Tick(0);
+    for (tick, message) in notes {
+        track.push(TrackEvent {
+            delta: u28::from((tick - time).0 as u32),
+            kind: TrackEventKind::Midi { channel, message },
+        });
+        time = tick;
+    }
+    (track, time)
+}
+
+#[test]
+fn test_parse_pitch() {
+    assert_eq!(parse_pitch("C4"), Ok(60.into()));
+    assert_eq!(parse_pitch("e4"), Ok(64.into()));
+    assert_eq!(parse_pitch("F#3"), Ok(54.into()));
+    assert_eq!(parse_pitch("Bb2"), Ok(46.into()));
+    assert_eq!(parse_pitch("C-1"), Ok(0.into()));
+    assert_eq!(parse_pitch("72"), Ok(72.into()));
+    assert!(parse_pitch("H2").is_err());
+    assert!(parse_pitch("G#9").is_err());
+    assert!(parse_pitch("128").is_err());
+    assert!(parse_pitch("C2731").is_err());
+    assert!(parse_pitch("C-2731").is_err());
+    assert!(parse_pitch("Cb-1").is_err());
+}
+
+#[test]
+fn test_parse_doubling() {
+    let doubling = Doubling::from_str("hi-hat=marimba:E4, G4,B4").unwrap();
+    assert_eq!(doubling.part, DrumPart::HiHat);
+    assert_eq!(doubling.program, u7::from(12));
+    assert_eq!(doubling.pitches, vec![u7::from(64), u7::from(67), u7::from(71)]);
+    assert_eq!(doubling.name(), "marimba");
+    let numbered = Doubling::from_str("kick=81:C2").unwrap();
+    assert_eq!((numbered.program, numbered.name()), (u7::from(81), "Doubling"));
+    assert!(Doubling::from_str("kick=theremin:C2").unwrap_err().starts_with("Unknown instrument 'theremin'"));
+    assert!(Doubling::from_str("kick=marimba").is_err());
+    assert!(Doubling::from_str("cowbell=marimba:C4").is_err());
+}
@@ -0,0 +1,70 @@
+use midly::num::u7;
+
+use crate::midi::core::{DrumMap, DrumPart};
+
+/// A hand percussion instrument a drum part is given to when the groove is played by an ensemble, a player per
+/// part, instead of a single drummer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Instrument {
+    pub name: &'static str,
+    /// General MIDI percussion key of the instrument, or of the closest one there is.
+    pub key: u7,
+}
+
+/// Instruments picked so that the low parts of the kit stay low and the cymbals go to the shakers and the
+/// woods, the parts keep their roles in the groove.
+pub fn instrument(part: DrumPart) -> Instrument {
+    let (name, key) = match part {
+        DrumPart::KickDrum => ("Surdo", 41),      // low floor tom
+        DrumPart::SnareDrum => ("Djembe", 63),    // open high conga
+        DrumPart::HiHat => ("Shaker", 70),        // maracas
+        DrumPart::CrashCymbal => ("Agogo", 67),   // high agogo
+        DrumPart::OpenHiHat => ("Tambourine", 54),
+        DrumPart::RideCymbal => ("Claves", 75),
+        DrumPart::Tom1 => ("Timbale", 65),        // high timbale
+        DrumPart::Tom2 => ("Conga", 62),          // mute high conga
+        DrumPart::Tom3 => ("Tumba", 64),          // low conga
+    };
+    Instrument { name, key: key.into() }
+}
+
+/// Keys of the ensemble instruments, with the parts moved by `overrides` played at the keys given there instead.
+pub fn drum_map(overrides: &DrumMap) -> DrumMap {
+    let mut map = DrumMap::default();
+    for part in DrumPart::ALL {
+        map.set(part, instrument(part).key);
+    }
+    for (part, key) in overrides.entries() {
+        map.set(part, key);
+    }
+    map
+}
+
+/// Who plays what: a line per player with the instrument, the drum part it replaces and its MIDI key.
+pub fn legend<I: IntoIterator<Item = DrumPart>>(parts: I, overrides: &DrumMap) -> String {
+    let map = drum_map(overrides);
+    parts
+        .into_iter()
+        .enumerate()
+        .map(|(i, part)| {
+            let key = map.entries().find(|(p, _)| *p == part).map_or(0, |(_, key)| key.as_int());
+            format!("Player {}: {:<10} {:<11} key {}\n", i + 1, instrument(part).name, part.name(), key)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+use crate::midi::core::parse_drum_mapping;
+
+#[test]
+fn test_legend() {
+    let mut overrides = DrumMap::default();
+    let (part, key) = parse_drum_mapping("hi-hat=82").unwrap();
+    overrides.set(part, key);
+    assert_eq!(
+        legend([DrumPart::KickDrum, DrumPart::HiHat, DrumPart::RideCymbal], &overrides),
+        "Player 1: Surdo      kick        key 41\n\
+         Player 2: Shaker     hi-hat      key 82\n\
+         Player 3: Claves     ride        key 75\n"
+    );
+}
@@ -0,0 +1,500 @@
+use std::str::FromStr;
+
+use crate::midi::core::{DrumPart, Event, Part, Tick, Velocity};
+use crate::midi::transform::{pair_notes, unpair_notes, PairedNote, Transform, TransformContext};
+use crate::random::{Domain, Streams};
+
+/// What's known about a note to a filter expression. Bars, beats and cycles are counted from 1, the way musicians
+/// count them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Variable {
+    Bar,
+    /// Beat within the bar, as long as the bottom of the time signature says.
+    Beat,
+    /// Ticks from the start of the beat, 48 per quarter note: 0 on the beat.
+    Offset,
+    Velocity,
+    /// Repetition of the converged pattern.
+    Cycle,
+}
+
+static VARIABLES: [(&str, Variable); 5] = [
+    ("bar", Variable::Bar),
+    ("beat", Variable::Beat),
+    ("offset", Variable::Offset),
+    ("velocity", Variable::Velocity),
+    ("cycle", Variable::Cycle),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operator {
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Remainder,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Comparison {
+    Equal,
+    NotEqual,
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Number {
+    Constant(f64),
+    Variable(Variable),
+    Negate(Box<Number>),
+    Binary(Box<Number>, Operator, Box<Number>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Condition {
+    /// `part == <part>`, or `part != <part>` when it's not `equal`.
+    Part { equal: bool, part: DrumPart },
+    Compare(Number, Comparison, Number),
+    Not(Box<Condition>),
+    And(Box<Condition>, Box<Condition>),
+    Or(Box<Condition>, Box<Condition>),
+}
+
+/// What happens to the notes a filter matches.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Action {
+    Mute,
+    /// Plays the notes at this velocity.
+    SetVelocity(u8),
+    /// Multiplies the velocity of the notes.
+    ScaleVelocity(f64),
+    /// Drops every note with this chance.
+    Thin(f64),
+}
+
+/// A condition on the notes of the drum parts and what to do with the matching ones, e.g.
+/// `part == hi-hat && beat % 2 == 0 -> velocity*0.5`. Without an action the notes are muted.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Filter {
+    pub condition: Condition,
+    pub action: Action,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Number(f64),
+    Name(String),
+    Symbol(&'static str),
+}
+
+/// Symbols of the expressions, the ones made of two characters go first.
+static SYMBOLS: [&str; 16] = ["==", "!=", "<=", ">=", "&&", "||", "<", ">", "!", "+", "-", "*", "/", "%", "(", ")"];
+
+fn tokens(s: &str) -> Result<Vec<Token>, String> {
+    // Part names have dashes in them, so they're matched as they are before anything else, longest first.
+    let mut parts: Vec<&str> = DrumPart::ALL.iter().map(|p| p.name()).collect();
+    parts.sort_by_key(|name| std::cmp::Reverse(name.len()));
+    let name_char = |c: char| c.is_ascii_alphanumeric() || c == '_';
+    let mut tokens = Vec::new();
+    let mut rest = s.trim_start();
+    while !rest.is_empty() {
+        let part = parts
+            .iter()
+            .find(|name| rest.starts_with(**name) && !rest[name.len()..].starts_with(name_char));
+        let (token, length) = if let Some(name) = part {
+            (Token::Name(name.to_string()), name.len())
+        } else if let Some(symbol) = SYMBOLS.iter().find(|symbol| rest.starts_with(**symbol)) {
+            (Token::Symbol(symbol), symbol.len())
+        } else if rest.starts_with(|c: char| c.is_ascii_digit() || c == '.') {
+            let length = rest.find(|c: char| !c.is_ascii_digit() && c != '.').unwrap_or(rest.len());
+            let number = &rest[..length];
+            (Token::Number(number.parse().map_err(|_| format!("Malformed number '{}'", number))?), length)
+        } else if rest.starts_with(|c: char| c.is_ascii_alphabetic()) {
+            let length = rest.find(|c: char| !name_char(c)).unwrap_or(rest.len());
+            (Token::Name(rest[..length].to_string()), length)
+        } else {
+            return Err(format!("Unexpected input: '{}'", rest));
+        };
+        tokens.push(token);
+        rest = rest[length..].trim_start();
+    }
+    Ok(tokens)
+}
+
+/// Recursive descent over the tokens, from the loosest operators to the tightest ones.
+struct Parser {
+    tokens: Vec<Token>,
+    position: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position)
+    }
+
+    fn symbol(&mut self, symbols: &[&'static str]) -> Option<&'static str> {
+        match self.peek() {
+            Some(Token::Symbol(s)) if symbols.contains(s) => {
+                let s = *s;
+                self.position += 1;
+                Some(s)
+            }
+            _ => None,
+        }
+    }
+
+    fn expect(&mut self, symbol: &'static str) -> Result<(), String> {
+        self.symbol(&[symbol]).map(|_| ()).ok_or_else(|| self.unexpected(&format!("'{}'", symbol)))
+    }
+
+    fn unexpected(&self, expected: &str) -> String {
+        match self.peek() {
+            None => format!("Expected {}, got the end", expected),
+            Some(Token::Number(n)) => format!("Expected {}, got {}", expected, n),
+            Some(Token::Name(name)) => format!("Expected {}, got '{}'", expected, name),
+            Some(Token::Symbol(s)) => format!("Expected {}, got '{}'", expected, s),
+        }
+    }
+
+    fn or(&mut self) -> Result<Condition, String> {
+        let mut condition = self.and()?;
+        while self.symbol(&["||"]).is_some() {
+            condition = Condition::Or(Box::new(condition), Box::new(self.and()?));
+        }
+        Ok(condition)
+    }
+
+    fn and(&mut self) -> Result<Condition, String> {
+        let mut condition = self.not()?;
+        while self.symbol(&["&&"]).is_some() {
+            condition = Condition::And(Box::new(condition), Box::new(self.not()?));
+        }
+        Ok(condition)
+    }
+
+    fn not(&mut self) -> Result<Condition, String> {
+        if self.symbol(&["!"]).is_some() {
+            return Ok(Condition::Not(Box::new(self.not()?)));
+        }
+        // A parenthesis opens either a condition or a number, try the condition first.
+        let start = self.position;
+        if self.symbol(&["("]).is_some() {
+            if let Ok(condition) = self.or().and_then(|c| self.expect(")").map(|_| c)) {
+                return Ok(condition);
+            }
+            self.position = start;
+        }
+        self.comparison()
+    }
+
+    fn comparison(&mut self) -> Result<Condition, String> {
+        if self.peek() == Some(&Token::Name("part".to_string())) {
+            self.position += 1;
+            let equal = match self.symbol(&["==", "!="]) {
+                Some("==") => true,
+                Some(_) => false,
+                None => return Err(self.unexpected("'==' or '!=' after 'part'")),
+            };
+            let part = match self.peek() {
+                Some(Token::Name(name)) => DrumPart::from_str(name)?,
+                _ => return Err(self.unexpected("a drum part")),
+            };
+            self.position += 1;
+            return Ok(Condition::Part { equal, part });
+        }
+        let left = self.sum()?;
+        let comparison = match self.symbol(&["==", "!=", "<=", ">=", "<", ">"]) {
+            Some("==") => Comparison::Equal,
+            Some("!=") => Comparison::NotEqual,
+            Some("<=") => Comparison::LessOrEqual,
+            Some(">=") => Comparison::GreaterOrEqual,
+            Some("<") => Comparison::Less,
+            Some(_) => Comparison::Greater,
+            None => return Err(self.unexpected("a comparison")),
+        };
+        Ok(Condition::Compare(left, comparison, self.sum()?))
+    }
+
+    fn sum(&mut self) -> Result<Number, String> {
+        let mut number = self.product()?;
+        while let Some(symbol) = self.symbol(&["+", "-"]) {
+            let operator = if symbol == "+" { Operator::Add } else { Operator::Subtract };
+            number = Number::Binary(Box::new(number), operator, Box::new(self.product()?));
+        }
+        Ok(number)
+    }
+
+    fn product(&mut self) -> Result<Number, String> {
+        let mut number = self.factor()?;
+        while let Some(symbol) = self.symbol(&["*", "/", "%"]) {
+            let operator = match symbol {
+                "*" => Operator::Multiply,
+                "/" => Operator::Divide,
+                _ => Operator::Remainder,
+            };
+            number = Number::Binary(Box::new(number), operator, Box::new(self.factor()?));
+        }
+        Ok(number)
+    }
+
+    fn factor(&mut self) -> Result<Number, String> {
+        if self.symbol(&["-"]).is_some() {
+            return Ok(Number::Negate(Box::new(self.factor()?)));
+        }
+        if self.symbol(&["("]).is_some() {
+            let number = self.sum()?;
+            self.expect(")")?;
+            return Ok(number);
+        }
+        let number = match self.peek() {
+            Some(Token::Number(n)) => Number::Constant(*n),
+            Some(Token::Name(name)) => match VARIABLES.iter().find(|(v, _)| v == name) {
+                Some((_, variable)) => Number::Variable(*variable),
+                None => {
+                    let names: Vec<&str> = VARIABLES.iter().map(|(name, _)| *name).collect();
+                    return Err(format!("Unknown variable '{}', expected 'part', {}", name, names.join(", ")));
+                }
+            },
+            _ => return Err(self.unexpected("a number")),
+        };
+        self.position += 1;
+        Ok(number)
+    }
+}
+
+impl FromStr for Condition {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parser = Parser { tokens: tokens(s)?, position: 0 };
+        let condition = parser.or()?;
+        match parser.peek() {
+            None => Ok(condition),
+            Some(_) => Err(parser.unexpected("the end")),
+        }
+    }
+}
+
+impl FromStr for Action {
+    type Err = String;
+
+    /// Reads `mute`, `velocity=<1..127>`, `velocity*<factor>` or `thin=<0..1>`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s: String = s.split_whitespace().collect();
+        let error = || format!("Expected 'mute', 'velocity=<1..127>', 'velocity*<factor>' or 'thin=<0..1>', got '{}'", s);
+        if s == "mute" {
+            Ok(Action::Mute)
+        } else if let Some(velocity) = s.strip_prefix("velocity=") {
+            match velocity.parse::<u8>() {
+                Ok(v) if (1..=127).contains(&v) => Ok(Action::SetVelocity(v)),
+                _ => Err(error()),
+            }
+        } else if let Some(factor) = s.strip_prefix("velocity*") {
+            match factor.parse::<f64>() {
+                Ok(f) if f >= 0.0 => Ok(Action::ScaleVelocity(f)),
+                _ => Err(error()),
+            }
+        } else if let Some(chance) = s.strip_prefix("thin=") {
+            match chance.parse::<f64>() {
+                Ok(c) if (0.0..=1.0).contains(&c) => Ok(Action::Thin(c)),
+                _ => Err(error()),
+            }
+        } else {
+            Err(error())
+        }
+    }
+}
+
+impl FromStr for Filter {
+    type Err = String;
+
+    /// Reads `<condition> [-> <action>]`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (condition, action) = match s.split_once("->") {
+            Some((condition, action)) => (condition, Action::from_str(action)?),
+            None => (s, Action::Mute),
+        };
+        Ok(Filter { condition: Condition::from_str(condition)?, action })
+    }
+}
+
+impl Number {
+    fn value(&self, note: &PairedNote, context: &TransformContext) -> f64 {
+        match self {
+            Number::Constant(n) => *n,
+            Number::Variable(variable) => {
+                let tick = note.start.0;
+                let (bar, beat, cycle) = (context.bar_length().0, context.beat_length().0, context.cycle.0);
+                match variable {
+                    Variable::Bar => (tick / bar.max(1) + 1) as f64,
+                    Variable::Beat => (tick % bar.max(1) / beat.max(1) + 1) as f64,
+                    Variable::Offset => (tick % beat.max(1)) as f64,
+                    Variable::Velocity => note.velocity.0 as f64,
+                    Variable::Cycle => (tick / cycle.max(1) + 1) as f64,
+                }
+            }
+            Number::Negate(n) => -n.value(note, context),
+            Number::Binary(left, operator, right) => {
+                let (left, right) = (left.value(note, context), right.value(note, context));
+                match operator {
+                    Operator::Add => left + right,
+                    Operator::Subtract => left - right,
+                    Operator::Multiply => left * right,
+                    Operator::Divide => left / right,
+                    Operator::Remainder => left % right,
+                }
+            }
+        }
+    }
+}
+
+impl Condition {
+    fn matches(&self, note: &PairedNote, context: &TransformContext) -> bool {
+        match self {
+            Condition::Part { equal, part } => (note.part == Part::Drum(*part)) == *equal,
+            Condition::Compare(left, comparison, right) => {
+                let (left, right) = (left.value(note, context), right.value(note, context));
+                match comparison {
+                    Comparison::Equal => left == right,
+                    Comparison::NotEqual => left != right,
+                    Comparison::Less => left < right,
+                    Comparison::LessOrEqual => left <= right,
+                    Comparison::Greater => left > right,
+                    Comparison::GreaterOrEqual => left >= right,
+                }
+            }
+            Condition::Not(condition) => !condition.matches(note, context),
+            Condition::And(left, right) => left.matches(note, context) && right.matches(note, context),
+            Condition::Or(left, right) => left.matches(note, context) || right.matches(note, context),
+        }
+    }
+}
+
+/// Applies the filters to the notes of the drum parts in order, each one to what the previous ones left. Thinning
+/// rolls from a random generator of its own for every bar of every part.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Filters {
+    pub filters: Vec<Filter>,
+    pub seed: u64,
+}
+
+impl Transform for Filters {
+    fn apply(&self, events: Vec<Event<Tick>>, context: &TransformContext) -> Vec<Event<Tick>> {
+        let mut streams = Streams::new(self.seed);
+        let bar = context.bar_length().0.max(1);
+        let mut notes = pair_notes(&events);
+        for filter in &self.filters {
+            notes = notes
+                .into_iter()
+                .filter_map(|mut note| {
+                    let Part::Drum(part) = note.part else { return Some(note) };
+                    if !filter.condition.matches(&note, context) {
+                        return Some(note);
+                    }
+                    match filter.action {
+                        Action::Mute => None,
+                        Action::Thin(chance) => {
+                            let rng = streams.get(&[Domain::Thin.key(), part.seed_key(), (note.start.0 / bar) as u64]);
+                            (!rng.chance(chance)).then_some(note)
+                        }
+                        Action::SetVelocity(velocity) => {
+                            note.velocity = Velocity(velocity);
+                            Some(note)
+                        }
+                        Action::ScaleVelocity(factor) => {
+                            note.velocity = Velocity((note.velocity.0 as f64 * factor).round().clamp(1.0, 127.0) as u8);
+                            Some(note)
+                        }
+                    }
+                })
+                .collect();
+        }
+        unpair_notes(&notes)
+    }
+}
+
+#[cfg(test)]
+use crate::midi::core::{DrumPart::*, EventType::*};
+#[cfg(test)]
+use crate::midi::mask::{Mask, Masks};
+#[cfg(test)]
+use crate::midi::transform::{drum_note, test_context};
+#[cfg(test)]
+use std::collections::BTreeMap;
+
+#[test]
+fn test_parse_filter() {
+    let filter = Filter::from_str("part==hi-hat && (beat-1) % 2 == 0 || !(velocity > 64) -> velocity*0.5").unwrap();
+    assert_eq!(filter.action, Action::ScaleVelocity(0.5));
+    let Condition::Or(left, right) = filter.condition else { panic!("Expected '||' on top") };
+    assert!(matches!(*left, Condition::And(ref part, _) if **part == Condition::Part { equal: true, part: HiHat }));
+    assert!(matches!(*right, Condition::Not(_)));
+    assert_eq!(Filter::from_str("part != open-hi-hat").unwrap().action, Action::Mute);
+    assert_eq!(
+        Filter::from_str("velocity-10>50").unwrap().condition,
+        Condition::Compare(
+            Number::Binary(
+                Box::new(Number::Variable(Variable::Velocity)),
+                Operator::Subtract,
+                Box::new(Number::Constant(10.0))
+            ),
+            Comparison::Greater,
+            Number::Constant(50.0)
+        )
+    );
+    assert!(Filter::from_str("part == cowbell").is_err());
+    assert!(Filter::from_str("tempo > 100").unwrap_err().starts_with("Unknown variable 'tempo'"));
+    assert_eq!(Filter::from_str("beat == 1 &&").unwrap_err(), "Expected a number, got the end");
+    assert!(Filter::from_str("beat").is_err());
+    assert!(Filter::from_str("beat == 1 -> louder").is_err());
+    assert!(Filter::from_str("beat == 1 -> thin=2").is_err());
+}
+
+#[test]
+fn test_filters() {
+    let context = test_context(384, 192);
+    // Eighth notes on the hi-hat and quarter notes on the snare drum over two bars.
+    let mut notes: Vec<PairedNote> = (0..16)
+        .map(|i| drum_note(HiHat, i * 24, 12, 100))
+        .chain((0..8).map(|i| drum_note(SnareDrum, i * 48, 12, 100)))
+        .collect();
+    notes.sort_by_key(|n| n.start);
+    let filters = |filters: &[&str]| Filters {
+        filters: filters.iter().map(|f| Filter::from_str(f).unwrap()).collect(),
+        seed: 1,
+    };
+    let kept = |filters: Filters| pair_notes(&filters.apply(unpair_notes(&notes), &context));
+
+    // Only the backbeat is left of the snare drum, the off-beats of the hi-hat are softer in the second bar.
+    let result = kept(filters(&["part == snare && beat % 2 == 1", "part == hi-hat && offset > 0 && bar == 2 -> velocity=40"]));
+    let snare: Vec<u128> = result.iter().filter(|n| n.part == Part::Drum(SnareDrum)).map(|n| n.start.0).collect();
+    assert_eq!(snare, vec![48, 144, 240, 336]);
+    let soft: Vec<u128> = result.iter().filter(|n| n.velocity == Velocity(40)).map(|n| n.start.0).collect();
+    assert_eq!(soft, vec![216, 264, 312, 360]);
+
+    let thinned = kept(filters(&["part == hi-hat -> thin=0.5"]));
+    let hi_hat = thinned.iter().filter(|n| n.part == Part::Drum(HiHat)).count();
+    assert!(hi_hat > 0 && hi_hat < 16);
+    assert_eq!(thinned.len() - hi_hat, 8);
+    assert_eq!(kept(filters(&["part == hi-hat -> thin=0.5"])), thinned);
+    // The second repetition of the converged pattern is muted.
+    let events = filters(&["cycle == 2"]).apply(unpair_notes(&notes), &context);
+    assert!(events.iter().all(|e| e.tick < Tick(192) || !matches!(e.event_type, NoteOn(..))));
+}
+
+#[test]
+fn test_thinning_masked_hits() {
+    // A hit a bar, which both the mask and the filter roll for with the first number of the bar.
+    let bars = 768;
+    let context = test_context(bars * 192, 192);
+    let events = unpair_notes(&(0..bars).map(|i| drum_note(HiHat, i * 192, 12, 100)).collect::<Vec<_>>());
+    // A third of the hits are left by the mask and half of those by the filter, with the same seed for both.
+    let masks = Masks { masks: BTreeMap::from([(HiHat, Mask::from_str("1 3").unwrap())]), seed: 3 };
+    let filters = Filters { filters: vec![Filter::from_str("part == hi-hat -> thin=0.5").unwrap()], seed: 3 };
+    let kept = pair_notes(&filters.apply(masks.apply(events, &context), &context)).len();
+    let expected = bars as f64 / 3.0 / 2.0;
+    assert!((kept as f64 - expected).abs() < expected * 0.25, "{} hits kept, expected about {}", kept, expected);
+}
@@ -0,0 +1,77 @@
+use midly::{MidiMessage, Smf, TrackEventKind};
+
+static FNV_OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+static FNV_PRIME: u64 = 0x100000001b3;
+
+/// 64-bit FNV-1a. `std`'s hashers may change between Rust versions, this one never does.
+#[derive(Debug, Clone, Copy)]
+struct Fnv1a(u64);
+
+impl Fnv1a {
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 ^= *byte as u64;
+            self.0 = self.0.wrapping_mul(FNV_PRIME);
+        }
+    }
+}
+
+/// Stable hash of what's played in the MIDI file: the time, the key and the velocity of every note of every track.
+/// Note lengths, tempo and text don't count, so the same groove spelled differently gets the same fingerprint.
+pub fn fingerprint(smf: &Smf) -> u64 {
+    let mut hash = Fnv1a(FNV_OFFSET_BASIS);
+    for (i, track) in smf.tracks.iter().enumerate() {
+        hash.write(&(i as u32).to_le_bytes());
+        let mut time: u64 = 0;
+        for event in track {
+            time += event.delta.as_int() as u64;
+            if let TrackEventKind::Midi { channel, message: MidiMessage::NoteOn { key, vel } } = event.kind {
+                if vel > 0 {
+                    hash.write(&time.to_le_bytes());
+                    hash.write(&[channel.as_int(), key.as_int(), vel.as_int()]);
+                }
+            }
+        }
+    }
+    hash.0
+}
+
+/// Stable hash of the contents of a file, to tell whether it changed.
+pub fn hash(bytes: &[u8]) -> u64 {
+    let mut hash = Fnv1a(FNV_OFFSET_BASIS);
+    hash.write(bytes);
+    hash.0
+}
+
+#[cfg(test)]
+use std::collections::BTreeMap;
+#[cfg(test)]
+use crate::dsl::dsl::groups;
+#[cfg(test)]
+use crate::midi::core::{generate, DrumPart::*, RenderOptions};
+
+#[cfg(test)]
+fn pattern_fingerprint(kick: &str, snare: &str) -> u64 {
+    let smf = generate(
+        BTreeMap::from_iter([(KickDrum, groups(kick).unwrap()), (SnareDrum, groups(snare).unwrap())]),
+        kick,
+        &RenderOptions::default(),
+    ).unwrap();
+    fingerprint(&smf)
+}
+
+#[test]
+fn test_fingerprint() {
+    let groove = pattern_fingerprint("8x-x-", "4-x");
+    assert_eq!(groove, pattern_fingerprint("16x---x---", "8--x-"));
+    assert_ne!(groove, pattern_fingerprint("8x-x-", "4-X"));
+    assert_ne!(groove, pattern_fingerprint("8x-x-", "4x-"));
+    // The hash must stay the same between versions and platforms.
+    assert_eq!(pattern_fingerprint("4x", "4-x"), 0x9e9141a0f007e87d);
+}
+
+#[test]
+fn test_hash() {
+    assert_eq!(hash(b""), FNV_OFFSET_BASIS);
+    assert_eq!(hash(b"a"), 0xaf63dc4c8601ec8c);
+}
@@ -0,0 +1,114 @@
+use std::collections::BTreeMap;
+
+use crate::midi::core::{Event, Tick, Velocity};
+use crate::midi::transform::{pair_notes, unpair_notes, PairedNote, Transform, TransformContext};
+use crate::random::Rng;
+
+/// Swing value that leaves the beat straight.
+pub static STRAIGHT_SWING: f64 = 50.0;
+
+/// Takes the track off the grid a bit, so it sounds less mechanical.
+///
+/// Random changes depend only on the seed and the position of the note on the grid, so notes played together,
+/// such as a kick drum and the bass following it, are moved together.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Humanize {
+    /// Maximum shift of a note from the grid in ticks, either way.
+    pub timing: u16,
+    /// Maximum change of the velocity of a note, either way.
+    pub velocity: u8,
+    /// Part of the beat taken by its first half, in percents: 50 is straight, 66 is close to a triplet shuffle.
+    pub swing: f64,
+    pub seed: u64,
+}
+
+impl Humanize {
+    /// Delays the off-beat subdivisions, stretching the first half of every beat and squeezing the second one.
+    fn swing(&self, tick: Tick, beat: Tick) -> Tick {
+        if beat == Tick(0) || self.swing == STRAIGHT_SWING {
+            return tick;
+        }
+        let within = (tick.0 % beat.0) as f64;
+        let half = beat.0 as f64 / 2.0;
+        let split = beat.0 as f64 * self.swing / 100.0;
+        let swung = if within <= half {
+            within * split / half
+        } else {
+            split + (within - half) * (beat.0 as f64 - split) / half
+        };
+        Tick(tick.0 - tick.0 % beat.0 + swung.round() as u128)
+    }
+}
+
+impl Transform for Humanize {
+    fn apply(&self, events: Vec<Event<Tick>>, context: &TransformContext) -> Vec<Event<Tick>> {
+        let beat = context.beat_length();
+        let mut notes: Vec<PairedNote> = pair_notes(&events)
+            .into_iter()
+            .map(|n| {
+                let mut rng = Rng::new(self.seed ^ n.start.0 as u64);
+                let jitter = rng.below(2 * self.timing as u64 + 1) as i128 - self.timing as i128;
+                let shift = |tick: Tick| Tick((self.swing(tick, beat).0 as i128 + jitter).max(0) as u128);
+                let velocity_change = rng.below(2 * self.velocity as u64 + 1) as i16 - self.velocity as i16;
+                PairedNote {
+                    start: shift(n.start),
+                    end: shift(n.end),
+                    velocity: Velocity((n.velocity.0 as i16 + velocity_change).clamp(1, 127) as u8),
+                    ..n
+                }
+            })
+            .collect();
+        // Notes of the same part must not overlap, otherwise the next note would be cut off by the previous one.
+        notes.sort_by_key(|n| n.start);
+        let mut next_start = BTreeMap::new();
+        for n in notes.iter_mut().rev() {
+            if let Some(next) = next_start.insert(n.part, n.start) {
+                n.end = n.end.min(next).max(n.start);
+            }
+        }
+        unpair_notes(&notes)
+    }
+}
+
+#[cfg(test)]
+use crate::midi::core::{DrumPart::*, Part::*};
+#[cfg(test)]
+use crate::midi::transform::{drum_note, test_context};
+
+#[cfg(test)]
+fn context() -> TransformContext {
+    test_context(192, 192)
+}
+
+#[test]
+fn test_swing() {
+    let humanize = Humanize { timing: 0, velocity: 0, swing: 66.0, seed: 0 };
+    let hihat = |start: u128, end: u128| drum_note(HiHat, start, end - start, 100);
+    let events = unpair_notes(&[hihat(0, 24), hihat(24, 48), hihat(84, 96)]);
+    assert_eq!(
+        pair_notes(&humanize.apply(events, &context())),
+        vec![hihat(0, 32), hihat(32, 48), hihat(88, 96)]
+    );
+}
+
+#[test]
+fn test_humanize_moves_parts_together() {
+    let humanize = Humanize { timing: 5, velocity: 10, swing: STRAIGHT_SWING, seed: 17 };
+    let note = |part, start: u128| PairedNote {
+        part,
+        start: Tick(start),
+        end: Tick(start + 12),
+        velocity: Velocity(100),
+    };
+    let notes: Vec<PairedNote> = (0..8).flat_map(|i| [note(Drum(KickDrum), i * 24), note(Bass, i * 24)]).collect();
+    let humanized = pair_notes(&humanize.apply(unpair_notes(&notes), &context()));
+    assert_eq!(humanized.len(), notes.len());
+    assert_ne!(humanized, pair_notes(&unpair_notes(&notes)));
+    for pair in humanized.chunks(2) {
+        assert_eq!((pair[0].start, pair[0].velocity), (pair[1].start, pair[1].velocity));
+    }
+    for (original, moved) in notes.iter().step_by(2).zip(humanized.iter().step_by(2)) {
+        assert!(original.start.0.abs_diff(moved.start.0) <= 5);
+        assert!((90..=110).contains(&moved.velocity.0));
+    }
+}
@@ -0,0 +1,365 @@
+use std::collections::BTreeMap;
+
+use midly::{MetaMessage, MidiMessage, Smf, Timing, TrackEventKind};
+
+use crate::dsl::dsl::{BasicLength, KnownLength, LengthNames};
+use crate::midi::core::{DrumMap, DrumPart};
+use crate::midi::humanize::STRAIGHT_SWING;
+use crate::midi::time::{Pulse, TimeSignature};
+
+/// Imported notes at least this loud become accents.
+static ACCENT_VELOCITY: u8 = 114;
+/// Imported notes quieter than this become ghost notes.
+static GHOST_VELOCITY: u8 = 70;
+/// The most swing `detect_swing` looks for, same as the most `--swing` takes.
+static MAX_SWING: u32 = 75;
+/// Seconds per quarter note in files without a tempo, they're played at 120 BPM.
+static DEFAULT_QUARTER: f64 = 0.5;
+/// The slowest tempo `detect_tempo` picks.
+static MIN_DETECTED_TEMPO: f64 = 80.0;
+
+/// A drum part hit read from a MIDI file.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ImportedHit {
+    pub part: DrumPart,
+    /// Position from the start of the file, in beats of the time signature.
+    pub beat: f64,
+    pub velocity: u8,
+}
+
+/// Drum parts of a MIDI file written down as patterns.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Import {
+    pub time_signature: TimeSignature,
+    pub tempo: u16,
+    /// The file has no tempo, so it was detected from the notes.
+    pub tempo_detected: bool,
+    /// Swing the notes were played with, `STRAIGHT_SWING` if they weren't swung.
+    pub swing: f64,
+    pub patterns: BTreeMap<DrumPart, String>,
+    /// Notes on keys that aren't mapped to any drum part, these are left out.
+    pub skipped: usize,
+}
+
+impl Import {
+    /// The patterns as a pattern file, with the settings to render them with in a comment.
+    pub fn to_pattern_file(&self) -> String {
+        let mut settings = format!("--tempo {} --time-signature {}", self.tempo, self.time_signature);
+        if self.swing != STRAIGHT_SWING {
+            settings.push_str(&format!(" --swing {}", self.swing));
+        }
+        let mut file = format!("# Imported from MIDI, render with {}\nversion: 1\n", settings);
+        for (part, pattern) in &self.patterns {
+            file.push_str(&format!("{}: {}\n", part.name(), pattern));
+        }
+        file
+    }
+}
+
+/// A drum part, its position in quarter notes from the start and its velocity.
+type RawNote = (DrumPart, f64, u8);
+
+/// What `read_notes` finds in a MIDI file.
+struct Notes {
+    notes: Vec<RawNote>,
+    /// The first time signature of the file, 4/4 if there's none.
+    time_signature: TimeSignature,
+    /// The first tempo of the file in BPM, if there's one.
+    tempo: Option<f64>,
+    skipped: usize,
+}
+
+/// Reads the drum notes from all the tracks of the file, along with its time signature and tempo.
+fn read_notes(smf: &Smf, drum_map: &DrumMap) -> Notes {
+    let ticks_per_quarter = match smf.header.timing {
+        Timing::Metrical(t) => t.as_int() as f64,
+        Timing::Timecode(_, _) => 48.0,
+    };
+    let mut time_signature = None;
+    let mut tempo = None;
+    let mut notes = Vec::new();
+    let mut skipped = 0;
+    for track in &smf.tracks {
+        let mut tick = 0u64;
+        for event in track {
+            tick += event.delta.as_int() as u64;
+            match event.kind {
+                TrackEventKind::Meta(MetaMessage::TimeSignature(numerator, denominator, _, _)) => {
+                    let denominator = BasicLength::from_num(1 << denominator.min(6));
+                    if let (None, Ok(denominator)) = (time_signature, denominator) {
+                        time_signature = Some(TimeSignature { numerator, denominator });
+                    }
+                }
+                TrackEventKind::Meta(MetaMessage::Tempo(microseconds)) if microseconds.as_int() > 0 => {
+                    tempo = tempo.or(Some(60_000_000.0 / microseconds.as_int() as f64));
+                }
+                TrackEventKind::Midi { message: MidiMessage::NoteOn { key, vel }, .. } if vel > 0 => {
+                    match drum_map.part(key) {
+                        Some(part) => notes.push((part, tick as f64 / ticks_per_quarter, vel.as_int())),
+                        None => skipped += 1,
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+    notes.sort_by(|a, b| a.1.total_cmp(&b.1));
+    Notes {
+        notes,
+        time_signature: time_signature.unwrap_or(TimeSignature { numerator: 4, denominator: BasicLength::Fourth }),
+        tempo,
+        skipped,
+    }
+}
+
+/// Finds the tempo of notes played without a tempo map, from their positions in quarter notes at the default
+/// 120 BPM. Returns the tempo along with the notes moved onto its beats, the first note being on a beat.
+/// The tempo is picked between `MIN_DETECTED_TEMPO` and twice as much, as the notes alone can't tell
+/// e.g. 60 BPM from 120 BPM.
+fn detect_tempo(notes: &[RawNote]) -> Option<(f64, Vec<RawNote>)> {
+    let seconds: Vec<f64> = notes.iter().map(|(_, quarter, _)| quarter * DEFAULT_QUARTER).collect();
+    let pulse = Pulse::detect(&seconds)?;
+    let mut tempo = pulse.tempo();
+    while tempo < MIN_DETECTED_TEMPO {
+        tempo *= 2.0;
+    }
+    while tempo >= MIN_DETECTED_TEMPO * 2.0 {
+        tempo /= 2.0;
+    }
+    Some((tempo, at_tempo(notes, tempo, pulse.offset)))
+}
+
+/// Moves notes played without a tempo map onto the beats of the tempo, starting at `offset` seconds.
+fn at_tempo(notes: &[RawNote], tempo: f64, offset: f64) -> Vec<RawNote> {
+    notes
+        .iter()
+        .map(|&(part, quarter, velocity)| (part, (quarter * DEFAULT_QUARTER - offset) * tempo / 60.0, velocity))
+        .collect()
+}
+
+/// Moves a position within the beat, from 0 to 1, the way the swing does: the first half of the beat is stretched
+/// to `swing` percents of it and the second half is squeezed into the rest.
+fn swing_position(position: f64, swing: f64) -> f64 {
+    let split = swing / 100.0;
+    if position <= 0.5 {
+        position * split / 0.5
+    } else {
+        split + (position - 0.5) * (1.0 - split) / 0.5
+    }
+}
+
+/// Reverses `swing_position`.
+fn unswing_position(position: f64, swing: f64) -> f64 {
+    let split = swing / 100.0;
+    if position <= split {
+        position * 0.5 / split
+    } else {
+        0.5 + (position - split) * 0.5 / (1.0 - split)
+    }
+}
+
+/// Number of steps in a beat, 0 if the step doesn't fit into the beat evenly.
+fn steps_per_beat(time_signature: TimeSignature, step: BasicLength) -> u32 {
+    let beat = time_signature.denominator.to_128th();
+    if step.to_128th() > beat || !beat.is_multiple_of(step.to_128th()) {
+        0
+    } else {
+        beat / step.to_128th()
+    }
+}
+
+/// Finds the swing, in whole percents, whose grid of `step`s the hits are the closest to. Grids with a step
+/// of a beat or longer can't be swung.
+pub fn detect_swing(hits: &[ImportedHit], time_signature: TimeSignature, step: BasicLength) -> f64 {
+    let steps = steps_per_beat(time_signature, step);
+    if steps < 2 || !steps.is_multiple_of(2) {
+        return STRAIGHT_SWING;
+    }
+    let error = |swing: f64| -> f64 {
+        hits.iter()
+            .map(|hit| {
+                let position = hit.beat.fract();
+                (0..=steps)
+                    .map(|i| (swing_position(i as f64 / steps as f64, swing) - position).abs())
+                    .fold(f64::INFINITY, f64::min)
+            })
+            .sum()
+    };
+    let mut best = (STRAIGHT_SWING, error(STRAIGHT_SWING));
+    for swing in STRAIGHT_SWING as u32 + 1..=MAX_SWING {
+        let e = error(swing as f64);
+        // Straighter grids win ties, a small margin keeps rounding errors from swinging straight grooves.
+        if e < best.1 - 1e-6 {
+            best = (swing as f64, e);
+        }
+    }
+    best.0
+}
+
+/// Writes the hits down as patterns of `step`s, quantizing them to the grid swung by `swing`. Every pattern
+/// covers all the bars the hits span. Velocities become accents, hits and ghost notes. The length of the steps
+/// is written as `names` go.
+pub fn quantize(
+    hits: &[ImportedHit],
+    time_signature: TimeSignature,
+    step: BasicLength,
+    swing: f64,
+    names: LengthNames,
+) -> Result<BTreeMap<DrumPart, String>, String> {
+    let steps = steps_per_beat(time_signature, step);
+    if steps == 0 {
+        return Err(format!(
+            "1/{} notes don't fit into a beat of {}",
+            128 / step.to_128th(),
+            time_signature
+        ));
+    }
+    let steps_per_bar = steps as usize * time_signature.numerator as usize;
+    let mut slots: BTreeMap<DrumPart, BTreeMap<usize, u8>> = BTreeMap::new();
+    for hit in hits {
+        let beat = hit.beat.floor() + unswing_position(hit.beat.fract(), swing);
+        let index = (beat * steps as f64).round() as usize;
+        let velocity = slots.entry(hit.part).or_default().entry(index).or_default();
+        *velocity = (*velocity).max(hit.velocity);
+    }
+    let last = slots.values().filter_map(|s| s.keys().last()).max().copied().unwrap_or(0);
+    let length = (last / steps_per_bar + 1) * steps_per_bar;
+    Ok(slots
+        .into_iter()
+        .map(|(part, slots)| {
+            let notes: String = (0..length)
+                .map(|i| match slots.get(&i) {
+                    None => '-',
+                    Some(&v) if v >= ACCENT_VELOCITY => 'X',
+                    Some(&v) if v < GHOST_VELOCITY => 'g',
+                    Some(_) => 'x',
+                })
+                .collect();
+            (part, names.write(step, &notes))
+        })
+        .collect())
+}
+
+/// Reads the drum parts of the file and writes them down as patterns of `step`s, detecting the swing unless
+/// `swing` is given. Files without a tempo are taken as played freely at `tempo`, or at the tempo detected from
+/// the notes if it's not given, in both cases starting on the first note.
+pub fn import(
+    smf: &Smf,
+    drum_map: &DrumMap,
+    step: BasicLength,
+    swing: Option<f64>,
+    tempo: Option<f64>,
+    names: LengthNames,
+) -> Result<Import, String> {
+    let Notes { notes, time_signature, tempo: file_tempo, skipped } = read_notes(smf, drum_map);
+    let (tempo, tempo_detected, notes) = match (file_tempo, tempo) {
+        (Some(file_tempo), _) => (file_tempo, false, notes),
+        (None, Some(tempo)) => {
+            let offset = notes.first().map_or(0.0, |(_, quarter, _)| quarter * DEFAULT_QUARTER);
+            (tempo, false, at_tempo(&notes, tempo, offset))
+        }
+        (None, None) => match detect_tempo(&notes) {
+            Some((tempo, notes)) => (tempo, true, notes),
+            None => return Err("There are too few drum notes to detect the tempo, pass it instead".to_string()),
+        },
+    };
+    if notes.is_empty() {
+        return Err("There are no drum notes".to_string());
+    }
+    let quarters_per_beat = time_signature.denominator.to_128th() as f64 / BasicLength::Fourth.to_128th() as f64;
+    let hits: Vec<ImportedHit> = notes
+        .into_iter()
+        .map(|(part, quarter, velocity)| ImportedHit { part, beat: quarter / quarters_per_beat, velocity })
+        .collect();
+    let swing = swing.unwrap_or_else(|| detect_swing(&hits, time_signature, step));
+    let patterns = quantize(&hits, time_signature, step, swing, names)?;
+    Ok(Import {
+        time_signature,
+        tempo: tempo.round() as u16,
+        tempo_detected,
+        swing,
+        patterns,
+        skipped,
+    })
+}
+
+#[cfg(test)]
+use std::str::FromStr;
+#[cfg(test)]
+use crate::dsl::dsl::groups;
+#[cfg(test)]
+use crate::midi::core::{generate, DrumPart::*, RenderOptions};
+#[cfg(test)]
+use crate::midi::humanize::Humanize;
+
+#[test]
+fn test_swing_position() {
+    assert_eq!(swing_position(0.5, 66.0), 0.66);
+    assert_eq!(swing_position(0.25, 50.0), 0.25);
+    for position in [0.0, 0.25, 0.5, 0.75, 1.0] {
+        assert!((unswing_position(swing_position(position, 62.0), 62.0) - position).abs() < 1e-9);
+    }
+}
+
+#[test]
+fn test_import() {
+    let parts = BTreeMap::from([
+        (KickDrum, groups("8x--x--").unwrap()),
+        (HiHat, groups("8xXgxxx").unwrap()),
+    ]);
+    let signature = TimeSignature::from_str("3/4").unwrap();
+    let render = |swing: f64| {
+        let options = RenderOptions {
+            time_signature: signature,
+            humanize: Some(Humanize { timing: 0, velocity: 0, swing, seed: 0 }),
+            ..Default::default()
+        };
+        let smf = generate(parts.clone(), "", &options).unwrap();
+        let mut bytes = Vec::new();
+        smf.write_std(&mut bytes).unwrap();
+        bytes
+    };
+    for swing in [STRAIGHT_SWING, 62.0] {
+        let bytes = render(swing);
+        let smf = Smf::parse(&bytes).unwrap();
+        let imported = import(&smf, &DrumMap::default(), BasicLength::Eighth, None, None, LengthNames::Numbers).unwrap();
+        assert_eq!(imported.swing, swing);
+        assert_eq!(imported.time_signature, signature);
+        assert_eq!((imported.tempo, imported.tempo_detected), (120, false));
+        assert_eq!(imported.patterns[&KickDrum], "8x--x--");
+        assert_eq!(imported.patterns[&HiHat], "8xXgxxx");
+    }
+    let bytes = render(62.0);
+    let smf = Smf::parse(&bytes).unwrap();
+    let imported = import(&smf, &DrumMap::default(), BasicLength::Eighth, None, None, LengthNames::British).unwrap();
+    assert_eq!(
+        imported.to_pattern_file(),
+        "# Imported from MIDI, render with --tempo 120 --time-signature 3/4 --swing 62\n\
+         version: 1\n\
+         kick: quaver x--x--\n\
+         hi-hat: quaver xXgxxx\n"
+    );
+    assert!(import(&smf, &DrumMap::default(), BasicLength::Half, None, None, LengthNames::Numbers).is_err());
+}
+
+#[test]
+fn test_import_without_tempo() {
+    let parts = BTreeMap::from([(KickDrum, groups("4x-x-").unwrap()), (HiHat, groups("16xxxxxxxxxxxxxxxx").unwrap())]);
+    let smf = generate(parts, "", &RenderOptions { tempos: vec![100], ..Default::default() }).unwrap();
+    let mut bytes = Vec::new();
+    smf.write_std(&mut bytes).unwrap();
+    // Played freely at 100 BPM and recorded without a tempo map: a quarter note takes 1.2 quarter notes at 120 BPM.
+    let mut smf = Smf::parse(&bytes).unwrap();
+    smf.header.timing = Timing::Metrical(40.into());
+    for track in smf.tracks.iter_mut() {
+        track.retain(|e| !matches!(e.kind, TrackEventKind::Meta(MetaMessage::Tempo(_))));
+    }
+    let imported = import(&smf, &DrumMap::default(), BasicLength::Sixteenth, None, None, LengthNames::Numbers).unwrap();
+    assert_eq!((imported.tempo, imported.tempo_detected), (100, true));
+    assert_eq!(imported.patterns[&KickDrum], "16x-------x-------");
+    assert_eq!(imported.patterns[&HiHat], "16xxxxxxxxxxxxxxxx");
+    // Twice as fast, the sixteenths become eighths.
+    let imported = import(&smf, &DrumMap::default(), BasicLength::Sixteenth, None, Some(200.0), LengthNames::Numbers).unwrap();
+    assert_eq!((imported.tempo, imported.tempo_detected), (200, false));
+    assert_eq!(imported.patterns[&HiHat], format!("16{}", "x-".repeat(16)));
+}
@@ -0,0 +1,134 @@
+use midly::{MetaMessage, Smf, Timing, TrackEvent, TrackEventKind};
+
+/// A marker: the tick it's at and its name.
+pub type Marker = (u32, String);
+
+/// Ticks per quarter note of the file.
+fn resolution(smf: &Smf) -> u32 {
+    match smf.header.timing {
+        Timing::Metrical(t) => t.as_int() as u32,
+        // Timecode-based files aren't produced by Poly, treat them as having the default resolution.
+        Timing::Timecode(_, _) => 48,
+    }
+}
+
+/// Time signature changes of the first track, the one taking the tempo map, with the length of a bar in ticks,
+/// along with the time the track ends at.
+fn bar_lengths(smf: &Smf) -> (Vec<(u32, u32)>, u32) {
+    let whole = resolution(smf) * 4;
+    let mut changes = Vec::new();
+    let mut tick = 0;
+    for event in smf.tracks.first().into_iter().flatten() {
+        tick += event.delta.as_int();
+        if let TrackEventKind::Meta(MetaMessage::TimeSignature(numerator, denominator, _, _)) = event.kind {
+            changes.push((tick, (numerator as u32 * whole) >> denominator));
+        }
+    }
+    (changes, tick)
+}
+
+/// Sections of a single groove: every repetition of the converged pattern, `bars` long, is a cycle of its own. The
+/// parts line up again at the start of every cycle.
+pub fn cycles(smf: &Smf, bars: u32) -> Vec<Marker> {
+    let (changes, end) = bar_lengths(smf);
+    let cycle = changes.first().map_or(0, |(_, bar)| bar * bars);
+    if cycle == 0 {
+        return Vec::new();
+    }
+    (0..end.div_ceil(cycle)).map(|i| (i * cycle, format!("cycle {}", i + 1))).collect()
+}
+
+/// A marker at the start of every bar, named after the bar, counting from 1, and the section it's in, e.g.
+/// `Bar 5 - verse`. Sections start at the given ticks, a bar starts at each of them and at every change of the
+/// time signature, even if the previous one isn't complete.
+pub fn markers(smf: &Smf, sections: &[Marker]) -> Vec<Marker> {
+    let (changes, end) = bar_lengths(smf);
+    let mut restarts: Vec<u32> = changes
+        .iter()
+        .map(|(tick, _)| *tick)
+        .chain(sections.iter().map(|(tick, _)| *tick))
+        .collect();
+    restarts.sort();
+    restarts.dedup();
+    let mut markers = Vec::new();
+    let mut tick = 0;
+    while tick < end {
+        let bar = changes.iter().rev().find(|(start, _)| *start <= tick).map_or(resolution(smf) * 4, |(_, bar)| *bar);
+        let name = match sections.iter().rev().find(|(start, _)| *start <= tick) {
+            Some((_, section)) => format!("Bar {} - {}", markers.len() + 1, section),
+            None => format!("Bar {}", markers.len() + 1),
+        };
+        markers.push((tick, name));
+        let next = restarts.iter().copied().find(|restart| *restart > tick).unwrap_or(u32::MAX);
+        tick = (tick + bar.max(1)).min(next);
+    }
+    markers
+}
+
+/// Writes the markers to the first track, before anything else happening at the same time. Editors read them as
+/// a cue list, placed in time by the tempo map of the file.
+pub fn add_markers<'a>(smf: &mut Smf<'a>, markers: &'a [Marker]) {
+    let Some(track) = smf.tracks.first_mut() else { return };
+    let mut tick = 0;
+    let mut events: Vec<(u32, TrackEventKind<'a>)> = track
+        .iter()
+        .map(|event| {
+            tick += event.delta.as_int();
+            (tick, event.kind)
+        })
+        .collect();
+    for (tick, name) in markers.iter().rev() {
+        let at = events.partition_point(|(t, _)| t < tick);
+        events.insert(at, (*tick, TrackEventKind::Meta(MetaMessage::Marker(name.as_bytes()))));
+    }
+    let mut previous = 0;
+    *track = events
+        .into_iter()
+        .map(|(tick, kind)| {
+            let delta = tick - previous;
+            previous = tick;
+            TrackEvent { delta: delta.into(), kind }
+        })
+        .collect();
+}
+
+#[cfg(test)]
+use std::collections::BTreeMap;
+#[cfg(test)]
+use std::str::FromStr;
+#[cfg(test)]
+use crate::dsl::dsl::groups;
+#[cfg(test)]
+use crate::midi::core::{generate, DrumPart, RenderOptions};
+#[cfg(test)]
+use crate::midi::time::TimeSignature;
+
+#[test]
+fn test_markers() {
+    // Three against four converges over 3 bars of 4/4, played at two tempos.
+    let patterns =
+        BTreeMap::from([(DrumPart::KickDrum, groups("8x--").unwrap()), (DrumPart::HiHat, groups("4x").unwrap())]);
+    let options = RenderOptions { tempos: vec![120, 140], ..Default::default() };
+    let mut smf = generate(patterns.clone(), "", &options).unwrap();
+    let sections = cycles(&smf, 3);
+    assert_eq!(sections, vec![(0, "cycle 1".to_string()), (576, "cycle 2".to_string())]);
+    let cues = markers(&smf, &sections);
+    assert_eq!(cues.len(), 6);
+    assert_eq!(cues[3], (576, "Bar 4 - cycle 2".to_string()));
+    assert_eq!(markers(&smf, &[])[1], (192, "Bar 2".to_string()));
+    add_markers(&mut smf, &cues);
+    let mut tick = 0;
+    let mut written = Vec::new();
+    for event in &smf.tracks[0] {
+        tick += event.delta.as_int();
+        if let TrackEventKind::Meta(MetaMessage::Marker(name)) = event.kind {
+            written.push((tick, String::from_utf8_lossy(name).into_owned()));
+        }
+    }
+    assert_eq!(written, cues);
+    // The notes stay where they were.
+    assert_eq!(tick, 1152);
+    let options = RenderOptions { time_signature: TimeSignature::from_str("7/8").unwrap(), ..Default::default() };
+    let smf = generate(patterns, "", &options).unwrap();
+    assert_eq!(markers(&smf, &[]).iter().map(|(tick, _)| *tick).collect::<Vec<_>>()[..3], [0, 168, 336]);
+}
@@ -0,0 +1,117 @@
+use std::collections::BTreeMap;
+use std::str::FromStr;
+
+use crate::dsl::dsl::BasicLength;
+use crate::midi::core::{DrumPart, Event, Part, Tick};
+use crate::midi::transform::{pair_notes, unpair_notes, PairedNote, Transform, TransformContext};
+use crate::random::{Domain, Streams};
+
+/// Chance for the hits of a drum part to be played, changing over time like a pattern. Every step is a digit
+/// from 0 (never played) to 9 (always played), the steps repeat for as long as the track goes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Mask {
+    pub step: BasicLength,
+    pub chances: Vec<u8>,
+}
+
+impl Mask {
+    /// Chance for a hit starting at the tick to be played, from 0 to 1.
+    pub fn chance(&self, tick: Tick) -> f64 {
+        let step = self.step.to_ticks().0;
+        let index = (tick.0 / step) as usize % self.chances.len();
+        self.chances[index] as f64 / 9.0
+    }
+}
+
+impl FromStr for Mask {
+    type Err = String;
+
+    /// Reads the length of a step followed by the steps, e.g. `16 9999 5555`. Spaces between the steps are ignored.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (step, chances) = s
+            .trim()
+            .split_once(char::is_whitespace)
+            .ok_or_else(|| format!("Expected '<length> <digits>', got '{}'", s))?;
+        let step = BasicLength::from_str(step)?;
+        let chances = chances
+            .chars()
+            .filter(|c| !c.is_whitespace())
+            .map(|c| {
+                c.to_digit(10)
+                    .map(|d| d as u8)
+                    .ok_or_else(|| format!("'{}' is not a digit from 0 to 9", c))
+            })
+            .collect::<Result<Vec<u8>, String>>()?;
+        if chances.is_empty() {
+            return Err(format!("No steps in '{}'", s));
+        }
+        Ok(Mask { step, chances })
+    }
+}
+
+/// Parses a mask of a single drum part like `hi-hat=16 9999 5555`.
+pub fn parse_part_mask(s: &str) -> Result<(DrumPart, Mask), String> {
+    let (part, mask) = s
+        .split_once('=')
+        .ok_or_else(|| format!("Expected '<part>=<mask>', got '{}'", s))?;
+    Ok((DrumPart::from_str(part)?, Mask::from_str(mask)?))
+}
+
+/// Leaves out some of the hits of the drum parts after their masks, rolling the chance anew on every hit. Every
+/// bar of every part rolls from a random generator of its own.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Masks {
+    pub masks: BTreeMap<DrumPart, Mask>,
+    pub seed: u64,
+}
+
+impl Transform for Masks {
+    fn apply(&self, events: Vec<Event<Tick>>, context: &TransformContext) -> Vec<Event<Tick>> {
+        let mut streams = Streams::new(self.seed);
+        let bar = context.bar_length().0.max(1);
+        let notes: Vec<PairedNote> = pair_notes(&events)
+            .into_iter()
+            .filter(|n| match n.part {
+                Part::Drum(part) => self.masks.get(&part).is_none_or(|mask| {
+                    let rng = streams.get(&[Domain::Mask.key(), part.seed_key(), (n.start.0 / bar) as u64]);
+                    rng.chance(mask.chance(n.start))
+                }),
+                _ => true,
+            })
+            .collect();
+        unpair_notes(&notes)
+    }
+}
+
+#[cfg(test)]
+use crate::midi::core::DrumPart::*;
+#[cfg(test)]
+use crate::midi::transform::{drum_note, test_context};
+
+#[test]
+fn test_parse_mask() {
+    let (part, mask) = parse_part_mask("hi-hat=16 9999 5550").unwrap();
+    assert_eq!(part, HiHat);
+    assert_eq!(mask, Mask { step: BasicLength::Sixteenth, chances: vec![9, 9, 9, 9, 5, 5, 5, 0] });
+    assert_eq!(mask.chance(Tick(0)), 1.0);
+    assert_eq!(mask.chance(Tick(12 * 7)), 0.0);
+    assert_eq!(mask.chance(Tick(12 * 8)), 1.0);
+    assert!(parse_part_mask("hi-hat=16").is_err());
+    assert!(parse_part_mask("hi-hat=16 9x").is_err());
+    assert!(parse_part_mask("hi-hat=3 99").is_err());
+    assert!(parse_part_mask("cowbell=16 99").is_err());
+}
+
+#[test]
+fn test_masks() {
+    let context = test_context(192, 192);
+    let note = |part: DrumPart, tick: u128| drum_note(part, tick, 24, 100);
+    let events = unpair_notes(&(0..8).flat_map(|i| [note(KickDrum, i * 24), note(HiHat, i * 24)]).collect::<Vec<_>>());
+    let masks = Masks { masks: BTreeMap::from([(HiHat, Mask::from_str("8 90").unwrap())]), seed: 0 };
+    // Every other hi-hat is left out, the kick drum isn't masked.
+    let expected: Vec<PairedNote> = (0..8)
+        .flat_map(|i| [Some(note(KickDrum, i * 24)), (i % 2 == 0).then(|| note(HiHat, i * 24))])
+        .flatten()
+        .collect();
+    assert_eq!(pair_notes(&masks.apply(events, &context)), expected);
+}
@@ -1,2 +1,33 @@
+#[cfg(feature = "std")]
 pub mod core;
-pub mod time;
\ No newline at end of file
+#[cfg(feature = "std")]
+pub mod doubling;
+#[cfg(feature = "std")]
+pub mod ensemble;
+#[cfg(feature = "std")]
+pub mod filter;
+#[cfg(feature = "std")]
+pub mod fingerprint;
+#[cfg(feature = "std")]
+pub mod humanize;
+#[cfg(feature = "std")]
+pub mod import;
+#[cfg(feature = "std")]
+pub mod markers;
+#[cfg(feature = "std")]
+pub mod mask;
+#[cfg(feature = "playback")]
+pub mod playback;
+#[cfg(feature = "std")]
+pub mod provenance;
+#[cfg(feature = "std")]
+pub mod target;
+pub mod time;
+#[cfg(feature = "std")]
+pub mod timeline;
+#[cfg(feature = "std")]
+pub mod transform;
+#[cfg(feature = "std")]
+pub mod trigger;
+#[cfg(feature = "std")]
+pub mod variant;
@@ -0,0 +1,298 @@
+use std::collections::BTreeSet;
+use std::fmt;
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use midly::live::LiveEvent;
+use midly::{MetaMessage, MidiMessage, Smf, Timing, TrackEventKind};
+
+use crate::midi::core::{DrumMap, DrumPart};
+
+/// MIDI default, used until the first tempo event.
+static DEFAULT_MICROSECONDS_PER_QUARTER: u64 = 500000;
+/// Controller turning off every note of a channel.
+static ALL_NOTES_OFF: u8 = 123;
+/// Longest the playback sleeps before checking whether it has been stopped.
+static STOP_CHECK_INTERVAL: Duration = Duration::from_millis(20);
+
+/// A MIDI message to send to an output port, `at` microseconds after the playback has started.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScheduledMessage {
+    pub at: u64,
+    pub message: Vec<u8>,
+}
+
+/// Lays the channel messages of all the tracks out in real time, following the tempo changes, and plays the
+/// whole file `loops` times in a row. Meta events aren't sent, they only drive the timing.
+pub fn schedule(smf: &Smf, loops: u32) -> Vec<ScheduledMessage> {
+    let (pass, length) = real_time(smf);
+    (0..loops as u64)
+        .flat_map(|i| {
+            pass.iter().map(move |m| ScheduledMessage {
+                at: m.at + length * i,
+                message: m.message.clone(),
+            })
+        })
+        .collect()
+}
+
+/// Writes the messages to a raw MIDI output in real time, each one flushed as its time comes. On Linux, that's an
+/// ALSA raw MIDI device such as `/dev/snd/midiC1D0`, which is also where a board acting as a USB MIDI gadget
+/// sends what it plays to the host, so no MIDI libraries are needed.
+///
+/// Setting `stop`, e.g. on Ctrl-C, stops the playback and turns off the notes still playing, see `all_notes_off`.
+pub fn stream<W: Write>(messages: &[ScheduledMessage], out: &mut W, stop: &AtomicBool) -> io::Result<()> {
+    let start = Instant::now();
+    for message in messages {
+        if !wait_until(start + Duration::from_micros(message.at), stop) {
+            for off in all_notes_off(messages) {
+                out.write_all(&off)?;
+            }
+            return out.flush();
+        }
+        out.write_all(&message.message)?;
+        out.flush()?;
+    }
+    Ok(())
+}
+
+/// Sleeps until `at`, unless `stop` is set in the meantime. Returns whether the time has come.
+pub fn wait_until(at: Instant, stop: &AtomicBool) -> bool {
+    loop {
+        if stop.load(Ordering::Relaxed) {
+            return false;
+        }
+        match at.checked_duration_since(Instant::now()) {
+            Some(wait) if !wait.is_zero() => sleep(wait.min(STOP_CHECK_INTERVAL)),
+            _ => return true,
+        }
+    }
+}
+
+/// An all-notes-off message for every channel the messages play on, for the notes left hanging when the playback
+/// stops halfway.
+pub fn all_notes_off(messages: &[ScheduledMessage]) -> Vec<Vec<u8>> {
+    let channels: BTreeSet<u8> = messages
+        .iter()
+        .filter_map(|m| m.message.first())
+        .filter(|status| (0x80..0xf0).contains(*status))
+        .map(|status| status & 0x0f)
+        .collect();
+    channels.into_iter().map(|channel| vec![0xb0 | channel, ALL_NOTES_OFF, 0]).collect()
+}
+
+/// Length of the file in microseconds, up to its last event.
+pub fn duration(smf: &Smf) -> u64 {
+    real_time(smf).1
+}
+
+/// Events of all the tracks merged, at their absolute time in ticks, along with the resolution of the file and the
+/// time of its last event.
+fn merged_events<'a>(smf: &Smf<'a>) -> (u64, Vec<(u64, TrackEventKind<'a>)>, u64) {
+    let ticks_per_quarter = match smf.header.timing {
+        Timing::Metrical(t) => t.as_int() as u64,
+        // Timecode-based files aren't produced by Poly, treat them as having the default resolution.
+        Timing::Timecode(_, _) => 48,
+    };
+    // The sort is stable, so events at the same tick keep the order of the tracks.
+    let mut events: Vec<(u64, TrackEventKind)> = Vec::new();
+    let mut end = 0;
+    for track in &smf.tracks {
+        let mut tick = 0;
+        for event in track {
+            tick += event.delta.as_int() as u64;
+            events.push((tick, event.kind));
+        }
+        end = end.max(tick);
+    }
+    events.sort_by_key(|(tick, _)| *tick);
+    (ticks_per_quarter, events, end)
+}
+
+/// Channel messages of a single pass over the file and its length, both in microseconds.
+fn real_time(smf: &Smf) -> (Vec<ScheduledMessage>, u64) {
+    let (ticks_per_quarter, events, end) = merged_events(smf);
+    let mut pass = Vec::new();
+    let mut tempo = DEFAULT_MICROSECONDS_PER_QUARTER;
+    let mut last_tick = 0;
+    let mut time = 0;
+    let mut advance = |tick: u64, tempo: u64| {
+        time += (tick - last_tick) * tempo / ticks_per_quarter;
+        last_tick = tick;
+        time
+    };
+    for (tick, kind) in events {
+        let at = advance(tick, tempo);
+        match kind {
+            TrackEventKind::Meta(MetaMessage::Tempo(t)) => tempo = t.as_int() as u64,
+            TrackEventKind::Midi { channel, message } => {
+                let mut bytes = Vec::new();
+                let event = LiveEvent::Midi { channel, message };
+                if event.write_std(&mut bytes).is_ok() {
+                    pass.push(ScheduledMessage { at, message: bytes });
+                }
+            }
+            _ => {}
+        }
+    }
+    let length = advance(end, tempo);
+    (pass, length)
+}
+
+/// A beat of the metronome shown instead of playing the file, see `metronome`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Beat {
+    /// Microseconds after the playback has started.
+    pub at: u64,
+    /// Bar of the beat counting from 1, over all the loops.
+    pub bar: u32,
+    /// Beat within the bar counting from 1, in the unit of the time signature: eighths in 7/8.
+    pub beat: u8,
+    pub beats_per_bar: u8,
+    /// Drum parts starting a note within the beat, in the order of drum parts.
+    pub parts: Vec<DrumPart>,
+}
+
+/// A line per beat: the number of the bar on its first beat, the beats of the bar with the current one marked and
+/// what's played during it, e.g. `   3 | X . . . | kick, hi-hat`.
+impl fmt::Display for Beat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let bar = if self.beat == 1 { self.bar.to_string() } else { String::new() };
+        let marks: Vec<&str> = (1..=self.beats_per_bar)
+            .map(|b| match b == self.beat {
+                true if b == 1 => "X",
+                true => "x",
+                false => ".",
+            })
+            .collect();
+        let parts: Vec<&str> = self.parts.iter().map(|p| p.name()).collect();
+        write!(f, "{}", format!("{:>4} | {} | {}", bar, marks.join(" "), parts.join(", ")).trim_end())
+    }
+}
+
+/// The beats of the file in real time, following the tempo and the time signature changes, `loops` times in a row,
+/// for a metronome to clap along with where there's nothing to play the file through. Drum parts are told by
+/// their keys in `drum_map`.
+pub fn metronome(smf: &Smf, drum_map: &DrumMap, loops: u32) -> Vec<Beat> {
+    let (ticks_per_quarter, events, end) = merged_events(smf);
+    let mut events = events.into_iter().peekable();
+    let mut pass = Vec::new();
+    let (mut tempo, mut time, mut last_tick) = (DEFAULT_MICROSECONDS_PER_QUARTER, 0, 0);
+    let mut advance = |tick: u64, tempo: u64| {
+        time += (tick - last_tick) * tempo / ticks_per_quarter;
+        last_tick = tick;
+        time
+    };
+    let (mut beats_per_bar, mut beat_ticks) = (4, ticks_per_quarter);
+    let (mut bar, mut beat, mut tick) = (1, 1, 0);
+    while tick < end {
+        let at = advance(tick, tempo);
+        let mut parts = Vec::new();
+        // Changes at the start of the beat apply to it, so its length is only known once they're read.
+        for first in [true, false] {
+            let until = if first { tick + 1 } else { tick + beat_ticks };
+            while let Some((t, kind)) = events.next_if(|(t, _)| *t < until) {
+                match kind {
+                    TrackEventKind::Meta(MetaMessage::Tempo(change)) => {
+                        advance(t, tempo);
+                        tempo = change.as_int() as u64;
+                    }
+                    TrackEventKind::Meta(MetaMessage::TimeSignature(numerator, denominator, _, _)) if first => {
+                        if beat != 1 {
+                            (bar, beat) = (bar + 1, 1);
+                        }
+                        beats_per_bar = numerator;
+                        beat_ticks = (ticks_per_quarter * 4) >> denominator;
+                    }
+                    // Drums are written on channel 11, or on channel 10 for the sound modules of `--target`, the other
+                    // channels play the bass and melodic instruments.
+                    TrackEventKind::Midi { channel, message: MidiMessage::NoteOn { key, vel } }
+                        if matches!(channel.as_int(), 9 | 10) && vel > 0 =>
+                    {
+                        parts.extend(drum_map.part(key));
+                    }
+                    _ => {}
+                }
+            }
+        }
+        parts.sort();
+        parts.dedup();
+        pass.push(Beat { at, bar, beat, beats_per_bar, parts });
+        tick += beat_ticks;
+        beat += 1;
+        if beat > beats_per_bar {
+            (bar, beat) = (bar + 1, 1);
+        }
+    }
+    let length = advance(end, tempo);
+    let bars = pass.last().map_or(0, |b| b.bar);
+    (0..loops)
+        .flat_map(|i| {
+            pass.iter().map(move |b| Beat { at: b.at + length * i as u64, bar: b.bar + bars * i, ..b.clone() })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+use std::collections::BTreeMap;
+#[cfg(test)]
+use crate::dsl::dsl::groups;
+#[cfg(test)]
+use crate::midi::core::{generate, DrumPart::*, RenderOptions};
+
+#[test]
+fn test_schedule() {
+    let options = RenderOptions { tempos: vec![120, 60], ..Default::default() };
+    let smf = generate(BTreeMap::from_iter([(KickDrum, groups("2x-").unwrap())]), "", &options).unwrap();
+    let note_ons: Vec<u64> = schedule(&smf, 2)
+        .iter()
+        .filter(|m| m.message[0] & 0xf0 == 0x90)
+        .map(|m| m.at)
+        .collect();
+    // A bar at 120 BPM takes 2 seconds, a bar at 60 BPM takes 4.
+    assert_eq!(note_ons, vec![0, 2000000, 6000000, 8000000]);
+    assert_eq!(duration(&smf), 6000000);
+    let first = &schedule(&smf, 1)[1];
+    assert_eq!(first.message, vec![0x9a, 36, 100]);
+}
+
+#[test]
+fn test_stream() {
+    let messages = [
+        ScheduledMessage { at: 0, message: vec![0x9a, 36, 100] },
+        ScheduledMessage { at: 2000, message: vec![0x8a, 36, 0] },
+    ];
+    let mut out = Vec::new();
+    let start = Instant::now();
+    stream(&messages, &mut out, &AtomicBool::new(false)).unwrap();
+    assert!(start.elapsed() >= Duration::from_micros(2000));
+    assert_eq!(out, vec![0x9a, 36, 100, 0x8a, 36, 0]);
+    // Stopped right away, nothing is played and the drum channel is silenced.
+    let mut out = Vec::new();
+    stream(&messages, &mut out, &AtomicBool::new(true)).unwrap();
+    assert_eq!(out, vec![0xba, 123, 0]);
+}
+
+#[test]
+fn test_metronome() {
+    let options = RenderOptions { tempos: vec![120, 60], ..Default::default() };
+    let patterns = BTreeMap::from_iter([(KickDrum, groups("2x-").unwrap()), (SnareDrum, groups("8-x").unwrap())]);
+    let smf = generate(patterns, "", &options).unwrap();
+    let beats = metronome(&smf, &DrumMap::default(), 2);
+    // A bar at 120 BPM then a bar at 60 BPM, twice.
+    let times: Vec<(u64, u32, u8)> = beats.iter().map(|b| (b.at / 1000, b.bar, b.beat)).collect();
+    assert_eq!(&times[0..5], [(0, 1, 1), (500, 1, 2), (1000, 1, 3), (1500, 1, 4), (2000, 2, 1)]);
+    assert_eq!(times[8..10], [(6000, 3, 1), (6500, 3, 2)]);
+    assert_eq!(beats.len(), 16);
+    assert_eq!(beats[0].parts, vec![KickDrum, SnareDrum]);
+    assert_eq!(beats[0].to_string(), "   1 | X . . . | kick, snare");
+    assert_eq!(beats[2].to_string(), "     | . . x . | snare");
+    let seven_eight = RenderOptions { time_signature: "7/8".parse().unwrap(), ..Default::default() };
+    let smf = generate(BTreeMap::from_iter([(KickDrum, groups("8x--").unwrap())]), "", &seven_eight).unwrap();
+    let beats = metronome(&smf, &DrumMap::default(), 1);
+    assert_eq!(beats.len(), 21);
+    assert_eq!(beats[7].to_string(), "   2 | X . . . . . . |");
+    assert_eq!(beats[9].to_string(), "     | . . x . . . . | kick");
+}
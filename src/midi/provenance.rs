@@ -0,0 +1,78 @@
+use std::path::Path;
+
+use crate::json::json_string;
+
+/// Where a rendered MIDI file came from, written next to it as JSON so that it can be rendered again exactly, or
+/// at least told apart from a file rendered some other way.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Provenance {
+    /// The seed everything random was drawn from.
+    pub seed: u64,
+    /// Arguments to `poly` that render the file again: the ones given, with a seed picked automatically and a
+    /// tapped tempo spelled out.
+    pub command: Vec<String>,
+    /// Every option with the values it was rendered with, given or default, by its long name.
+    pub options: Vec<(String, Vec<String>)>,
+    /// Files the groove was read from and the `hash` of their contents, the same files have to be there to
+    /// render it again.
+    pub inputs: Vec<(String, u64)>,
+    /// The `fingerprint` of the rendered file.
+    pub fingerprint: u64,
+}
+
+impl Provenance {
+    /// The provenance as JSON. Seeds and hashes are written as strings, as 64-bit numbers don't survive most JSON
+    /// readers.
+    pub fn to_json(&self) -> String {
+        let strings = |values: &[String]| values.iter().map(|v| json_string(v)).collect::<Vec<String>>().join(", ");
+        let options: Vec<String> = self
+            .options
+            .iter()
+            .map(|(name, values)| format!("    {}: [{}]", json_string(name), strings(values)))
+            .collect();
+        let inputs: Vec<String> = self
+            .inputs
+            .iter()
+            .map(|(path, hash)| format!("    {{ \"path\": {}, \"hash\": \"{:016x}\" }}", json_string(path), hash))
+            .collect();
+        format!(
+            "{{\n  \"generator\": {},\n  \"seed\": \"{}\",\n  \"command\": [{}],\n  \"options\": {{\n{}\n  }},\n  \
+             \"inputs\": [\n{}\n  ],\n  \"fingerprint\": \"{:016x}\"\n}}\n",
+            json_string(&format!("Polyrhythmix {}", env!("CARGO_PKG_VERSION"))),
+            self.seed,
+            strings(&self.command),
+            options.join(",\n"),
+            inputs.join(",\n"),
+            self.fingerprint
+        )
+    }
+}
+
+/// Where the provenance of a MIDI file goes: next to it, with the extension replaced, e.g. `groove.json` for
+/// `groove.mid`.
+pub fn sidecar_path(midi: &str) -> String {
+    Path::new(midi).with_extension("json").to_string_lossy().into_owned()
+}
+
+#[test]
+fn test_provenance_json() {
+    let provenance = Provenance {
+        seed: 18446744073709551615,
+        command: vec!["--kick".to_string(), "8x--x--".to_string()],
+        options: vec![
+            ("kick".to_string(), vec!["8x--x--".to_string()]),
+            ("tempo".to_string(), vec!["120".to_string()]),
+        ],
+        inputs: vec![("groove \"a\".poly".to_string(), 0xff)],
+        fingerprint: 0x9e9141a0f007e87d,
+    };
+    assert_eq!(
+        provenance.to_json().replace(env!("CARGO_PKG_VERSION"), "x"),
+        "{\n  \"generator\": \"Polyrhythmix x\",\n  \"seed\": \"18446744073709551615\",\n  \
+         \"command\": [\"--kick\", \"8x--x--\"],\n  \"options\": {\n    \"kick\": [\"8x--x--\"],\n    \
+         \"tempo\": [\"120\"]\n  },\n  \"inputs\": [\n    { \"path\": \"groove \\\"a\\\".poly\", \
+         \"hash\": \"00000000000000ff\" }\n  ],\n  \"fingerprint\": \"9e9141a0f007e87d\"\n}\n"
+    );
+    assert_eq!(sidecar_path("out/groove.mid"), "out/groove.json");
+    assert_eq!(sidecar_path("groove"), "groove.json");
+}
@@ -0,0 +1,244 @@
+use std::fmt;
+use std::str::FromStr;
+
+use midly::num::u7;
+use midly::{MetaMessage, MidiMessage, Smf, TrackEvent, TrackEventKind};
+
+/// Family of hardware sound modules the output is written for. They agree on the General MIDI percussion keys from
+/// 35 to 81, but not on the keys around them, on how to pick the drum kit, or on how to reset the module to a known
+/// state.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Target {
+    /// General MIDI Level 1: keys 35 to 81 only, no bank selects.
+    Gm,
+    /// General MIDI Level 2, the keys of Poly's drum maps.
+    Gm2,
+    /// Roland GS, with the same keys as General MIDI Level 2.
+    Gs,
+    /// Yamaha XG, with the keys below 35 and the surdos and the castanets moved around.
+    Xg,
+}
+
+/// MIDI channel of the drums, 10 counting from 1.
+static DRUM_CHANNEL: u8 = 9;
+/// Channel Poly writes the notes of the drums, the bass and the click to, 11 counting from 1, where Guitar Pro
+/// expects them.
+static POLY_CHANNEL: u8 = 10;
+
+impl FromStr for Target {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "gm" => Ok(Target::Gm),
+            "gm2" => Ok(Target::Gm2),
+            "gs" => Ok(Target::Gs),
+            "xg" => Ok(Target::Xg),
+            _ => Err(format!("Unknown target '{}', expected 'gm', 'gm2', 'gs' or 'xg'", s)),
+        }
+    }
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Target::Gm => "GM",
+            Target::Gm2 => "GM2",
+            Target::Gs => "GS",
+            Target::Xg => "XG",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl Target {
+    /// The system exclusive message resetting a module of the family, without the leading `0xF0`.
+    pub fn reset(self) -> &'static [u8] {
+        match self {
+            // GM System On
+            Target::Gm => &[0x7e, 0x7f, 0x09, 0x01, 0xf7],
+            // GM2 System On
+            Target::Gm2 => &[0x7e, 0x7f, 0x09, 0x03, 0xf7],
+            // GS Reset
+            Target::Gs => &[0x41, 0x10, 0x42, 0x12, 0x40, 0x00, 0x7f, 0x00, 0x41, 0xf7],
+            // XG System On
+            Target::Xg => &[0x43, 0x10, 0x4c, 0x00, 0x00, 0x7e, 0x00, 0xf7],
+        }
+    }
+
+    /// Most and least significant bytes of the bank to select before a program change on the channel, if the
+    /// family selects banks at all. GS picks the drum kit by the program alone.
+    pub fn bank(self, channel: u8) -> Option<(u8, u8)> {
+        match (self, channel == DRUM_CHANNEL) {
+            (Target::Gm, _) => None,
+            (Target::Gm2, true) => Some((120, 0)),
+            (Target::Gm2, false) => Some((121, 0)),
+            (Target::Gs, true) => None,
+            (Target::Xg, true) => Some((127, 0)),
+            (Target::Gs | Target::Xg, false) => Some((0, 0)),
+        }
+    }
+
+    /// The key of the family playing the percussion sound of a General MIDI Level 2 key. General MIDI Level 1
+    /// doesn't have the sounds around its keys, the closest ones it has play them instead. Keys no family has a
+    /// sound for are left as they are, they're for kits mapped some other way.
+    pub fn key(self, key: u7) -> u7 {
+        let key = key.as_int();
+        let moved = match self {
+            Target::Gm2 | Target::Gs => key,
+            Target::Gm => match key {
+                27 | 31 | 32 => 37, // high Q, sticks, square click: side stick
+                28..=30 => 39,      // slap, scratches: hand clap
+                33 => 76,           // metronome click: high wood block
+                34 => 56,           // metronome bell: cowbell
+                82 => 70,           // shaker: maracas
+                83 => 54,           // jingle bell: tambourine
+                84 => 81,           // belltree: open triangle
+                85 => 75,           // castanets: claves
+                86 => 43,           // mute surdo: high floor tom
+                87 => 41,           // open surdo: low floor tom
+                _ => key,
+            },
+            Target::Xg => match key {
+                27 => 15,
+                28 => 16,
+                29 => 17,
+                30 => 18,
+                31 => 32,
+                32 => 20,
+                33 => 21,
+                34 => 22,
+                85 => 30,
+                86 => 13,
+                87 => 14,
+                _ => key,
+            },
+        };
+        moved.into()
+    }
+}
+
+/// Rewrites the file for the target: resets the module at the start of the first track, selects the bank before
+/// every program change and moves the drums to the drum channel and to the keys of the family. The notes Poly
+/// writes to channel 11 go to the channel of the program of their track instead, or to the drum channel if it has
+/// none. Returns the keys moved, with the keys they were moved to.
+pub fn retarget(smf: &mut Smf, target: Target) -> Vec<(u7, u7)> {
+    let mut moved = Vec::new();
+    for (i, track) in smf.tracks.iter_mut().enumerate() {
+        let program_channel = track.iter().find_map(|event| match event.kind {
+            TrackEventKind::Midi { channel, message: MidiMessage::ProgramChange { .. } } => Some(channel),
+            _ => None,
+        });
+        let notes_channel = program_channel.unwrap_or(DRUM_CHANNEL.into());
+        let mut events = Vec::with_capacity(track.len() + 1);
+        if i == 0 {
+            events.push(TrackEvent { delta: 0.into(), kind: TrackEventKind::SysEx(target.reset()) });
+        }
+        for event in track.iter() {
+            let mut event = *event;
+            match &mut event.kind {
+                TrackEventKind::Midi { channel, message: MidiMessage::ProgramChange { .. } } => {
+                    if let Some((msb, lsb)) = target.bank(channel.as_int()) {
+                        let controller = |controller: u8, value: u8| TrackEventKind::Midi {
+                            channel: *channel,
+                            message: MidiMessage::Controller { controller: controller.into(), value: value.into() },
+                        };
+                        events.push(TrackEvent { delta: event.delta, kind: controller(0, msb) });
+                        events.push(TrackEvent { delta: 0.into(), kind: controller(32, lsb) });
+                        event.delta = 0.into();
+                    }
+                }
+                TrackEventKind::Midi {
+                    channel,
+                    message: MidiMessage::NoteOn { key, .. } | MidiMessage::NoteOff { key, .. },
+                } => {
+                    if *channel == POLY_CHANNEL {
+                        *channel = notes_channel;
+                    }
+                    if *channel == DRUM_CHANNEL {
+                        let to = target.key(*key);
+                        if to != *key && !moved.contains(&(*key, to)) {
+                            moved.push((*key, to));
+                        }
+                        *key = to;
+                    }
+                }
+                TrackEventKind::Meta(MetaMessage::MidiChannel(channel)) if *channel == POLY_CHANNEL => {
+                    *channel = notes_channel;
+                }
+                _ => {}
+            }
+            events.push(event);
+        }
+        *track = events;
+    }
+    moved
+}
+
+#[cfg(test)]
+use std::collections::BTreeMap;
+#[cfg(test)]
+use crate::dsl::dsl::groups;
+#[cfg(test)]
+use crate::midi::core::{generate, parse_drum_mapping, DrumMap, DrumPart, RenderOptions};
+
+#[test]
+fn test_target_keys() {
+    assert_eq!(Target::from_str("xg"), Ok(Target::Xg));
+    assert!(Target::from_str("mt32").is_err());
+    assert_eq!(Target::Gm.key(82.into()), u7::from(70));
+    assert_eq!(Target::Gm.key(36.into()), u7::from(36));
+    assert_eq!(Target::Xg.key(86.into()), u7::from(13));
+    assert_eq!(Target::Gs.key(86.into()), u7::from(86));
+    assert_eq!(Target::Gm.key(100.into()), u7::from(100));
+}
+
+#[test]
+fn test_retarget() {
+    let mut drum_map = DrumMap::default();
+    let (part, key) = parse_drum_mapping("hi-hat=82").unwrap();
+    drum_map.set(part, key);
+    let patterns =
+        BTreeMap::from([(DrumPart::KickDrum, groups("4x").unwrap()), (DrumPart::HiHat, groups("8x").unwrap())]);
+    let options = RenderOptions { add_bass: true, drum_map, ..Default::default() };
+    let render = || generate(patterns.clone(), "", &options).unwrap();
+    let mut smf = render();
+    assert_eq!(retarget(&mut smf, Target::Gm), vec![(u7::from(82), u7::from(70))]);
+    assert_eq!(smf.tracks[0][0].kind, TrackEventKind::SysEx(Target::Gm.reset()));
+    let controllers = |smf: &Smf, track: usize| -> Vec<(u8, u8, u8)> {
+        smf.tracks[track]
+            .iter()
+            .filter_map(|e| match e.kind {
+                TrackEventKind::Midi { channel, message: MidiMessage::Controller { controller, value } } => {
+                    Some((channel.as_int(), controller.as_int(), value.as_int()))
+                }
+                _ => None,
+            })
+            .collect()
+    };
+    assert_eq!(controllers(&smf, 0), vec![]);
+    let notes = |smf: &Smf, track: usize| -> Vec<(u8, u8)> {
+        let mut notes: Vec<(u8, u8)> = smf.tracks[track]
+            .iter()
+            .filter_map(|e| match e.kind {
+                TrackEventKind::Midi { channel, message: MidiMessage::NoteOn { key, .. } } => {
+                    Some((channel.as_int(), key.as_int()))
+                }
+                _ => None,
+            })
+            .collect();
+        notes.sort();
+        notes.dedup();
+        notes
+    };
+    assert_eq!(notes(&smf, 0), vec![(9, 36), (9, 70)]);
+    assert_eq!(notes(&smf, 1), vec![(0, 28)]);
+    let mut smf = render();
+    assert_eq!(retarget(&mut smf, Target::Xg), vec![]);
+    assert_eq!(controllers(&smf, 0), vec![(9, 0, 127), (9, 32, 0)]);
+    assert_eq!(controllers(&smf, 1), vec![(0, 0, 0), (0, 32, 0)]);
+    // The bank goes right before the program change, and the notes keep their times.
+    let end = |smf: &Smf| smf.tracks[0].iter().map(|e| e.delta.as_int()).sum::<u32>();
+    assert_eq!(end(&smf), end(&render()));
+    assert!(matches!(smf.tracks[0][3].kind, TrackEventKind::Midi { message: MidiMessage::ProgramChange { .. }, .. }));
+}
@@ -1,8 +1,19 @@
 extern crate derive_more;
 
-use std::{cmp::Ordering, str::FromStr};
+#[cfg(test)]
+use core::cmp::Ordering;
+use core::str::FromStr;
+#[cfg(feature = "std")]
+use std::time::Instant;
 
-use crate::dsl::dsl::{BasicLength, GroupOrNote, KnownLength, Note, Group, groups, Times, EIGHTH, FOURTH};
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use crate::dsl::dsl::{BasicLength, GroupOrNote, KnownLength, Note};
+use crate::error::PolyError;
+#[cfg(test)]
+use crate::dsl::dsl::{Group, Times, EIGHTH, FOURTH};
 
 use BasicLength::*;
 #[allow(unused_imports)]
@@ -18,7 +29,8 @@ pub struct TimeSignature {
 }
 
 impl TimeSignature {
-    pub(crate) fn to_midi(&self) -> (u8, u8) {
+    /// The numerator and the power of two of the denominator, as in the MIDI time signature event.
+    pub fn to_midi(self) -> (u8, u8) {
         let denominator = match self.denominator {
             Whole => 0, // FIXME: should it be an error?
             Half => 1,
@@ -32,24 +44,31 @@ impl TimeSignature {
     }
 }
 
+impl core::fmt::Display for TimeSignature {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{}/{}", self.numerator, 128 / self.denominator.to_128th())
+    }
+}
+
 impl FromStr for TimeSignature {
-    type Err = String;
+    type Err = PolyError;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let error = |message: String| Err(PolyError::TimeSignature(message));
         let mut after_split = s.splitn(2, '/');
         let num = after_split.next();
         let den = after_split.next();
         match (num, den) {
-            (None, None) => Err(format!("Can't parse neither numerator nor denominator of a time signature: {}", s)),
-            (None, Some(_)) => Err(format!("Can't parse time signature numerator: {}", s)),
-            (Some(_), None) => Err(format!("Can't parse time signature denominator: {}", s)),
+            (None, None) => error(format!("Can't parse neither numerator nor denominator of a time signature: {}", s)),
+            (None, Some(_)) => error(format!("Can't parse time signature numerator: {}", s)),
+            (Some(_), None) => error(format!("Can't parse time signature denominator: {}", s)),
             (Some(numerator_str), Some(d)) => {
                 match BasicLength::from_str(d) {
                     Ok(denominator) => match u8::from_str(numerator_str) {
-                        Ok(numerator) => Ok(TimeSignature { numerator, denominator }),
-                        Err(_) => Err(format!("Can't parse time signature numerator: {}", s)),
+                        Ok(numerator) if numerator > 0 => Ok(TimeSignature { numerator, denominator }),
+                        _ => error(format!("Can't parse time signature numerator: {}", s)),
                     } ,
-                    Err(e) => Err(e),
+                    Err(e) => error(e),
                 }
             }
         }
@@ -59,13 +78,17 @@ impl FromStr for TimeSignature {
 #[test]
 fn test_time_signature_from_str() {
     assert_eq!(TimeSignature::from_str("4/4").unwrap(), TimeSignature { numerator: 4, denominator: Fourth });
+    assert!(TimeSignature::from_str("0/4").is_err());
+    assert!(TimeSignature::from_str("4/x").is_err());
+    assert!(TimeSignature::from_str("4/3").is_err());
+    assert_eq!(TimeSignature::from_str("7/16").unwrap().to_string(), "7/16");
 }
 
-impl std::ops::Mul<u8> for TimeSignature {
+impl core::ops::Mul<u8> for TimeSignature {
     type Output = TimeSignature;
     fn mul(self, rhs: u8) -> TimeSignature {
         TimeSignature {
-            numerator: self.numerator * rhs as u8,
+            numerator: self.numerator * rhs,
             denominator: self.denominator,
         }
     }
@@ -103,11 +126,17 @@ fn test_time_signature_known_length() {
 }
 
 impl TimeSignature {
-    pub fn converges<T: KnownLength, I: IntoIterator<Item = T>>(&self, multiple: I) -> Result<u32, String> {
+    /// Bars it takes for the patterns to line up again with each other and with the bar. Patterns without any length
+    /// never line up.
+    pub fn converges<T: KnownLength, I: IntoIterator<Item = T>>(&self, multiple: I) -> Result<u32, PolyError> {
         let bar_len = self.to_128th();
-        let result = multiple
-            .into_iter()
-            .fold(bar_len, |acc, t| lowest_common_divisor(t.to_128th(), acc));
+        let mut result = bar_len;
+        for t in multiple {
+            match t.to_128th() {
+                0 => return Err(PolyError::NoNotes),
+                length => result = lowest_common_divisor(length, result),
+            }
+        }
 
         let limit = 1000;
 
@@ -116,7 +145,7 @@ impl TimeSignature {
         if limit > out {
             Ok(out)
         } else {
-            Err("Does not converge".to_string())
+            Err(PolyError::DoesNotConverge)
         }
     }
 }
@@ -124,7 +153,7 @@ impl TimeSignature {
 fn lowest_common_divisor(a: u32, b: u32) -> u32 {
     let mut lcm = u32::max(a, b);
 
-    while lcm % a != 0 || lcm % b != 0 {
+    while !lcm.is_multiple_of(a) || !lcm.is_multiple_of(b) {
         lcm += 1;
     }
 
@@ -152,17 +181,19 @@ fn test_converges() {
         denominator: BasicLength::Fourth,
     };
     let thirteen_eights = Group {
+        dynamic: None,
         notes: vec![SingleNote(Hit)],
-        length: FOURTH.clone(),
+        length: *FOURTH,
         times: Times(12),
     };
     let in_shards_poly = Group {
+        dynamic: None,
         notes: vec![
             GroupOrNote::SingleNote(Note::Hit),
             GroupOrNote::SingleNote(Note::Rest),
             GroupOrNote::SingleGroup(thirteen_eights),
         ],
-        length: EIGHTH.clone(),
+        length: *EIGHTH,
         times: Times(1),
     };
     assert_eq!(three_fourth.converges(vec![four_fourth]), Ok(4));
@@ -170,4 +201,174 @@ fn test_converges() {
     assert_eq!(four_fourth.converges(vec![three_fourth, four_fourth]), Ok(3));
     assert_eq!(four_fourth.converges(vec![three_fourth, six_fourth, four_fourth]), Ok(3));
     assert_eq!(four_fourth.converges(vec![in_shards_poly]), Ok(13));
+    let nothing = Group { dynamic: None, notes: vec![], length: *EIGHTH, times: Times(1) };
+    assert_eq!(four_fourth.converges(vec![nothing]), Err(PolyError::NoNotes));
+}
+
+/// Times closer than this, in seconds, are taken as played together.
+#[cfg(feature = "std")]
+static SIMULTANEOUS: f64 = 0.03;
+/// Intervals up to this much longer than the shortest one are taken as the same step of the pulse.
+#[cfg(feature = "std")]
+static STEP_SPREAD: f64 = 1.5;
+
+/// A steady pulse some times fall on: the times are close to `offset + period * n` for whole numbers `n`.
+#[cfg(feature = "std")]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Pulse {
+    /// Seconds between two steps of the pulse.
+    pub period: f64,
+    /// Time of the first step in seconds.
+    pub offset: f64,
+}
+
+#[cfg(feature = "std")]
+impl Pulse {
+    /// Finds the pulse the times, in seconds, fall on. The shortest interval between the times is taken as a step,
+    /// longer intervals may skip steps, so missed taps or rests don't throw the pulse off. The period and the offset
+    /// are then fitted to all the times by least squares. Returns `None` if there are less than two distinct times.
+    pub fn detect(times: &[f64]) -> Option<Pulse> {
+        let mut distinct: Vec<f64> = Vec::new();
+        for &time in times {
+            if distinct.last().is_none_or(|last| time - last >= SIMULTANEOUS) {
+                distinct.push(time);
+            }
+        }
+        let mut intervals: Vec<f64> = distinct.windows(2).map(|w| w[1] - w[0]).collect();
+        intervals.sort_by(f64::total_cmp);
+        let shortest = *intervals.first()?;
+        let steps: Vec<f64> = intervals.into_iter().take_while(|i| *i <= shortest * STEP_SPREAD).collect();
+        let mut pulse = Pulse { period: steps[steps.len() / 2], offset: distinct[0] };
+        // Every pass gets the steps the times fall on more precisely.
+        for _ in 0..3 {
+            pulse = pulse.fit(&distinct);
+        }
+        Some(pulse)
+    }
+
+    /// Step of the pulse closest to the time.
+    pub fn step(&self, time: f64) -> f64 {
+        ((time - self.offset) / self.period).round()
+    }
+
+    /// Least squares fit of the times against the steps they fall on.
+    fn fit(&self, times: &[f64]) -> Pulse {
+        let steps: Vec<f64> = times.iter().map(|t| self.step(*t)).collect();
+        let n = times.len() as f64;
+        let mean_step = steps.iter().sum::<f64>() / n;
+        let mean_time = times.iter().sum::<f64>() / n;
+        let covariance: f64 = steps.iter().zip(times).map(|(s, t)| (s - mean_step) * (t - mean_time)).sum();
+        let variance: f64 = steps.iter().map(|s| (s - mean_step).powi(2)).sum();
+        if variance == 0.0 {
+            return *self;
+        }
+        let period = covariance / variance;
+        Pulse { period, offset: mean_time - period * mean_step }
+    }
+
+    /// Tempo in BPM if every step of the pulse is a beat.
+    pub fn tempo(&self) -> f64 {
+        60.0 / self.period
+    }
+}
+
+/// Estimates the tempo in BPM from a sequence of tap timestamps, one tap per beat.
+/// Returns `None` if there are less than two taps or the taps are too far apart to make a sensible tempo.
+#[cfg(feature = "std")]
+pub fn tempo_from_taps(taps: &[Instant]) -> Option<u16> {
+    let times: Vec<f64> = taps.iter().map(|t| (*t - taps[0]).as_secs_f64()).collect();
+    tempo_from_times(&times)
+}
+
+#[cfg(feature = "std")]
+fn tempo_from_times(times: &[f64]) -> Option<u16> {
+    let tempo = Pulse::detect(times)?.tempo().round();
+    if tempo >= 1.0 && tempo <= u16::MAX as f64 {
+        Some(tempo as u16)
+    } else {
+        None
+    }
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn test_tempo_from_times() {
+    assert_eq!(tempo_from_times(&[]), None);
+    assert_eq!(tempo_from_times(&[0.0, 0.5, 1.0, 1.5]), Some(120));
+    assert_eq!(tempo_from_times(&[0.0, 0.48, 1.0]), Some(120));
+    assert_eq!(tempo_from_times(&[0.0, 0.0]), None);
+    // A missed tap doesn't slow the tempo down.
+    assert_eq!(tempo_from_times(&[0.0, 0.5, 1.5, 2.0]), Some(120));
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn test_detect_pulse() {
+    // Sixteenths at 100 BPM with a rest, a chord and a bit of jitter.
+    let times = [0.15, 0.3, 0.3, 0.45, 0.602, 0.75, 1.05, 1.2];
+    let pulse = Pulse::detect(&times).unwrap();
+    assert!((pulse.tempo() - 400.0).abs() < 1.0);
+    assert!((pulse.offset - 0.15).abs() < 0.005);
+    assert_eq!(pulse.step(1.05), 6.0);
+}
+
+/// A range of tempos to audition a pattern at, written as `from:to:step` (e.g. `80:160:10`).
+/// The step is optional and defaults to 10 BPM. Both ends of the range are included.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TempoSweep {
+    pub from: u16,
+    pub to: u16,
+    pub step: u16,
+}
+
+impl TempoSweep {
+    pub fn tempos(&self) -> Vec<u16> {
+        let mut out = Vec::new();
+        let mut tempo = self.from;
+        if self.from <= self.to {
+            while tempo <= self.to {
+                out.push(tempo);
+                tempo = match tempo.checked_add(self.step) {
+                    Some(t) => t,
+                    None => break,
+                };
+            }
+        } else {
+            while tempo >= self.to {
+                out.push(tempo);
+                tempo = match tempo.checked_sub(self.step) {
+                    Some(t) => t,
+                    None => break,
+                };
+            }
+        }
+        out
+    }
+}
+
+impl FromStr for TempoSweep {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parse = |x: &str| match u16::from_str(x) {
+            Ok(0) => Err(format!("Tempo sweep values should be positive: {}", s)),
+            Ok(n) => Ok(n),
+            Err(_) => Err(format!("Can't parse tempo sweep: {}", s)),
+        };
+        let parts: Vec<&str> = s.split(':').collect();
+        match parts.as_slice() {
+            [from, to] => Ok(TempoSweep { from: parse(from)?, to: parse(to)?, step: 10 }),
+            [from, to, step] => Ok(TempoSweep { from: parse(from)?, to: parse(to)?, step: parse(step)? }),
+            _ => Err(format!("Tempo sweep should look like 'from:to' or 'from:to:step': {}", s)),
+        }
+    }
+}
+
+#[test]
+fn test_tempo_sweep() {
+    assert_eq!(TempoSweep::from_str("80:120:20").unwrap().tempos(), vec![80, 100, 120]);
+    assert_eq!(TempoSweep::from_str("80:105").unwrap().tempos(), vec![80, 90, 100]);
+    assert_eq!(TempoSweep::from_str("120:100:10").unwrap().tempos(), vec![120, 110, 100]);
+    assert!(TempoSweep::from_str("80").is_err());
+    assert!(TempoSweep::from_str("80:120:0").is_err());
 }
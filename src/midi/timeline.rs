@@ -0,0 +1,252 @@
+use std::cmp::min;
+use std::collections::BTreeMap;
+use std::ops::Range;
+use std::str::FromStr;
+
+use midly::Smf;
+
+use crate::dsl::dsl::Groups;
+use crate::error::PolyError;
+use crate::midi::core::{
+    drums_track_header, end_tracks, merge_into_iterator, tracks_to_smf, write_events, DrumMap, DrumPart, Event,
+    EventGrid, MidiTempo, Part, Tick, Velocity,
+};
+use crate::midi::time::TimeSignature;
+use crate::midi::transform::{pair_notes, unpair_notes, PairedNote};
+
+/// What the groove does in the bar of a fill, as real drummers drop the time they keep to play one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Ducking {
+    /// The groove stops for the fill.
+    #[default]
+    Mute,
+    /// Only the notes of the groove on the beats are kept, softer.
+    Thin,
+    /// The groove goes on under the fill.
+    Keep,
+}
+
+impl FromStr for Ducking {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "mute" => Ok(Ducking::Mute),
+            "thin" => Ok(Ducking::Thin),
+            "keep" => Ok(Ducking::Keep),
+            _ => Err(format!("Unknown ducking '{}', expected 'mute', 'thin' or 'keep'", s)),
+        }
+    }
+}
+
+/// Share of the velocity the notes of the groove keep under a fill when it's thinned out.
+static THIN_VELOCITY: (u32, u32) = (3, 5);
+
+/// Rendered drum events, for arranging at the event level when the patterns alone aren't flexible enough:
+/// parts of a render can be cut out with `slice`, glued together with `concat` and looped with `repeat`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Timeline {
+    /// Bars are counted in this time signature, it's also the one written to the MIDI file.
+    pub time_signature: TimeSignature,
+    /// Sorted by time.
+    events: Vec<Event<Tick>>,
+    length: Tick,
+}
+
+impl Timeline {
+    pub fn new(time_signature: TimeSignature, mut events: Vec<Event<Tick>>, length: Tick) -> Timeline {
+        events.sort();
+        Timeline { time_signature, events, length }
+    }
+
+    /// Lays out the drum parts over the bars they take to converge.
+    pub fn from_groups(
+        groups: &BTreeMap<DrumPart, Groups>,
+        time_signature: TimeSignature,
+    ) -> Result<Timeline, PolyError> {
+        let events_iter = merge_into_iterator(groups, time_signature, None, 1)?;
+        let length = Timeline::bar_length_of(time_signature) * events_iter.bars as u128;
+        Ok(Timeline::new(time_signature, events_iter.collect(), length))
+    }
+
+    fn bar_length_of(time_signature: TimeSignature) -> Tick {
+        time_signature.denominator.to_ticks() * time_signature.numerator as u128
+    }
+
+    pub fn bar_length(&self) -> Tick {
+        Timeline::bar_length_of(self.time_signature)
+    }
+
+    pub fn events(&self) -> &[Event<Tick>] {
+        &self.events
+    }
+
+    pub fn length(&self) -> Tick {
+        self.length
+    }
+
+    /// Number of bars, the last one may be incomplete.
+    pub fn bars(&self) -> u32 {
+        let bar = self.bar_length().0;
+        if bar == 0 {
+            0
+        } else {
+            self.length.0.div_ceil(bar) as u32
+        }
+    }
+
+    /// Cuts out the bars in the range, counting from zero. Notes starting within the range are kept,
+    /// the ones ringing over its end are cut short.
+    pub fn slice(&self, bars: Range<u32>) -> Timeline {
+        let bar = self.bar_length();
+        let start = min(bar * bars.start as u128, self.length);
+        let end = min(bar * bars.end as u128, self.length).max(start);
+        let notes: Vec<PairedNote> = pair_notes(&self.events)
+            .into_iter()
+            .filter(|n| n.start >= start && n.start < end)
+            .map(|n| PairedNote {
+                start: n.start - start,
+                end: min(n.end, end) - start,
+                ..n
+            })
+            .collect();
+        Timeline::new(self.time_signature, unpair_notes(&notes), end - start)
+    }
+
+    /// Appends `other` right after the end of this timeline, keeping the time signature of this one.
+    pub fn concat(&self, other: &Timeline) -> Timeline {
+        let mut events = self.events.clone();
+        events.extend(other.events.iter().map(|e| Event::new(e.tick + self.length, e.event_type)));
+        Timeline::new(self.time_signature, events, self.length + other.length)
+    }
+
+    /// Plays `other` along with this timeline, from its start. The timeline is as long as the longer one.
+    pub fn overlay(&self, other: &Timeline) -> Timeline {
+        let mut events = self.events.clone();
+        events.extend_from_slice(&other.events);
+        Timeline::new(self.time_signature, events, self.length.max(other.length))
+    }
+
+    /// Makes room for a fill in the bars of the range: the notes of the parts in `fill` are left out, as the fill
+    /// plays them, and the other ones are ducked. Notes outside the range are kept as they are.
+    pub fn duck(&self, bars: Range<u32>, ducking: Ducking, fill: &[DrumPart]) -> Timeline {
+        let bar = self.bar_length();
+        let (start, end) = (bar * bars.start as u128, bar * bars.end as u128);
+        let beat = self.time_signature.denominator.to_ticks();
+        let notes: Vec<PairedNote> = pair_notes(&self.events)
+            .into_iter()
+            .filter_map(|n| {
+                if n.start < start || n.start >= end {
+                    return Some(n);
+                }
+                let replaced = matches!(n.part, Part::Drum(part) if fill.contains(&part));
+                match ducking {
+                    _ if replaced => None,
+                    Ducking::Mute => None,
+                    Ducking::Thin if beat.0 == 0 || (n.start - start).0 % beat.0 != 0 => None,
+                    Ducking::Thin => {
+                        let velocity = n.velocity.0 as u32 * THIN_VELOCITY.0 / THIN_VELOCITY.1;
+                        Some(PairedNote { velocity: Velocity(velocity.max(1) as u8), ..n })
+                    }
+                    Ducking::Keep => Some(n),
+                }
+            })
+            .collect();
+        Timeline::new(self.time_signature, unpair_notes(&notes), self.length)
+    }
+
+    /// Plays the timeline `times` times in a row.
+    pub fn repeat(&self, times: u32) -> Timeline {
+        (0..times).fold(
+            Timeline::new(self.time_signature, Vec::new(), Tick(0)),
+            |acc, _| acc.concat(self),
+        )
+    }
+
+    /// Writes the timeline to a MIDI file with a single drum track.
+    pub fn to_smf<'a>(&self, text: &'a str, tempo: u16, drum_map: &DrumMap) -> Smf<'a> {
+        let mut track = drums_track_header(self.time_signature, MidiTempo::from_tempo(tempo), text, "Drumkit");
+        let last = write_events(EventGrid::new(self.events.clone(), self.length), &[], drum_map, &mut track);
+        tracks_to_smf(end_tracks(vec![(track, last)], self.length))
+    }
+}
+
+#[cfg(test)]
+use crate::dsl::dsl::groups;
+#[cfg(test)]
+use crate::midi::core::{DrumPart::*, EventType::*, Part::*};
+
+#[cfg(test)]
+fn timeline(pattern: &str) -> Timeline {
+    Timeline::from_groups(
+        &BTreeMap::from_iter([(KickDrum, groups(pattern).unwrap())]),
+        TimeSignature::from_str("4/4").unwrap(),
+    )
+    .unwrap()
+}
+
+#[test]
+fn test_timeline_slice() {
+    // Converges over 3 bars, with a half note hit every three quarters.
+    let full = timeline("2x4-");
+    assert_eq!(full.bars(), 3);
+    let second_bar = full.slice(1..2);
+    assert_eq!(second_bar.length(), Tick(192));
+    assert_eq!(
+        second_bar.events(),
+        [
+            Event::new(Tick(96), NoteOn(Drum(KickDrum), Velocity(100))),
+            Event::new(Tick(192), NoteOff(Drum(KickDrum))),
+        ]
+    );
+    // The second hit rings over the bar line and is cut short.
+    assert_eq!(
+        full.slice(0..1).events()[2..],
+        [
+            Event::new(Tick(144), NoteOn(Drum(KickDrum), Velocity(100))),
+            Event::new(Tick(192), NoteOff(Drum(KickDrum))),
+        ]
+    );
+    assert_eq!(full.slice(2..10).length(), Tick(192));
+    assert_eq!(full.slice(5..7).length(), Tick(0));
+}
+
+#[test]
+fn test_timeline_concat_and_repeat() {
+    let bar = timeline("4x---");
+    let two_bars = bar.concat(&bar);
+    assert_eq!(two_bars, bar.repeat(2));
+    assert_eq!(two_bars.bars(), 2);
+    assert_eq!(two_bars.slice(1..2), bar);
+    assert_eq!(bar.repeat(0).length(), Tick(0));
+
+    let smf = two_bars.to_smf("", 120, &DrumMap::default());
+    let length: u32 = smf.tracks[0].iter().map(|e| e.delta.as_int()).sum();
+    assert_eq!(length, 384);
+}
+
+#[test]
+fn test_timeline_duck() {
+    let groove = Timeline::from_groups(
+        &BTreeMap::from_iter([(KickDrum, groups("4x").unwrap()), (HiHat, groups("8x").unwrap())]),
+        TimeSignature::from_str("4/4").unwrap(),
+    )
+    .unwrap()
+    .repeat(2);
+    let under = |ducking: Ducking| groove.duck(1..2, ducking, &[KickDrum]).slice(1..2);
+    assert_eq!(under(Ducking::Mute).events(), []);
+    let thin = under(Ducking::Thin);
+    assert_eq!(thin.events().len(), 8);
+    let hits: Vec<Event<Tick>> = thin.events().iter().filter(|e| matches!(e.event_type, NoteOn(..))).copied().collect();
+    assert_eq!(hits, (0..4).map(|i| Event::new(Tick(i * 48), NoteOn(Drum(HiHat), Velocity(60)))).collect::<Vec<_>>());
+    assert_eq!(under(Ducking::Keep).events().len(), 16);
+    // The first bar isn't touched.
+    assert_eq!(groove.duck(1..2, Ducking::Mute, &[]).slice(0..1), groove.slice(0..1));
+    assert_eq!(Ducking::from_str("thin"), Ok(Ducking::Thin));
+    assert!(Ducking::from_str("duck").is_err());
+
+    let fill = timeline("16xxxx").slice(0..1);
+    let bar = groove.slice(1..2).duck(0..1, Ducking::Mute, &[]).overlay(&fill);
+    assert_eq!(bar.length(), Tick(192));
+    assert_eq!(bar.events(), fill.events());
+}
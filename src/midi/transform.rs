@@ -0,0 +1,466 @@
+use std::cmp::min;
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::str::FromStr;
+
+use dyn_clone::DynClone;
+
+use crate::dsl::dsl::BasicLength;
+use crate::midi::core::{DrumPart, Event, EventType, Part, Tick, Velocity};
+use crate::midi::time::TimeSignature;
+use crate::random::{Domain, Rng};
+
+use DrumPart::*;
+use EventType::*;
+use Part::*;
+
+/// What a `Transform` may need to know about the track besides the events.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TransformContext {
+    pub time_signature: TimeSignature,
+    /// Length of the whole drum track.
+    pub length: Tick,
+    /// Length of a single repetition of the converged pattern, the track is made of these.
+    pub cycle: Tick,
+}
+
+impl TransformContext {
+    pub fn bar_length(&self) -> Tick {
+        self.time_signature.denominator.to_ticks() * self.time_signature.numerator as u128
+    }
+
+    pub fn beat_length(&self) -> Tick {
+        self.time_signature.denominator.to_ticks()
+    }
+
+    /// Position of the tick within the track, from 0 at the start to 1 at the end.
+    pub fn position(&self, tick: Tick) -> f64 {
+        if self.length == Tick(0) {
+            0.0
+        } else {
+            tick.0 as f64 / self.length.0 as f64
+        }
+    }
+}
+
+/// A post-processing step applied to the drum events after they're laid out, before they're written to a MIDI track.
+/// Events are sorted by time when passed to `apply` and are expected to be sorted when returned.
+pub trait Transform: DynClone + Debug {
+    fn apply(&self, events: Vec<Event<Tick>>, context: &TransformContext) -> Vec<Event<Tick>>;
+}
+
+dyn_clone::clone_trait_object!(Transform);
+
+/// A note with both of its ends, it's easier to move and drop notes this way than by matching `NoteOn` and `NoteOff`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PairedNote {
+    pub part: Part,
+    pub start: Tick,
+    pub end: Tick,
+    pub velocity: Velocity,
+}
+
+/// Matches every `NoteOn` with the next `NoteOff` of the same part, a `NoteOn` without one ends right where it
+/// starts. Goes through the events once, keeping the notes still waiting for their `NoteOff` by part.
+pub fn pair_notes(events: &[Event<Tick>]) -> Vec<PairedNote> {
+    let mut notes = Vec::new();
+    let mut open: HashMap<Part, Vec<usize>> = HashMap::new();
+    for event in events {
+        match event.event_type {
+            NoteOn(part, velocity) => {
+                open.entry(part).or_default().push(notes.len());
+                notes.push(PairedNote { part, start: event.tick, end: event.tick, velocity });
+            }
+            NoteOff(part) => {
+                for i in open.remove(&part).unwrap_or_default() {
+                    notes[i].end = event.tick;
+                }
+            }
+        }
+    }
+    notes
+}
+
+/// Reverses `pair_notes`.
+pub fn unpair_notes(notes: &[PairedNote]) -> Vec<Event<Tick>> {
+    let mut events: Vec<Event<Tick>> = notes
+        .iter()
+        .flat_map(|n| {
+            [
+                Event::new(n.start, NoteOn(n.part, n.velocity)),
+                Event::new(n.end, NoteOff(n.part)),
+            ]
+        })
+        .collect();
+    events.sort();
+    events
+}
+
+/// A 4/4 context for tests, `length` ticks long with the converged pattern repeating every `cycle` ticks.
+#[cfg(test)]
+pub(crate) fn test_context(length: u128, cycle: u128) -> TransformContext {
+    TransformContext {
+        time_signature: TimeSignature { numerator: 4, denominator: BasicLength::Fourth },
+        length: Tick(length),
+        cycle: Tick(cycle),
+    }
+}
+
+/// A note of the drum part for tests, `length` ticks long.
+#[cfg(test)]
+pub(crate) fn drum_note(part: DrumPart, start: u128, length: u128, velocity: u8) -> PairedNote {
+    PairedNote { part: Drum(part), start: Tick(start), end: Tick(start + length), velocity: Velocity(velocity) }
+}
+
+#[test]
+fn test_pair_notes() {
+    let events = vec![
+        Event::new(Tick(0), NoteOn(Drum(KickDrum), Velocity(100))),
+        Event::new(Tick(0), NoteOn(Drum(HiHat), Velocity(80))),
+        Event::new(Tick(12), NoteOff(Drum(HiHat))),
+        Event::new(Tick(24), NoteOff(Drum(KickDrum))),
+    ];
+    let notes = pair_notes(&events);
+    assert_eq!(
+        notes,
+        vec![
+            PairedNote { part: Drum(KickDrum), start: Tick(0), end: Tick(24), velocity: Velocity(100) },
+            PairedNote { part: Drum(HiHat), start: Tick(0), end: Tick(12), velocity: Velocity(80) },
+        ]
+    );
+    let mut sorted = events.clone();
+    sorted.sort();
+    assert_eq!(unpair_notes(&notes), sorted);
+    // Notes waiting for a note off of their part share it, a note without one ends where it starts.
+    let events = vec![
+        Event::new(Tick(0), NoteOn(Drum(SnareDrum), Velocity(100))),
+        Event::new(Tick(6), NoteOn(Drum(SnareDrum), Velocity(40))),
+        Event::new(Tick(12), NoteOff(Drum(SnareDrum))),
+        Event::new(Tick(24), NoteOn(Drum(SnareDrum), Velocity(100))),
+    ];
+    assert_eq!(
+        pair_notes(&events),
+        vec![drum_note(SnareDrum, 0, 12, 100), drum_note(SnareDrum, 6, 6, 40), drum_note(SnareDrum, 24, 0, 100)]
+    );
+}
+
+/// A value changing over the course of the whole track, from 0 to 1.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Automation {
+    /// Rises steadily from the start to the end.
+    Build,
+    /// Rises until the position (from 0 to 1) and then falls back.
+    PeakAt(f64),
+}
+
+impl Automation {
+    /// Value of the automation at the position, both are from 0 to 1.
+    pub fn at(&self, position: f64) -> f64 {
+        let position = position.clamp(0.0, 1.0);
+        match *self {
+            Automation::Build => position,
+            Automation::PeakAt(peak) if position <= peak => {
+                if peak == 0.0 { 1.0 } else { position / peak }
+            }
+            Automation::PeakAt(peak) => {
+                if peak == 1.0 { 1.0 } else { (1.0 - position) / (1.0 - peak) }
+            }
+        }
+    }
+}
+
+impl FromStr for Automation {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once('=') {
+            None if s == "build" => Ok(Automation::Build),
+            Some(("peak-at", p)) => match f64::from_str(p) {
+                Ok(p) if (0.0..=1.0).contains(&p) => Ok(Automation::PeakAt(p)),
+                _ => Err(format!("Peak position should be a number from 0 to 1: {}", s)),
+            },
+            _ => Err(format!("Expected 'build' or 'peak-at=<position>': {}", s)),
+        }
+    }
+}
+
+#[test]
+fn test_automation() {
+    assert_eq!(Automation::from_str("build"), Ok(Automation::Build));
+    assert_eq!(Automation::from_str("peak-at=0.75"), Ok(Automation::PeakAt(0.75)));
+    assert!(Automation::from_str("peak-at=2").is_err());
+    assert!(Automation::from_str("fade").is_err());
+    assert_eq!(Automation::Build.at(0.25), 0.25);
+    assert_eq!(Automation::PeakAt(0.5).at(0.25), 0.5);
+    assert_eq!(Automation::PeakAt(0.5).at(0.5), 1.0);
+    assert_eq!(Automation::PeakAt(0.5).at(1.0), 0.0);
+}
+
+/// Intensity at which soft notes, such as ghost notes, stop being played.
+static SOFT_NOTES_THRESHOLD: f64 = 0.35;
+/// Notes with velocity below this one are considered soft.
+pub(crate) static SOFT_NOTE_VELOCITY: u8 = 50;
+/// Intensity at which cymbals are only played on the beat.
+static SPARSE_CYMBALS_THRESHOLD: f64 = 0.2;
+/// Intensity at which a crash is added on every downbeat.
+static CRASH_THRESHOLD: f64 = 0.9;
+/// Velocity of the quietest hit is scaled by this much at the lowest intensity.
+static MIN_VELOCITY_SCALE: f64 = 0.6;
+
+/// Shapes the intensity of the whole track after the automation: at low intensity velocities are scaled down,
+/// soft notes and off-beat cymbals are left out, at high intensity crashes are added on downbeats.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct IntensityArc {
+    pub automation: Automation,
+}
+
+impl Transform for IntensityArc {
+    fn apply(&self, events: Vec<Event<Tick>>, context: &TransformContext) -> Vec<Event<Tick>> {
+        let intensity = |tick: Tick| self.automation.at(context.position(tick));
+        let beat = context.beat_length();
+        let bar = context.bar_length();
+        let mut notes: Vec<PairedNote> = pair_notes(&events)
+            .into_iter()
+            .filter(|n| {
+                let i = intensity(n.start);
+                let is_soft = n.velocity.0 < SOFT_NOTE_VELOCITY;
+                let is_cymbal = matches!(n.part, Drum(HiHat | OpenHiHat | RideCymbal | CrashCymbal));
+                let on_beat = beat == Tick(0) || n.start.0 % beat.0 == 0;
+                !(is_soft && i < SOFT_NOTES_THRESHOLD || is_cymbal && !on_beat && i < SPARSE_CYMBALS_THRESHOLD)
+            })
+            .map(|n| {
+                let scale = MIN_VELOCITY_SCALE + (1.0 - MIN_VELOCITY_SCALE) * intensity(n.start);
+                let velocity = (n.velocity.0 as f64 * scale).round().clamp(1.0, 127.0) as u8;
+                PairedNote { velocity: Velocity(velocity), ..n }
+            })
+            .collect();
+        if bar > Tick(0) {
+            let mut downbeat = Tick(0);
+            while downbeat < context.length {
+                let has_crash = notes.iter().any(|n| n.part == Drum(CrashCymbal) && n.start == downbeat);
+                if intensity(downbeat) >= CRASH_THRESHOLD && !has_crash {
+                    notes.push(PairedNote {
+                        part: Drum(CrashCymbal),
+                        start: downbeat,
+                        end: downbeat + beat,
+                        velocity: Velocity(127),
+                    });
+                }
+                downbeat = downbeat + bar;
+            }
+        }
+        unpair_notes(&notes)
+    }
+}
+
+#[test]
+fn test_intensity_arc() {
+    let context = test_context(384, 192);
+    let hihat = |tick: u128, velocity: u8| drum_note(HiHat, tick, 24, velocity);
+    let events = unpair_notes(&[hihat(0, 100), hihat(24, 100), hihat(48, 40), hihat(216, 100), hihat(360, 100)]);
+    let arc = IntensityArc { automation: Automation::PeakAt(0.5) };
+    assert_eq!(
+        pair_notes(&arc.apply(events, &context)),
+        vec![
+            hihat(0, 60),
+            drum_note(CrashCymbal, 192, 48, 127),
+            hihat(216, 95),
+        ]
+    );
+}
+
+/// A bar with at least this many tom hits in its last beat is considered a fill.
+static FILL_TOM_HITS: usize = 2;
+
+/// Takes crash placement over: crashes are only played on structurally strong downbeats, each of them gets one
+/// with the chance, crashes anywhere else are left out.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Crashes {
+    /// Chance for every strong downbeat to get a crash, from 0 to 1.
+    pub chance: f64,
+    pub seed: u64,
+}
+
+impl Crashes {
+    /// Strong downbeats of the track: the start of every repetition of the converged pattern, where all the parts
+    /// line up again, and the downbeats right after fills.
+    pub fn strong_points(events: &[Event<Tick>], context: &TransformContext) -> Vec<Tick> {
+        let mut points = vec![Tick(0)];
+        if context.cycle > Tick(0) {
+            let mut start = context.cycle;
+            while start < context.length {
+                points.push(start);
+                start = start + context.cycle;
+            }
+        }
+        let bar = context.bar_length();
+        let beat = context.beat_length();
+        if bar > Tick(0) {
+            let mut downbeat = bar;
+            while downbeat < context.length {
+                let toms = events
+                    .iter()
+                    .filter(|e| matches!(e.event_type, NoteOn(Drum(Tom1 | Tom2 | Tom3), _)))
+                    .filter(|e| e.tick < downbeat && e.tick >= downbeat - beat)
+                    .count();
+                if toms >= FILL_TOM_HITS {
+                    points.push(downbeat);
+                }
+                downbeat = downbeat + bar;
+            }
+        }
+        points.sort();
+        points.dedup();
+        points
+    }
+
+    /// Leaves crashes only on the points and rolls the chance for the points without one, every point with a random
+    /// generator of its own. The points are expected to be sorted.
+    pub fn place(&self, events: Vec<Event<Tick>>, points: &[Tick]) -> Vec<Event<Tick>> {
+        let mut notes: Vec<PairedNote> = pair_notes(&events)
+            .into_iter()
+            .filter(|n| n.part != Drum(CrashCymbal) || points.contains(&n.start))
+            .collect();
+        for &point in points {
+            let has_crash = notes.iter().any(|n| n.part == Drum(CrashCymbal) && n.start == point);
+            let keys = [Domain::Crash.key(), CrashCymbal.seed_key(), point.0 as u64];
+            if !has_crash && Rng::derive(self.seed, &keys).chance(self.chance) {
+                notes.push(PairedNote {
+                    part: Drum(CrashCymbal),
+                    start: point,
+                    end: point + BasicLength::Fourth.to_ticks(),
+                    velocity: Velocity(127),
+                });
+            }
+        }
+        unpair_notes(&notes)
+    }
+}
+
+impl Transform for Crashes {
+    fn apply(&self, events: Vec<Event<Tick>>, context: &TransformContext) -> Vec<Event<Tick>> {
+        let points = Crashes::strong_points(&events, context);
+        self.place(events, &points)
+    }
+}
+
+#[test]
+fn test_crashes() {
+    let context = test_context(768, 384);
+    let note = |part: DrumPart, tick: u128| drum_note(part, tick, 48, 100);
+    let crash = |tick: u128| drum_note(CrashCymbal, tick, 48, 127);
+    // A fill at the end of the first bar and crashes all over the place.
+    let events = unpair_notes(&[
+        note(Tom1, 144),
+        note(Tom3, 168),
+        note(CrashCymbal, 0),
+        note(CrashCymbal, 96),
+        note(CrashCymbal, 576),
+    ]);
+    assert_eq!(Crashes::strong_points(&events, &context), vec![Tick(0), Tick(192), Tick(384)]);
+    let crashes = Crashes { chance: 1.0, seed: 0 };
+    assert_eq!(
+        pair_notes(&crashes.apply(events.clone(), &context)),
+        vec![note(CrashCymbal, 0), note(Tom1, 144), note(Tom3, 168), crash(192), crash(384)]
+    );
+    let crashes = Crashes { chance: 0.0, seed: 0 };
+    assert_eq!(
+        pair_notes(&crashes.apply(events, &context)),
+        vec![note(CrashCymbal, 0), note(Tom1, 144), note(Tom3, 168)]
+    );
+}
+
+/// Number of bars `Ending::Fade` fades out over.
+static FADE_BARS: u128 = 2;
+/// Velocities are scaled down by this much at the very end of `Ending::Fade`.
+static FADE_MIN_VELOCITY_SCALE: f64 = 0.1;
+
+/// How the track ends, instead of the loop just stopping mid-groove.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ending {
+    /// An extra bar with a big crash and kick on the downbeat, ringing out for the whole bar.
+    Crash,
+    /// An extra bar with a short unison hit of kick, snare and crash on the downbeat.
+    Button,
+    /// The last bars get quieter and quieter.
+    Fade,
+}
+
+impl Ending {
+    /// Adds the ending to the events of the whole track. Returns the events along with the new length of the track.
+    pub fn apply(self, events: Vec<Event<Tick>>, context: &TransformContext) -> (Vec<Event<Tick>>, Tick) {
+        let bar = context.bar_length();
+        let downbeat = context.length;
+        let hit = |part: DrumPart, length: Tick| PairedNote {
+            part: Drum(part),
+            start: downbeat,
+            end: downbeat + length,
+            velocity: Velocity(127),
+        };
+        let mut notes = pair_notes(&events);
+        match self {
+            Ending::Crash => {
+                notes.push(hit(KickDrum, context.beat_length()));
+                notes.push(hit(CrashCymbal, bar));
+                (unpair_notes(&notes), context.length + bar)
+            }
+            Ending::Button => {
+                let length = BasicLength::Sixteenth.to_ticks();
+                notes.extend([hit(KickDrum, length), hit(SnareDrum, length), hit(CrashCymbal, length)]);
+                (unpair_notes(&notes), context.length + bar)
+            }
+            Ending::Fade => {
+                let fade_length = min(bar * FADE_BARS, context.length);
+                let fade_start = context.length - fade_length;
+                for n in notes.iter_mut().filter(|n| n.start >= fade_start && fade_length > Tick(0)) {
+                    let progress = (n.start - fade_start).0 as f64 / fade_length.0 as f64;
+                    let scale = 1.0 - (1.0 - FADE_MIN_VELOCITY_SCALE) * progress;
+                    n.velocity = Velocity((n.velocity.0 as f64 * scale).round().clamp(1.0, 127.0) as u8);
+                }
+                (unpair_notes(&notes), context.length)
+            }
+        }
+    }
+}
+
+impl FromStr for Ending {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "crash" => Ok(Ending::Crash),
+            "button" => Ok(Ending::Button),
+            "fade" => Ok(Ending::Fade),
+            _ => Err(format!("Expected 'crash', 'button' or 'fade': {}", s)),
+        }
+    }
+}
+
+#[test]
+fn test_ending() {
+    let context = test_context(384, 192);
+    let note = drum_note;
+    let events = unpair_notes(&[note(HiHat, 0, 24, 100), note(HiHat, 192, 24, 100), note(HiHat, 288, 24, 100)]);
+
+    let (crash, length) = Ending::Crash.apply(events.clone(), &context);
+    assert_eq!(length, Tick(576));
+    assert_eq!(
+        pair_notes(&crash)[3..],
+        [note(KickDrum, 384, 48, 127), note(CrashCymbal, 384, 192, 127)]
+    );
+
+    let (button, length) = Ending::Button.apply(events.clone(), &context);
+    assert_eq!(length, Tick(576));
+    assert_eq!(
+        pair_notes(&button)[3..],
+        [note(KickDrum, 384, 12, 127), note(SnareDrum, 384, 12, 127), note(CrashCymbal, 384, 12, 127)]
+    );
+
+    let (fade, length) = Ending::Fade.apply(events, &context);
+    assert_eq!(length, Tick(384));
+    assert_eq!(
+        pair_notes(&fade),
+        vec![note(HiHat, 0, 24, 100), note(HiHat, 192, 24, 55), note(HiHat, 288, 24, 32)]
+    );
+    assert!(Ending::from_str("stop").is_err());
+}
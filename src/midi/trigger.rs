@@ -0,0 +1,142 @@
+use std::collections::BTreeSet;
+use std::fmt;
+use std::str::FromStr;
+
+use crate::midi::core::{DrumPart, Event, Part, Tick};
+use crate::midi::transform::{pair_notes, unpair_notes, PairedNote, Transform, TransformContext, SOFT_NOTE_VELOCITY};
+
+/// Whether the notes a rule applies to are played when the other part is playing or when it's not.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Condition {
+    If,
+    Unless,
+}
+
+/// A rule keeping a drum part out of another one's way, e.g. `snare.ghost unless kick` plays the ghost notes
+/// of the snare drum only where the kick drum rests. Parts play together when their notes start at the same tick.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Trigger {
+    pub part: DrumPart,
+    /// Apply the rule to the soft notes of the part only, such as ghost notes.
+    pub ghosts_only: bool,
+    pub condition: Condition,
+    pub other: DrumPart,
+}
+
+impl Trigger {
+    fn applies_to(&self, note: &PairedNote) -> bool {
+        note.part == Part::Drum(self.part) && (!self.ghosts_only || note.velocity.0 < SOFT_NOTE_VELOCITY)
+    }
+}
+
+impl FromStr for Trigger {
+    type Err = String;
+
+    /// Reads `<part>[.ghost] if <part>` or `<part>[.ghost] unless <part>`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let words: Vec<&str> = s.split_whitespace().collect();
+        let [target, condition, other] = words[..] else {
+            return Err(format!("Expected '<part>[.ghost] if|unless <part>', got '{}'", s));
+        };
+        let (part, ghosts_only) = match target.strip_suffix(".ghost") {
+            Some(part) => (part, true),
+            None => (target, false),
+        };
+        let condition = match condition {
+            "if" => Condition::If,
+            "unless" => Condition::Unless,
+            _ => return Err(format!("Expected 'if' or 'unless': {}", condition)),
+        };
+        Ok(Trigger {
+            part: DrumPart::from_str(part)?,
+            ghosts_only,
+            condition,
+            other: DrumPart::from_str(other)?,
+        })
+    }
+}
+
+impl fmt::Display for Trigger {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let condition = match self.condition {
+            Condition::If => "if",
+            Condition::Unless => "unless",
+        };
+        let ghost = if self.ghosts_only { ".ghost" } else { "" };
+        write!(f, "{}{} {} {}", self.part.name(), ghost, condition, self.other.name())
+    }
+}
+
+/// Leaves out the notes breaking any of the rules. The rules are checked against the notes as they were before
+/// any of them got left out, so the order of the rules doesn't matter.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Triggers(pub Vec<Trigger>);
+
+impl Transform for Triggers {
+    fn apply(&self, events: Vec<Event<Tick>>, _context: &TransformContext) -> Vec<Event<Tick>> {
+        let notes = pair_notes(&events);
+        let starts: BTreeSet<(Part, Tick)> = notes.iter().map(|n| (n.part, n.start)).collect();
+        let plays = |part: DrumPart, tick: Tick| starts.contains(&(Part::Drum(part), tick));
+        let kept: Vec<PairedNote> = notes
+            .iter()
+            .filter(|n| {
+                self.0.iter().filter(|rule| rule.applies_to(n)).all(|rule| match rule.condition {
+                    Condition::If => plays(rule.other, n.start),
+                    Condition::Unless => !plays(rule.other, n.start),
+                })
+            })
+            .copied()
+            .collect();
+        unpair_notes(&kept)
+    }
+}
+
+#[cfg(test)]
+use crate::midi::core::DrumPart::*;
+#[cfg(test)]
+use crate::midi::transform::{drum_note, test_context};
+
+#[test]
+fn test_parse_trigger() {
+    let rule = Trigger::from_str("snare.ghost unless kick").unwrap();
+    assert_eq!(
+        rule,
+        Trigger { part: SnareDrum, ghosts_only: true, condition: Condition::Unless, other: KickDrum }
+    );
+    assert_eq!(rule.to_string(), "snare.ghost unless kick");
+    assert_eq!(Trigger::from_str("open-hi-hat  if hi-hat").unwrap().to_string(), "open-hi-hat if hi-hat");
+    assert!(Trigger::from_str("snare unless").is_err());
+    assert!(Trigger::from_str("snare when kick").is_err());
+    assert!(Trigger::from_str("snare.accent unless kick").is_err());
+}
+
+#[test]
+fn test_triggers() {
+    let context = test_context(96, 96);
+    let note = |part: DrumPart, tick: u128, velocity: u8| drum_note(part, tick, 12, velocity);
+    let events = unpair_notes(&[
+        note(KickDrum, 0, 100),
+        note(SnareDrum, 0, 40),
+        note(OpenHiHat, 12, 100),
+        note(SnareDrum, 24, 100),
+        note(OpenHiHat, 24, 100),
+        note(SnareDrum, 36, 40),
+        note(KickDrum, 48, 100),
+        note(SnareDrum, 48, 100),
+    ]);
+    let rules = Triggers(vec![
+        Trigger::from_str("snare.ghost unless kick").unwrap(),
+        Trigger::from_str("open-hi-hat unless snare").unwrap(),
+    ]);
+    assert_eq!(
+        pair_notes(&rules.apply(events, &context)),
+        vec![
+            note(KickDrum, 0, 100),
+            note(OpenHiHat, 12, 100),
+            note(SnareDrum, 24, 100),
+            note(SnareDrum, 36, 40),
+            note(KickDrum, 48, 100),
+            note(SnareDrum, 48, 100),
+        ]
+    );
+}
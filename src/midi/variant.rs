@@ -0,0 +1,125 @@
+use std::fmt;
+use std::str::FromStr;
+
+use crate::midi::core::RenderOptions;
+use crate::midi::humanize::{Humanize, STRAIGHT_SWING};
+
+/// A change to how the groove is rendered, to hear it another way.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Setting {
+    /// Plays the whole track at this tempo, replacing a tempo sweep too.
+    Tempo(u16),
+    /// Swings the beat, see `Humanize::swing`.
+    Swing(f64),
+    /// Halves the tempo, every note takes twice as long. The patterns are played as they are, it's not a half-time
+    /// feel with the backbeat moved.
+    HalfTime,
+    /// Doubles the tempo, the patterns are played as they are.
+    DoubleTime,
+}
+
+impl FromStr for Setting {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once('=') {
+            None if s == "halftime" => Ok(Setting::HalfTime),
+            None if s == "doubletime" => Ok(Setting::DoubleTime),
+            Some(("tempo", tempo)) => match tempo.parse::<u16>() {
+                Ok(tempo) if tempo > 0 => Ok(Setting::Tempo(tempo)),
+                _ => Err(format!("Expected a positive tempo in BPM, got '{}'", tempo)),
+            },
+            Some(("swing", swing)) => match swing.parse::<f64>() {
+                // No swing at all is straight.
+                Ok(0.0) => Ok(Setting::Swing(STRAIGHT_SWING)),
+                Ok(swing) if (STRAIGHT_SWING..=75.0).contains(&swing) => Ok(Setting::Swing(swing)),
+                _ => Err(format!("Expected a swing from {} to 75, got '{}'", STRAIGHT_SWING, swing)),
+            },
+            _ => Err(format!(
+                "Unknown setting '{}', expected 'tempo=<BPM>', 'swing=<50..75>', 'halftime' or 'doubletime'",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Setting {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Setting::Tempo(tempo) => write!(f, "tempo{}", tempo),
+            Setting::Swing(swing) => write!(f, "swing{}", swing),
+            Setting::HalfTime => write!(f, "halftime"),
+            Setting::DoubleTime => write!(f, "doubletime"),
+        }
+    }
+}
+
+/// Settings rendered together into a file of their own, e.g. `tempo=90,swing=60`. They're applied in order.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Variant(pub Vec<Setting>);
+
+impl FromStr for Variant {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.split(',')
+            .map(|setting| Setting::from_str(setting.trim()))
+            .collect::<Result<Vec<Setting>, String>>()
+            .map(Variant)
+    }
+}
+
+/// The variant as a file name suffix, e.g. `tempo90-swing60`.
+impl fmt::Display for Variant {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let settings: Vec<String> = self.0.iter().map(|s| s.to_string()).collect();
+        write!(f, "{}", settings.join("-"))
+    }
+}
+
+impl Variant {
+    /// The options to render the variant with, everything the settings don't change is left as it is.
+    pub fn apply(&self, options: &RenderOptions) -> RenderOptions {
+        let mut options = options.clone();
+        for setting in &self.0 {
+            match *setting {
+                Setting::Tempo(tempo) => options.tempos = vec![tempo],
+                Setting::Swing(swing) => {
+                    let humanize = options.humanize.get_or_insert(Humanize {
+                        timing: 0,
+                        velocity: 0,
+                        swing: STRAIGHT_SWING,
+                        seed: 0,
+                    });
+                    humanize.swing = swing;
+                }
+                Setting::HalfTime => options.tempos.iter_mut().for_each(|t| *t = (*t / 2).max(1)),
+                Setting::DoubleTime => options.tempos.iter_mut().for_each(|t| *t = t.saturating_mul(2)),
+            }
+        }
+        options
+    }
+}
+
+#[test]
+fn test_parse_variant() {
+    let variant = Variant::from_str("tempo=90, swing=62.5,halftime").unwrap();
+    assert_eq!(variant, Variant(vec![Setting::Tempo(90), Setting::Swing(62.5), Setting::HalfTime]));
+    assert_eq!(variant.to_string(), "tempo90-swing62.5-halftime");
+    assert!(Variant::from_str("swing=80").is_err());
+    assert!(Variant::from_str("swing=20").is_err());
+    assert_eq!(Variant::from_str("swing=0").unwrap(), Variant(vec![Setting::Swing(STRAIGHT_SWING)]));
+    assert!(Variant::from_str("tempo=0").is_err());
+    assert!(Variant::from_str("shuffle").unwrap_err().starts_with("Unknown setting 'shuffle'"));
+}
+
+#[test]
+fn test_apply_variant() {
+    let options = RenderOptions { tempos: vec![120, 131], ..Default::default() };
+    let variant = |s| Variant::from_str(s).unwrap().apply(&options);
+    assert_eq!(variant("halftime").tempos, vec![60, 65]);
+    assert_eq!(variant("tempo=90,doubletime").tempos, vec![180]);
+    let swung = variant("swing=60");
+    assert_eq!(swung.tempos, vec![120, 131]);
+    assert_eq!(swung.humanize, Some(Humanize { timing: 0, velocity: 0, swing: 60.0, seed: 0 }));
+}
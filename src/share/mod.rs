@@ -0,0 +1,216 @@
+#[cfg(feature = "qr")]
+pub mod qr;
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::str::FromStr;
+
+use crate::dsl::version::DslVersion;
+use crate::error::PolyError;
+use crate::midi::core::{parse_drum_mapping, DrumMap, DrumPart};
+use crate::midi::time::TimeSignature;
+
+static DEFAULT_TEMPO: u16 = 120;
+static DEFAULT_TIME_SIGNATURE: &str = "4/4";
+
+/// URL-safe alphabet, so that the encoded grooves survive being pasted into links.
+static BASE64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_";
+
+/// How a groove is written when it's shared.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShareEncoding {
+    /// Readable, e.g. `poly=1;tempo=120;signature=4/4;kick=8x--x--`.
+    Plain,
+    /// The plain form in URL-safe Base64, for places that mangle punctuation.
+    Base64,
+}
+
+impl FromStr for ShareEncoding {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "plain" => Ok(ShareEncoding::Plain),
+            "base64" => Ok(ShareEncoding::Base64),
+            _ => Err(format!("Unknown share encoding '{}', expected 'plain' or 'base64'", s)),
+        }
+    }
+}
+
+/// A groove's patterns, tempo, time signature and drum map, written as a single line. The form is
+/// canonical: the same groove is always written the same way, whatever order the options were given in and
+/// however the patterns were spaced.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Share {
+    pub version: DslVersion,
+    pub patterns: BTreeMap<DrumPart, String>,
+    pub tempo: u16,
+    pub time_signature: TimeSignature,
+    pub drum_map: DrumMap,
+}
+
+impl Share {
+    pub fn new(
+        version: DslVersion,
+        patterns: BTreeMap<DrumPart, String>,
+        tempo: u16,
+        time_signature: TimeSignature,
+        drum_map: DrumMap,
+    ) -> Self {
+        let patterns = patterns
+            .into_iter()
+            .map(|(part, pattern)| (part, pattern.split_whitespace().collect()))
+            .collect();
+        let drum_map = drum_map.without_defaults();
+        Share { version, patterns, tempo, time_signature, drum_map }
+    }
+
+    pub fn encode(&self, encoding: ShareEncoding) -> String {
+        match encoding {
+            ShareEncoding::Plain => self.to_string(),
+            ShareEncoding::Base64 => to_base64(self.to_string().as_bytes()),
+        }
+    }
+}
+
+impl fmt::Display for Share {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "poly={};tempo={};signature={}", self.version.number(), self.tempo, self.time_signature)?;
+        for (part, pattern) in &self.patterns {
+            write!(f, ";{}={}", part.name(), pattern)?;
+        }
+        for (part, key) in self.drum_map.entries() {
+            write!(f, ";map.{}={}", part.name(), key)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Share {
+    type Err = PolyError;
+
+    /// Reads both the plain and the Base64 form. Tempo and time signature default to 120 BPM in 4/4.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let error = |message: String| PolyError::Share(message);
+        let s = s.trim();
+        let plain = match s.starts_with("poly=") {
+            true => s.to_string(),
+            false => from_base64(s)
+                .and_then(|bytes| String::from_utf8(bytes).ok())
+                .ok_or_else(|| error("neither a plain nor a Base64 groove".to_string()))?,
+        };
+        let mut fields = plain.split(';');
+        let version = match fields.next().and_then(|f| f.strip_prefix("poly=")).map(u32::from_str) {
+            Some(Ok(number)) => DslVersion::try_from(number)?,
+            _ => return Err(error("expected the DSL version first, e.g. 'poly=1'".to_string())),
+        };
+        let mut tempo = DEFAULT_TEMPO;
+        let mut time_signature = TimeSignature::from_str(DEFAULT_TIME_SIGNATURE)?;
+        let mut patterns = BTreeMap::new();
+        let mut drum_map = DrumMap::default();
+        for field in fields {
+            let (key, value) = field
+                .split_once('=')
+                .ok_or_else(|| error(format!("expected 'key=value', got '{}'", field)))?;
+            match key {
+                "tempo" => match u16::from_str(value) {
+                    Ok(t) if t > 0 => tempo = t,
+                    _ => return Err(error(format!("{} is not a tempo", value))),
+                },
+                "signature" => time_signature = TimeSignature::from_str(value)?,
+                _ => match key.strip_prefix("map.") {
+                    Some(part) => {
+                        let (part, key) = parse_drum_mapping(&format!("{}={}", part, value)).map_err(error)?;
+                        drum_map.set(part, key);
+                    }
+                    None => {
+                        let part = DrumPart::from_str(key).map_err(error)?;
+                        version.parse(value)?;
+                        patterns.insert(part, value.to_string());
+                    }
+                },
+            }
+        }
+        if patterns.is_empty() {
+            return Err(error("the groove has no drum parts".to_string()));
+        }
+        Ok(Share::new(version, patterns, tempo, time_signature, drum_map))
+    }
+}
+
+fn to_base64(bytes: &[u8]) -> String {
+    let mut out = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let n = chunk.iter().enumerate().fold(0u32, |n, (i, b)| n | (*b as u32) << (16 - 8 * i));
+        // Padding is left out, the length tells how many bytes the last chunk has.
+        for i in 0..=chunk.len() {
+            out.push(BASE64[(n >> (18 - 6 * i)) as usize & 63] as char);
+        }
+    }
+    out
+}
+
+fn from_base64(s: &str) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(s.len() * 3 / 4);
+    let mut bits = 0u32;
+    let mut count = 0;
+    for c in s.trim_end_matches('=').bytes() {
+        bits = bits << 6 | BASE64.iter().position(|b| *b == c)? as u32;
+        count += 6;
+        if count >= 8 {
+            count -= 8;
+            out.push((bits >> count) as u8);
+            bits &= (1 << count) - 1;
+        }
+    }
+    Some(out)
+}
+
+#[cfg(test)]
+use midly::num::u7;
+#[cfg(test)]
+use crate::midi::core::DrumPart::*;
+
+#[test]
+fn test_base64() {
+    for s in ["", "p", "po", "pol", "poly=1;kick=8x--x--"] {
+        assert_eq!(from_base64(&to_base64(s.as_bytes())), Some(s.as_bytes().to_vec()));
+    }
+    assert_eq!(to_base64(b"poly"), "cG9seQ");
+    assert_eq!(from_base64("cG9seQ=="), Some(b"poly".to_vec()));
+    assert_eq!(from_base64("cG9s*"), None);
+}
+
+#[test]
+fn test_share() {
+    let mut drum_map = DrumMap::default();
+    drum_map.set(RideCymbal, u7::from(59));
+    let share = Share::new(
+        DslVersion::V1,
+        BTreeMap::from_iter([(SnareDrum, "4-x".to_string()), (KickDrum, "8x--x-- ".to_string())]),
+        90,
+        TimeSignature::from_str("7/8").unwrap(),
+        drum_map,
+    );
+    let plain = share.encode(ShareEncoding::Plain);
+    assert_eq!(plain, "poly=1;tempo=90;signature=7/8;kick=8x--x--;snare=4-x;map.ride=59");
+    assert_eq!(Share::from_str(&plain), Ok(share.clone()));
+    assert_eq!(Share::from_str(&share.encode(ShareEncoding::Base64)), Ok(share));
+    // Spacing doesn't change the canonical form.
+    let spaced = Share::from_str("poly=1;kick=8x-- x--").unwrap();
+    assert_eq!(spaced.to_string(), "poly=1;tempo=120;signature=4/4;kick=8x--x--");
+    // Nor does mapping a part to the key it's played at anyway.
+    let mapped = Share::from_str("poly=1;kick=4x;map.kick=36;map.ride=59").unwrap();
+    assert_eq!(mapped.to_string(), "poly=1;tempo=120;signature=4/4;kick=4x;map.ride=59");
+}
+
+#[test]
+fn test_share_errors() {
+    assert!(Share::from_str("poly=2;kick=4x").is_err());
+    assert!(Share::from_str("tempo=120;kick=4x").is_err());
+    assert!(Share::from_str("poly=1;tempo=120").is_err());
+    assert!(Share::from_str("poly=1;cowbell=4x").is_err());
+    assert!(Share::from_str("poly=1;kick=4x(").is_err());
+    assert!(Share::from_str("poly=1;kick=4x;map.kick=200").is_err());
+    assert!(Share::from_str("poly=1;kick=4x;tempo=0").is_err());
+}
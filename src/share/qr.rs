@@ -0,0 +1,121 @@
+use qrcodegen::{QrCode, QrCodeEcc};
+
+use crate::checksum::crc32;
+use crate::error::PolyError;
+
+/// Light modules around the code that scanners need to find it.
+static QUIET_ZONE: i32 = 4;
+
+fn encode(text: &str) -> Result<QrCode, PolyError> {
+    // Medium error correction still recovers a code shown on a slightly blurry slide or video frame.
+    QrCode::encode_text(text, QrCodeEcc::Medium)
+        .map_err(|_| PolyError::Share("the groove is too long for a QR code".to_string()))
+}
+
+/// Whether the module is dark, the quiet zone included.
+fn dark(code: &QrCode, x: i32, y: i32) -> bool {
+    code.get_module(x - QUIET_ZONE, y - QUIET_ZONE)
+}
+
+/// Draws the QR code with block characters, two rows of modules per line. Dark modules are blank, so that the code
+/// reads right on a terminal with a dark background.
+pub fn terminal(text: &str) -> Result<String, PolyError> {
+    let code = encode(text)?;
+    let size = code.size() + QUIET_ZONE * 2;
+    let mut out = String::new();
+    for y in (0..size).step_by(2) {
+        for x in 0..size {
+            out.push(match (dark(&code, x, y), y + 1 < size && dark(&code, x, y + 1)) {
+                (true, true) => ' ',
+                (true, false) => '▄',
+                (false, true) => '▀',
+                (false, false) => '█',
+            });
+        }
+        out.push('\n');
+    }
+    Ok(out)
+}
+
+/// Black and white PNG image of the QR code, `scale` pixels per module.
+pub fn png(text: &str, scale: u32) -> Result<Vec<u8>, PolyError> {
+    let code = encode(text)?;
+    let side = (code.size() + QUIET_ZONE * 2) as u32 * scale;
+    // Every row of pixels starts with the filter type, none.
+    let mut pixels = Vec::with_capacity(((side + 1) * side) as usize);
+    for y in 0..side {
+        pixels.push(0);
+        for x in 0..side {
+            let dark = dark(&code, (x / scale) as i32, (y / scale) as i32);
+            pixels.push(if dark { 0 } else { 255 });
+        }
+    }
+
+    let mut header = Vec::with_capacity(13);
+    header.extend_from_slice(&side.to_be_bytes());
+    header.extend_from_slice(&side.to_be_bytes());
+    // 8-bit grayscale, the default compression, filtering and no interlacing.
+    header.extend_from_slice(&[8, 0, 0, 0, 0]);
+
+    let mut out = b"\x89PNG\r\n\x1a\n".to_vec();
+    chunk(&mut out, b"IHDR", &header);
+    chunk(&mut out, b"IDAT", &zlib_stored(&pixels));
+    chunk(&mut out, b"IEND", &[]);
+    Ok(out)
+}
+
+fn chunk(out: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    out.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    let start = out.len();
+    out.extend_from_slice(kind);
+    out.extend_from_slice(data);
+    let crc = crc32(&out[start..]);
+    out.extend_from_slice(&crc.to_be_bytes());
+}
+
+/// Wraps the data into a zlib stream without compressing it. QR codes are small enough for it not to matter, and
+/// it saves a dependency on a deflate implementation.
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    let mut out = vec![0x78, 0x01];
+    let mut blocks = data.chunks(u16::MAX as usize).peekable();
+    if blocks.peek().is_none() {
+        out.extend_from_slice(&[1, 0, 0, 0xff, 0xff]);
+    }
+    while let Some(block) = blocks.next() {
+        out.push(blocks.peek().is_none() as u8);
+        out.extend_from_slice(&(block.len() as u16).to_le_bytes());
+        out.extend_from_slice(&(!(block.len() as u16)).to_le_bytes());
+        out.extend_from_slice(block);
+    }
+    let (a, b) = data.iter().fold((1u32, 0u32), |(a, b), byte| {
+        let a = (a + *byte as u32) % 65521;
+        (a, (b + a) % 65521)
+    });
+    out.extend_from_slice(&(b << 16 | a).to_be_bytes());
+    out
+}
+
+#[test]
+fn test_adler32() {
+    // Adler-32 of "Wikipedia" is 0x11e60398.
+    assert_eq!(zlib_stored(b"Wikipedia")[16..], [0x11, 0xe6, 0x03, 0x98]);
+}
+
+#[test]
+fn test_qr() {
+    let text = "poly=1;tempo=120;signature=4/4;kick=8x--x--";
+    let code = encode(text).unwrap();
+    let side = (code.size() + QUIET_ZONE * 2) as usize;
+    let drawn = terminal(text).unwrap();
+    let lines: Vec<&str> = drawn.lines().collect();
+    assert_eq!(lines.len(), side.div_ceil(2));
+    assert!(lines.iter().all(|l| l.chars().count() == side));
+    // The quiet zone is light all around.
+    assert!(lines[0].chars().all(|c| c == '█'));
+
+    let image = png(text, 3).unwrap();
+    assert!(image.starts_with(b"\x89PNG\r\n\x1a\n\0\0\0\x0dIHDR"));
+    assert_eq!(image[16..20], ((side * 3) as u32).to_be_bytes());
+    assert!(image.ends_with(b"IEND\xae\x42\x60\x82"));
+    assert!(terminal(&"x".repeat(5000)).is_err());
+}
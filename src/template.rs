@@ -0,0 +1,69 @@
+use std::str::FromStr;
+
+use crate::midi::time::TimeSignature;
+
+/// Names output files when only the directory is given, the extension goes after it.
+pub static DEFAULT_TEMPLATE: &str = "{name}_{tempo}bpm_{sig}";
+/// Names output files for `--auto-name`, the same patterns get the same name whatever they were read from.
+pub static AUTO_NAME_TEMPLATE: &str = "{hash}_{tempo}bpm_{sig}";
+
+/// What the variables of an output file name stand for:
+///
+/// * `{name}` - the name of the pattern file or the arrangement without the extension, `groove` for patterns given
+///   as options
+/// * `{tempo}` - the tempo in BPM, the first one of a sweep
+/// * `{sig}` - the time signature, written `7-8` as file names can't hold slashes
+/// * `{hash}` - 8 hexadecimal digits of a hash of the patterns, or of the arrangement
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Variables<'a> {
+    pub name: &'a str,
+    pub hash: u64,
+    pub tempo: u16,
+    pub time_signature: TimeSignature,
+}
+
+/// Fills the variables in, e.g. `grooves/{name}_{tempo}bpm.mid` into `grooves/bembe_120bpm.mid`. Text outside the
+/// braces is kept as it is.
+pub fn expand(template: &str, variables: &Variables) -> Result<String, String> {
+    let mut name = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        name.push_str(&rest[..start]);
+        let end = rest[start..].find('}').ok_or_else(|| format!("Unclosed '{{' in '{}'", template))?;
+        let value = match &rest[start + 1..start + end] {
+            "name" => variables.name.to_string(),
+            "tempo" => variables.tempo.to_string(),
+            "sig" => variables.time_signature.to_string().replace('/', "-"),
+            "hash" => format!("{:08x}", variables.hash >> 32),
+            variable => {
+                let expected = "expected {name}, {tempo}, {sig} or {hash}";
+                return Err(format!("Unknown variable '{{{}}}' in '{}', {}", variable, template, expected));
+            }
+        };
+        name.push_str(&value);
+        rest = &rest[start + end + 1..];
+    }
+    name.push_str(rest);
+    Ok(name)
+}
+
+/// Checks the variables of the template, to report a mistake before anything is rendered.
+pub fn validate(template: &str) -> Result<String, String> {
+    let time_signature = TimeSignature::from_str("4/4").map_err(|e| e.to_string())?;
+    let variables = Variables { name: "", hash: 0, tempo: 0, time_signature };
+    expand(template, &variables).map(|_| template.to_string())
+}
+
+#[test]
+fn test_expand() {
+    let time_signature = TimeSignature::from_str("12/8").unwrap();
+    let variables = Variables { name: "bembe", hash: 0x9e9141a0f007e87d, tempo: 132, time_signature };
+    assert_eq!(expand(DEFAULT_TEMPLATE, &variables), Ok("bembe_132bpm_12-8".to_string()));
+    assert_eq!(expand(AUTO_NAME_TEMPLATE, &variables), Ok("9e9141a0_132bpm_12-8".to_string()));
+    assert_eq!(expand("out/{name}.mid", &variables), Ok("out/bembe.mid".to_string()));
+    assert_eq!(expand("groove.mid", &variables), Ok("groove.mid".to_string()));
+    assert!(expand("{bpm}.mid", &variables).is_err());
+    assert!(expand("{name.mid", &variables).is_err());
+    assert_eq!(validate("{sig}-{tempo}"), Ok("{sig}-{tempo}".to_string()));
+    assert!(validate("{}").is_err());
+}
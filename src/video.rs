@@ -0,0 +1,266 @@
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::f64::consts::TAU;
+
+use crate::dsl::dsl::{Groups, KnownLength, Note};
+use crate::dsl::grid::to_384th;
+use crate::midi::core::DrumPart;
+use crate::midi::time::TimeSignature;
+
+/// Frames per second, every frame lasts exactly 4 hundredths of a second as GIF counts them.
+static FPS: u32 = 25;
+/// How long a hit stays lit after the playhead passes it, in seconds.
+static FLASH: f64 = 0.12;
+static BACKGROUND: u8 = 0;
+static RING: u8 = 1;
+static PLAYHEAD: u8 = 2;
+
+/// Colors of the parts, the dim one is used for the hits that aren't playing.
+static PART_COLORS: [[u8; 3]; 9] = [
+    [0xe6, 0x39, 0x46],
+    [0xf4, 0xa2, 0x61],
+    [0xe9, 0xc4, 0x6a],
+    [0x2a, 0x9d, 0x8f],
+    [0x8a, 0xb1, 0x7d],
+    [0x45, 0x7b, 0x9d],
+    [0xa8, 0x6c, 0xc1],
+    [0xd0, 0x6c, 0x9f],
+    [0x9e, 0x9e, 0x9e],
+];
+
+fn palette() -> Vec<u8> {
+    let mut palette = vec![0x14, 0x14, 0x14, 0x3c, 0x3c, 0x3c, 0xff, 0xff, 0xff];
+    for color in PART_COLORS {
+        palette.extend(color);
+        palette.extend(color.map(|c| c / 3));
+    }
+    // GIF palettes have a power of two colors.
+    palette.resize(32 * 3, 0);
+    palette
+}
+
+fn bright(part: usize) -> u8 {
+    3 + part as u8 * 2
+}
+
+fn dim(part: usize) -> u8 {
+    4 + part as u8 * 2
+}
+
+/// A drum part drawn as a ring, going around once per repetition of its pattern.
+#[derive(Debug, Clone, PartialEq)]
+struct Ring {
+    color: usize,
+    /// In 384ths of a whole note.
+    cycle: u32,
+    /// Start of every note that's played and its size.
+    hits: Vec<(u32, u32)>,
+}
+
+fn ring(part: DrumPart, groups: &Groups) -> Ring {
+    let mut time = 0;
+    let mut hits = Vec::new();
+    for group in &groups.0 {
+        let length = to_384th(group.length);
+        for note in &group.notes {
+            let size = match note {
+                Note::Rest => None,
+                Note::Ghost => Some(3),
+                Note::Hit => Some(5),
+                Note::Accent => Some(7),
+            };
+            if let Some(size) = size {
+                hits.push((time, size));
+            }
+            time += length;
+        }
+    }
+    let color = DrumPart::ALL.iter().position(|p| *p == part).unwrap_or(0);
+    Ring { color, cycle: time, hits }
+}
+
+struct Canvas {
+    size: usize,
+    pixels: Vec<u8>,
+}
+
+impl Canvas {
+    fn set(&mut self, x: f64, y: f64, color: u8) {
+        let (x, y) = (x.round(), y.round());
+        if x >= 0.0 && y >= 0.0 && (x as usize) < self.size && (y as usize) < self.size {
+            self.pixels[y as usize * self.size + x as usize] = color;
+        }
+    }
+
+    /// Point at `radius` from the center and `turn` of the full circle clockwise from the top.
+    fn at(&self, radius: f64, turn: f64) -> (f64, f64) {
+        let center = self.size as f64 / 2.0;
+        (center + radius * (turn * TAU).sin(), center - radius * (turn * TAU).cos())
+    }
+
+    fn circle(&mut self, radius: f64, color: u8) {
+        let steps = (radius * TAU).ceil().max(1.0) as usize;
+        for i in 0..steps {
+            let (x, y) = self.at(radius, i as f64 / steps as f64);
+            self.set(x, y, color);
+        }
+    }
+
+    fn dot(&mut self, (x, y): (f64, f64), radius: f64, color: u8) {
+        let r = radius.ceil() as i32;
+        for dy in -r..=r {
+            for dx in -r..=r {
+                if (dx * dx + dy * dy) as f64 <= radius * radius {
+                    self.set(x + dx as f64, y + dy as f64, color);
+                }
+            }
+        }
+    }
+}
+
+/// Draws the rings at `time`, in 384ths from the start. `flash` is how long a hit stays lit, in 384ths.
+fn draw(canvas: &mut Canvas, rings: &[Ring], time: f64, flash: f64) {
+    canvas.pixels.fill(BACKGROUND);
+    let outer = canvas.size as f64 / 2.0 - 12.0;
+    let spacing = (outer - 16.0) / rings.len().max(1) as f64;
+    for (i, ring) in rings.iter().enumerate() {
+        let radius = outer - spacing * i as f64;
+        canvas.circle(radius, RING);
+        let position = time % ring.cycle as f64;
+        for (start, size) in &ring.hits {
+            let since = position - *start as f64;
+            // Hits at the end of the cycle are still lit when the next one starts.
+            let lit = (0.0..flash).contains(&since) || (0.0..flash).contains(&(since + ring.cycle as f64));
+            let turn = *start as f64 / ring.cycle as f64;
+            match lit {
+                true => canvas.dot(canvas.at(radius, turn), *size as f64 + 2.0, bright(ring.color)),
+                false => canvas.dot(canvas.at(radius, turn), *size as f64, dim(ring.color)),
+            }
+        }
+        canvas.dot(canvas.at(radius, position / ring.cycle as f64), 2.5, PLAYHEAD);
+    }
+}
+
+/// A frame with only the part of the canvas that differs from the previous one, drawn over it.
+fn changes(previous: &[u8], canvas: &Canvas) -> gif::Frame<'static> {
+    let size = canvas.size;
+    let (mut left, mut top, mut right, mut bottom) = (size, size, 0, 0);
+    for (i, (a, b)) in previous.iter().zip(&canvas.pixels).enumerate() {
+        if a != b {
+            let (x, y) = (i % size, i / size);
+            left = left.min(x);
+            right = right.max(x);
+            top = top.min(y);
+            bottom = bottom.max(y);
+        }
+    }
+    if left > right {
+        // Nothing has changed, the frame still has to take its time.
+        (left, right, top, bottom) = (0, 0, 0, 0);
+    }
+    let buffer: Vec<u8> = (top..=bottom)
+        .flat_map(|y| canvas.pixels[y * size + left..=y * size + right].iter().copied())
+        .collect();
+    gif::Frame {
+        left: left as u16,
+        top: top as u16,
+        width: (right - left + 1) as u16,
+        height: (bottom - top + 1) as u16,
+        buffer: Cow::Owned(buffer),
+        ..Default::default()
+    }
+}
+
+/// Animated GIF of the parts as rings of hits with a playhead going around each of them, in time with the tempo,
+/// over the bars the parts take to converge. The rings go from the outside inwards in the order of the parts.
+pub fn render_gif(
+    groups: &BTreeMap<DrumPart, Groups>,
+    time_signature: TimeSignature,
+    tempo: u16,
+    size: u16,
+) -> Result<Vec<u8>, String> {
+    if tempo == 0 {
+        return Err("Tempo has to be positive".to_string());
+    }
+    let rings: Vec<Ring> = groups.iter().map(|(part, groups)| ring(*part, groups)).filter(|r| r.cycle > 0).collect();
+    if rings.is_empty() {
+        return Err("No parts to draw".to_string());
+    }
+    let bars = time_signature.converges(groups.values()).map_err(|e| e.to_string())?;
+    let length = (time_signature.to_128th() * 3 * bars) as f64;
+    // A quarter note is 96 384ths.
+    let per_second = tempo as f64 / 60.0 * 96.0;
+    let frames = (length / per_second * FPS as f64).ceil() as u32;
+
+    let mut out = Vec::new();
+    {
+        let mut encoder = gif::Encoder::new(&mut out, size, size, &palette()).map_err(|e| e.to_string())?;
+        encoder.set_repeat(gif::Repeat::Infinite).map_err(|e| e.to_string())?;
+        let mut canvas = Canvas { size: size as usize, pixels: vec![BACKGROUND; size as usize * size as usize] };
+        let mut previous: Option<Vec<u8>> = None;
+        for i in 0..frames {
+            draw(&mut canvas, &rings, i as f64 * per_second / FPS as f64, FLASH * per_second);
+            let frame = match &previous {
+                None => gif::Frame {
+                    width: size,
+                    height: size,
+                    buffer: Cow::Borrowed(&canvas.pixels),
+                    ..Default::default()
+                },
+                Some(previous) => changes(previous, &canvas),
+            };
+            encoder
+                .write_frame(&gif::Frame { delay: (100 / FPS) as u16, dispose: gif::DisposalMethod::Keep, ..frame })
+                .map_err(|e| e.to_string())?;
+            previous = Some(canvas.pixels.clone());
+        }
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+use std::str::FromStr;
+#[cfg(test)]
+use crate::dsl::dsl::groups;
+
+#[test]
+fn test_ring() {
+    let r = ring(DrumPart::SnareDrum, &groups("8-X-g").unwrap());
+    assert_eq!(r, Ring { color: 1, cycle: 192, hits: vec![(48, 7), (144, 3)] });
+}
+
+#[test]
+fn test_draw() {
+    let rings = vec![ring(DrumPart::KickDrum, &groups("4x---").unwrap())];
+    let mut canvas = Canvas { size: 100, pixels: vec![BACKGROUND; 100 * 100] };
+    // The hit is at the top, lit right after the playhead passes it and dim later.
+    draw(&mut canvas, &rings, 10.0, 20.0);
+    assert_eq!(canvas.pixels[12 * 100 + 50], bright(0));
+    draw(&mut canvas, &rings, 100.0, 20.0);
+    assert_eq!(canvas.pixels[12 * 100 + 50], dim(0));
+    // The playhead is a quarter of the way around, on the right.
+    assert_eq!(canvas.pixels[50 * 100 + 88], PLAYHEAD);
+}
+
+#[test]
+fn test_render_gif() {
+    let parts = BTreeMap::from_iter([
+        (DrumPart::KickDrum, groups("8x--x--").unwrap()),
+        (DrumPart::SnareDrum, groups("4-x").unwrap()),
+    ]);
+    let four_four = TimeSignature::from_str("4/4").unwrap();
+    let gif = render_gif(&parts, four_four, 120, 64).unwrap();
+    assert!(gif.starts_with(b"GIF89a"));
+    let mut decoder = gif::DecodeOptions::new().read_info(gif.as_slice()).unwrap();
+    assert_eq!((decoder.width(), decoder.height()), (64, 64));
+    let mut frames = 0;
+    while let Some(frame) = decoder.read_next_frame().unwrap() {
+        assert_eq!(frame.delay, 4);
+        assert!(frame.left + frame.width <= 64 && frame.top + frame.height <= 64);
+        frames += 1;
+    }
+    // 3 bars at 120 BPM are 6 seconds.
+    assert_eq!(frames, 150);
+    assert!(render_gif(&parts, four_four, 0, 64).is_err());
+    assert!(render_gif(&BTreeMap::new(), four_four, 120, 64).is_err());
+}